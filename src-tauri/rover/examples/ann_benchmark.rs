@@ -0,0 +1,127 @@
+//! Sweeps the HNSW parameters and reports recall@k and query latency.
+//! Runs on the CPU without the ONNX models.
+//!
+//! Synthetic data:
+//!     cargo run --release --features bench --example ann_benchmark -- --count 20000 --queries 200 --k 10
+//! Stored feature vectors from an existing database:
+//!     cargo run --release --features bench --example ann_benchmark -- --db /path/to/sqlite.refrover.db
+//! Stored feature vectors of a model other than the default:
+//!     cargo run --release --features bench --example ann_benchmark -- --db /path/to/sqlite.refrover.db --model clip-vit-l-14-336px
+//! Quantized index (f32, f16 or int8):
+//!     cargo run --release --features bench --example ann_benchmark -- --encoding int8
+
+use std::path::PathBuf;
+
+use app::ann::{HnswParams, DEFAULT_EF_CONSTRUCTION, DEFAULT_MAX_NB_CONNECTION, DEFAULT_NB_LAYER};
+use app::ann_benchmark;
+use app::embedding;
+use app::preprocessing::FEATURE_VECTOR_LENGTH;
+use app::quantization::VectorEncoding;
+
+struct Args
+{
+    db: Option<PathBuf>,
+    model: String,
+    count: usize,
+    queries: usize,
+    k: usize,
+    clusters: usize,
+    spread: f32,
+    seed: u64,
+    encoding: VectorEncoding,
+}
+
+fn parse_args() -> anyhow::Result<Args>
+{
+    let mut args = Args {
+        db: None,
+        model: embedding::DEFAULT_MODEL_ID.to_string(),
+        count: 10000,
+        queries: 200,
+        k: 10,
+        clusters: 100,
+        spread: 0.8,
+        seed: 42,
+        encoding: VectorEncoding::F32,
+    };
+
+    let mut iter = std::env::args().skip(1);
+    while let Some(flag) = iter.next()
+    {
+        let mut value = || iter.next().ok_or(anyhow::anyhow!("Missing value for {}", flag));
+        match flag.as_str()
+        {
+            "--db" => args.db = Some(PathBuf::from(value()?)),
+            "--model" => args.model = value()?,
+            "--count" => args.count = value()?.parse()?,
+            "--queries" => args.queries = value()?.parse()?,
+            "--k" => args.k = value()?.parse()?,
+            "--clusters" => args.clusters = value()?.parse()?,
+            "--spread" => args.spread = value()?.parse()?,
+            "--seed" => args.seed = value()?.parse()?,
+            "--encoding" => args.encoding = serde_json::from_value(serde_json::Value::String(value()?))?,
+            _ => return Err(anyhow::anyhow!("Unknown argument {:?}", flag)),
+        }
+    }
+    Ok(args)
+}
+
+fn main() -> anyhow::Result<()>
+{
+    let args = parse_args()?;
+
+    let mut vectors = match &args.db
+    {
+        Some(db) => {
+            println!("Loading stored {} feature vectors from {:?}...", args.model, db);
+            ann_benchmark::load_stored_feature_vectors(db, &args.model)?
+        },
+        None => {
+            println!("Generating {} synthetic {}-d vectors in {} clusters...", args.count + args.queries, FEATURE_VECTOR_LENGTH, args.clusters);
+            ann_benchmark::synthetic_feature_vectors(args.count + args.queries, FEATURE_VECTOR_LENGTH, args.clusters, args.spread, args.seed)
+        }
+    };
+
+    // Hold out the last vectors as queries; they are not inserted into the index.
+    let num_queries = args.queries.min(vectors.len() / 2);
+    let queries = vectors.split_off(vectors.len() - num_queries);
+    println!("{} indexed vectors, {} queries, k = {}, encoding {:?}", vectors.len(), queries.len(), args.k, args.encoding);
+
+    let mut params = Vec::new();
+    for max_nb_connection in [16, 32, DEFAULT_MAX_NB_CONNECTION]
+    {
+        for ef_construction in [100, 200, DEFAULT_EF_CONSTRUCTION, 800]
+        {
+            params.push(HnswParams {
+                max_nb_connection,
+                nb_layer: DEFAULT_NB_LAYER,
+                ef_construction,
+                max_elems: vectors.len(),
+            });
+        }
+    }
+    let ef_args = [args.k, 16, 32, 64, 128, 256];
+
+    println!("Computing exact ground truth...");
+    let ground_truth = ann_benchmark::brute_force_knn(&vectors, &queries, args.k)?;
+
+    println!("{:>6} {:>6} {:>6} {:>8} {:>12} {:>12} {:>12}", "M", "ef_c", "ef", "recall", "build", "mean", "p99");
+    for params in &params
+    {
+        // Run each index configuration separately so results print as they become available.
+        let results = ann_benchmark::run_sweep(&vectors, &queries, &ground_truth, args.k, &[*params], args.encoding, &ef_args)?;
+        for r in results
+        {
+            println!("{:>6} {:>6} {:>6} {:>8.4} {:>12.2?} {:>12.2?} {:>12.2?}",
+                r.params.max_nb_connection,
+                r.params.ef_construction,
+                r.ef_arg,
+                r.recall,
+                r.build_time,
+                r.mean_latency,
+                r.p99_latency);
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,3 @@
+-- Note that any quantized rows can no longer be read after reverting this migration.
+-- Set the vector_encoding setting to "f32" and restart the app before reverting.
+ALTER TABLE image_features_vit_l_14_336_px DROP COLUMN encoding;
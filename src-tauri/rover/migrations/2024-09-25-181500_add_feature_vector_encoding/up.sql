@@ -0,0 +1,5 @@
+-- Records the format of each feature_vector BLOB; see the quantization module.
+-- 0 is a bincode serialized Vec<f32>, which is the format of every existing row.
+-- Rows are converted to the configured format (if it differs) at startup by db::convert_feature_vector_encodings(),
+-- since the conversion can't be expressed in SQL.
+ALTER TABLE image_features_vit_l_14_336_px ADD COLUMN encoding INTEGER NOT NULL DEFAULT 0;
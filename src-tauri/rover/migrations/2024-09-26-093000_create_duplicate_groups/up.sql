@@ -0,0 +1,9 @@
+-- Groups of near-duplicate images, e.g. re-saved or re-cropped copies of the same image.
+-- Each file is in at most one group; files without any near-duplicates are not stored.
+-- The groups are replaced each time duplicate detection runs (see duplicates.rs).
+CREATE TABLE duplicate_groups (
+    file_id VARCHAR(36) PRIMARY KEY NOT NULL,
+    group_id VARCHAR(36) NOT NULL,
+    FOREIGN KEY (file_id) REFERENCES files(id)
+);
+CREATE INDEX duplicate_groups_group_id_index ON duplicate_groups(group_id);
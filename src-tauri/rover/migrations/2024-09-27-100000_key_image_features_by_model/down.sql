@@ -0,0 +1,12 @@
+-- Features of models other than clip-vit-l-14-336px are lost when reverting this migration.
+CREATE TABLE image_features_vit_l_14_336_px (
+    id VARCHAR(36) PRIMARY KEY NOT NULL,
+    feature_vector BLOB NOT NULL,
+    encoding INTEGER NOT NULL DEFAULT 0,
+    FOREIGN KEY (id) REFERENCES files(id)
+);
+
+INSERT INTO image_features_vit_l_14_336_px (id, feature_vector, encoding)
+    SELECT file_id, feature_vector, encoding FROM image_features WHERE model_id = 'clip-vit-l-14-336px';
+
+DROP TABLE image_features;
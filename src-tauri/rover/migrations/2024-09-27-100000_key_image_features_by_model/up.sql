@@ -0,0 +1,21 @@
+-- Stores feature vectors keyed by the ID of the embedding model which produced them (see embedding.rs),
+-- so that the feature spaces of different models can be stored side by side.
+-- This replaces image_features_vit_l_14_336_px, whose rows are the features of the
+-- "clip-vit-l-14-336px" model.
+--
+-- The referenced file is expected to be an image file, but this is not enforced within the DB.
+CREATE TABLE image_features (
+    file_id VARCHAR(36) NOT NULL,
+    model_id TEXT NOT NULL,
+    feature_vector BLOB NOT NULL,
+    -- See quantization::VectorEncoding.
+    encoding INTEGER NOT NULL DEFAULT 0,
+    PRIMARY KEY (file_id, model_id),
+    FOREIGN KEY (file_id) REFERENCES files(id)
+);
+CREATE INDEX image_features_model_id_index ON image_features(model_id);
+
+INSERT INTO image_features (file_id, model_id, feature_vector, encoding)
+    SELECT id, 'clip-vit-l-14-336px', feature_vector, encoding FROM image_features_vit_l_14_336_px;
+
+DROP TABLE image_features_vit_l_14_336_px;
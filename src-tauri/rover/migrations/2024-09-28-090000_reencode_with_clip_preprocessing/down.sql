@@ -0,0 +1,2 @@
+-- The deleted features can't be restored; the files are re-encoded on startup.
+SELECT 1;
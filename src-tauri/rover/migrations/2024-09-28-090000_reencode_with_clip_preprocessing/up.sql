@@ -0,0 +1,8 @@
+-- The clip-vit-l-14-336px features were encoded from images which were resized to fit within 336x336
+-- and padded, without CLIP's mean/std normalization. Now that preprocessing matches CLIP's reference
+-- (shortest-side resize, center crop, normalization), those features are not comparable to text features
+-- or to newly encoded images, so they are deleted; files without features for the active model are
+-- re-encoded on startup (see embedding::encode_files_without_features()).
+DELETE FROM image_features WHERE model_id = 'clip-vit-l-14-336px';
+-- The groups were found by comparing the old features.
+DELETE FROM duplicate_groups;
@@ -0,0 +1,10 @@
+-- Files waiting to be encoded with the active model, worked through by the encoding worker (see encoding_queue.rs).
+-- The queue is persistent, so encoding picks up where it left off when the app is restarted.
+-- Jobs with a higher priority are encoded first; jobs of the same priority in the order they were queued (by id).
+CREATE TABLE encoding_jobs (
+    id INTEGER PRIMARY KEY NOT NULL,
+    file_id VARCHAR(36) NOT NULL UNIQUE,
+    priority INTEGER NOT NULL DEFAULT 0,
+    FOREIGN KEY (file_id) REFERENCES files(id)
+);
+CREATE INDEX encoding_jobs_priority_index ON encoding_jobs(priority DESC, id);
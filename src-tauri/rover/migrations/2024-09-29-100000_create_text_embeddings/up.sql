@@ -0,0 +1,12 @@
+-- Text embeddings of search queries, keyed by the embedding model's ID and the normalized query,
+-- so that repeated queries skip inference across restarts (see text_embedding_cache.rs).
+-- Feature vectors are stored as f32 (see quantization::VectorEncoding).
+-- The least recently used rows are removed to keep the table to a configured size.
+CREATE TABLE text_embeddings (
+    model_id TEXT NOT NULL,
+    query TEXT NOT NULL,
+    feature_vector BLOB NOT NULL,
+    used_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (model_id, query)
+);
+CREATE INDEX text_embeddings_used_at_index ON text_embeddings(used_at);
@@ -0,0 +1,17 @@
+-- Feature vectors of tiles of images (see regions.rs), with the tile's bounding box in pixels of the image.
+-- Each row has its own ID, which is its key in the search index; a file has any number of regions per model.
+CREATE TABLE region_features (
+    id VARCHAR(36) PRIMARY KEY NOT NULL,
+    file_id VARCHAR(36) NOT NULL,
+    model_id TEXT NOT NULL,
+    x INTEGER NOT NULL,
+    y INTEGER NOT NULL,
+    width INTEGER NOT NULL,
+    height INTEGER NOT NULL,
+    feature_vector BLOB NOT NULL,
+    -- See quantization::VectorEncoding.
+    encoding INTEGER NOT NULL DEFAULT 0,
+    FOREIGN KEY (file_id) REFERENCES files(id)
+);
+CREATE INDEX region_features_file_id_model_id_index ON region_features(file_id, model_id);
+CREATE INDEX region_features_model_id_index ON region_features(model_id);
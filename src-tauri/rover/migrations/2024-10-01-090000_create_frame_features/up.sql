@@ -0,0 +1,17 @@
+-- Feature vectors of frames of animations (see animation.rs), with the frame's index and when it is shown.
+-- Each row has its own ID, which is its key in the search index; a file has any number of frames per model.
+-- The first frame is encoded as the image itself, in image_features.
+CREATE TABLE frame_features (
+    id VARCHAR(36) PRIMARY KEY NOT NULL,
+    file_id VARCHAR(36) NOT NULL,
+    model_id TEXT NOT NULL,
+    frame_index INTEGER NOT NULL,
+    -- Milliseconds from the start of the animation.
+    timestamp_ms BIGINT NOT NULL,
+    feature_vector BLOB NOT NULL,
+    -- See quantization::VectorEncoding.
+    encoding INTEGER NOT NULL DEFAULT 0,
+    FOREIGN KEY (file_id) REFERENCES files(id)
+);
+CREATE INDEX frame_features_file_id_model_id_index ON frame_features(file_id, model_id);
+CREATE INDEX frame_features_model_id_index ON frame_features(model_id);
@@ -0,0 +1 @@
+ALTER TABLE watched_directories DROP COLUMN indexed_extensions;
@@ -0,0 +1,3 @@
+-- The extensions of the files indexed in each watched directory, as a JSON array; see the file_filter module.
+-- NULL uses the indexed_extensions setting, which is the case for every existing row.
+ALTER TABLE watched_directories ADD COLUMN indexed_extensions TEXT;
@@ -0,0 +1 @@
+ALTER TABLE failed_encodings DROP COLUMN kind;
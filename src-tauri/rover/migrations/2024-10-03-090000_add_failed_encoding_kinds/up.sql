@@ -0,0 +1,14 @@
+-- The kind of each failure (see FailedEncodingKind::to_db() in failed_encodings.rs), determined from the typed error
+-- when it is recorded. The error message is only displayed.
+ALTER TABLE failed_encodings ADD COLUMN kind INTEGER NOT NULL DEFAULT 6;
+
+-- Failures recorded before the kind was stored are classified once, by their message.
+UPDATE failed_encodings SET kind = CASE
+    WHEN error LIKE 'Error encoding image%' THEN 5
+    WHEN error LIKE '%kind: NotFound%' THEN 0
+    WHEN error LIKE '%Unsupported(%' THEN 1
+    WHEN error LIKE '%Decoding(%' THEN 2
+    WHEN error LIKE '%Limits(%' THEN 3
+    WHEN error LIKE '%IoError(%' THEN 4
+    ELSE 6
+END;
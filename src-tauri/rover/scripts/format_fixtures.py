@@ -0,0 +1,117 @@
+"""
+Writes the Photoshop (.psd) and Krita (.kra) test fixtures, for the tests in src/image_formats.rs.
+
+The fixtures are tiny, so that they can be checked in without git LFS. Each is a 32x16 image whose left half is
+red and whose right half is blue. The Krita document's preview is a 16x8 green image, so tests can tell which of
+the two embedded images was read. Only the standard library is needed:
+
+    python scripts/format_fixtures.py
+
+Run from src-tauri/rover.
+"""
+
+import struct
+import zipfile
+import zlib
+from pathlib import Path
+
+TEST_IMAGES = Path(__file__).resolve().parent.parent / "test_images"
+WIDTH = 32
+HEIGHT = 16
+RED = (255, 0, 0)
+BLUE = (0, 0, 255)
+GREEN = (0, 255, 0)
+
+
+def split_image(width, height):
+    """Rows of RGB pixels, red on the left half and blue on the right."""
+    return [[RED if x < width // 2 else BLUE for x in range(width)] for _ in range(height)]
+
+
+def solid_image(width, height, color):
+    return [[color] * width for _ in range(height)]
+
+
+def packbits(row):
+    """PackBits encodes a row of bytes, as Photoshop compresses image data."""
+    out = bytearray()
+    i = 0
+    while i < len(row):
+        run = 1
+        while i + run < len(row) and run < 128 and row[i + run] == row[i]:
+            run += 1
+        if run > 1:
+            out += struct.pack(">b", 1 - run) + bytes([row[i]])
+            i += run
+        else:
+            literal_end = i + 1
+            while literal_end < len(row) and literal_end - i < 128 and (
+                literal_end + 1 >= len(row) or row[literal_end] != row[literal_end + 1]
+            ):
+                literal_end += 1
+            out += struct.pack(">b", literal_end - i - 1) + bytes(row[i:literal_end])
+            i = literal_end
+    return bytes(out)
+
+
+def psd(pixels):
+    """A flattened 8 bit RGB Photoshop document with RLE compressed image data, and no layers or resources."""
+    height = len(pixels)
+    width = len(pixels[0])
+    channels = 3
+    header = b"8BPS" + struct.pack(">H6xHIIHH", 1, channels, height, width, 8, 3)
+    color_mode_data = struct.pack(">I", 0)
+    image_resources = struct.pack(">I", 0)
+    layer_and_mask_info = struct.pack(">I", 0)
+
+    rows = [packbits(bytes(pixel[channel] for pixel in row)) for channel in range(channels) for row in pixels]
+    byte_counts = b"".join(struct.pack(">H", len(row)) for row in rows)
+    image_data = struct.pack(">H", 1) + byte_counts + b"".join(rows)
+    return header + color_mode_data + image_resources + layer_and_mask_info + image_data
+
+
+def png(pixels):
+    height = len(pixels)
+    width = len(pixels[0])
+
+    def chunk(kind, data):
+        return struct.pack(">I", len(data)) + kind + data + struct.pack(">I", zlib.crc32(kind + data))
+
+    raw = b"".join(b"\x00" + bytes(channel for pixel in row for channel in pixel) for row in pixels)
+    return (
+        b"\x89PNG\r\n\x1a\n"
+        + chunk(b"IHDR", struct.pack(">IIBBBBB", width, height, 8, 2, 0, 0, 0))
+        + chunk(b"IDAT", zlib.compress(raw))
+        + chunk(b"IEND", b"")
+    )
+
+
+def kra(merged, preview):
+    """A Krita document: a zip whose first entry is its (uncompressed) mimetype, with the merged image and a preview."""
+    path = TEST_IMAGES / "split.kra"
+    entries = [
+        ("mimetype", b"application/x-krita", zipfile.ZIP_STORED),
+        ("maindoc.xml", b'<?xml version="1.0" encoding="UTF-8"?>\n<DOC syntaxVersion="2"/>\n', zipfile.ZIP_DEFLATED),
+        ("mergedimage.png", png(merged), zipfile.ZIP_DEFLATED),
+        ("preview.png", png(preview), zipfile.ZIP_DEFLATED),
+    ]
+    with zipfile.ZipFile(path, "w") as archive:
+        for name, data, compression in entries:
+            # A fixed timestamp, so that running this again writes the same file.
+            info = zipfile.ZipInfo(name, date_time=(2024, 1, 1, 0, 0, 0))
+            info.compress_type = compression
+            archive.writestr(info, data)
+    return path
+
+
+def main():
+    psd_path = TEST_IMAGES / "split.psd"
+    psd_path.write_bytes(psd(split_image(WIDTH, HEIGHT)))
+    print(f"Wrote {psd_path}")
+
+    kra_path = kra(split_image(WIDTH, HEIGHT), solid_image(WIDTH // 2, HEIGHT // 2, GREEN))
+    print(f"Wrote {kra_path}")
+
+
+if __name__ == "__main__":
+    main()
@@ -0,0 +1,44 @@
+"""
+Writes the reference CLIP embeddings of the test images, for the golden-value tests in src/clip.rs.
+
+Uses OpenAI's reference implementation, so that our preprocessing (src/preprocessing.rs) and
+ONNX models can be checked against it:
+
+    pip install torch pillow git+https://github.com/openai/CLIP.git
+    python scripts/reference_embeddings.py
+
+Run from src-tauri/rover, after pulling the test images with git LFS.
+"""
+
+import json
+from pathlib import Path
+
+import clip
+import torch
+from PIL import Image
+
+MODEL_NAME = "ViT-L/14@336px"
+TEST_IMAGES = Path(__file__).resolve().parent.parent / "test_images"
+IMAGES = ["duck.jpg"]
+
+
+def main():
+    model, preprocess = clip.load(MODEL_NAME, device="cpu")
+    model.eval()
+    for filename in IMAGES:
+        image = preprocess(Image.open(TEST_IMAGES / filename)).unsqueeze(0)
+        with torch.no_grad():
+            features = model.encode_image(image).float()
+        # Our models output L2 normalized feature vectors; see src/clip.rs.
+        features = features / features.norm(dim=-1, keepdim=True)
+        out_path = TEST_IMAGES / (Path(filename).stem + "_reference_embedding.json")
+        out_path.write_text(json.dumps({
+            "model": MODEL_NAME,
+            "image": filename,
+            "embedding": features[0].tolist(),
+        }))
+        print(f"Wrote {out_path}")
+
+
+if __name__ == "__main__":
+    main()
@@ -0,0 +1,414 @@
+/// Animated images (GIF and WebP): sampling their frames for encoding, reading their frame count and duration,
+/// and writing animated thumbnails.
+///
+/// Loading an image decodes only the first frame of an animation, which often says little about the rest of it
+/// (e.g. a walk cycle reference which starts on a title card). When enabled, the encoding pipeline also encodes a few
+/// frames sampled evenly through the animation, stored in the frame_features table with their index and timestamp.
+/// Frames are added to the search index under their own IDs, like regions (see regions.rs); a search matching a frame
+/// returns its file, with the frame which matched so the frontend can seek to it.
+
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, Write};
+use std::path::Path;
+
+use image::codecs::gif::GifDecoder;
+use image::codecs::webp::{WebPDecoder, WebPEncoder};
+use image::{AnimationDecoder, DynamicImage, ExtendedColorType, ImageDecoder, ImageFormat, ImageResult, RgbImage};
+use serde::{Deserialize, Serialize};
+
+use crate::image_loading;
+use crate::uuid::UUID;
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct AnimationSettings
+{
+    /// Whether the frames of animations are encoded. Each frame is encoded like an image, so this multiplies
+    /// the encoding time and index size of animations by up to max_frames; it is off by default.
+    /// Files which were encoded while this was disabled are not encoded again.
+    pub enabled: bool,
+    /// The most frames encoded per animation, including the first (which is encoded as the image itself).
+    pub max_frames: u32,
+    /// The most frames in an animated thumbnail; longer animations are sampled evenly. 1 or less makes still thumbnails.
+    pub max_thumbnail_frames: u32,
+}
+
+impl Default for AnimationSettings
+{
+    fn default() -> Self
+    {
+        AnimationSettings { enabled: false, max_frames: 8, max_thumbnail_frames: 48 }
+    }
+}
+
+/// A frame of an animation: its index, and when it is shown, in milliseconds from the start.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Frame
+{
+    pub index: u32,
+    pub timestamp_ms: u64,
+}
+
+/// The file of a frame feature vector in the search index, and which frame of the file it is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileFrame
+{
+    pub file_id: UUID,
+    pub frame: Frame,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AnimationInfo
+{
+    pub frame_count: u32,
+    /// The total duration of one loop of the animation.
+    pub duration_ms: u64,
+}
+
+/// Browsers play frames with no delay at this delay, so we do too.
+const DEFAULT_FRAME_DELAY_MS: u64 = 100;
+
+/// The time a frame with the given delay is shown for.
+fn frame_delay_ms(delay_ms: u64) -> u64
+{
+    if delay_ms == 0 { DEFAULT_FRAME_DELAY_MS } else { delay_ms }
+}
+
+fn animation_format(path: &Path) -> Option<ImageFormat>
+{
+    match ImageFormat::from_path(path)
+    {
+        Ok(format @ (ImageFormat::Gif | ImageFormat::WebP)) => Some(format),
+        _ => None,
+    }
+}
+
+/// Reads the frame count and duration of an animated GIF or WebP, without decoding its frames.
+/// None for other files, and for GIFs and WebPs with a single frame.
+pub fn animation_info(path: &Path) -> Option<AnimationInfo>
+{
+    let mut reader = BufReader::new(File::open(path).ok()?);
+    let info = match animation_format(path)?
+    {
+        ImageFormat::Gif => read_gif_info(&mut reader),
+        _ => read_webp_info(&mut reader),
+    };
+    info.ok().filter(|info| info.frame_count > 1)
+}
+
+fn read_u8<R: Read>(reader: &mut R) -> io::Result<u8>
+{
+    let mut byte = [0u8; 1];
+    reader.read_exact(&mut byte)?;
+    Ok(byte[0])
+}
+
+/// Walks the blocks of a GIF, counting its images and summing the delays of their graphic control extensions.
+fn read_gif_info<R: Read + Seek>(reader: &mut BufReader<R>) -> io::Result<AnimationInfo>
+{
+    // The header and logical screen descriptor, then the global color table.
+    let mut header = [0u8; 13];
+    reader.read_exact(&mut header)?;
+    if &header[..3] != b"GIF" {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Not a GIF"));
+    }
+    let flags = header[10];
+    if flags & 0x80 != 0 {
+        reader.seek_relative(3 << ((flags & 0x07) + 1))?;
+    }
+
+    let mut info = AnimationInfo { frame_count: 0, duration_ms: 0 };
+    let mut delay_ms = 0;
+    loop
+    {
+        let block = match read_u8(reader)
+        {
+            Ok(block) => block,
+            // Some encoders leave out the trailer.
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        };
+        match block
+        {
+            // Extension
+            0x21 => {
+                if read_u8(reader)? == 0xF9 {
+                    // Graphic control extension: size, flags, delay in hundredths of a second, transparent index.
+                    let mut control = [0u8; 5];
+                    reader.read_exact(&mut control)?;
+                    delay_ms = u16::from_le_bytes([control[2], control[3]]) as u64 * 10;
+                }
+                skip_gif_sub_blocks(reader)?;
+            },
+            // Image descriptor, then the local color table and the image data.
+            0x2C => {
+                let mut descriptor = [0u8; 9];
+                reader.read_exact(&mut descriptor)?;
+                let flags = descriptor[8];
+                if flags & 0x80 != 0 {
+                    reader.seek_relative(3 << ((flags & 0x07) + 1))?;
+                }
+                // LZW minimum code size
+                read_u8(reader)?;
+                skip_gif_sub_blocks(reader)?;
+                info.frame_count += 1;
+                info.duration_ms += frame_delay_ms(delay_ms);
+                delay_ms = 0;
+            },
+            // Trailer
+            0x3B => break,
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "Unknown GIF block")),
+        }
+    }
+    Ok(info)
+}
+
+fn skip_gif_sub_blocks<R: Read + Seek>(reader: &mut BufReader<R>) -> io::Result<()>
+{
+    loop
+    {
+        let size = read_u8(reader)?;
+        if size == 0 {
+            return Ok(());
+        }
+        reader.seek_relative(size as i64)?;
+    }
+}
+
+/// Walks the chunks of a WebP, counting its ANMF (animation frame) chunks and summing their durations.
+fn read_webp_info<R: Read + Seek>(reader: &mut BufReader<R>) -> io::Result<AnimationInfo>
+{
+    let mut header = [0u8; 12];
+    reader.read_exact(&mut header)?;
+    if &header[..4] != b"RIFF" || &header[8..] != b"WEBP" {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Not a WebP"));
+    }
+
+    let mut info = AnimationInfo { frame_count: 0, duration_ms: 0 };
+    let mut chunk_header = [0u8; 8];
+    // The file may end after any chunk.
+    while reader.read_exact(&mut chunk_header).is_ok()
+    {
+        let size = u32::from_le_bytes([chunk_header[4], chunk_header[5], chunk_header[6], chunk_header[7]]) as i64;
+        let mut skip = size + size % 2;
+        if &chunk_header[..4] == b"ANMF" {
+            // The frame's offset and size (3 bytes each), then its duration.
+            let mut frame_header = [0u8; 15];
+            reader.read_exact(&mut frame_header)?;
+            info.frame_count += 1;
+            info.duration_ms += frame_delay_ms(u32::from_le_bytes([frame_header[12], frame_header[13], frame_header[14], 0]) as u64);
+            skip -= frame_header.len() as i64;
+        }
+        reader.seek_relative(skip)?;
+    }
+    Ok(info)
+}
+
+/// The indices of up to `max_frames` frames spread evenly through the animation, starting with the first.
+pub fn sample_frame_indices(frame_count: u32, max_frames: u32) -> Vec<u32>
+{
+    let samples = frame_count.min(max_frames).max(1) as u64;
+    (0..samples).map(|i| (i * frame_count as u64 / samples) as u32).collect()
+}
+
+/// Decodes the frames with the given (ascending) indices of an animated GIF or WebP.
+/// Only the requested frames are kept, so memory use does not depend on the length of the animation.
+/// Decoding is limited like that of other images (see image_loading::decode_limits()), including the kept frames.
+pub fn load_frames(path: &Path, indices: &[u32]) -> ImageResult<Vec<(Frame, DynamicImage)>>
+{
+    if indices.is_empty() {
+        return Ok(Vec::new());
+    }
+    let reader = BufReader::new(File::open(path)?);
+    let frames = match animation_format(path)
+    {
+        Some(ImageFormat::Gif) => {
+            let mut decoder = GifDecoder::new(reader)?;
+            limit_frames(&mut decoder, indices.len())?;
+            decoder.into_frames()
+        },
+        _ => {
+            let mut decoder = WebPDecoder::new(reader)?;
+            limit_frames(&mut decoder, indices.len())?;
+            decoder.into_frames()
+        },
+    };
+
+    let mut loaded = Vec::with_capacity(indices.len());
+    let mut timestamp_ms = 0;
+    let mut indices = indices.iter().peekable();
+    for (index, frame) in frames.enumerate()
+    {
+        let Some(next) = indices.peek() else { break };
+        let frame = frame?;
+        let (numerator, denominator) = frame.delay().numer_denom_ms();
+        if index as u32 == **next {
+            indices.next();
+            loaded.push((Frame { index: index as u32, timestamp_ms }, DynamicImage::ImageRgba8(frame.into_buffer())));
+        }
+        timestamp_ms += frame_delay_ms((numerator / denominator.max(1)) as u64);
+    }
+    Ok(loaded)
+}
+
+/// Checks that `frame_count` frames of the animation, each an RGBA buffer of its full size, fit within the decode limits
+/// before any are decoded, and leaves the rest of the limits to the decoder. The GIF decoder checks the buffer of each
+/// frame it decodes against them; the WebP decoder only checks the animation's dimensions.
+fn limit_frames(decoder: &mut impl ImageDecoder, frame_count: usize) -> ImageResult<()>
+{
+    let (width, height) = decoder.dimensions();
+    let frame_bytes = u64::from(width) * u64::from(height) * 4;
+    let mut limits = image_loading::decode_limits();
+    limits.reserve(frame_bytes.saturating_mul(frame_count as u64))?;
+    decoder.set_limits(limits)
+}
+
+/// The time each sampled frame is shown for: until the next sampled frame, and until the end of the animation for the last.
+pub fn frame_durations(frames: &[Frame], info: &AnimationInfo) -> Vec<u64>
+{
+    frames.iter().enumerate().map(|(i, frame)| {
+        let end = frames.get(i + 1).map_or(info.duration_ms, |next| next.timestamp_ms);
+        frame_delay_ms(end.saturating_sub(frame.timestamp_ms))
+    }).collect()
+}
+
+/// Writes the frames as a looping animated WebP, each shown for its duration in milliseconds.
+/// The frames must have the same size.
+///
+/// The image crate can only encode still WebPs, so each frame is encoded as a lossless still image, and its bitstream
+/// is wrapped in an animation frame (ANMF) chunk; see https://developers.google.com/speed/webp/docs/riff_container.
+pub fn write_animated_webp<W: Write>(writer: &mut W, frames: &[(RgbImage, u64)]) -> ImageResult<()>
+{
+    let (width, height) = frames.first().map_or((1, 1), |(frame, _)| frame.dimensions());
+    let u24 = |value: u32| value.to_le_bytes()[..3].to_vec();
+
+    let mut chunks = Vec::new();
+    // VP8X: the animation flag, then the canvas size.
+    let mut vp8x = vec![0x02, 0, 0, 0];
+    vp8x.extend(u24(width - 1));
+    vp8x.extend(u24(height - 1));
+    write_chunk(&mut chunks, b"VP8X", &vp8x);
+    // ANIM: a white background, looping forever.
+    write_chunk(&mut chunks, b"ANIM", &[255, 255, 255, 255, 0, 0]);
+    for (frame, duration_ms) in frames
+    {
+        let mut still = Vec::new();
+        WebPEncoder::new_lossless(&mut still).encode(frame.as_raw(), frame.width(), frame.height(), ExtendedColorType::Rgb8)?;
+        // The still image's chunks follow the 12 byte RIFF header.
+        let mut anmf = vec![0; 6];
+        anmf.extend(u24(frame.width() - 1));
+        anmf.extend(u24(frame.height() - 1));
+        anmf.extend(u24((*duration_ms).min(0xFF_FFFF) as u32));
+        // Don't blend with the previous frame, since every frame covers the canvas.
+        anmf.push(0x02);
+        anmf.extend(&still[12..]);
+        write_chunk(&mut chunks, b"ANMF", &anmf);
+    }
+
+    writer.write_all(b"RIFF")?;
+    writer.write_all(&(chunks.len() as u32 + 4).to_le_bytes())?;
+    writer.write_all(b"WEBP")?;
+    writer.write_all(&chunks)?;
+    Ok(())
+}
+
+/// Writes a RIFF chunk, padded to an even length.
+fn write_chunk(out: &mut Vec<u8>, fourcc: &[u8; 4], data: &[u8])
+{
+    out.extend(fourcc);
+    out.extend((data.len() as u32).to_le_bytes());
+    out.extend(data);
+    if data.len() % 2 == 1 {
+        out.push(0);
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use image::codecs::gif::GifEncoder;
+    use image::{Delay, GenericImageView, Rgb, Rgba, RgbaImage};
+
+    use crate::test_files::TempDir;
+
+    use super::*;
+
+    const COLORS: [[u8; 3]; 4] = [[255, 0, 0], [0, 255, 0], [0, 0, 255], [255, 255, 0]];
+
+    /// Writes a GIF of solid color frames, each shown for `delay_ms`.
+    fn write_gif(path: &Path, delay_ms: u32)
+    {
+        let frames = COLORS.iter().map(|color| {
+            let buffer = RgbaImage::from_pixel(8, 4, Rgba([color[0], color[1], color[2], 255]));
+            image::Frame::from_parts(buffer, 0, 0, Delay::from_numer_denom_ms(delay_ms, 1))
+        });
+        GifEncoder::new(File::create(path).unwrap()).encode_frames(frames).unwrap();
+    }
+
+    #[test]
+    fn gif_frames_are_sampled()
+    {
+        let dir = TempDir::new("animation");
+        let path = dir.join("animation.gif");
+        write_gif(&path, 50);
+
+        assert_eq!(animation_info(&path), Some(AnimationInfo { frame_count: 4, duration_ms: 200 }));
+        let indices = sample_frame_indices(4, 3);
+        assert_eq!(indices, vec![0, 1, 2]);
+        let frames = load_frames(&path, &[1, 3]).unwrap();
+        let frames: Vec<(Frame, Rgba<u8>)> = frames.iter().map(|(frame, image)| (*frame, image.get_pixel(0, 0))).collect();
+        assert_eq!(frames, vec![
+            (Frame { index: 1, timestamp_ms: 50 }, Rgba([0, 255, 0, 255])),
+            (Frame { index: 3, timestamp_ms: 150 }, Rgba([255, 255, 0, 255])),
+        ]);
+    }
+
+    #[test]
+    fn gif_frames_without_a_delay_are_shown_at_the_default_delay()
+    {
+        let dir = TempDir::new("animation");
+        let path = dir.join("animation.gif");
+        write_gif(&path, 0);
+
+        let info = animation_info(&path).unwrap();
+        assert_eq!(info, AnimationInfo { frame_count: 4, duration_ms: 400 });
+        let frames: Vec<Frame> = load_frames(&path, &[0, 2]).unwrap().into_iter().map(|(frame, _)| frame).collect();
+        assert_eq!(frames, vec![Frame { index: 0, timestamp_ms: 0 }, Frame { index: 2, timestamp_ms: 200 }]);
+        assert_eq!(frame_durations(&frames, &info), vec![200, 200]);
+    }
+
+    #[test]
+    fn frame_sampling_and_durations()
+    {
+        assert_eq!(sample_frame_indices(100, 4), vec![0, 25, 50, 75]);
+        assert_eq!(sample_frame_indices(2, 8), vec![0, 1]);
+
+        let info = AnimationInfo { frame_count: 100, duration_ms: 1000 };
+        let frames = [Frame { index: 0, timestamp_ms: 0 }, Frame { index: 50, timestamp_ms: 600 }];
+        assert_eq!(frame_durations(&frames, &info), vec![600, 400]);
+        // Frames without a delay are shown at the default delay.
+        let frames = [Frame { index: 0, timestamp_ms: 0 }, Frame { index: 1, timestamp_ms: 0 }];
+        assert_eq!(frame_durations(&frames, &AnimationInfo { frame_count: 2, duration_ms: 0 }), vec![100, 100]);
+    }
+
+    #[test]
+    fn animated_webp_thumbnails_can_be_read_back()
+    {
+        let frames: Vec<(RgbImage, u64)> = COLORS.iter().map(|color| (RgbImage::from_pixel(6, 3, Rgb(*color)), 80)).collect();
+        let dir = TempDir::new("animation");
+        let path = dir.join("animation.webp");
+        let mut file = File::create(&path).unwrap();
+        write_animated_webp(&mut file, &frames).unwrap();
+        drop(file);
+
+        assert_eq!(animation_info(&path), Some(AnimationInfo { frame_count: 4, duration_ms: 320 }));
+        let decoded = load_frames(&path, &[0, 1, 2, 3]).unwrap();
+        assert_eq!(decoded.len(), 4);
+        for ((frame, image), color) in decoded.iter().zip(COLORS)
+        {
+            assert_eq!(frame.timestamp_ms, frame.index as u64 * 80);
+            assert_eq!(image.dimensions(), (6, 3));
+            assert_eq!(image.get_pixel(5, 2), Rgba([color[0], color[1], color[2], 255]));
+        }
+    }
+}
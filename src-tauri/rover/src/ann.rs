@@ -10,12 +10,24 @@ use anyhow::{Context, Ok};
 /// It can also easily be used for reverse image search by using the output of
 /// encode_image() as the search vector instead.
 
-use diesel::{query_dsl::methods::SelectDsl, RunQueryDsl, SelectableHelper};
-use hnsw_rs::{hnsw::Hnsw, prelude::DistCosine};
-use rustc_hash::FxHashMap;
-use tauri::{App, Manager};
+use std::fs;
+use std::path::Path;
+use std::thread::{self, JoinHandle};
 
-use crate::{db, models::ImageFeatureVitL14336Px, schema::image_features_vit_l_14_336_px, state::{ConnectionPoolState, SearchState}, uuid::UUID};
+use half::f16;
+use hnsw_rs::{api::AnnT, filter::FilterT, hnsw::Hnsw, hnswio::HnswIo, prelude::DistCosine};
+use log::{error, info};
+use rustc_hash::{FxHashMap, FxHashSet};
+use serde::{Deserialize, Serialize};
+use tauri::{AppHandle, Manager};
+
+use crate::events::Event;
+use crate::flat_index::FlatIndex;
+use crate::interface::IndexBuildProgress;
+use crate::quantization::{self, DistCosineF16, DistCosineI8, VectorEncoding};
+use crate::animation::{FileFrame, Frame};
+use crate::regions::{FileRegion, Region};
+use crate::{models::{FrameFeature, ImageFeature, RegionFeature}, queries, state::{ConnectionPoolState, SearchState}, uuid::UUID};
 
 // The maximum number of links from one point to others.
 // Values from 16 to 64 are standard, with higher being more time consuming.
@@ -28,59 +40,218 @@ pub const DEFAULT_NB_LAYER: usize = 16;
 pub const DEFAULT_EF_CONSTRUCTION: usize = 400;
 pub const DEFAULT_MAX_ELEMS: usize = 10000;
 
+/// Construction parameters for the HNSW index.
+/// See the constants above for a description of each parameter.
+/// The ann_benchmark harness (`cargo run --release --features bench --example ann_benchmark`) reports the recall, build time and latency
+/// of other values against your own library.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HnswParams {
+    pub max_nb_connection: usize,
+    pub nb_layer: usize,
+    pub ef_construction: usize,
+    pub max_elems: usize,
+}
+
+impl Default for HnswParams
+{
+    fn default() -> Self
+    {
+        HnswParams {
+            max_nb_connection: DEFAULT_MAX_NB_CONNECTION,
+            nb_layer: DEFAULT_NB_LAYER,
+            ef_construction: DEFAULT_EF_CONSTRUCTION,
+            max_elems: DEFAULT_MAX_ELEMS,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct HnswElement {
     pub feature_vector: Vec<f32>,
     pub id: UUID,
 }
 
+/// A nearest neighbor index over feature vectors, keyed by the UUIDs of the files in the database.
+/// Feature vectors are passed in and out as f32; an implementation may hold them in another format.
+///
+/// The backend is chosen at runtime with the index_backend setting (see new_index()).
+pub trait VectorIndex: Send
+{
+    /// Adds the elements to the index. Inserting an ID which is already in the index replaces its feature vector.
+    fn insert(&mut self, data: Vec<HnswElement>);
+
+    /// Removes the elements with the given IDs from the index. IDs which are not in the index are ignored.
+    fn remove(&mut self, ids: &[UUID]);
+
+    /// Returns the knbn nearest neighbors with a cosine distance less than distance_threshold, nearest first.
+    /// Only elements for which `filter` returns true are considered, so the filter does not reduce the number of results.
+    ///
+    /// @param  ef_arg This parameter controls the width of the search in the lowest level of an HNSW index,
+    /// it MUST be greater than number of neighbours asked (knbn) but CAN be less than DEFAULT_EF_CONSTRUCTION.
+    /// As a rule of thumb could be between the number of neighbours we will ask for (knbn arg in search method) and DEFAULT_MAX_NB_CONNECTION.
+    /// It does not limit the number of neighbours returned; recall will be lower if ef_arg is lower, but search is slower with high ef_arg.
+    /// Exact indices ignore it.
+    /// @param distance_threshold the value that the distance must be less than to be included in the results.
+    /// Range of cosine distance is from 0 to 2, 0 — identical vectors, 1 — no correlation, 2 — absolutely different.
+    /// In practice, due to high-dimensional feature vectors, ~0.79 will be very semantically similar,
+    /// and ~0.85 will be very semantically different (this is a rough estimate, check for a given dataset).
+    fn search_filtered(
+        &self,
+        query: &[f32],
+        knbn: usize,
+        ef_arg: usize,
+        distance_threshold: f32,
+        filter: &dyn Fn(&UUID) -> bool) -> Vec<(UUID, f32)>;
+
+    /// Returns the knbn nearest neighbors with a cosine distance less than distance_threshold, nearest first.
+    /// See search_filtered().
+    fn search(&self, query: &[f32], knbn: usize, ef_arg: usize, distance_threshold: f32) -> Vec<(UUID, f32)>
+    {
+        self.search_filtered(query, knbn, ef_arg, distance_threshold, &|_| true)
+    }
+
+    /// The number of elements in the index.
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool
+    {
+        self.len() == 0
+    }
+
+    /// Writes the index to files within `directory`, which is created if necessary.
+    fn save(&self, directory: &Path) -> anyhow::Result<()>;
+
+    /// Reads an index written by save().
+    fn load(directory: &Path) -> anyhow::Result<Self> where Self: Sized;
+}
+
+/// The implementation of VectorIndex used for searches.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum IndexBackend
+{
+    /// Approximate search with HNSW; fast for large libraries.
+    #[default]
+    Hnsw,
+    /// Exact search by brute force; see flat_index.rs.
+    Flat,
+}
+
+/// Creates an empty index with the given backend.
+/// The encoding determines the format of the feature vectors held by an HNSW index; the flat index always holds f32.
+pub fn new_index(backend: IndexBackend, encoding: VectorEncoding) -> Box<dyn VectorIndex>
+{
+    match backend
+    {
+        IndexBackend::Hnsw => Box::new(HnswSearch::new(encoding)),
+        IndexBackend::Flat => Box::new(FlatIndex::new()),
+    }
+}
+
+/// The underlying hnsw_rs index, with its components stored according to a VectorEncoding.
+/// Feature vectors are always passed in and out as f32; they are quantized on insertion (and for queries).
+enum HnswIndex<'a>
+{
+    F32(Hnsw<'a, f32, DistCosine>),
+    F16(Hnsw<'a, f16, DistCosineF16>),
+    Int8(Hnsw<'a, i8, DistCosineI8>),
+}
+
+const HNSW_BASENAME: &str = "hnsw";
+const HNSW_METADATA_FILENAME: &str = "hnsw_metadata.bin";
+
+/// Everything about an HnswSearch which hnsw_rs does not save for us.
+#[derive(Serialize, Deserialize)]
+struct HnswMetadata
+{
+    encoding: VectorEncoding,
+    hnsw_id_to_file_id_map: Vec<(usize, UUID)>,
+    removed: Vec<usize>,
+    current_id: usize,
+}
+
 /// Note that HNSW does not support removing points.
-/// To resolve this, the HNSW structure is rebuilt on start-up each time,
-/// which should be fast enough for our application with just a few tens
-/// of thousands of images at most. This does mean that IDs returned by
-/// a query may not be valid if the corresponding image has been removed.
-/// This may mean that the K nearest neighbors might in fact be fewer than K.
+/// Removed points are instead marked as removed and filtered out of search results, though they remain
+/// in the graph (and in memory) until the index is rebuilt. The index is rebuilt on start-up each time
+/// (see spawn_index_build()), which should be fast enough for our application with just a few tens
+/// of thousands of images at most.
 pub struct HnswSearch<'a> {
-    hnsw: Hnsw<'a, f32, DistCosine>,
+    hnsw: HnswIndex<'a>,
+    encoding: VectorEncoding,
     /// The hnsw crate uses usize for the ID of the elements in the index.
     /// We need to map these to the UUIDs of the documents in the database.
     /// While usize may not be large enough to map 1:1 with UUIDs, we functionally
     /// should never have this issue. We use UUIDs to maintain uniqueness across DBs
     /// in case we want to merge them, but usize is fine for this purpose.
     hnsw_id_to_file_id_map: FxHashMap<usize, UUID>,
+    file_id_to_hnsw_id_map: FxHashMap<UUID, usize>,
+    /// The hnsw IDs of points which have been removed (or replaced), and must be filtered out of search results.
+    removed: FxHashSet<usize>,
     current_id: usize,
 }
 
 impl<'a> HnswSearch<'a>
 {
-    pub fn new() -> HnswSearch<'a>
+    pub fn new(encoding: VectorEncoding) -> HnswSearch<'a>
     {
-        let max_nb_connection = DEFAULT_MAX_NB_CONNECTION;
-        let nb_layer = DEFAULT_NB_LAYER;
-        let ef_c = DEFAULT_EF_CONSTRUCTION;
-        let nb_elem = DEFAULT_MAX_ELEMS;
+        Self::with_params(HnswParams::default(), encoding)
+    }
+
+    pub fn with_params(params: HnswParams, encoding: VectorEncoding) -> HnswSearch<'a>
+    {
+        let max_nb_connection = params.max_nb_connection;
+        let nb_layer = params.nb_layer;
+        let ef_c = params.ef_construction;
+        let nb_elem = params.max_elems;
         let hnsw_id_to_file_id_map = FxHashMap::default();
         let current_id = 0;
-        let mut hnsw = Hnsw::<f32, DistCosine>::new(
-            max_nb_connection, 
-            nb_elem, 
-            nb_layer,
-            ef_c,
-            DistCosine{}
-            );
         // Enabled according to ann-glove25-angular example from hnsw_rs.
-        // Angular data may be highly clustered, so we enable this.
-        hnsw.set_extend_candidates(true);
+        // Angular data may be highly clustered, so we enable extend_candidates.
+        let hnsw = match encoding
+        {
+            VectorEncoding::F32 => {
+                let mut hnsw = Hnsw::<f32, DistCosine>::new(max_nb_connection, nb_elem, nb_layer, ef_c, DistCosine{});
+                hnsw.set_extend_candidates(true);
+                HnswIndex::F32(hnsw)
+            },
+            VectorEncoding::F16 => {
+                let mut hnsw = Hnsw::<f16, DistCosineF16>::new(max_nb_connection, nb_elem, nb_layer, ef_c, DistCosineF16{});
+                hnsw.set_extend_candidates(true);
+                HnswIndex::F16(hnsw)
+            },
+            VectorEncoding::Int8 => {
+                let mut hnsw = Hnsw::<i8, DistCosineI8>::new(max_nb_connection, nb_elem, nb_layer, ef_c, DistCosineI8{});
+                hnsw.set_extend_candidates(true);
+                HnswIndex::Int8(hnsw)
+            },
+        };
         HnswSearch
-        { 
+        {
             hnsw,
+            encoding,
             hnsw_id_to_file_id_map,
+            file_id_to_hnsw_id_map: FxHashMap::default(),
+            removed: FxHashSet::default(),
             current_id
         }
     }
 
-    pub fn insert_slice(&mut self, data: Vec<HnswElement>)
+    /// The format in which feature vectors are held by this index.
+    /// New feature vectors should be stored in the same format.
+    pub fn encoding(&self) -> VectorEncoding
+    {
+        self.encoding
+    }
+}
+
+impl VectorIndex for HnswSearch<'static>
+{
+    fn insert(&mut self, data: Vec<HnswElement>)
     {
+        // Replacing a feature vector means removing the old point.
+        let replaced: Vec<UUID> = data.iter().map(|elem| elem.id).filter(|id| self.file_id_to_hnsw_id_map.contains_key(id)).collect();
+        self.remove(&replaced);
+
         // Generate the IDs for the elements
         let ids: Vec<usize> = (self.current_id..self.current_id + data.len()).collect();
         self.current_id += data.len();
@@ -88,81 +259,508 @@ impl<'a> HnswSearch<'a>
         for (id, elem) in ids.iter().zip(data.iter())
         {
             self.hnsw_id_to_file_id_map.insert(*id, elem.id);
+            self.file_id_to_hnsw_id_map.insert(elem.id, *id);
         }
-        // Convert the data to the format needed by hnsw_rs
-        let data_for_par_insertion: Vec<(&[f32], usize)> = data
-            .iter()
-            .zip(ids.iter())
-            .map(|(elem, id)| (&elem.feature_vector[..], *id))
-            .collect();
-        // Insert the elements into the HNSW index
-        for d in data_for_par_insertion.iter()
+        // Convert the data to the format needed by hnsw_rs and insert the elements into the HNSW index
+        match &mut self.hnsw
         {
-            self.hnsw.insert_slice(*d);
+            HnswIndex::F32(hnsw) => {
+                for (elem, id) in data.iter().zip(ids.iter())
+                {
+                    hnsw.insert_slice((&elem.feature_vector[..], *id));
+                }
+            },
+            HnswIndex::F16(hnsw) => {
+                for (elem, id) in data.iter().zip(ids.iter())
+                {
+                    let quantized = quantization::quantize_f16(&elem.feature_vector);
+                    hnsw.insert_slice((&quantized[..], *id));
+                }
+            },
+            HnswIndex::Int8(hnsw) => {
+                for (elem, id) in data.iter().zip(ids.iter())
+                {
+                    let (_, quantized) = quantization::quantize_i8(&elem.feature_vector);
+                    hnsw.insert_slice((&quantized[..], *id));
+                }
+            },
         }
     }
 
-    /// Returns a vector of IDs of the knbn nearest neighbors, with the corresponding distances (for eg ranking).
-    /// The IDs are the UUIDs of the documents in the database.
-    /// The distances are the cosine distances between the query vector and the feature vectors of the neighbors.
-    /// 
-    /// @param  ef_arg This parameter controls the width of the search in the lowest level,
-    /// it MUST be greater than number of neighbours asked (knbn) but CAN be less than DEFAULT_EF_CONSTRUCTION.
-    /// As a rule of thumb could be between the number of neighbours we will ask for (knbn arg in search method) and DEFAULT_MAX_NB_CONNECTION.
-    /// It does not limit the number of neighbours returned; recall will be lower if ef_arg is lower, but search is slower with high ef_arg.
-    /// @param distance_threshold the value that the distance must be less than to be included in the results.
-    /// We use the cosine distance for our HNSW search.
-    /// Range of cosine distance is from 0 to 2, 0 — identical vectors, 1 — no correlation, 2 — absolutely different.
-    /// In practice, due to high-dimensional feature vectors, ~0.79 will be very semantically similar,
-    /// and ~0.85 will be very semantically different (this is a rough estimate, check for a given dataset).
-    pub fn search(&self, query: &[f32], knbn: usize, ef_arg: usize, distance_threshold: f32) -> Vec<(UUID, f32)>
+    fn remove(&mut self, ids: &[UUID])
     {
-        let knn_neighbours = self.hnsw.search(query, knbn, ef_arg);
-        // Map the IDs to the UUIDs. Neighbor.d_id (short for data_id) corresponds to the usize ID.
+        for id in ids
+        {
+            if let Some(hnsw_id) = self.file_id_to_hnsw_id_map.remove(id) {
+                self.hnsw_id_to_file_id_map.remove(&hnsw_id);
+                self.removed.insert(hnsw_id);
+            }
+        }
+    }
 
-        // TODO Filter these results based on some constant, tweak it.
-        let results: Vec<(UUID, f32)> = knn_neighbours
-            .iter()
-            .map(|n| -> (UUID, f32)
+    fn search_filtered(
+        &self,
+        query: &[f32],
+        knbn: usize,
+        ef_arg: usize,
+        distance_threshold: f32,
+        filter: &dyn Fn(&UUID) -> bool) -> Vec<(UUID, f32)>
+    {
+        // Filtering within the search (rather than on its results) keeps removed and filtered out points
+        // from taking the place of valid neighbors.
+        let hnsw_filter = |hnsw_id: &usize| -> bool {
+            match self.hnsw_id_to_file_id_map.get(hnsw_id)
             {
-                (self.hnsw_id_to_file_id_map[&n.d_id], n.distance)
-            })
-            .filter(|(_, distance)| *distance < distance_threshold)
-            .collect();
-        results
+                Some(file_id) => filter(file_id),
+                None => false,
+            }
+        };
+        let hnsw_filter: &dyn FilterT = &hnsw_filter;
+        let knn_neighbours = match &self.hnsw
+        {
+            HnswIndex::F32(hnsw) => hnsw.search_filter(query, knbn, ef_arg, Some(hnsw_filter)),
+            HnswIndex::F16(hnsw) => hnsw.search_filter(&quantization::quantize_f16(query), knbn, ef_arg, Some(hnsw_filter)),
+            HnswIndex::Int8(hnsw) => hnsw.search_filter(&quantization::quantize_i8(query).1, knbn, ef_arg, Some(hnsw_filter)),
+        };
+        // Map the IDs to the UUIDs. Neighbor.d_id (short for data_id) corresponds to the usize ID.
+        knn_neighbours
+            .iter()
+            .filter(|n| n.distance < distance_threshold)
+            .filter_map(|n| self.hnsw_id_to_file_id_map.get(&n.d_id).map(|id| (*id, n.distance)))
+            .collect()
+    }
+
+    fn len(&self) -> usize
+    {
+        self.hnsw_id_to_file_id_map.len()
+    }
+
+    fn save(&self, directory: &Path) -> anyhow::Result<()>
+    {
+        fs::create_dir_all(directory)?;
+        match &self.hnsw
+        {
+            HnswIndex::F32(hnsw) => hnsw.file_dump(directory, HNSW_BASENAME)?,
+            HnswIndex::F16(hnsw) => hnsw.file_dump(directory, HNSW_BASENAME)?,
+            HnswIndex::Int8(hnsw) => hnsw.file_dump(directory, HNSW_BASENAME)?,
+        };
+        let metadata = HnswMetadata {
+            encoding: self.encoding,
+            hnsw_id_to_file_id_map: self.hnsw_id_to_file_id_map.iter().map(|(k, v)| (*k, *v)).collect(),
+            removed: self.removed.iter().copied().collect(),
+            current_id: self.current_id,
+        };
+        fs::write(directory.join(HNSW_METADATA_FILENAME), bincode::serialize(&metadata)?)?;
+        Ok(())
+    }
+
+    fn load(directory: &Path) -> anyhow::Result<Self>
+    {
+        let metadata: HnswMetadata = bincode::deserialize(&fs::read(directory.join(HNSW_METADATA_FILENAME))?)?;
+        // hnsw_rs ties the lifetime of a reloaded index to its HnswIo.
+        // We read the whole index into memory (the HnswIo does not memory map the data),
+        // so leaking the small HnswIo is cheap and gives us an index which can live in the SearchState.
+        let hnsw_io: &'static mut HnswIo = Box::leak(Box::new(HnswIo::new(directory, HNSW_BASENAME)));
+        let hnsw = match metadata.encoding
+        {
+            VectorEncoding::F32 => {
+                let mut hnsw = hnsw_io.load_hnsw::<f32, DistCosine>()?;
+                hnsw.set_extend_candidates(true);
+                HnswIndex::F32(hnsw)
+            },
+            VectorEncoding::F16 => {
+                let mut hnsw = hnsw_io.load_hnsw::<f16, DistCosineF16>()?;
+                hnsw.set_extend_candidates(true);
+                HnswIndex::F16(hnsw)
+            },
+            VectorEncoding::Int8 => {
+                let mut hnsw = hnsw_io.load_hnsw::<i8, DistCosineI8>()?;
+                hnsw.set_extend_candidates(true);
+                HnswIndex::Int8(hnsw)
+            },
+        };
+        let file_id_to_hnsw_id_map = metadata.hnsw_id_to_file_id_map.iter().map(|(k, v)| (*v, *k)).collect();
+        Ok(HnswSearch {
+            hnsw,
+            encoding: metadata.encoding,
+            hnsw_id_to_file_id_map: metadata.hnsw_id_to_file_id_map.into_iter().collect(),
+            file_id_to_hnsw_id_map,
+            removed: metadata.removed.into_iter().collect(),
+            current_id: metadata.current_id,
+        })
     }
 }
 
-/// Populates the HNSW index with the feature vectors from the database, intended for startup.
-/// As more images are added to the application during runtime, they should be added to the HNSW index as necessary.
-pub fn populate_hnsw(app: &mut App) -> anyhow::Result<()>
+/// The number of elements inserted into the HNSW index between progress updates during the startup build.
+const INDEX_BUILD_CHUNK_SIZE: usize = 500;
+
+/// Builds the search index from the feature vectors in the database on a background thread, intended for startup.
+///
+/// The SearchState is expected to start with an empty index and a FlatIndex fallback.
+/// The thread loads the feature vectors into the fallback (so searches work right away), builds a fresh
+/// index of the configured backend without holding the SearchState lock, and then swaps it in, along with
+/// any changes that were made to the fallback in the meantime (e.g. by the initial scan or the file watchers).
+/// Progress is emitted to the front-end with the IndexBuildProgress event.
+///
+/// As more images are added to the application during runtime, they should be added to the index as necessary.
+pub fn spawn_index_build(app_handle: AppHandle) -> JoinHandle<()>
 {
-    let pool_state = app.state::<ConnectionPoolState>();
+    thread::spawn(move || {
+        info!("Building search index...");
+        let now = std::time::Instant::now();
+        match build_index(&app_handle)
+        {
+            std::result::Result::Ok(()) => info!("Search index rebuild took {:?}", now.elapsed()),
+            Err(e) => {
+                // Searches will continue to use the flat fallback.
+                error!("Error building search index: {:?}", e);
+                emit_index_build_progress(&app_handle, IndexBuildProgress { indexed: 0, total: 0, done: true, error: Some(e.to_string()) });
+            },
+        }
+    })
+}
 
-    let connection = &mut db::get_db_connection(&pool_state)?;
-    
-    let results = SelectDsl::select(image_features_vit_l_14_336_px::table, ImageFeatureVitL14336Px::as_select())
-        .load::<ImageFeatureVitL14336Px>(connection).context("Unable to load image features")?;
+fn build_index(app_handle: &AppHandle) -> anyhow::Result<()>
+{
+    let search_state = app_handle.state::<SearchState>();
+    let model_id = search_state.0.lock().unwrap().model_id.clone();
 
-    // Create the HnswElements
-    let hnsw_elements = convert_rows_to_hnsw_elements(&results)?;
+    let (elements, file_regions, file_frames) = {
+        let pool_state = app_handle.state::<ConnectionPoolState>();
+        let mut connection = pool_state.get_connection()?;
+        let rows = queries::get_all_image_feature_data(&model_id, &mut connection).context("Unable to load image features")?;
+        let mut elements = convert_rows_to_hnsw_elements(&rows)?;
+        drop(rows);
+        // Regions are indexed alongside the whole images, under their own IDs.
+        let region_rows = queries::get_all_region_feature_data(&model_id, &mut connection).context("Unable to load region features")?;
+        let regions = convert_region_rows_to_hnsw_elements(&region_rows)?;
+        let file_regions: Vec<(UUID, FileRegion)> = regions.iter().map(|(element, file_region)| (element.id, *file_region)).collect();
+        elements.extend(regions.into_iter().map(|(element, _)| element));
+        // As are frames of animations.
+        let frame_rows = queries::get_all_frame_feature_data(&model_id, &mut connection).context("Unable to load frame features")?;
+        let frames = convert_frame_rows_to_hnsw_elements(&frame_rows)?;
+        let file_frames: Vec<(UUID, FileFrame)> = frames.iter().map(|(element, file_frame)| (element.id, *file_frame)).collect();
+        elements.extend(frames.into_iter().map(|(element, _)| element));
+        (elements, file_regions, file_frames)
+    };
+    let total = elements.len();
+    let region_count = file_regions.len();
+    let frame_count = file_frames.len();
+    let loaded_ids: FxHashSet<UUID> = elements.iter().map(|e| e.id).collect();
+
+    let (backend, encoding) = {
+        let mut state = search_state.0.lock().unwrap();
+        if state.model_id != model_id {
+            info!("The active model changed; discarding the index build for {}", model_id);
+            return Ok(());
+        }
+        state.insert_loaded(elements.clone());
+        state.regions.extend(file_regions);
+        state.frames.extend(file_frames);
+        (state.backend, state.encoding)
+    };
+    info!("Loaded {} feature vectors ({} of image regions, {} of animation frames) for exact search", total, region_count, frame_count);
 
-    // Get the HnswSearch from the app's SearchState
-    let state = app.state::<SearchState>();
-    let mut state = state.0.lock().unwrap();
+    let mut index = new_index(backend, encoding);
+    let mut indexed = 0;
+    emit_index_build_progress(app_handle, IndexBuildProgress { indexed, total, done: false, error: None });
+    for chunk in elements.chunks(INDEX_BUILD_CHUNK_SIZE)
+    {
+        index.insert(chunk.to_vec());
+        indexed += chunk.len();
+        emit_index_build_progress(app_handle, IndexBuildProgress { indexed, total, done: false, error: None });
+    }
+    drop(elements);
 
-    // Add the elements to the Hnsw
-    state.hnsw.insert_slice(hnsw_elements);
+    {
+        let mut state = search_state.0.lock().unwrap();
+        // The active model may have been switched during the build (see embedding::set_active_model()),
+        // in which case a build for the new model is responsible for the state.
+        if state.model_id != model_id || state.fallback.is_none() {
+            info!("The search state changed during the build; discarding the index built for {}", model_id);
+            return Ok(());
+        }
+        state.finish_build(index, &loaded_ids);
+    }
 
+    emit_index_build_progress(app_handle, IndexBuildProgress { indexed, total, done: true, error: None });
     Ok(())
 }
 
-pub fn convert_rows_to_hnsw_elements(rows: &[ImageFeatureVitL14336Px]) -> anyhow::Result<Vec<HnswElement>>
+fn emit_index_build_progress(app_handle: &AppHandle, progress: IndexBuildProgress)
+{
+    let emit_result = app_handle.emit_all(Event::IndexBuildProgress.event_name(), progress);
+    if emit_result.is_err()
+    {
+        error!("Error emitting index build progress: {:?}", emit_result);
+    }
+}
+
+pub fn convert_rows_to_hnsw_elements(rows: &[ImageFeature]) -> anyhow::Result<Vec<HnswElement>>
 {
     Ok(rows.iter().map(
         |x| Ok(HnswElement 
         {
-            feature_vector: bincode::deserialize(&x.feature_vector[..])?,
-            id: x.id,
+            feature_vector: quantization::decode(&x.feature_vector[..], VectorEncoding::from_db(x.encoding)?)?,
+            id: x.file_id,
         })).collect::<anyhow::Result<Vec<HnswElement>>>()?)
 }
+
+/// Converts region feature rows to elements keyed by the regions' IDs, with the file and bounding box of each region.
+pub fn convert_region_rows_to_hnsw_elements(rows: &[RegionFeature]) -> anyhow::Result<Vec<(HnswElement, FileRegion)>>
+{
+    rows.iter().map(|x| {
+        let element = HnswElement
+        {
+            feature_vector: quantization::decode(&x.feature_vector[..], VectorEncoding::from_db(x.encoding)?)?,
+            id: x.id,
+        };
+        let region = Region { x: x.x as u32, y: x.y as u32, width: x.width as u32, height: x.height as u32 };
+        Ok((element, FileRegion { file_id: x.file_id, region }))
+    }).collect()
+}
+
+/// Converts frame feature rows to elements keyed by the frames' IDs, with the file, index and timestamp of each frame.
+pub fn convert_frame_rows_to_hnsw_elements(rows: &[FrameFeature]) -> anyhow::Result<Vec<(HnswElement, FileFrame)>>
+{
+    rows.iter().map(|x| {
+        let element = HnswElement
+        {
+            feature_vector: quantization::decode(&x.feature_vector[..], VectorEncoding::from_db(x.encoding)?)?,
+            id: x.id,
+        };
+        let frame = Frame { index: x.frame_index as u32, timestamp_ms: x.timestamp_ms as u64 };
+        Ok((element, FileFrame { file_id: x.file_id, frame }))
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests
+{
+    use crate::ann_benchmark::{self, BenchmarkIndex};
+    use crate::preprocessing::FEATURE_VECTOR_LENGTH;
+    use crate::state::InnerSearchState;
+    use crate::test_files::TempDir;
+
+    use super::*;
+
+    const K: usize = 10;
+
+    /// Synthetic data and held-out queries; small enough to keep the test fast in debug builds.
+    fn data_and_queries() -> (Vec<Vec<f32>>, Vec<Vec<f32>>)
+    {
+        let mut data = ann_benchmark::synthetic_feature_vectors(1050, FEATURE_VECTOR_LENGTH, 20, 0.8, 7);
+        let queries = data.split_off(1000);
+        (data, queries)
+    }
+
+    #[test]
+    fn brute_force_finds_self()
+    {
+        let (data, _) = data_and_queries();
+        let queries: Vec<Vec<f32>> = data[..10].to_vec();
+        let ground_truth = ann_benchmark::brute_force_knn(&data, &queries, K).unwrap();
+        for (i, neighbors) in ground_truth.iter().enumerate()
+        {
+            assert_eq!(neighbors.len(), K);
+            assert_eq!(neighbors[0], i);
+        }
+    }
+
+    #[test]
+    fn recall_at_k_counts_overlap()
+    {
+        let truth = vec![vec![0, 1, 2, 3]];
+        assert_eq!(ann_benchmark::recall_at_k(&truth, &[vec![0, 1, 2, 3]], 4), 1.0);
+        assert_eq!(ann_benchmark::recall_at_k(&truth, &[vec![3, 2, 9, 8]], 4), 0.5);
+        assert_eq!(ann_benchmark::recall_at_k(&truth, &[vec![]], 4), 0.0);
+    }
+
+    #[test]
+    fn default_params_recall()
+    {
+        let (data, queries) = data_and_queries();
+        let ground_truth = ann_benchmark::brute_force_knn(&data, &queries, K).unwrap();
+
+        let index = BenchmarkIndex::build(&data, HnswParams::default(), VectorEncoding::F32);
+        assert_eq!(index.hnsw.len(), data.len());
+
+        let (results, _) = index.search(&queries, K, DEFAULT_MAX_NB_CONNECTION);
+        let recall = ann_benchmark::recall_at_k(&ground_truth, &results, K);
+        assert!(recall > 0.95, "recall@{} was {}", K, recall);
+    }
+
+    #[test]
+    fn sweep_reports_every_combination()
+    {
+        let (data, queries) = data_and_queries();
+        let params = [
+            HnswParams { max_nb_connection: 16, ef_construction: 100, ..HnswParams::default() },
+            HnswParams { max_nb_connection: 32, ef_construction: 200, ..HnswParams::default() },
+        ];
+        let ef_args = [K, 64];
+        let ground_truth = ann_benchmark::brute_force_knn(&data, &queries[..10], K).unwrap();
+        let results = ann_benchmark::run_sweep(&data, &queries[..10], &ground_truth, K, &params, VectorEncoding::F32, &ef_args).unwrap();
+        assert_eq!(results.len(), params.len() * ef_args.len());
+        for r in results
+        {
+            assert!((0.0..=1.0).contains(&r.recall));
+        }
+    }
+
+    /// Quantized indices should lose very little recall relative to the f32 baseline.
+    #[test]
+    fn quantized_recall_regression()
+    {
+        let (data, queries) = data_and_queries();
+        let ground_truth = ann_benchmark::brute_force_knn(&data, &queries, K).unwrap();
+
+        let recall = |encoding: VectorEncoding| {
+            let index = BenchmarkIndex::build(&data, HnswParams::default(), encoding);
+            let (results, _) = index.search(&queries, K, DEFAULT_MAX_NB_CONNECTION);
+            ann_benchmark::recall_at_k(&ground_truth, &results, K)
+        };
+
+        let baseline = recall(VectorEncoding::F32);
+        let f16_recall = recall(VectorEncoding::F16);
+        let i8_recall = recall(VectorEncoding::Int8);
+        assert!(f16_recall >= baseline - 0.01, "f16 recall {} vs f32 recall {}", f16_recall, baseline);
+        assert!(i8_recall >= baseline - 0.03, "int8 recall {} vs f32 recall {}", i8_recall, baseline);
+    }
+
+    #[test]
+    fn flat_index_is_exact()
+    {
+        let (data, queries) = data_and_queries();
+        let ground_truth = ann_benchmark::brute_force_knn(&data, &queries, K).unwrap();
+
+        let elements: Vec<HnswElement> = data.iter().map(|v| HnswElement { feature_vector: v.clone(), id: uuid::Uuid::new_v4().into() }).collect();
+        let ids: Vec<UUID> = elements.iter().map(|e| e.id).collect();
+        let mut index = FlatIndex::new();
+        index.insert(elements.clone());
+        // Inserting the same elements again should not duplicate them.
+        index.insert(elements[..10].to_vec());
+        assert_eq!(index.len(), data.len());
+
+        for (query, truth) in queries.iter().zip(ground_truth.iter())
+        {
+            let results = index.search(query, K, K, f32::MAX);
+            // Compare as sets, since near-ties may be ordered differently due to floating point summation order.
+            let expected: FxHashSet<UUID> = truth.iter().map(|i| ids[*i]).collect();
+            let actual: FxHashSet<UUID> = results.iter().map(|(id, _)| *id).collect();
+            assert_eq!(actual, expected);
+            assert!(results.windows(2).all(|w| w[0].1 <= w[1].1));
+        }
+
+        let nothing = index.search(&queries[0], K, K, -1.0);
+        assert!(nothing.is_empty());
+    }
+
+    fn elements_with_ids(data: &[Vec<f32>]) -> Vec<HnswElement>
+    {
+        data.iter().map(|v| HnswElement { feature_vector: v.clone(), id: uuid::Uuid::new_v4().into() }).collect()
+    }
+
+    /// Removal and filtering behave the same for every backend.
+    fn check_remove_and_filter(index: &mut dyn VectorIndex)
+    {
+        let (data, _) = data_and_queries();
+        let elements = elements_with_ids(&data[..200]);
+        index.insert(elements.clone());
+        assert_eq!(index.len(), 200);
+
+        // Each element is its own nearest neighbor.
+        let query = &elements[0];
+        assert_eq!(index.search(&query.feature_vector, 1, DEFAULT_MAX_NB_CONNECTION, f32::MAX)[0].0, query.id);
+
+        let removed: Vec<UUID> = elements[..50].iter().map(|e| e.id).collect();
+        index.remove(&removed);
+        index.remove(&removed);
+        assert_eq!(index.len(), 150);
+        let results = index.search(&query.feature_vector, K, DEFAULT_MAX_NB_CONNECTION, f32::MAX);
+        assert_eq!(results.len(), K);
+        assert!(results.iter().all(|(id, _)| !removed.contains(id)));
+
+        let allowed: FxHashSet<UUID> = elements[150..].iter().map(|e| e.id).collect();
+        let results = index.search_filtered(&query.feature_vector, K, DEFAULT_MAX_NB_CONNECTION, f32::MAX, &|id| allowed.contains(id));
+        assert!(!results.is_empty());
+        assert!(results.iter().all(|(id, _)| allowed.contains(id)));
+
+        // Re-inserting a removed element makes it searchable again.
+        index.insert(vec![query.clone()]);
+        assert_eq!(index.len(), 151);
+        assert_eq!(index.search(&query.feature_vector, 1, DEFAULT_MAX_NB_CONNECTION, f32::MAX)[0].0, query.id);
+    }
+
+    #[test]
+    fn remove_and_filter()
+    {
+        check_remove_and_filter(&mut FlatIndex::new());
+        for encoding in [VectorEncoding::F32, VectorEncoding::F16, VectorEncoding::Int8]
+        {
+            check_remove_and_filter(&mut HnswSearch::new(encoding));
+        }
+    }
+
+    fn check_save_and_load<T: VectorIndex>(mut index: T)
+    {
+        let (data, queries) = data_and_queries();
+        let elements = elements_with_ids(&data);
+        index.insert(elements.clone());
+        index.remove(&[elements[0].id]);
+
+        let directory = TempDir::new("index");
+        index.save(directory.path()).unwrap();
+        let loaded = T::load(directory.path()).unwrap();
+
+        assert_eq!(loaded.len(), index.len());
+        for query in &queries
+        {
+            assert_eq!(
+                loaded.search(query, K, DEFAULT_MAX_NB_CONNECTION, f32::MAX),
+                index.search(query, K, DEFAULT_MAX_NB_CONNECTION, f32::MAX));
+        }
+    }
+
+    #[test]
+    fn save_and_load()
+    {
+        check_save_and_load(FlatIndex::new());
+        check_save_and_load(HnswSearch::new(VectorEncoding::F32));
+        check_save_and_load(HnswSearch::new(VectorEncoding::Int8));
+    }
+
+    #[test]
+    fn changes_during_the_build_are_applied_to_the_built_index()
+    {
+        let (data, queries) = data_and_queries();
+        let elements = elements_with_ids(&data[..100]);
+        let reencoded = |i: usize| HnswElement { feature_vector: queries[i].clone(), id: elements[i].id };
+        let mut state = InnerSearchState::new_building("model", IndexBackend::Hnsw, VectorEncoding::F32);
+
+        // Re-encoded after the build loaded its feature vectors, but before they were added to the fallback...
+        state.insert(vec![reencoded(0)]);
+        state.insert_loaded(elements.clone());
+        // ...and while the index was built.
+        state.insert(vec![reencoded(1)]);
+        state.remove(&[elements[2].id]);
+        let added = HnswElement { feature_vector: queries[2].clone(), id: uuid::Uuid::new_v4().into() };
+        state.insert(vec![added.clone()]);
+
+        let mut index = new_index(IndexBackend::Hnsw, VectorEncoding::F32);
+        index.insert(elements.clone());
+        let loaded_ids: FxHashSet<UUID> = elements.iter().map(|e| e.id).collect();
+        state.finish_build(index, &loaded_ids);
+
+        assert!(!state.is_building());
+        assert_eq!(state.index.len(), 100);
+        for (query, id) in [(&queries[0], elements[0].id), (&queries[1], elements[1].id), (&queries[2], added.id)]
+        {
+            let results = state.search(query, 1, DEFAULT_MAX_NB_CONNECTION, f32::MAX);
+            assert_eq!(results[0].0, id);
+            assert!(results[0].1 < 1e-4);
+        }
+        let results = state.search(&data[2], K, DEFAULT_MAX_NB_CONNECTION, f32::MAX);
+        assert!(results.iter().all(|(id, _)| *id != elements[2].id));
+    }
+}
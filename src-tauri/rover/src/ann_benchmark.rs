@@ -0,0 +1,257 @@
+/// Recall and latency measurement for the HNSW index in the ann module.
+/// This lets us choose the HNSW parameters (and the ef_arg the front-end sends with a search)
+/// from data rather than by rule of thumb.
+///
+/// Everything here runs offline on the CPU; no ONNX model is needed. Feature vectors are either
+/// generated synthetically (clustered, L2 normalized vectors, which resemble CLIP features better
+/// than uniformly random ones) or loaded from an existing RefRover database.
+/// Exact ground truth is computed by brute force, which is cheap since the vectors are normalized
+/// and the cosine distance reduces to 1 - dot product.
+///
+/// See `examples/ann_benchmark.rs` for a command line runner.
+
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use diesel::{Connection, SqliteConnection};
+use ndarray::{Array2, Axis};
+use rustc_hash::FxHashMap;
+
+use crate::ann::{self, HnswElement, HnswParams, HnswSearch, VectorIndex};
+use crate::preprocessing::FEATURE_VECTOR_LENGTH;
+use crate::quantization::VectorEncoding;
+use crate::queries;
+use crate::uuid::UUID;
+
+/// A small, deterministic PRNG (SplitMix64) so that benchmark runs are reproducible
+/// without pulling in a dependency for random number generation.
+pub struct SplitMix64
+{
+    state: u64,
+}
+
+impl SplitMix64
+{
+    pub fn new(seed: u64) -> Self
+    {
+        SplitMix64 { state: seed }
+    }
+
+    pub fn next_u64(&mut self) -> u64
+    {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in [0, 1).
+    pub fn next_f32(&mut self) -> f32
+    {
+        (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+    }
+
+    /// Standard normal, via the Box-Muller transform.
+    pub fn next_gaussian(&mut self) -> f32
+    {
+        let u1 = self.next_f32().max(f32::MIN_POSITIVE);
+        let u2 = self.next_f32();
+        (-2.0 * u1.ln()).sqrt() * (2.0 * std::f32::consts::PI * u2).cos()
+    }
+}
+
+pub fn normalize(vector: &mut [f32])
+{
+    let norm = vector.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm == 0.0 {
+        return;
+    }
+    vector.iter_mut().for_each(|x| *x /= norm);
+}
+
+/// Generates `count` L2 normalized vectors of length `dimension`, drawn around `num_clusters` random centers.
+/// `spread` is the standard deviation of the per-component noise relative to the (unit length) centers;
+/// larger values make the clusters overlap more, and the search harder.
+pub fn synthetic_feature_vectors(count: usize, dimension: usize, num_clusters: usize, spread: f32, seed: u64) -> Vec<Vec<f32>>
+{
+    let mut rng = SplitMix64::new(seed);
+    let num_clusters = num_clusters.max(1);
+
+    let centers: Vec<Vec<f32>> = (0..num_clusters).map(|_| {
+        let mut center: Vec<f32> = (0..dimension).map(|_| rng.next_gaussian()).collect();
+        normalize(&mut center);
+        center
+    }).collect();
+
+    // Scale the noise so that `spread` is independent of the dimension.
+    let noise_scale = spread / (dimension as f32).sqrt();
+    (0..count).map(|_| {
+        let center = &centers[(rng.next_u64() % num_clusters as u64) as usize];
+        let mut v: Vec<f32> = center.iter().map(|c| c + rng.next_gaussian() * noise_scale).collect();
+        normalize(&mut v);
+        v
+    }).collect()
+}
+
+/// Loads the image feature vectors stored for the model from the SQLite database at `db_path`,
+/// e.g. the sqlite.refrover.db file in the app data directory.
+pub fn load_stored_feature_vectors(db_path: &Path, model_id: &str) -> anyhow::Result<Vec<Vec<f32>>>
+{
+    let db_path = db_path.to_str().ok_or(anyhow::anyhow!("Error converting path to string"))?;
+    let mut connection = SqliteConnection::establish(db_path)?;
+    let rows = queries::get_all_image_feature_data(model_id, &mut connection)?;
+    let elements = ann::convert_rows_to_hnsw_elements(&rows)?;
+    Ok(elements.into_iter().map(|e| e.feature_vector).collect())
+}
+
+fn to_matrix(vectors: &[Vec<f32>]) -> anyhow::Result<Array2<f32>>
+{
+    let dimension = vectors.first().map(|v| v.len()).unwrap_or(FEATURE_VECTOR_LENGTH);
+    let flat: Vec<f32> = vectors.iter().flatten().copied().collect();
+    Ok(Array2::from_shape_vec((vectors.len(), dimension), flat)?)
+}
+
+/// Computes the exact k nearest neighbors of each query by brute force.
+/// Returns, for each query, the indices into `data` of its neighbors, nearest first.
+/// Assumes all vectors are L2 normalized.
+pub fn brute_force_knn(data: &[Vec<f32>], queries: &[Vec<f32>], k: usize) -> anyhow::Result<Vec<Vec<usize>>>
+{
+    let data = to_matrix(data)?;
+    let queries = to_matrix(queries)?;
+    // (num_queries, num_data) matrix of cosine similarities.
+    let similarities = queries.dot(&data.t());
+
+    Ok(similarities.axis_iter(Axis(0)).map(|row| {
+        let mut indices: Vec<usize> = (0..row.len()).collect();
+        let k = k.min(indices.len());
+        if k == 0 {
+            return Vec::new();
+        }
+        // Partial sort; we only need the top k, in order.
+        indices.select_nth_unstable_by(k - 1, |a, b| row[*b].total_cmp(&row[*a]));
+        indices.truncate(k);
+        indices.sort_unstable_by(|a, b| row[*b].total_cmp(&row[*a]));
+        indices
+    }).collect())
+}
+
+/// The fraction of the true k nearest neighbors that were returned, averaged over all queries.
+pub fn recall_at_k(ground_truth: &[Vec<usize>], results: &[Vec<usize>], k: usize) -> f32
+{
+    if ground_truth.is_empty() {
+        return 1.0;
+    }
+    let total: f32 = ground_truth.iter().zip(results.iter()).map(|(truth, result)| {
+        let truth = &truth[..k.min(truth.len())];
+        if truth.is_empty() {
+            return 1.0;
+        }
+        let result = &result[..k.min(result.len())];
+        let found = truth.iter().filter(|t| result.contains(t)).count();
+        found as f32 / truth.len() as f32
+    }).sum();
+    total / ground_truth.len() as f32
+}
+
+/// An HNSW index over a benchmark data set, which maps search results back to indices into the data set.
+pub struct BenchmarkIndex
+{
+    pub hnsw: HnswSearch<'static>,
+    pub build_time: Duration,
+    uuid_to_index: FxHashMap<UUID, usize>,
+}
+
+impl BenchmarkIndex
+{
+    pub fn build(data: &[Vec<f32>], params: HnswParams, encoding: VectorEncoding) -> Self
+    {
+        let mut hnsw = HnswSearch::with_params(params, encoding);
+        let mut uuid_to_index = FxHashMap::default();
+        let elements: Vec<HnswElement> = data.iter().enumerate().map(|(i, v)| {
+            let id: UUID = uuid::Uuid::new_v4().into();
+            uuid_to_index.insert(id, i);
+            HnswElement { feature_vector: v.clone(), id }
+        }).collect();
+
+        let now = Instant::now();
+        hnsw.insert(elements);
+        let build_time = now.elapsed();
+
+        BenchmarkIndex { hnsw, build_time, uuid_to_index }
+    }
+
+    /// Searches for each query, returning the indices of the neighbors and the latency of each search.
+    pub fn search(&self, queries: &[Vec<f32>], k: usize, ef_arg: usize) -> (Vec<Vec<usize>>, Vec<Duration>)
+    {
+        queries.iter().map(|q| {
+            let now = Instant::now();
+            // A threshold above the maximum cosine distance of 2.0 disables filtering.
+            let results = self.hnsw.search(q, k, ef_arg.max(k), f32::MAX);
+            let latency = now.elapsed();
+            let indices = results.iter().map(|(id, _)| self.uuid_to_index[id]).collect();
+            (indices, latency)
+        }).unzip()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BenchmarkResult
+{
+    pub params: HnswParams,
+    pub encoding: VectorEncoding,
+    pub ef_arg: usize,
+    pub k: usize,
+    pub recall: f32,
+    pub build_time: Duration,
+    pub mean_latency: Duration,
+    pub p99_latency: Duration,
+}
+
+fn percentile(latencies: &mut [Duration], p: f32) -> Duration
+{
+    if latencies.is_empty() {
+        return Duration::ZERO;
+    }
+    latencies.sort_unstable();
+    let idx = ((latencies.len() - 1) as f32 * p).round() as usize;
+    latencies[idx]
+}
+
+/// Builds one index per entry in `params` and searches it once for every value in `ef_args`,
+/// reporting recall@k against the exact ground truth along with build time and query latency.
+/// `ground_truth` is the exact k nearest neighbors of each query (see brute_force_knn()); it is computed once by the caller,
+/// since it doesn't depend on the parameters and is the slowest part of a sweep over few configurations.
+pub fn run_sweep(
+    data: &[Vec<f32>],
+    queries: &[Vec<f32>],
+    ground_truth: &[Vec<usize>],
+    k: usize,
+    params: &[HnswParams],
+    encoding: VectorEncoding,
+    ef_args: &[usize]) -> anyhow::Result<Vec<BenchmarkResult>>
+{
+    let mut out = Vec::new();
+    for p in params
+    {
+        let index = BenchmarkIndex::build(data, *p, encoding);
+        for ef_arg in ef_args
+        {
+            let (results, mut latencies) = index.search(queries, k, *ef_arg);
+            let recall = recall_at_k(ground_truth, &results, k);
+            let mean_latency = latencies.iter().sum::<Duration>() / latencies.len().max(1) as u32;
+            let p99_latency = percentile(&mut latencies, 0.99);
+            out.push(BenchmarkResult {
+                params: *p,
+                encoding,
+                ef_arg: (*ef_arg).max(k),
+                k,
+                recall,
+                build_time: index.build_time,
+                mean_latency,
+                p99_latency,
+            });
+        }
+    }
+    Ok(out)
+}
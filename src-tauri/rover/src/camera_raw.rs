@@ -0,0 +1,525 @@
+/// Camera RAW files (Canon CR2, Nikon NEF, Sony ARW and Adobe DNG), loaded from the JPEG preview they embed.
+///
+/// RAW files hold the sensor's data before demosaicing, white balance and tone mapping, which each camera maker does
+/// differently; but each of these formats is a TIFF container which also holds JPEG previews rendered by the camera,
+/// the largest usually at or near full size. We decode the largest preview, which is what the photographer saw on the
+/// camera. Files without a usable preview are demosaiced at half resolution from uncompressed sensor data, with the
+/// white balance the camera recorded (in DNGs) and a plain gamma curve; compressed sensor data is not supported.
+/// Their camera EXIF data is read by image_loading::read_camera_info(), like that of JPEGs.
+///
+/// This module is only built with the camera-raw cargo feature.
+
+use std::collections::{HashSet, VecDeque};
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+
+use image::{DynamicImage, ImageFormat, ImageResult, RgbImage};
+
+use crate::image_loading::{self, format_decoding_error, format_io_error, format_unsupported_error};
+
+/// The extensions of files which are loaded as camera RAW files.
+pub const RAW_EXTENSIONS: [&str; 4] = ["cr2", "nef", "arw", "dng"];
+
+/// The name of the formats in errors.
+const FORMAT_NAME: &str = "camera RAW";
+
+// The TIFF and DNG tags we read.
+const IMAGE_WIDTH: u16 = 0x0100;
+const IMAGE_LENGTH: u16 = 0x0101;
+const BITS_PER_SAMPLE: u16 = 0x0102;
+const COMPRESSION: u16 = 0x0103;
+const PHOTOMETRIC_INTERPRETATION: u16 = 0x0106;
+const STRIP_OFFSETS: u16 = 0x0111;
+const SAMPLES_PER_PIXEL: u16 = 0x0115;
+const STRIP_BYTE_COUNTS: u16 = 0x0117;
+const SUB_IFDS: u16 = 0x014A;
+const JPEG_INTERCHANGE_FORMAT: u16 = 0x0201;
+const JPEG_INTERCHANGE_FORMAT_LENGTH: u16 = 0x0202;
+const CFA_REPEAT_PATTERN_DIM: u16 = 0x828D;
+const CFA_PATTERN: u16 = 0x828E;
+const BLACK_LEVEL: u16 = 0xC61A;
+const WHITE_LEVEL: u16 = 0xC61D;
+const AS_SHOT_NEUTRAL: u16 = 0xC628;
+
+/// The photometric interpretation of sensor data behind a color filter array.
+const PHOTOMETRIC_CFA: u32 = 32803;
+
+/// Bounds on what we read from a file, so that a corrupt one can't make us allocate without limit or loop.
+const MAX_IFDS: usize = 64;
+const MAX_IFD_ENTRIES: u16 = 1024;
+const MAX_ENTRY_SIZE: usize = 1 << 20;
+
+/// Whether the file is loaded as a camera RAW file, by its extension.
+pub fn is_camera_raw(path: &Path) -> bool
+{
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| RAW_EXTENSIONS.contains(&extension.to_ascii_lowercase().as_str()))
+}
+
+/// Loads the largest JPEG preview in the file, or demosaics its sensor data if it has no preview which can be decoded.
+/// The image is as stored; the caller applies the EXIF orientation.
+pub fn load_raw(path: &Path) -> ImageResult<DynamicImage>
+{
+    let mut tiff = TiffReader::open(path)?;
+    let ifds = tiff.read_ifds().map_err(|e| format_io_error(FORMAT_NAME, e))?;
+
+    let mut previews: Vec<(u64, u64)> = ifds.iter().filter_map(Ifd::jpeg_preview).collect();
+    previews.sort_by_key(|(_, length)| std::cmp::Reverse(*length));
+    for (offset, length) in previews
+    {
+        // Some "previews" are the sensor data as a lossless JPEG, which the image crate can't decode; skip them.
+        let data = tiff.read_at(offset, length).map_err(|e| format_io_error(FORMAT_NAME, e))?;
+        if let Ok(image) = image_loading::load_from_memory(&data, ImageFormat::Jpeg) {
+            return Ok(image);
+        }
+    }
+
+    let sensor = ifds.iter().find(|ifd| ifd.photometric_interpretation == PHOTOMETRIC_CFA)
+        .ok_or_else(|| format_decoding_error(FORMAT_NAME, "The file has no preview or sensor data which can be read"))?;
+    demosaic(&mut tiff, sensor, &ifds[0])
+}
+
+/// The size of the largest image in the file (the sensor data or the full size preview), as stored.
+pub fn raw_dimensions(path: &Path) -> Option<(u32, u32)>
+{
+    let mut tiff = TiffReader::open(path).ok()?;
+    tiff.read_ifds().ok()?.iter()
+        .map(|ifd| (ifd.width, ifd.height))
+        .filter(|(width, height)| *width > 0 && *height > 0)
+        .max_by_key(|(width, height)| *width as u64 * *height as u64)
+}
+
+/// The fields of an image file directory which we use.
+#[derive(Debug, Default)]
+struct Ifd
+{
+    width: u32,
+    height: u32,
+    bits_per_sample: u32,
+    samples_per_pixel: u32,
+    compression: u32,
+    photometric_interpretation: u32,
+    strip_offsets: Vec<u64>,
+    strip_byte_counts: Vec<u64>,
+    sub_ifds: Vec<u64>,
+    jpeg_offset: Option<u64>,
+    jpeg_length: Option<u64>,
+    cfa_repeat_pattern_dim: Vec<u32>,
+    cfa_pattern: Vec<u32>,
+    black_level: Vec<f64>,
+    white_level: Option<f64>,
+    as_shot_neutral: Vec<f64>,
+}
+
+impl Ifd
+{
+    /// The offset and length of the JPEG stored by this IFD, if it stores one.
+    /// Older files point to it with JPEGInterchangeFormat; newer ones store it as a single JPEG compressed strip.
+    fn jpeg_preview(&self) -> Option<(u64, u64)>
+    {
+        if let (Some(offset), Some(length)) = (self.jpeg_offset, self.jpeg_length) {
+            return Some((offset, length));
+        }
+        match (self.compression, self.strip_offsets.as_slice(), self.strip_byte_counts.as_slice())
+        {
+            (6 | 7, [offset], [length]) if self.photometric_interpretation != PHOTOMETRIC_CFA => Some((*offset, *length)),
+            _ => None,
+        }
+    }
+}
+
+struct TiffReader
+{
+    reader: BufReader<File>,
+    big_endian: bool,
+    first_ifd: u64,
+    file_length: u64,
+}
+
+impl TiffReader
+{
+    fn open(path: &Path) -> ImageResult<Self>
+    {
+        let file = File::open(path)?;
+        let file_length = file.metadata()?.len();
+        let mut reader = BufReader::new(file);
+        let mut header = [0u8; 8];
+        reader.read_exact(&mut header).map_err(|e| format_io_error(FORMAT_NAME, e))?;
+        let big_endian = match &header[..4]
+        {
+            b"II*\0" => false,
+            b"MM\0*" => true,
+            _ => return Err(format_decoding_error(FORMAT_NAME, "The file is not a TIFF based RAW file")),
+        };
+        let mut tiff = TiffReader { reader, big_endian, first_ifd: 0, file_length };
+        tiff.first_ifd = tiff.u32([header[4], header[5], header[6], header[7]]) as u64;
+        Ok(tiff)
+    }
+
+    fn u16(&self, bytes: [u8; 2]) -> u16
+    {
+        if self.big_endian { u16::from_be_bytes(bytes) } else { u16::from_le_bytes(bytes) }
+    }
+
+    fn u32(&self, bytes: [u8; 4]) -> u32
+    {
+        if self.big_endian { u32::from_be_bytes(bytes) } else { u32::from_le_bytes(bytes) }
+    }
+
+    fn read_at(&mut self, offset: u64, length: u64) -> io::Result<Vec<u8>>
+    {
+        if offset.saturating_add(length) > self.file_length {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+        }
+        self.reader.seek(SeekFrom::Start(offset))?;
+        let mut data = vec![0; length as usize];
+        self.reader.read_exact(&mut data)?;
+        Ok(data)
+    }
+
+    /// Reads IFD0 and the IFDs chained after it, and their SubIFDs, IFD0 first.
+    fn read_ifds(&mut self) -> io::Result<Vec<Ifd>>
+    {
+        let mut ifds = Vec::new();
+        let mut visited = HashSet::new();
+        let mut queue = VecDeque::from([self.first_ifd]);
+        while let Some(offset) = queue.pop_front()
+        {
+            if offset == 0 || offset >= self.file_length || !visited.insert(offset) || ifds.len() >= MAX_IFDS {
+                continue;
+            }
+            let (ifd, next) = self.read_ifd(offset)?;
+            queue.push_back(next);
+            queue.extend(ifd.sub_ifds.iter().copied());
+            ifds.push(ifd);
+        }
+        if ifds.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "The file has no image file directories"));
+        }
+        Ok(ifds)
+    }
+
+    /// Reads the IFD at the offset, and the offset of the next IFD (0 if it is the last).
+    fn read_ifd(&mut self, offset: u64) -> io::Result<(Ifd, u64)>
+    {
+        let data = self.read_at(offset, 2)?;
+        let count = self.u16([data[0], data[1]]);
+        if count > MAX_IFD_ENTRIES {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Too many IFD entries"));
+        }
+        let data = self.read_at(offset + 2, count as u64 * 12 + 4)?;
+        let (entries, next) = data.split_at(count as usize * 12);
+        let next = self.u32([next[0], next[1], next[2], next[3]]) as u64;
+
+        let mut ifd = Ifd { samples_per_pixel: 1, ..Ifd::default() };
+        for entry in entries.chunks_exact(12)
+        {
+            let tag = self.u16([entry[0], entry[1]]);
+            let kind = self.u16([entry[2], entry[3]]);
+            let count = self.u32([entry[4], entry[5], entry[6], entry[7]]);
+            let values = self.read_values(kind, count, [entry[8], entry[9], entry[10], entry[11]])?;
+            let first = values.first().copied().unwrap_or(0.0);
+            let integers = || values.iter().map(|value| *value as u64).collect::<Vec<u64>>();
+            match tag
+            {
+                IMAGE_WIDTH => ifd.width = first as u32,
+                IMAGE_LENGTH => ifd.height = first as u32,
+                BITS_PER_SAMPLE => ifd.bits_per_sample = first as u32,
+                SAMPLES_PER_PIXEL => ifd.samples_per_pixel = first as u32,
+                COMPRESSION => ifd.compression = first as u32,
+                PHOTOMETRIC_INTERPRETATION => ifd.photometric_interpretation = first as u32,
+                STRIP_OFFSETS => ifd.strip_offsets = integers(),
+                STRIP_BYTE_COUNTS => ifd.strip_byte_counts = integers(),
+                SUB_IFDS => ifd.sub_ifds = integers(),
+                JPEG_INTERCHANGE_FORMAT => ifd.jpeg_offset = Some(first as u64),
+                JPEG_INTERCHANGE_FORMAT_LENGTH => ifd.jpeg_length = Some(first as u64),
+                CFA_REPEAT_PATTERN_DIM => ifd.cfa_repeat_pattern_dim = values.iter().map(|value| *value as u32).collect(),
+                CFA_PATTERN => ifd.cfa_pattern = values.iter().map(|value| *value as u32).collect(),
+                BLACK_LEVEL => ifd.black_level = values,
+                WHITE_LEVEL => ifd.white_level = Some(first),
+                AS_SHOT_NEUTRAL => ifd.as_shot_neutral = values,
+                _ => {},
+            }
+        }
+        Ok((ifd, next))
+    }
+
+    /// Reads the numeric values of an IFD entry, which are stored in the entry if they fit in 4 bytes,
+    /// and otherwise at the offset in the entry. Entries of other types (e.g. ASCII) have no values.
+    fn read_values(&mut self, kind: u16, count: u32, value: [u8; 4]) -> io::Result<Vec<f64>>
+    {
+        let size = match kind
+        {
+            // BYTE, UNDEFINED
+            1 | 7 => 1,
+            // SHORT
+            3 => 2,
+            // LONG, IFD
+            4 | 13 => 4,
+            // RATIONAL, SRATIONAL
+            5 | 10 => 8,
+            _ => return Ok(Vec::new()),
+        };
+        let length = size * count as usize;
+        if length > MAX_ENTRY_SIZE {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "IFD entry is too large"));
+        }
+        let data = if length <= 4 { value[..length].to_vec() } else { self.read_at(self.u32(value) as u64, length as u64)? };
+        let values = data.chunks_exact(size)
+            .map(|bytes| match kind
+            {
+                1 | 7 => bytes[0] as f64,
+                3 => self.u16([bytes[0], bytes[1]]) as f64,
+                4 | 13 => self.u32([bytes[0], bytes[1], bytes[2], bytes[3]]) as f64,
+                5 => {
+                    let denominator = self.u32([bytes[4], bytes[5], bytes[6], bytes[7]]);
+                    if denominator == 0 { 0.0 } else { self.u32([bytes[0], bytes[1], bytes[2], bytes[3]]) as f64 / denominator as f64 }
+                },
+                _ => {
+                    let numerator = self.u32([bytes[0], bytes[1], bytes[2], bytes[3]]) as i32;
+                    let denominator = self.u32([bytes[4], bytes[5], bytes[6], bytes[7]]) as i32;
+                    if denominator == 0 { 0.0 } else { numerator as f64 / denominator as f64 }
+                },
+            })
+            .collect();
+        Ok(values)
+    }
+}
+
+/// Demosaics uncompressed sensor data behind a 2x2 color filter array (e.g. RGGB) by taking each 2x2 block of sensor
+/// pixels as one pixel, averaging its greens. The result is half the size of the sensor, which is plenty for thumbnails
+/// and the model. Values are scaled from the black level to the white level, white balanced with the camera's
+/// AsShotNeutral (if it recorded one, in IFD0), and gamma corrected.
+fn demosaic(tiff: &mut TiffReader, sensor: &Ifd, ifd0: &Ifd) -> ImageResult<DynamicImage>
+{
+    if sensor.compression != 1 {
+        return Err(format_unsupported_error(FORMAT_NAME, "Compressed sensor data without a preview is not supported"));
+    }
+    let bytes_per_sample = match sensor.bits_per_sample
+    {
+        8 => 1,
+        9..=16 => 2,
+        bits => return Err(format_unsupported_error(FORMAT_NAME, format!("{} bit sensor data is not supported", bits))),
+    };
+    let pattern_is_2x2_rgb = sensor.cfa_repeat_pattern_dim == [2, 2]
+        && sensor.cfa_pattern.len() == 4
+        && (0..3).all(|color| sensor.cfa_pattern.contains(&color))
+        && sensor.cfa_pattern.iter().all(|color| *color < 3);
+    if sensor.samples_per_pixel != 1 || !pattern_is_2x2_rgb {
+        return Err(format_unsupported_error(FORMAT_NAME, "Only 2x2 red, green and blue color filter arrays are supported"));
+    }
+
+    // The header may claim any size, so it is checked against the decode limits and the strips before allocating.
+    let data_size = u64::from(sensor.width)
+        .checked_mul(u64::from(sensor.height))
+        .and_then(|samples| samples.checked_mul(bytes_per_sample as u64))
+        .unwrap_or(u64::MAX);
+    image_loading::decode_limits().reserve(data_size)?;
+    let strips_size = sensor.strip_byte_counts.iter()
+        .take(sensor.strip_offsets.len())
+        .fold(0u64, |sum, length| sum.saturating_add(*length));
+    if strips_size < data_size {
+        return Err(format_decoding_error(FORMAT_NAME, "The sensor data is truncated"));
+    }
+
+    let (width, height) = (sensor.width as usize, sensor.height as usize);
+    let data_size = data_size as usize;
+    let mut data = Vec::with_capacity(data_size);
+    for (offset, length) in sensor.strip_offsets.iter().zip(&sensor.strip_byte_counts)
+    {
+        if data.len() >= data_size {
+            break;
+        }
+        data.extend(tiff.read_at(*offset, *length).map_err(|e| format_io_error(FORMAT_NAME, e))?);
+    }
+    let sample = |x: usize, y: usize| -> f64 {
+        let i = (y * width + x) * bytes_per_sample;
+        if bytes_per_sample == 1 { data[i] as f64 } else { tiff.u16([data[i], data[i + 1]]) as f64 }
+    };
+
+    let black = if sensor.black_level.is_empty() { 0.0 } else { sensor.black_level.iter().sum::<f64>() / sensor.black_level.len() as f64 };
+    let white = sensor.white_level.unwrap_or(((1u64 << sensor.bits_per_sample) - 1) as f64);
+    let range = (white - black).max(1.0);
+    // AsShotNeutral is the camera's response to a neutral color; scale red and blue to match green.
+    let gains = match ifd0.as_shot_neutral.as_slice()
+    {
+        [red, green, blue] if *red > 0.0 && *blue > 0.0 => [green / red, 1.0, green / blue],
+        _ => [1.0, 1.0, 1.0],
+    };
+
+    let mut image = RgbImage::new((width / 2) as u32, (height / 2) as u32);
+    for (x, y, pixel) in image.enumerate_pixels_mut()
+    {
+        let (x, y) = (x as usize * 2, y as usize * 2);
+        let mut sums = [0.0; 3];
+        let mut counts = [0.0; 3];
+        for (i, color) in sensor.cfa_pattern.iter().enumerate()
+        {
+            sums[*color as usize] += sample(x + i % 2, y + i / 2);
+            counts[*color as usize] += 1.0;
+        }
+        for channel in 0..3
+        {
+            let linear = ((sums[channel] / counts[channel] - black) / range * gains[channel]).clamp(0.0, 1.0);
+            pixel.0[channel] = (linear.powf(1.0 / 2.2) * 255.0).round() as u8;
+        }
+    }
+    Ok(DynamicImage::ImageRgb8(image))
+}
+
+#[cfg(test)]
+mod tests
+{
+    use image::ImageError;
+
+    use crate::test_files::TempDir;
+
+    use super::*;
+
+    /// An IFD entry with a value which fits in the entry: (tag, type, count, value).
+    type Entry = (u16, u16, u32, [u8; 4]);
+
+    fn short(tag: u16, value: u16) -> Entry
+    {
+        let [a, b] = value.to_le_bytes();
+        (tag, 3, 1, [a, b, 0, 0])
+    }
+
+    fn long(tag: u16, value: u32) -> Entry
+    {
+        (tag, 4, 1, value.to_le_bytes())
+    }
+
+    /// Writes a little endian TIFF whose IFDs (each of which must have 12 entries or fewer) come straight after the
+    /// header, followed by `data`. IFD0 is the only top level IFD; the others are reached by SubIFDs.
+    fn write_tiff(path: &Path, ifds: &[Vec<Entry>], data: &[u8])
+    {
+        let mut tiff = b"II*\0".to_vec();
+        tiff.extend(8u32.to_le_bytes());
+        for ifd in ifds
+        {
+            tiff.extend((ifd.len() as u16).to_le_bytes());
+            for (tag, kind, count, value) in ifd
+            {
+                tiff.extend(tag.to_le_bytes());
+                tiff.extend(kind.to_le_bytes());
+                tiff.extend(count.to_le_bytes());
+                tiff.extend(value);
+            }
+            tiff.extend(0u32.to_le_bytes());
+        }
+        tiff.extend(data);
+        std::fs::write(path, tiff).unwrap();
+    }
+
+    /// The offset of `data` in a TIFF written by write_tiff with IFDs of these sizes, and the offset of each IFD.
+    fn layout(entry_counts: &[u32]) -> (u32, Vec<u32>)
+    {
+        let mut offset = 8;
+        let mut ifd_offsets = Vec::new();
+        for count in entry_counts
+        {
+            ifd_offsets.push(offset);
+            offset += 2 + 12 * count + 4;
+        }
+        (offset, ifd_offsets)
+    }
+
+    /// A 4x4 sensor behind an RGGB color filter array, with 12 bit values: bright red, half green and dark blue.
+    fn sensor_ifd(data_offset: u32) -> (Vec<Entry>, Vec<u8>)
+    {
+        let samples: Vec<u16> = (0..16).map(|i| match (i % 4 % 2, i / 4 % 2)
+        {
+            (0, 0) => 4095,
+            (1, 1) => 0,
+            _ => 2048,
+        }).collect();
+        let data: Vec<u8> = samples.iter().flat_map(|sample| sample.to_le_bytes()).collect();
+        let ifd = vec![
+            long(IMAGE_WIDTH, 4),
+            long(IMAGE_LENGTH, 4),
+            short(BITS_PER_SAMPLE, 12),
+            short(COMPRESSION, 1),
+            short(PHOTOMETRIC_INTERPRETATION, PHOTOMETRIC_CFA as u16),
+            long(STRIP_OFFSETS, data_offset),
+            short(SAMPLES_PER_PIXEL, 1),
+            long(STRIP_BYTE_COUNTS, data.len() as u32),
+            (CFA_REPEAT_PATTERN_DIM, 3, 2, [2, 0, 2, 0]),
+            (CFA_PATTERN, 1, 4, [0, 1, 1, 2]),
+        ];
+        (ifd, data)
+    }
+
+    #[test]
+    fn the_largest_preview_is_loaded()
+    {
+        let jpeg = |width: u32, height: u32, color: [u8; 3]| {
+            let mut jpeg = std::io::Cursor::new(Vec::new());
+            RgbImage::from_pixel(width, height, image::Rgb(color)).write_to(&mut jpeg, ImageFormat::Jpeg).unwrap();
+            jpeg.into_inner()
+        };
+        // A small green thumbnail in IFD0, and a larger blue preview in a SubIFD, as in NEFs.
+        let (thumbnail, preview) = (jpeg(8, 4, [0, 255, 0]), jpeg(64, 32, [0, 0, 255]));
+        let (data_offset, ifd_offsets) = layout(&[5, 3]);
+        let ifd0 = vec![
+            long(IMAGE_WIDTH, 8),
+            long(IMAGE_LENGTH, 4),
+            (SUB_IFDS, 4, 1, ifd_offsets[1].to_le_bytes()),
+            long(JPEG_INTERCHANGE_FORMAT, data_offset),
+            long(JPEG_INTERCHANGE_FORMAT_LENGTH, thumbnail.len() as u32),
+        ];
+        let preview_ifd = vec![
+            short(COMPRESSION, 6),
+            long(JPEG_INTERCHANGE_FORMAT, data_offset + thumbnail.len() as u32),
+            long(JPEG_INTERCHANGE_FORMAT_LENGTH, preview.len() as u32),
+        ];
+        let dir = TempDir::new("raw");
+        let path = dir.join("photo.nef");
+        write_tiff(&path, &[ifd0, preview_ifd], &[thumbnail, preview].concat());
+
+        assert!(is_camera_raw(&path));
+        let image = load_raw(&path).unwrap().to_rgb8();
+        assert_eq!(image.dimensions(), (64, 32));
+        let [red, green, blue] = image.get_pixel(32, 16).0;
+        assert!(red < 16 && green < 16 && blue > 240);
+        assert_eq!(raw_dimensions(&path), Some((8, 4)));
+    }
+
+    #[test]
+    fn sensor_data_is_demosaiced_without_a_preview()
+    {
+        let (data_offset, _) = layout(&[10]);
+        let (mut ifd, data) = sensor_ifd(data_offset);
+        let dir = TempDir::new("raw");
+        let path = dir.join("photo.dng");
+        write_tiff(&path, &[ifd.clone()], &data);
+
+        let image = load_raw(&path).unwrap().to_rgb8();
+        assert_eq!(image.dimensions(), (2, 2));
+        // Full red, half green (which is about 186 after gamma correction) and no blue.
+        assert_eq!(image.get_pixel(1, 1).0, [255, 186, 0]);
+        assert_eq!(raw_dimensions(&path), Some((4, 4)));
+
+        // Compressed sensor data can't be demosaiced.
+        ifd[3] = short(COMPRESSION, 7);
+        write_tiff(&path, &[ifd], &data);
+        assert!(matches!(load_raw(&path), Err(ImageError::Unsupported(_))));
+
+        // Nor can a truncated file, or one whose strips don't hold the whole sensor.
+        write_tiff(&path, &[sensor_ifd(data_offset).0], &data[..8]);
+        assert!(matches!(load_raw(&path), Err(ImageError::Decoding(_))));
+        let (mut ifd, _) = sensor_ifd(data_offset);
+        ifd[7] = long(STRIP_BYTE_COUNTS, 8);
+        write_tiff(&path, &[ifd], &data);
+        assert!(matches!(load_raw(&path), Err(ImageError::Decoding(_))));
+
+        // A sensor too large to decode fails before anything is allocated.
+        let (mut ifd, _) = sensor_ifd(data_offset);
+        ifd[0] = long(IMAGE_WIDTH, 1 << 20);
+        ifd[1] = long(IMAGE_LENGTH, 1 << 20);
+        write_tiff(&path, &[ifd], &data);
+        assert!(matches!(load_raw(&path), Err(ImageError::Limits(_))));
+    }
+}
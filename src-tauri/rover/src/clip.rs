@@ -1,18 +1,15 @@
 use std::path::Path;
 
-use diesel::{RunQueryDsl, SqliteConnection};
 use image::DynamicImage;
-use log::{info, trace, warn};
-use ndarray::{Array, Array2, Dim, IxDyn, Axis};
-use ort::{self, inputs, GraphOptimizationLevel};
-use ort::DirectMLExecutionProvider;
+use log::info;
+use ndarray::{Array, Array2, Array3, Dim, IxDyn, Axis};
+use ort::{self, inputs};
 use anyhow;
 
-use crate::models::{NewFailedEncoding, NewImageFeaturesVitL14336Px};
-use crate::preprocessing::{self, FEATURE_VECTOR_LENGTH};
-use crate::uuid::UUID;
-use crate::{ann, queries};
-use crate::state::{ClipState, SearchState};
+use crate::embedding::{ImageEncoder, ModelInfo, TextEncoder};
+use crate::model_files::ModelPaths;
+use crate::onnx::OnnxSettings;
+use crate::preprocessing;
 
 pub struct ForwardResults
 {
@@ -20,8 +17,8 @@ pub struct ForwardResults
     pub logits_per_text: Array<f32, IxDyn>,
 }
 
-/// The CLIP search model.
-/// Supports 3 model functions: `encode_text()`, `encode_image()`, and `forward()`.
+/// The CLIP search model, as a pair of encoders which can be loaded and used independently.
+/// Supports 3 model functions: `encode_text()` (ClipText), `encode_image()` (ClipVisual), and `forward()`.
 /// The encoding functions outputs the latent feature vectore of text and images.
 /// The forward function outputs the similarity score between the text and image.
 /// See the [OpenAI CLIP paper](https://arxiv.org/abs/2103.00020) for more details.
@@ -35,14 +32,14 @@ pub struct ForwardResults
 /// The ONNX representation is created by: https://github.com/jalberse/CLIP-to-onnx-converter
 pub struct Clip
 {
-    visual_session: ort::Session,
-    text_session: ort::Session,
+    pub visual: ClipVisual,
+    pub text: ClipText,
     logit_scale: f32,
 }
 
 impl Clip
 {
-    pub fn new() -> Result<Self, ort::Error>
+    pub fn new(info: &'static ModelInfo, onnx_settings: &OnnxSettings, model_paths: &ModelPaths) -> Result<Self, ort::Error>
     {
         // TODO Switch to load-dynamic, possibly
         // TODO Ensure we can load models when shipping executables;
@@ -57,40 +54,86 @@ impl Clip
         // TODO And ensure that the env variable for the process points to it:
         //      std::env::set_var("ORT_DYLIB_PATH", "./libonnxruntime.so");
 
-        // TODO It looks like takes some time to load these. I think we'll want separate models so we can load them individually and asynchronously?
-        //      They aren't actually that related to each other other than semantically, since they're separate ONNX graphs.
-        //      Like, waiting a bit to initialize visual aint bad because that's mostly in the background.
-        //      Text we do want to be fast + first since that's the main thing we're doing.
-        //      The combined model we'll use for something like suggested tags, but that's it.
-        //       It's advantageous to split these.
-        //   We also definitely want to initiate the session once on startup and keep it around the whole process.
-
-        // TODO When we package the app, we'll be copying the ONNX files to be local to the executable.
-        //      That will change the path to the models (unless tauri is doing some magic with the path).
-        //      So, we should update these accordingly.
-        let visual_session = ort::Session::builder()?
-            .with_optimization_level(GraphOptimizationLevel::Level3)?
-            .with_intra_threads(4)?
-            .with_execution_providers([DirectMLExecutionProvider::default().build()])?
-            .commit_from_file(Path::new(env!("CARGO_MANIFEST_DIR")).join("models").join("ViT-L_14_336px_visual.onnx"))?;
-
-        let text_session = ort::Session::builder()?
-            .with_optimization_level(GraphOptimizationLevel::Level3)?
-            .with_intra_threads(4)?
-            .with_execution_providers([DirectMLExecutionProvider::default().build()])?
-            .commit_from_file(Path::new(env!("CARGO_MANIFEST_DIR")).join("models").join("ViT-L_14_336px_transformer.onnx"))?;
+        // The app loads the encoders separately (see embedding::spawn_model_load());
+        // the combined model is for something like suggested tags, which need both.
+        let visual = ClipVisual::new(info, onnx_settings, &model_paths.visual)?;
+        let text = ClipText::new(info, onnx_settings, &model_paths.text)?;
 
         let logit_scale = f32::ln(1.0 / 0.01);
 
-        Ok( Clip { visual_session, text_session, logit_scale } )
+        Ok( Clip { visual, text, logit_scale } )
+    }
+
+    /// Given a batch of images and a batch of text tokens, returns two Tensors,
+    /// containing the logit scores corresponding to each image and text input.
+    pub fn forward(&self, images: Array<f32, Dim<[usize; 4]>>, tokens: Array2<i32>) -> anyhow::Result<ForwardResults>
+    {
+        let image_features = self.visual.encode_image(images)?;
+        let text_features = self.text.encode_text(tokens)?;
+    
+        // Note that these are already normalized (this convention differs from CLIP)
+
+        // cosine similarity as logits
+        let logit_scale = self.logit_scale.exp();
+        let logits_per_image = image_features.dot(&text_features.t()) * logit_scale;
+        let logits_per_text = logits_per_image.t().to_owned();
+
+        Ok(
+            ForwardResults{
+                logits_per_image: logits_per_image.into_dyn(),
+                logits_per_text: logits_per_text.into_dyn(),
+            }
+        )
+    }
+}
+
+fn normalize_feature_vectors(feature_vectors: &mut Array2<f32>)
+{
+    feature_vectors.axis_iter_mut(Axis(0)).for_each(|mut row| {
+        let norm = row.dot(&row).sqrt();
+        if norm == 0.0 {
+            return;
+        }
+        row /= norm;
+    });
+}
+
+/// The vision portion of the CLIP model.
+pub struct ClipVisual
+{
+    info: &'static ModelInfo,
+    session: ort::Session,
+}
+
+impl ClipVisual
+{
+    pub fn new(info: &'static ModelInfo, onnx_settings: &OnnxSettings, path: &Path) -> Result<Self, ort::Error>
+    {
+        let session = onnx_settings.session_builder("visual")?
+            .commit_from_file(path)?;
+        Ok(ClipVisual { info, session })
+    }
+}
+
+impl ImageEncoder for ClipVisual
+{
+    fn info(&self) -> &'static ModelInfo
+    {
+        self.info
+    }
+
+    fn preprocess_image(&self, image: &DynamicImage) -> Array3<f32>
+    {
+        let resized_image = preprocessing::resize_and_center_crop(image, self.image_input_size() as u32);
+        preprocessing::image_to_clip_array(&resized_image, self.image_input_size())
     }
 
     /// Given a batch of images, returns the image features encoded by the vision portion of the CLIP model.
     /// Use the preprocessing::load_image() function to load the image
-    /// and convert it into an array for this input.
+    /// and preprocess_images() to convert it into an array for this input.
     /// 
-    /// Returns a 2D array of shape (batch_size, FEATURE_VECTOR_LENGTH).
-    pub fn encode_image(&self, images: Array<f32, Dim<[usize; 4]>>) -> anyhow::Result<Array2<f32>>
+    /// Returns a 2D array of shape (batch_size, feature_vector_length()).
+    fn encode_image(&self, images: Array<f32, Dim<[usize; 4]>>) -> anyhow::Result<Array2<f32>>
     {
         let images_len = images.len_of(Axis(0));
         if images_len == 0 {
@@ -98,7 +141,7 @@ impl Clip
         }
         info!("Images len: {}", images_len);
         info!("Running visual session...");
-        let outputs = self.visual_session.run(inputs![images]?)?;
+        let outputs = self.session.run(inputs![images]?)?;
         info!("Ran visual session");
 
 
@@ -107,7 +150,7 @@ impl Clip
         // First dimension is for each image in the batch; the second is the feature vector per image.
         let output = output.try_extract_tensor::<f32>()?;
 
-        let mut output = output.to_shape((images_len, FEATURE_VECTOR_LENGTH))?.to_owned();
+        let mut output = output.to_shape((images_len, self.feature_vector_length()))?.to_owned();
 
         // For example:
         // With one image in the batch....
@@ -119,200 +162,78 @@ impl Clip
 
         // Normalize the output feature vectors. Our HNSW index assumes L2 normalized vectors,
         // so that the cosine similarity is equivalent to the dot product, which is cheaper.
-        Self::normalize_feature_vectors(&mut output);
+        normalize_feature_vectors(&mut output);
 
         Ok(output)
     }
+}
+
+/// The language portion of the CLIP model.
+pub struct ClipText
+{
+    info: &'static ModelInfo,
+    session: ort::Session,
+    tokenizer: instant_clip_tokenizer::Tokenizer,
+}
+
+impl ClipText
+{
+    pub fn new(info: &'static ModelInfo, onnx_settings: &OnnxSettings, path: &Path) -> Result<Self, ort::Error>
+    {
+        let session = onnx_settings.session_builder("text")?
+            .commit_from_file(path)?;
+        let tokenizer = instant_clip_tokenizer::Tokenizer::new();
+        Ok(ClipText { info, session, tokenizer })
+    }
+}
+
+impl TextEncoder for ClipText
+{
+    fn info(&self) -> &'static ModelInfo
+    {
+        self.info
+    }
+
+    fn tokenize(&self, texts: &[&str]) -> Array2<i32>
+    {
+        preprocessing::tokenize_batch(texts.to_vec(), &self.tokenizer)
+    }
 
     /// Given a batch of text tokens, returns the text features encoded by the language portion of the CLIP model.
-    /// Generate tokens using preprocessing::tokenize_batch().
+    /// Generate tokens using tokenize().
     /// 
-    /// Returns a 2D array of shape (batch_size, FEATURE_VECTOR_LENGTH).
-    pub fn encode_text(&self, tokens: Array2<i32>) -> anyhow::Result<Array2<f32>>
+    /// Returns a 2D array of shape (batch_size, feature_vector_length()).
+    fn encode_text(&self, tokens: Array2<i32>) -> anyhow::Result<Array2<f32>>
     {
         if tokens.len_of(Axis(0)) == 0 {
             return Err(anyhow::anyhow!("No text to encode!"));
         }
         let tokens_len = tokens.len_of(Axis(0));
-        let outputs = self.text_session.run(inputs![tokens]?)?;
+        let outputs = self.session.run(inputs![tokens]?)?;
 
         let output = &outputs["FEATURES_EMBEDDED"];
 
         // First dimension is for each text in the batch; the second is the feature vector per text.
         let output = output.try_extract_tensor::<f32>()?;
 
-        let mut output: Array2<f32> = output.to_shape((tokens_len, FEATURE_VECTOR_LENGTH))?.to_owned();
+        let mut output: Array2<f32> = output.to_shape((tokens_len, self.feature_vector_length()))?.to_owned();
 
         // Normalize the output feature vectors. Our HNSW index assumes L2 normalized vectors,
         // so that the cosine similarity is equivalent to the dot product, which is cheaper.
-        Self::normalize_feature_vectors(&mut output);
+        normalize_feature_vectors(&mut output);
 
         Ok(output)
     }
-
-    /// Given a batch of images and a batch of text tokens, returns two Tensors,
-    /// containing the logit scores corresponding to each image and text input.
-    pub fn forward(&self, images: Array<f32, Dim<[usize; 4]>>, tokens: Array2<i32>) -> anyhow::Result<ForwardResults>
-    {
-        let image_features = self.encode_image(images)?;
-        let text_features = self.encode_text(tokens)?;
-    
-        // Note that these are already normalized (this convention differs from CLIP)
-
-        // cosine similarity as logits
-        let logit_scale = self.logit_scale.exp();
-        let logits_per_image = image_features.dot(&text_features.t()) * logit_scale;
-        let logits_per_text = logits_per_image.t().to_owned();
-
-        Ok(
-            ForwardResults{
-                logits_per_image: logits_per_image.into_dyn(),
-                logits_per_text: logits_per_text.into_dyn(),
-            }
-        )
-    }
-
-    pub fn encode_image_files(&self, files: &[UUID], connection: &mut SqliteConnection) -> anyhow::Result<()>
-    {
-        use crate::schema::image_features_vit_l_14_336_px;
-        use crate::schema::failed_encodings;
-        
-        let files = queries::get_filepaths(files, connection)?;
-
-        info!("Encoding images with CLIP...");
-
-        // Encode images with CLIP and add encodings to the database
-        for chunk in files.chunks(32)
-        {
-            info!("Images in chunk: \n {:?}", chunk);
-            // Load and preprocess our images.
-            let images = preprocessing::load_image_batch(&chunk);
-             
-            info!("Loaded images");
-
-            // Split images into those that succesfully loaded and those that failed.
-            // Images may fail to load because they are not images, not found, etc.
-            let (images, failed_images) = images.into_iter().partition::<Vec<_>, _>(|(_, img)| img.is_ok());
- 
-            info!("Successfully loaded images: {}", images.len());
-            info!("Failed to load images: {}", failed_images.len());
-
-            if images.is_empty()
-            {
-                warn!("No images to encode! Either you're throwing me a lot of junk data or there's likely a bug!");
-            }
-            else
-            {
-                // Handle images that succesfully loaded.
-                // Unwrap the succesful images. This is safe because we just partitioned.
-                let images: Vec<(UUID, Box<DynamicImage>)> = images.into_iter().map(|(uuid, img)| (uuid, img.expect("Couldn't unwrap image!"))).collect();
-                trace!("Resizing...");
-                let resized_images = preprocessing::resize_images(images);
-                trace!("Resized images");
-                trace!("Converting to clip format...");
-                let image_clip_input = preprocessing::image_to_clip_format(resized_images);
-                trace!("Converted to clip format");
-                
-                // TODO We need to handle failures here. They seem to come up occasionally?
-                //      If we do get a failure, then to avoid just failing for the whole batch,
-                //      we could try for each individual image. Then individual failures can be put in the failed_encodings table.
-                //      Also, it just crashes right now - we'd rather log it properly and continue?
-                //      At least, it was crashing in our db::init() logic, but that might just be because our error
-                //        handling was set to crash on error and we weren't logging or something.
-                //        Hopefully there's not just a panic-type thing in ORT, I doubt it,
-                //        unless it's something odd with the GPU side?
-                trace!("Encoding images...");
-                let image_encodings: Array2<f32> = self.encode_image(image_clip_input)?;
-                trace!("Encoded images");
-                
-                // Serialize each image encodings with bincode; convert the first axis of the ndarray to a vec
-                trace!("Serializing encodings...");
-                let serialized_encodings: anyhow::Result<Vec<Vec<u8>>> = image_encodings.outer_iter().map(|row| {
-                    Ok(bincode::serialize(&row.to_vec())?)
-                }).collect();
-                let serialized_encodings = serialized_encodings?;
-                trace!("Serialized encodings");
-                
-                // Insert the image encodings into the image_features_vit_l_14_336_px table
-                // The encoding is serialized with serde.
-                // The ID of the encoding is the same as the file ID.
-                let new_image_features: Vec<NewImageFeaturesVitL14336Px> = chunk.iter().zip(serialized_encodings.iter()).map(|((file_id, _), encoding)| {
-                    NewImageFeaturesVitL14336Px {
-                        id: file_id.to_string(),
-                        feature_vector: encoding
-                    }
-                }).collect();
-                
-                diesel::insert_into(image_features_vit_l_14_336_px::table)
-                .values(&new_image_features)
-                .execute(connection)?;
-            }
- 
-            if !failed_images.is_empty()
-            {
-                // Convert the failed images into NewFailedEncoding structs and insert them into the failed_encodings table.
-                // The unwrap is safe because we just partitioned, so these are all Err results.
-                let new_failed_encodings: Vec<NewFailedEncoding> = failed_images.into_iter().map(|(uuid, img)| {
-                    NewFailedEncoding {
-                        id: uuid,
-                        error: img.as_ref().err().expect("Expected error!").to_string(),
-                        failed_at: None
-                    }
-                }).collect();
-                
-                diesel::insert_into(failed_encodings::table)
-                .values(&new_failed_encodings)
-                .execute(connection)?;
-            }
-        }
-
-        Ok(())
-    }
-
-    pub fn encode_files_and_add_to_search(
-        file_ids: &[UUID],
-        connection: &mut SqliteConnection,
-        clip_state: tauri::State<'_, ClipState>,
-        search_state: tauri::State<'_, SearchState<'_>>
-    ) -> anyhow::Result<()>
-    {
-        // Note that we pass in the clip_state rather than using &self here
-        // so that we can quickly release the lock on the app's CLIP state after
-        // encoding the images.
-        {
-            let clip = &clip_state.0.lock().unwrap().clip;
-            clip.encode_image_files(file_ids, connection)?;
-        }
-
-        // Add the resulting encodings to the HNSW search index.
-        let image_features = queries::get_image_feature_data(file_ids, connection)?; 
-        {
-            let hnsw_elements = ann::convert_rows_to_hnsw_elements(&image_features)?;
-            let mut search_inner = search_state.0.lock().unwrap();
-            let hnsw = &mut search_inner.hnsw;
-            hnsw.insert_slice(hnsw_elements);
-        }
-
-        Ok(())
-    }
-
-    fn normalize_feature_vectors(feature_vectors: &mut Array2<f32>)
-    {
-        feature_vectors.axis_iter_mut(Axis(0)).for_each(|mut row| {
-            let norm = row.dot(&row).sqrt();
-            if norm == 0.0 {
-                return;
-            }
-            row /= norm;
-        });
-    }
 }
 
 #[cfg(test)]
 mod tests {
     use ndarray::ArrayView;
     use approx;
-    use ort::CPUExecutionProvider;
+    use ort::{CPUExecutionProvider, GraphOptimizationLevel};
+
+    use crate::embedding::CLIP_VIT_L_14_336PX;
+    use crate::uuid::UUID;
 
     use super::*;
 
@@ -344,16 +265,14 @@ mod tests {
     #[test]
     fn forward_equivalence()
     {
-        let clip = Clip::new().unwrap();
+        let clip = Clip::new(&CLIP_VIT_L_14_336PX, &OnnxSettings::default(), &ModelPaths::in_directory(&Path::new(env!("CARGO_MANIFEST_DIR")).join("models"))).unwrap();
 
         let texts = vec![
             "A photo of a duck",
             "A photo of a cat.",
         ];
 
-        let tokenizer = instant_clip_tokenizer::Tokenizer::new();
-
-        let tokens = preprocessing::tokenize_batch(texts, &tokenizer);
+        let tokens = clip.text.tokenize(&texts);
 
         let images = preprocessing::load_image_batch(&[
             (uuid::Uuid::new_v4().into(), Path::new(env!("CARGO_MANIFEST_DIR")).join("test_images").join("duck.jpg")),
@@ -363,9 +282,7 @@ mod tests {
         // Unwrap the images
         let images: Vec<(UUID, Box<DynamicImage>)> = images.into_iter().map(|(uuid, img)| (uuid, img.unwrap())).collect();
 
-        let resized_images = preprocessing::resize_images(images);
-
-        let image_clip_input = preprocessing::image_to_clip_format(resized_images);
+        let image_clip_input = clip.visual.preprocess_images(images);
 
         let expected_forward_results = forward_onnx(image_clip_input.clone(), tokens.clone()).unwrap();
         let actual_forward_results = clip.forward(image_clip_input, tokens).unwrap();
@@ -373,4 +290,28 @@ mod tests {
         assert!(approx::relative_eq!(expected_forward_results.logits_per_image, actual_forward_results.logits_per_image, epsilon = 0.0001));
         assert!(approx::relative_eq!(expected_forward_results.logits_per_text, actual_forward_results.logits_per_text, epsilon = 0.0001));
     }
-}
\ No newline at end of file
+
+    /// Checks our preprocessing and model against the embedding of OpenAI's reference implementation.
+    /// Small differences are expected (PIL's and the image crate's bicubic filters differ slightly,
+    /// as do PyTorch and the ONNX runtime), but a wrong crop or normalization lowers the similarity well below this.
+    #[test]
+    #[ignore = "requires test_images/duck_reference_embedding.json; generate it with scripts/reference_embeddings.py"]
+    fn duck_matches_reference_embedding()
+    {
+        let test_images = Path::new(env!("CARGO_MANIFEST_DIR")).join("test_images");
+        let reference = std::fs::read_to_string(test_images.join("duck_reference_embedding.json"))
+            .expect("Generate test_images/duck_reference_embedding.json with scripts/reference_embeddings.py");
+        let reference: serde_json::Value = serde_json::from_str(&reference).unwrap();
+        let reference: Vec<f32> = serde_json::from_value(reference["embedding"].clone()).unwrap();
+
+        let clip = Clip::new(&CLIP_VIT_L_14_336PX, &OnnxSettings::default(), &ModelPaths::in_directory(&Path::new(env!("CARGO_MANIFEST_DIR")).join("models"))).unwrap();
+        let images = preprocessing::load_image_batch(&[(uuid::Uuid::new_v4().into(), test_images.join("duck.jpg"))]);
+        let images: Vec<(UUID, Box<DynamicImage>)> = images.into_iter().map(|(uuid, img)| (uuid, img.unwrap())).collect();
+        let embedding = clip.visual.encode_image(clip.visual.preprocess_images(images)).unwrap();
+
+        assert_eq!(embedding.len(), reference.len());
+        // Both are L2 normalized.
+        let cosine_similarity: f32 = embedding.iter().zip(reference.iter()).map(|(a, b)| a * b).sum();
+        assert!(cosine_similarity > 0.99, "Cosine similarity with the reference embedding is {}", cosine_similarity);
+    }
+}
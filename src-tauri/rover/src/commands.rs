@@ -1,23 +1,32 @@
-use log::info;
+use std::collections::HashSet;
+
+use diesel::SqliteConnection;
+use log::{info, warn};
 use tauri::Manager;
 use uuid::Uuid;
 use walkdir::WalkDir;
 
-use crate::clip::Clip;
+use crate::embedding;
+use crate::encoding_queue::{self, JobPriority};
+use crate::file_filter::FileFilter;
 use crate::models::NewFile;
 use crate::notify_handlers::{FsEventHandler, FS_WATCHER_DEBOUNCER_DURATION};
-use crate::state::{ClipState, ClipTokenizerState, ConnectionPoolState, FsWatcherState, SearchState};
+use crate::state::{ConnectionPoolState, EncodingStatsState, FsWatcherState, InnerSearchState, SearchState, SettingsState, TextEmbeddingCacheState, TextModelState};
 use crate::uuid::UUID;
-use crate::{db, junk_drawer, queries, thumbnails};
-use crate::preprocessing;
+use crate::{animation, db, duplicates, failed_encodings, image_loading, junk_drawer, queries, text_embedding_cache, thumbnails, video};
 use imghdr;
-use crate::interface::{FileMetadata, ImageSize, Thumbnail};
+use crate::interface::{DuplicateFile, DuplicateGroup, EmbeddingModelInfo, EncodingQueueStatus, EncodingThroughput, FailedEncodingGroup, FileMetadata, ImageSize, RetrySummary, ScanSummary, SearchResult, Thumbnail};
 use anyhow_tauri::{IntoTAResult, TAResult};
 
 use rayon::prelude::*; // For par_iter
 
-/// Search for image UUIDs which match a query string according to CLIP encodings.
-/// Returns a list of UUIDs of images which match the query.
+/// When the index holds region or frame feature vectors, searches fetch this many times the requested number of neighbors,
+/// since a file may match by several of its regions or frames.
+const FILE_PART_NEIGHBOR_FACTOR: usize = 4;
+
+/// Search for image UUIDs which match a query string according to the active embedding model's encodings.
+/// Returns a list of UUIDs of images which match the query, each with the region of the image which matched
+/// if region embeddings are enabled (see regions.rs), or the frame of an animation which matched (see animation.rs).
 /// We return the UUIDs so that separate API calls can be made to fetch the metadata
 /// and thumbnails; this allows us to display metadata and results more quickly
 /// while the thumbnails are still loading/generating.
@@ -29,10 +38,10 @@ pub async fn search_images<'a>(
         ef_arg: usize,
         distance_threshold: f32,
         search_state: tauri::State<'_, SearchState<'a>>,
-        clip_state: tauri::State<'_, ClipState>,
-        tokenizer_state: tauri::State<'_, ClipTokenizerState>,
+        text_model_state: tauri::State<'_, TextModelState>,
         pool_state: tauri::State<'_, ConnectionPoolState>,
-    ) -> TAResult<Vec<UUID>>
+        text_embedding_cache_state: tauri::State<'_, TextEmbeddingCacheState>,
+    ) -> TAResult<Vec<SearchResult>>
 {
     // Ensure that each entry of path_prefixes has a trailing backslash,
     // since they should be directories.
@@ -53,25 +62,24 @@ pub async fn search_images<'a>(
         (false, false) => {
             info!("Searching for \"{:?}\" with path prefixes {:?}", query_string, path_prefixes);
             // We have both a natural language query and a filter for specific folders.
-            // We want to do an HNSW search, and filter the resulting UUIDs to only those in the specified folders.
-            let uuids = hnsw_search(query_string, number_neighbors, ef_arg, distance_threshold, search_state, clip_state, tokenizer_state)?;
+            // We want to do an HNSW search, restricted to the files in the specified folders.
+            // The restriction is applied within the search, so that files outside of the folders don't take up
+            // the places of matching files among the nearest neighbors.
             let mut connection = pool_state.get_connection().into_ta_result()?;
             let file_ids_matching_prefix = queries::get_files_with_prefix(&path_prefixes, &mut connection)?;
-            let file_ids_matching_prefix: Vec<UUID> = file_ids_matching_prefix.into_iter().map(|x| x.id).collect();
-            // Filter the UUIDs to only those in the specified folders.
-            // First, construct a set from the file_ids_matching_prefix, for O(1) lookup.
-            let file_ids_matching_prefix_set: std::collections::HashSet<UUID> = file_ids_matching_prefix.iter().cloned().collect();
-            let out: Vec<UUID> = uuids.into_iter().filter(|x| file_ids_matching_prefix_set.contains(x)).collect();
-            info!("Found {:?} results", out.len());
-            Ok(out)
+            let file_ids_matching_prefix_set: HashSet<UUID> = file_ids_matching_prefix.into_iter().map(|x| x.id).collect();
+            let results = hnsw_search(query_string, number_neighbors, ef_arg, distance_threshold, Some(&file_ids_matching_prefix_set), search_state, text_model_state, text_embedding_cache_state, &mut connection)?;
+            info!("Found {:?} results", results.len());
+            Ok(results)
         },
         (true, false) => {
             info!("Searching for \"{:?}\" with no path prefix filter", query_string);
             // We have a natural language query but no filter for specific folders.
             // We want to do an HNSW search across all folders.
-            let uuids = hnsw_search(query_string, number_neighbors, ef_arg, distance_threshold, search_state, clip_state, tokenizer_state)?;
-            info!("Found {:?} results", uuids.len());
-            Ok(uuids)
+            let mut connection = pool_state.get_connection().into_ta_result()?;
+            let results = hnsw_search(query_string, number_neighbors, ef_arg, distance_threshold, None, search_state, text_model_state, text_embedding_cache_state, &mut connection)?;
+            info!("Found {:?} results", results.len());
+            Ok(results)
         },
         (false, true) => {
             info!("Searching for no query with path prefixes {:?}", path_prefixes);
@@ -79,48 +87,92 @@ pub async fn search_images<'a>(
             // Simply return all UUIDs with any of the specified prefixes.
             let mut connection = pool_state.get_connection().into_ta_result()?;
             let file_ids_matching_prefix = queries::get_files_with_prefix(&path_prefixes, &mut connection)?;
-            let file_ids_matching_prefix: Vec<UUID> = file_ids_matching_prefix.into_iter().map(|x| x.id).collect();
+            let file_ids_matching_prefix: Vec<SearchResult> = file_ids_matching_prefix.into_iter().map(|x| SearchResult { file_id: x.id, region: None, frame: None }).collect();
             info!("Found {:?} files matching prefix", file_ids_matching_prefix.len());
             Ok(file_ids_matching_prefix)
         }
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 fn hnsw_search<'a>(
     query_string: &str,
     number_neighbors: usize,
     ef_arg: usize,
     distance_threshold: f32,
+    allowed_file_ids: Option<&HashSet<UUID>>,
     search_state: tauri::State<'_, SearchState<'a>>,
-    clip_state: tauri::State<'_, ClipState>,
-    tokenizer_state: tauri::State<'_, ClipTokenizerState>,
-) -> anyhow::Result<Vec<UUID>>
+    text_model_state: tauri::State<'_, TextModelState>,
+    text_embedding_cache_state: tauri::State<'_, TextEmbeddingCacheState>,
+    connection: &mut SqliteConnection,
+) -> anyhow::Result<Vec<SearchResult>>
 {
-    let mut hnsw_search = search_state.0.lock().unwrap();
-    let hnsw = &mut hnsw_search.hnsw;
-    
-    let tokenizer = &tokenizer_state.0.lock().unwrap().tokenizer;
-    let query = preprocessing::tokenize(query_string, tokenizer);
-        
-    let clip = &mut clip_state.0.lock().unwrap().clip;
-        
-    let query_vector = clip.encode_text(query).into_ta_result()?;
-        
-    let query_vector_slice = query_vector.as_slice()
-        .ok_or(anyhow::anyhow!("Error converting query vector to slice for query {:?}", query_string))
-        .into_ta_result()?;
-    
+    // The index isn't locked while the query is encoded, so that the encoding worker and other searches don't wait for
+    // the text model.
+    let model_id = search_state.0.lock().unwrap().model_id.clone();
+
+    // Repeated queries (e.g. when only the folder filters change) are served from the cache, skipping the model.
+    let query_vector = text_embedding_cache::get_or_encode(&text_embedding_cache_state, &model_id, query_string, connection, || {
+        let text_model_state = text_model_state.0.lock().unwrap();
+        let model = text_model_state.model()?;
+        if model.id() != model_id {
+            return Err(anyhow::anyhow!("The search index does not contain {} feature vectors", model.id()));
+        }
+
+        let query = model.tokenize(&[query_string]);
+        let query_vector = model.encode_text(query)?;
+        let query_vector_slice = query_vector.as_slice()
+            .ok_or(anyhow::anyhow!("Error converting query vector to slice for query {:?}", query_string))?;
+        Ok(query_vector_slice.to_vec())
+    })?;
+
     info!("Searching for {:?}", query_string);
+    let search = search_state.0.lock().unwrap();
+    if search.model_id != model_id {
+        return Err(anyhow::anyhow!("The active model was switched from {} to {} during the search", model_id, search.model_id));
+    }
+    Ok(search_index(&search, &query_vector, number_neighbors, ef_arg, distance_threshold, allowed_file_ids))
+}
+
+/// Searches the index for the nearest neighbors of the query vector, optionally restricted to a set of file IDs.
+/// A file which matches by several of its regions or frames (or by those and the whole image) is returned once, at its best match.
+fn search_index(
+    search: &InnerSearchState,
+    query_vector: &[f32],
+    number_neighbors: usize,
+    ef_arg: usize,
+    distance_threshold: f32,
+    allowed_file_ids: Option<&HashSet<UUID>>,
+) -> Vec<SearchResult>
+{
     let now = std::time::Instant::now();
+    // Fetch extra neighbors if the index holds regions or frames, so that enough distinct files are left once each file's matches are merged.
+    let knbn = if search.has_file_parts() { number_neighbors * FILE_PART_NEIGHBOR_FACTOR } else { number_neighbors };
     // Ensure ef_arg >= num_neighbors.
-    let ef_arg = ef_arg.max(number_neighbors);
-    let search_results = hnsw.search(query_vector_slice, number_neighbors, ef_arg, distance_threshold);
+    let ef_arg = ef_arg.max(knbn);
+    let search_results = match allowed_file_ids
+    {
+        Some(allowed) => search.search_filtered(query_vector, knbn, ef_arg, distance_threshold, &|id| allowed.contains(id)),
+        None => search.search(query_vector, knbn, ef_arg, distance_threshold),
+    };
     let elapsed = now.elapsed();
+    if search.is_building() {
+        info!("Search index is still being built; used exact search.");
+    }
     info!("Search took {:?} for {:?} neighbors with ef_ arg {:?} and distance threshold {:?}", elapsed, number_neighbors, ef_arg, distance_threshold);
     info!("Found {:?} results", search_results.len());
-    
-    let search_results_uuids: Vec<UUID> = search_results.iter().map(|x| x.0).collect();
-    Ok(search_results_uuids)
+
+    // Results are ordered by distance, so the first match of each file is its best.
+    let mut seen = HashSet::new();
+    search_results.into_iter()
+        .map(|(id, _)| SearchResult {
+            file_id: search.file_id(&id),
+            region: search.file_region(&id).map(|file_region| file_region.region),
+            frame: search.file_frame(&id).map(|file_frame| file_frame.frame),
+        })
+        .filter(|result| seen.insert(result.file_id))
+        .take(number_neighbors)
+        .collect()
 }
 
 /// Fetches the thumbnail filenames for a list of file IDs.
@@ -214,10 +266,27 @@ pub async fn fetch_metadata(
 
     let image_type = imghdr::from_file(&filepath).into_ta_result()?;
     
-    let dimensions = imagesize::size(&filepath);
-    let dimensions = match dimensions {
-        Ok(dim) => Some(ImageSize { width: dim.width as u32, height: dim.height as u32 }),
-        Err(_) => None
+    let mut dimensions = image_loading::image_dimensions(filepath).map(|(width, height)| ImageSize { width, height });
+
+    let animation_info = animation::animation_info(filepath);
+    let (mut frame_count, mut duration_ms) = (animation_info.map(|info| info.frame_count), animation_info.map(|info| info.duration_ms));
+
+    let video_filepath = if video::is_video(filepath) {
+        // ffprobe isn't run while videos are off; the front-end can still play the video.
+        let video_settings = app_handle.state::<SettingsState>().0.lock().unwrap().settings.video.clone();
+        if video_settings.enabled {
+            match video::probe(filepath, &video_settings)
+            {
+                Ok(info) => {
+                    dimensions = Some(ImageSize { width: info.width, height: info.height });
+                    (frame_count, duration_ms) = (info.frame_count, info.duration_ms);
+                },
+                Err(e) => warn!("Error probing video {:?}: {}", filepath, e),
+            }
+        }
+        Some(filepath.to_str().ok_or(anyhow::anyhow!("Unable to convert {:?} to a string. Is it valid UTF-8?", filepath))?.to_string())
+    } else {
+        None
     };
     
     let filename = filepath.file_name()
@@ -233,6 +302,10 @@ pub async fn fetch_metadata(
         size: dimensions,
         date_created,
         date_modified,
+        frame_count,
+        duration_ms,
+        video_filepath,
+        camera: image_loading::read_camera_info(filepath),
     };
 
     Ok(metadata)
@@ -241,15 +314,17 @@ pub async fn fetch_metadata(
 /// Note that this does not handle recursive watching of subdirectories.
 /// If the user wants to watch subdirectories, the front-end should construct that set of
 /// directories and invoke this command for each one.
+/// Only files with the given extensions (or whose content is an image with one of them) are indexed;
+/// if None, the indexed_extensions setting is used. See file_filter.rs.
 #[tauri::command]
 pub async fn add_watched_directory(
     directory: String,
+    extensions: Option<Vec<String>>,
     watcher_state: tauri::State<'_, FsWatcherState>,
-    clip_state: tauri::State<'_, ClipState>,
     pool_state: tauri::State<'_, ConnectionPoolState>,
-    search_state: tauri::State<'_, SearchState<'_>>,
+    settings_state: tauri::State<'_, SettingsState>,
     app_handle: tauri::AppHandle,
-) -> TAResult<()>
+) -> TAResult<ScanSummary>
 {
     // TODO And send a message showing we're doing analysis... 
     //      I'm also thinking that the status should account for potentially-multiple background threads doing work.
@@ -277,13 +352,20 @@ pub async fn add_watched_directory(
     // https://docs.diesel.rs/2.0.x/diesel/connection/trait.Connection.html#method.transaction
     let watched_dir_uuid = queries::insert_watched_directory(
         &directory,
+        extensions.as_deref(),
         &mut connection
     ).into_ta_result()?;
 
+    let file_filter = match &extensions
+    {
+        Some(extensions) => FileFilter::new(extensions),
+        None => FileFilter::new(&settings_state.0.lock().unwrap().settings.indexed_extensions),
+    };
     let fs_event_handler = FsEventHandler {
         app_handle: app_handle.clone(),
         watch_directory_id: watched_dir_uuid,
         watch_directory_path: directory_path.to_path_buf(),
+        file_filter: file_filter.clone(),
     };
     let watcher = notify_debouncer_full::new_debouncer(
         FS_WATCHER_DEBOUNCER_DURATION,
@@ -304,6 +386,7 @@ pub async fn add_watched_directory(
     // Recursively get all entries in the directory, skipping errors
     let mut file_ids: Vec<UUID> = Vec::new();
     let mut new_files: Vec<NewFile> = Vec::new();
+    let mut summary = ScanSummary::default();
     for entry in WalkDir::new(directory_path)
         .follow_links(false)
         .into_iter()
@@ -311,6 +394,10 @@ pub async fn add_watched_directory(
     {
         let file_path = entry.path();
         if file_path.is_file() {
+            if !file_filter.is_indexable(file_path) {
+                summary.skipped += 1;
+                continue;
+            }
             let file_uuid = Uuid::new_v4().into();
             let file_path_str = file_path.to_str().ok_or(anyhow::anyhow!("Unable to convert path to string"))?;
             file_ids.push(file_uuid);
@@ -324,13 +411,13 @@ pub async fn add_watched_directory(
         }
     }
     queries::insert_files_rows(&new_files, &mut connection).into_ta_result()?;
+    summary.added = new_files.len();
+    info!("Added watched directory {:?}: {} new files, skipped {} which aren't indexed", directory_path, summary.added, summary.skipped);
 
-    // Encode images and store results in the DB.
-    // Note this is relatively long-running; this command is async, so it will not block the main thread.
-    // But it's a good idea to keep this as the last step in the command so other tables are updated quickly.
-    Clip::encode_files_and_add_to_search(&file_ids, &mut connection, clip_state, search_state)?;
+    // Queue the images for encoding; the encoding worker stores the results in the DB and adds them to the search index.
+    encoding_queue::enqueue(&app_handle, &file_ids, JobPriority::NewFiles).into_ta_result()?;
 
-    Ok(())
+    Ok(summary)
 }
 
 #[tauri::command]
@@ -369,7 +456,9 @@ pub async fn delete_watched_directory(
 
     match watched_dir_uuid {
         Some(uuid) => {
-            queries::delete_watched_directories_cascade(&[uuid], &mut connection, app_handle)?;
+            let file_ids = queries::delete_watched_directories_cascade(&[uuid], &mut connection, app_handle.clone())?;
+            // Keep removed files out of search results.
+            app_handle.state::<SearchState>().0.lock().unwrap().remove(&file_ids);
             Ok(())
         },
         None => {
@@ -382,4 +471,292 @@ pub async fn delete_watched_directory(
 // TODO We want non-blocking (non-async) commands that just check if a directory is watched already or not (and maybe check if it exists on DB or something?)
 //      We'll call that when we want to add/remove dirs from our list.
 //      Then we'll call the async commands to actually add/remove them, which would take longer.
-//   Possibly we want to mark them as deleted? And then we have a command that says "okay take a dir marked for deletion and go do that".
\ No newline at end of file
+//   Possibly we want to mark them as deleted? And then we have a command that says "okay take a dir marked for deletion and go do that".
+
+/// Finds groups of near-duplicate images (e.g. re-saved or re-cropped copies) among all encoded files,
+/// replacing the previously found groups. Returns the number of groups found.
+/// The groups can then be fetched with get_duplicate_groups().
+///
+/// @param distance_threshold The cosine distance below which two images are considered copies;
+/// defaults to duplicates::DEFAULT_DUPLICATE_DISTANCE_THRESHOLD.
+#[tauri::command]
+pub async fn find_duplicates(
+    distance_threshold: Option<f32>,
+    app_handle: tauri::AppHandle,
+) -> TAResult<usize>
+{
+    let distance_threshold = distance_threshold.unwrap_or(duplicates::DEFAULT_DUPLICATE_DISTANCE_THRESHOLD);
+    let num_groups = duplicates::detect_duplicates(&app_handle, distance_threshold).into_ta_result()?;
+    Ok(num_groups)
+}
+
+/// Gets the groups of near-duplicate images found by the last call to find_duplicates(),
+/// with the file size and dimensions of each file.
+#[tauri::command]
+pub async fn get_duplicate_groups(
+    pool_state: tauri::State<'_, ConnectionPoolState>,
+) -> TAResult<Vec<DuplicateGroup>>
+{
+    let mut connection = pool_state.get_connection().into_ta_result()?;
+    let groups = queries::get_duplicate_groups(&mut connection)?;
+
+    let out = groups.into_par_iter().map(|(group_id, members)| {
+        let files = members.into_iter().map(|(file_id, filepath)| {
+            let file_size = std::fs::metadata(&filepath).ok().map(|m| m.len());
+            let size = image_loading::image_dimensions(&filepath).map(|(width, height)| ImageSize { width, height });
+            DuplicateFile { file_id, filepath: filepath.to_string_lossy().to_string(), file_size, size }
+        }).collect();
+        DuplicateGroup { group_id, files }
+    }).collect();
+
+    Ok(out)
+}
+
+/// Gets the files which failed to load or encode, with their errors, grouped by the kind of error.
+#[tauri::command]
+pub async fn get_failed_encodings(
+    app_handle: tauri::AppHandle,
+) -> TAResult<Vec<FailedEncodingGroup>>
+{
+    let groups = failed_encodings::get_failed_encoding_groups(&app_handle).into_ta_result()?;
+    Ok(groups)
+}
+
+/// Retries encoding the given files which previously failed, or all of them if file_ids is omitted.
+/// The files are removed from the failed encodings and queued ahead of other files; those which fail again
+/// are recorded with their new errors once they are encoded.
+#[tauri::command]
+pub async fn retry_failed_encodings(
+    file_ids: Option<Vec<UUID>>,
+    app_handle: tauri::AppHandle,
+) -> TAResult<RetrySummary>
+{
+    let summary = failed_encodings::retry_failed_encodings(&app_handle, file_ids.as_deref(), JobPriority::Visible).into_ta_result()?;
+    Ok(summary)
+}
+
+/// Gets the throughput of the most recent encoding run and of all runs since the app started.
+#[tauri::command]
+pub fn get_encoding_stats(
+    encoding_stats_state: tauri::State<'_, EncodingStatsState>,
+) -> TAResult<EncodingThroughput>
+{
+    let stats = encoding_stats_state.0.lock().unwrap();
+    Ok(EncodingThroughput { last_run: stats.last_run, total: stats.total })
+}
+
+/// Gets the number of files waiting to be encoded, and whether encoding is paused.
+/// The status is also emitted with the EncodingQueueStatus event whenever it changes.
+#[tauri::command]
+pub fn get_encoding_queue_status(
+    app_handle: tauri::AppHandle,
+) -> TAResult<EncodingQueueStatus>
+{
+    let status = encoding_queue::get_status(&app_handle).into_ta_result()?;
+    Ok(status)
+}
+
+/// Pauses encoding after the batch in progress. Queued files stay queued until encoding is resumed.
+#[tauri::command]
+pub fn pause_encoding(
+    app_handle: tauri::AppHandle,
+) -> TAResult<()>
+{
+    encoding_queue::pause(&app_handle).into_ta_result()?;
+    Ok(())
+}
+
+#[tauri::command]
+pub fn resume_encoding(
+    app_handle: tauri::AppHandle,
+) -> TAResult<()>
+{
+    encoding_queue::resume(&app_handle).into_ta_result()?;
+    Ok(())
+}
+
+/// Removes the given files from the encoding queue, or every queued file if file_ids is omitted.
+#[tauri::command]
+pub fn cancel_encoding(
+    file_ids: Option<Vec<UUID>>,
+    app_handle: tauri::AppHandle,
+) -> TAResult<()>
+{
+    encoding_queue::cancel(&app_handle, file_ids.as_deref()).into_ta_result()?;
+    Ok(())
+}
+
+/// Moves the given files, e.g. those visible in the gallery, to the front of the encoding queue.
+/// Files which are already encoded are ignored.
+#[tauri::command]
+pub fn prioritize_encoding(
+    file_ids: Vec<UUID>,
+    app_handle: tauri::AppHandle,
+) -> TAResult<()>
+{
+    encoding_queue::enqueue(&app_handle, &file_ids, JobPriority::Visible).into_ta_result()?;
+    Ok(())
+}
+
+/// Lists the embedding models RefRover supports, whether their files are installed, and which is active.
+#[tauri::command]
+pub fn get_embedding_models(
+    settings_state: tauri::State<'_, SettingsState>,
+    app_handle: tauri::AppHandle,
+) -> TAResult<Vec<EmbeddingModelInfo>>
+{
+    let settings = settings_state.0.lock().unwrap().settings.clone();
+    let models = embedding::REGISTRY.iter().map(|info| {
+        EmbeddingModelInfo {
+            id: info.id.to_string(),
+            name: info.name.to_string(),
+            feature_vector_length: info.feature_vector_length,
+            installed: embedding::is_installed(&app_handle, info, settings.model_directory.as_deref()),
+            active: info.id == settings.active_model,
+        }
+    }).collect();
+    Ok(models)
+}
+
+/// Makes the model the active embedding model, used for encoding and search.
+/// Files which have not been encoded with the model yet are encoded in the background;
+/// until then, they do not appear in search results.
+#[tauri::command]
+pub async fn set_active_embedding_model(
+    model_id: String,
+    app_handle: tauri::AppHandle,
+) -> TAResult<()>
+{
+    embedding::set_active_model(&app_handle, &model_id).into_ta_result()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests
+{
+    use crate::animation::{FileFrame, Frame};
+    use crate::ann::{HnswElement, IndexBackend};
+    use crate::ann_benchmark;
+    use crate::preprocessing::FEATURE_VECTOR_LENGTH;
+    use crate::quantization::VectorEncoding;
+    use crate::regions::{FileRegion, Region};
+
+    use super::*;
+
+    /// A flat (exact) search state containing `count` synthetic feature vectors, and their IDs.
+    fn flat_search_state(count: usize) -> (InnerSearchState<'static>, Vec<HnswElement>)
+    {
+        let data = ann_benchmark::synthetic_feature_vectors(count, FEATURE_VECTOR_LENGTH, 5, 0.8, 11);
+        let elements: Vec<HnswElement> = data.into_iter().map(|v| HnswElement { feature_vector: v, id: Uuid::new_v4().into() }).collect();
+        let mut search = InnerSearchState::new_building(embedding::DEFAULT_MODEL_ID, IndexBackend::Flat, VectorEncoding::F32);
+        // Not building; search the flat index directly.
+        search.fallback = None;
+        search.insert(elements.clone());
+        (search, elements)
+    }
+
+    #[test]
+    fn search_index_finds_query_first()
+    {
+        let (search, elements) = flat_search_state(100);
+        for elem in &elements[..10]
+        {
+            let results = search_index(&search, &elem.feature_vector, 5, 5, f32::MAX, None);
+            assert_eq!(results.len(), 5);
+            assert_eq!(results[0].file_id, elem.id);
+            assert_eq!(results[0].region, None);
+        }
+    }
+
+    #[test]
+    fn search_index_respects_allowed_file_ids()
+    {
+        let (search, elements) = flat_search_state(100);
+        let allowed: HashSet<UUID> = elements[50..].iter().map(|e| e.id).collect();
+        // The query is outside of the allowed set, but we should still get a full set of neighbors from within it.
+        let results = search_index(&search, &elements[0].feature_vector, 10, 10, f32::MAX, Some(&allowed));
+        assert_eq!(results.len(), 10);
+        assert!(results.iter().all(|result| allowed.contains(&result.file_id)));
+
+        let results = search_index(&search, &elements[0].feature_vector, 10, 10, f32::MAX, Some(&HashSet::new()));
+        assert!(results.is_empty());
+    }
+
+    #[test]
+    fn search_index_excludes_removed_files()
+    {
+        let (mut search, elements) = flat_search_state(100);
+        search.remove(&[elements[0].id]);
+        let results = search_index(&search, &elements[0].feature_vector, 10, 10, f32::MAX, None);
+        assert_eq!(results.len(), 10);
+        assert!(!results.iter().any(|result| result.file_id == elements[0].id));
+    }
+
+    #[test]
+    fn search_index_falls_back_while_building()
+    {
+        let (_, elements) = flat_search_state(20);
+        let mut search = InnerSearchState::new_building(embedding::DEFAULT_MODEL_ID, IndexBackend::Hnsw, VectorEncoding::F32);
+        search.insert(elements.clone());
+        assert!(search.is_building());
+        assert!(search.index.is_empty());
+        let results = search_index(&search, &elements[3].feature_vector, 1, 1, f32::MAX, None);
+        assert_eq!(results, vec![SearchResult { file_id: elements[3].id, region: None, frame: None }]);
+    }
+
+    #[test]
+    fn search_index_merges_the_matches_of_regions()
+    {
+        let (mut search, elements) = flat_search_state(100);
+        // The first two elements are the regions of another file, instead of files themselves.
+        search.remove(&[elements[0].id, elements[1].id]);
+        let file_id = elements[50].id;
+        let region = |x| Region { x, y: 0, width: 100, height: 100 };
+        search.insert_regions(vec![
+            (elements[0].clone(), FileRegion { file_id, region: region(0) }),
+            (elements[1].clone(), FileRegion { file_id, region: region(50) }),
+        ]);
+
+        // The region which matched is reported, and the file is only returned once.
+        let results = search_index(&search, &elements[0].feature_vector, 10, 10, f32::MAX, None);
+        assert_eq!(results[0], SearchResult { file_id, region: Some(region(0)), frame: None });
+        assert_eq!(results.len(), 10);
+        assert_eq!(results.iter().filter(|result| result.file_id == file_id).count(), 1);
+        assert!(!results.iter().any(|result| result.file_id == elements[0].id));
+
+        // Regions are filtered by their file.
+        let allowed: HashSet<UUID> = elements[2..50].iter().map(|e| e.id).collect();
+        let results = search_index(&search, &elements[0].feature_vector, 10, 10, f32::MAX, Some(&allowed));
+        assert!(results.iter().all(|result| allowed.contains(&result.file_id) && result.region.is_none()));
+
+        // Removing the file removes its regions.
+        search.remove(&[file_id]);
+        assert!(search.regions.is_empty());
+        let results = search_index(&search, &elements[0].feature_vector, 10, 10, f32::MAX, None);
+        assert!(!results.iter().any(|result| result.file_id == file_id));
+    }
+
+    #[test]
+    fn search_index_reports_the_matching_frame()
+    {
+        let (mut search, elements) = flat_search_state(100);
+        // The first two elements are frames of an animation.
+        search.remove(&[elements[0].id, elements[1].id]);
+        let file_id = elements[50].id;
+        let frame = |index| Frame { index, timestamp_ms: index as u64 * 40 };
+        search.insert_frames(vec![
+            (elements[0].clone(), FileFrame { file_id, frame: frame(4) }),
+            (elements[1].clone(), FileFrame { file_id, frame: frame(8) }),
+        ]);
+
+        let results = search_index(&search, &elements[1].feature_vector, 10, 10, f32::MAX, None);
+        assert_eq!(results[0], SearchResult { file_id, region: None, frame: Some(frame(8)) });
+        assert_eq!(results.iter().filter(|result| result.file_id == file_id).count(), 1);
+
+        // Removing the file removes its frames.
+        search.remove(&[file_id]);
+        assert!(search.frames.is_empty());
+        let results = search_index(&search, &elements[1].feature_vector, 10, 10, f32::MAX, None);
+        assert!(!results.iter().any(|result| result.file_id == file_id));
+    }
+}
@@ -9,12 +9,14 @@ use diesel::prelude::*;
 use diesel::r2d2::{ConnectionManager, Pool, PooledConnection};
 use diesel::sqlite::SqliteConnection;
 use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
+use log::info;
 use uuid::Uuid;
 
 use crate::models::NewTag;
+use crate::quantization::{self, VectorEncoding};
 use crate::state::ConnectionPoolState;
 use crate::db;
-use crate::queries::{add_tag_edge, delete_tag_edge, get_edge_id};
+use crate::queries::{self, add_tag_edge, delete_tag_edge, get_edge_id};
 
 const MIGRATIONS: EmbeddedMigrations = embed_migrations!();
 
@@ -76,8 +78,9 @@ pub fn get_connection_pool(app_handle: &tauri::AppHandle) -> anyhow::Result<Pool
     }
 }
 
-pub fn init(pool_state: &tauri::State<'_, ConnectionPoolState>, populate_dummy_data: bool) -> anyhow::Result<()> {
+pub fn init(pool_state: &tauri::State<'_, ConnectionPoolState>, populate_dummy_data: bool, vector_encoding: VectorEncoding) -> anyhow::Result<()> {
     run_migrations(pool_state)?;
+    convert_feature_vector_encodings(pool_state, vector_encoding)?;
     
     // TODO Remove this eventually, it's just for testing. We will eventually be populating the DB via the UI and calling into more specific functions.
     if populate_dummy_data {
@@ -101,6 +104,45 @@ fn run_migrations(pool_state: &tauri::State<'_, ConnectionPoolState>) -> anyhow:
     anyhow::Ok(())
 }
 
+/// Converts any stored feature vectors (of files, regions and frames) which are not in the target encoding
+/// (e.g. after the user changes the vector_encoding setting, or rows written before quantization was supported)
+/// to the target encoding. This is done in a single transaction, so a failure leaves the tables unchanged.
+fn convert_feature_vector_encodings(pool_state: &tauri::State<'_, ConnectionPoolState>, target: VectorEncoding) -> anyhow::Result<()> {
+    let mut connection = get_db_connection(pool_state)?;
+
+    let images = queries::get_image_feature_data_not_in_encoding(target.to_db(), &mut connection)?;
+    let regions = queries::get_region_feature_data_not_in_encoding(target.to_db(), &mut connection)?;
+    let frames = queries::get_frame_feature_data_not_in_encoding(target.to_db(), &mut connection)?;
+    let count = images.len() + regions.len() + frames.len();
+    if count == 0 {
+        return Ok(());
+    }
+
+    let convert = |feature_vector: &[u8], encoding: i32| -> anyhow::Result<Vec<u8>> {
+        let vector = quantization::decode(feature_vector, VectorEncoding::from_db(encoding)?)?;
+        quantization::encode(&vector, target)
+    };
+    info!("Converting {} feature vectors to {:?}...", count, target);
+    connection.transaction(|connection| {
+        for row in &images {
+            let converted = convert(&row.feature_vector, row.encoding)?;
+            queries::update_image_feature_vector(row.file_id, &row.model_id, &converted, target.to_db(), connection)?;
+        }
+        for row in &regions {
+            let converted = convert(&row.feature_vector, row.encoding)?;
+            queries::update_region_feature_vector(row.id, &converted, target.to_db(), connection)?;
+        }
+        for row in &frames {
+            let converted = convert(&row.feature_vector, row.encoding)?;
+            queries::update_frame_feature_vector(row.id, &converted, target.to_db(), connection)?;
+        }
+        anyhow::Ok(())
+    })?;
+    info!("Converted feature vectors.");
+
+    Ok(())
+}
+
 /// Gets the path to the SQLite database file.
 /// Ensures that its parent directory exists.
 /// The DB file may not exist yet; this function just gets the path.
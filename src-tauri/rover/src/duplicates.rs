@@ -0,0 +1,229 @@
+/// Near-duplicate detection, for finding re-saved and re-cropped copies of the same image.
+///
+/// Every file's feature vector is used as a query against the search index; any neighbors within a tight
+/// cosine distance are considered copies of the file. Since "is a near-duplicate of" is not transitive
+/// (A may be close to B and B close to C without A being close to C), the pairs are clustered with
+/// union-find, so that each group contains every file connected to another by a chain of near-duplicates.
+/// The groups are stored in the duplicate_groups table, replacing the results of the previous run.
+
+use log::info;
+use rustc_hash::FxHashMap;
+use tauri::{AppHandle, Manager};
+
+use crate::ann::{self, HnswElement, DEFAULT_MAX_NB_CONNECTION};
+use crate::queries;
+use crate::state::{ConnectionPoolState, InnerSearchState, SearchState};
+use crate::uuid::UUID;
+
+/// The cosine distance below which two images are considered near-duplicates.
+/// Re-encoded or resized copies are typically well below 0.02; light crops and colour adjustments below 0.05.
+/// Semantically similar but distinct images are rarely below 0.1.
+pub const DEFAULT_DUPLICATE_DISTANCE_THRESHOLD: f32 = 0.05;
+
+/// The number of neighbors to look for per file. Groups may be larger than this, since they are joined transitively.
+const MAX_NEIGHBORS: usize = 16;
+
+/// The number of files to query for per lock of the SearchState, so that searches from the front-end are not
+/// blocked for the duration of the job.
+const QUERY_CHUNK_SIZE: usize = 256;
+
+/// A disjoint-set forest over the indices 0..n, with path compression and union by size.
+struct UnionFind
+{
+    parent: Vec<usize>,
+    size: Vec<usize>,
+}
+
+impl UnionFind
+{
+    fn new(n: usize) -> Self
+    {
+        UnionFind { parent: (0..n).collect(), size: vec![1; n] }
+    }
+
+    fn find(&mut self, x: usize) -> usize
+    {
+        let mut root = x;
+        while self.parent[root] != root {
+            root = self.parent[root];
+        }
+        // Point everything on the path directly at the root.
+        let mut x = x;
+        while self.parent[x] != root {
+            let next = self.parent[x];
+            self.parent[x] = root;
+            x = next;
+        }
+        root
+    }
+
+    fn union(&mut self, a: usize, b: usize)
+    {
+        let (a, b) = (self.find(a), self.find(b));
+        if a == b {
+            return;
+        }
+        let (large, small) = if self.size[a] >= self.size[b] { (a, b) } else { (b, a) };
+        self.parent[small] = large;
+        self.size[large] += self.size[small];
+    }
+}
+
+/// Finds the groups of near-duplicates among the elements by searching the index for the neighbors of each.
+/// Only the elements are searched; the rest of the index (e.g. regions and frames) is ignored.
+/// Returns the groups with at least two members, largest first, with the members in the order of the elements.
+pub fn find_duplicate_groups(search: &InnerSearchState, elements: &[HnswElement], distance_threshold: f32) -> Vec<Vec<UUID>>
+{
+    let id_to_index: FxHashMap<UUID, usize> = elements.iter().enumerate().map(|(i, e)| (e.id, i)).collect();
+    let mut union_find = UnionFind::new(elements.len());
+    add_neighbors(search, elements, 0, distance_threshold, &id_to_index, &mut union_find);
+    collect_groups(elements, &mut union_find)
+}
+
+/// Searches for the neighbors of the elements, unioning each element with its near-duplicates.
+/// `offset` is the index of elements[0] within the full set of elements.
+/// Only the elements themselves are searched, so that regions and frames (e.g. a video's own keyframes) in the index
+/// don't take the places of neighboring files.
+fn add_neighbors(
+    search: &InnerSearchState,
+    elements: &[HnswElement],
+    offset: usize,
+    distance_threshold: f32,
+    id_to_index: &FxHashMap<UUID, usize>,
+    union_find: &mut UnionFind)
+{
+    for (i, elem) in elements.iter().enumerate()
+    {
+        // The element itself is typically the nearest neighbor, so ask for one more.
+        let neighbors = search.search_elements_filtered(&elem.feature_vector, MAX_NEIGHBORS + 1, DEFAULT_MAX_NB_CONNECTION, distance_threshold,
+            &|id| id_to_index.contains_key(id));
+        for (neighbor_id, _) in neighbors
+        {
+            if let Some(neighbor) = id_to_index.get(&neighbor_id) {
+                union_find.union(offset + i, *neighbor);
+            }
+        }
+    }
+}
+
+fn collect_groups(elements: &[HnswElement], union_find: &mut UnionFind) -> Vec<Vec<UUID>>
+{
+    let mut root_to_group: FxHashMap<usize, usize> = FxHashMap::default();
+    let mut groups: Vec<Vec<UUID>> = Vec::new();
+    for (i, elem) in elements.iter().enumerate()
+    {
+        let root = union_find.find(i);
+        let group = *root_to_group.entry(root).or_insert_with(|| {
+            groups.push(Vec::new());
+            groups.len() - 1
+        });
+        groups[group].push(elem.id);
+    }
+    groups.retain(|group| group.len() > 1);
+    // Stable, so groups of equal size keep the order of their first member.
+    groups.sort_by(|a, b| b.len().cmp(&a.len()));
+    groups
+}
+
+/// Finds the near-duplicate groups among all encoded files and stores them, replacing any previous groups.
+/// Returns the number of groups found.
+///
+/// This may take some time for large libraries, so it should not be run on the main thread.
+pub fn detect_duplicates(app_handle: &AppHandle, distance_threshold: f32) -> anyhow::Result<usize>
+{
+    let now = std::time::Instant::now();
+    let pool_state = app_handle.state::<ConnectionPoolState>();
+    let mut connection = pool_state.get_connection()?;
+
+    // Compare the feature vectors of the model whose vectors are in the index.
+    let search_state = app_handle.state::<SearchState>();
+    let model_id = search_state.0.lock().unwrap().model_id.clone();
+    let rows = queries::get_all_image_feature_data(&model_id, &mut connection)?;
+    let elements = ann::convert_rows_to_hnsw_elements(&rows)?;
+    drop(rows);
+    info!("Searching for near-duplicates among {} files with distance threshold {}", elements.len(), distance_threshold);
+
+    let id_to_index: FxHashMap<UUID, usize> = elements.iter().enumerate().map(|(i, e)| (e.id, i)).collect();
+    let mut union_find = UnionFind::new(elements.len());
+    for (chunk_index, chunk) in elements.chunks(QUERY_CHUNK_SIZE).enumerate()
+    {
+        let search = search_state.0.lock().unwrap();
+        if search.model_id != model_id {
+            return Err(anyhow::anyhow!("The active model changed while searching for near-duplicates"));
+        }
+        add_neighbors(&search, chunk, chunk_index * QUERY_CHUNK_SIZE, distance_threshold, &id_to_index, &mut union_find);
+    }
+    let groups = collect_groups(&elements, &mut union_find);
+
+    queries::replace_duplicate_groups(&groups, &mut connection)?;
+    info!("Found {} groups of near-duplicates in {:?}", groups.len(), now.elapsed());
+    Ok(groups.len())
+}
+
+#[cfg(test)]
+mod tests
+{
+    use crate::ann::IndexBackend;
+    use crate::ann_benchmark::{self, normalize};
+    use crate::embedding;
+    use crate::preprocessing::FEATURE_VECTOR_LENGTH;
+    use crate::quantization::VectorEncoding;
+    use crate::regions::{FileRegion, Region};
+
+    use super::*;
+
+    #[test]
+    fn union_find_joins_transitively()
+    {
+        let mut union_find = UnionFind::new(6);
+        union_find.union(0, 1);
+        union_find.union(1, 2);
+        union_find.union(4, 5);
+        assert_eq!(union_find.find(0), union_find.find(2));
+        assert_eq!(union_find.find(4), union_find.find(5));
+        assert_ne!(union_find.find(0), union_find.find(4));
+        assert_ne!(union_find.find(3), union_find.find(0));
+        assert_eq!(union_find.size[union_find.find(0)], 3);
+    }
+
+    /// A slightly perturbed copy of the vector, standing in for a re-saved copy of an image.
+    fn perturbed(vector: &[f32], seed: u64) -> Vec<f32>
+    {
+        let mut rng = ann_benchmark::SplitMix64::new(seed);
+        let mut out: Vec<f32> = vector.iter().map(|x| x + rng.next_gaussian() * 0.002).collect();
+        normalize(&mut out);
+        out
+    }
+
+    #[test]
+    fn finds_groups_of_copies()
+    {
+        // Spread out, so that distinct images are far from each other.
+        let originals = ann_benchmark::synthetic_feature_vectors(50, FEATURE_VECTOR_LENGTH, 50, 0.5, 13);
+        let element = |v: Vec<f32>| HnswElement { feature_vector: v, id: uuid::Uuid::new_v4().into() };
+        let mut elements: Vec<HnswElement> = originals.iter().map(|v| element(v.clone())).collect();
+        // Three copies of the first image, and one of the second.
+        let copies_of_first: Vec<HnswElement> = (0..3).map(|i| element(perturbed(&originals[0], i))).collect();
+        let copy_of_second = element(perturbed(&originals[1], 3));
+        elements.extend(copies_of_first.iter().cloned());
+        elements.push(copy_of_second.clone());
+
+        let mut search = InnerSearchState::new_building(embedding::DEFAULT_MODEL_ID, IndexBackend::Flat, VectorEncoding::F32);
+        search.fallback = None;
+        search.insert(elements.clone());
+
+        // Regions of the first image, identical to it and so nearer than its copies, don't take their places among its neighbors.
+        let regions = (0..MAX_NEIGHBORS as u32 + 1).map(|i| {
+            let region = Region { x: i, y: 0, width: 10, height: 10 };
+            (element(originals[0].clone()), FileRegion { file_id: elements[0].id, region })
+        }).collect();
+        search.insert_regions(regions);
+
+        let groups = find_duplicate_groups(&search, &elements, DEFAULT_DUPLICATE_DISTANCE_THRESHOLD);
+        assert_eq!(groups.len(), 2);
+        let mut expected_first = vec![elements[0].id];
+        expected_first.extend(copies_of_first.iter().map(|e| e.id));
+        assert_eq!(groups[0], expected_first);
+        assert_eq!(groups[1], vec![elements[1].id, copy_of_second.id]);
+    }
+}
@@ -0,0 +1,440 @@
+/// Embedding models, which map images and text into a shared feature space for search.
+///
+/// Each model is described by a ModelInfo in the REGISTRY, and is implemented by an ImageEncoder and a TextEncoder,
+/// which are loaded and locked separately, so that searches don't wait on image encoding.
+/// Feature vectors are stored keyed by the model's ID, so the features of several models can be kept
+/// side by side; only the active model (the active_model setting) is loaded, and the search index
+/// is built from the active model's features only.
+/// Switching the active model encodes any files which have no features for the new model yet
+/// (see set_active_model()), while the features for the previous model are kept, so switching back is quick.
+
+use std::thread::{self, JoinHandle};
+
+use diesel::SqliteConnection;
+use image::DynamicImage;
+use log::{error, info, trace, warn};
+use ndarray::{Array, Array2, Array3, Dim};
+use rayon::prelude::*;
+use tauri::Manager;
+
+use crate::clip::{ClipText, ClipVisual};
+use crate::encoding_pipeline::{self, DecodeSettings, EncodingStats, PipelineSettings};
+use crate::encoding_queue;
+use crate::error::Error;
+use crate::model_files::{self, ModelPaths};
+use crate::models::{NewFailedEncoding, NewFrameFeature, NewImageFeature, NewRegionFeature};
+use crate::onnx::OnnxSettings;
+use crate::preprocessing;
+use crate::quantization::{self, VectorEncoding};
+use crate::settings::Settings;
+use crate::state::{EncodingStatsState, InnerModelState, InnerSearchState, SearchState, SettingsState, TextModelState, VisualModelState};
+use crate::uuid::UUID;
+use crate::{ann, queries};
+
+/// The family of a model, which determines how it is loaded and run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Architecture
+{
+    /// A CLIP model exported to a pair of ONNX graphs for the visual and text encoders;
+    /// see https://github.com/jalberse/CLIP-to-onnx-converter
+    Clip,
+}
+
+/// Describes an embedding model which RefRover knows how to run.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ModelInfo
+{
+    /// A stable identifier, which keys the stored feature vectors. Never change the ID of a released model.
+    pub id: &'static str,
+    /// A human readable name.
+    pub name: &'static str,
+    pub architecture: Architecture,
+    /// The width and height of the (square) image input.
+    pub image_input_size: usize,
+    pub feature_vector_length: usize,
+    /// The model files, which are listed in models/manifest.json. See model_files.rs.
+    pub visual_filename: &'static str,
+    pub text_filename: &'static str,
+}
+
+pub const CLIP_VIT_L_14_336PX: ModelInfo = ModelInfo {
+    id: "clip-vit-l-14-336px",
+    name: "CLIP ViT-L/14@336px",
+    architecture: Architecture::Clip,
+    image_input_size: preprocessing::IMAGE_INPUT_SIZE,
+    feature_vector_length: preprocessing::FEATURE_VECTOR_LENGTH,
+    visual_filename: model_files::VISUAL_MODEL_FILENAME,
+    text_filename: model_files::TEXT_MODEL_FILENAME,
+};
+
+pub const DEFAULT_MODEL_ID: &str = CLIP_VIT_L_14_336PX.id;
+
+/// Every model RefRover supports. A model is installed if its files can be found (see model_files.rs).
+pub static REGISTRY: &[ModelInfo] = &[CLIP_VIT_L_14_336PX];
+
+pub fn find_model_info(model_id: &str) -> Result<&'static ModelInfo, Error>
+{
+    REGISTRY.iter().find(|info| info.id == model_id).ok_or_else(|| Error::UnknownModel(model_id.to_string()))
+}
+
+/// Whether the model's files can be found. This does not validate the files, which happens when the model is loaded.
+pub fn is_installed(app_handle: &tauri::AppHandle, info: &ModelInfo, model_directory: Option<&std::path::Path>) -> bool
+{
+    let candidates = model_files::candidate_directories(app_handle, model_directory);
+    model_files::find_model(info.visual_filename, &candidates).is_ok()
+        && model_files::find_model(info.text_filename, &candidates).is_ok()
+}
+
+/// The image half of a model, which encodes images into L2 normalized feature vectors
+/// in the space shared with its TextEncoder.
+pub trait ImageEncoder: Send + Sync
+{
+    fn info(&self) -> &'static ModelInfo;
+
+    fn id(&self) -> &'static str
+    {
+        self.info().id
+    }
+
+    fn image_input_size(&self) -> usize
+    {
+        self.info().image_input_size
+    }
+
+    fn feature_vector_length(&self) -> usize
+    {
+        self.info().feature_vector_length
+    }
+
+    /// Converts a loaded image into a (3, image_input_size(), image_input_size()) array.
+    fn preprocess_image(&self, image: &DynamicImage) -> Array3<f32>;
+
+    /// Converts loaded images into the input of encode_image().
+    fn preprocess_images(&self, images: Vec<(UUID, Box<DynamicImage>)>) -> Array<f32, Dim<[usize; 4]>>
+    {
+        let images: Vec<Array3<f32>> = images.par_iter().map(|(_, image)| self.preprocess_image(image)).collect();
+        encoding_pipeline::stack_images(&images, self.image_input_size())
+    }
+
+    /// Given a batch of preprocessed images, returns a 2D array of shape (batch_size, feature_vector_length()).
+    fn encode_image(&self, images: Array<f32, Dim<[usize; 4]>>) -> anyhow::Result<Array2<f32>>;
+}
+
+/// The text half of a model, which encodes text into L2 normalized feature vectors
+/// in the space shared with its ImageEncoder.
+pub trait TextEncoder: Send + Sync
+{
+    fn info(&self) -> &'static ModelInfo;
+
+    fn id(&self) -> &'static str
+    {
+        self.info().id
+    }
+
+    fn feature_vector_length(&self) -> usize
+    {
+        self.info().feature_vector_length
+    }
+
+    /// Converts a batch of text into the input of encode_text().
+    fn tokenize(&self, texts: &[&str]) -> Array2<i32>;
+
+    /// Given a batch of tokenized text, returns a 2D array of shape (batch_size, feature_vector_length()).
+    fn encode_text(&self, tokens: Array2<i32>) -> anyhow::Result<Array2<f32>>;
+}
+
+/// Finds, validates and loads the model's text encoder.
+pub fn load_text_encoder(
+    app_handle: &tauri::AppHandle,
+    info: &'static ModelInfo,
+    onnx_settings: &OnnxSettings,
+    model_directory: Option<&std::path::Path>) -> Result<Box<dyn TextEncoder>, Error>
+{
+    let path = model_files::resolve_file(app_handle, info.text_filename, model_directory)?;
+    match info.architecture
+    {
+        Architecture::Clip => Ok(Box::new(ClipText::new(info, onnx_settings, &path)?)),
+    }
+}
+
+/// Finds, validates and loads the model's image encoder.
+pub fn load_image_encoder(
+    app_handle: &tauri::AppHandle,
+    info: &'static ModelInfo,
+    onnx_settings: &OnnxSettings,
+    model_directory: Option<&std::path::Path>) -> Result<Box<dyn ImageEncoder>, Error>
+{
+    let path = model_files::resolve_file(app_handle, info.visual_filename, model_directory)?;
+    match info.architecture
+    {
+        Architecture::Clip => Ok(Box::new(ClipVisual::new(info, onnx_settings, &path)?)),
+    }
+}
+
+/// Loads the model's encoders on a background thread, into the TextModelState and then the VisualModelState,
+/// which should already be loading the model (see InnerModelState::loading()).
+/// The text encoder is loaded first, since searches need it; image encoding happens in the background anyway.
+/// The encoding worker is woken once the image encoder is loaded.
+pub fn spawn_model_load(app_handle: tauri::AppHandle, info: &'static ModelInfo) -> JoinHandle<()>
+{
+    thread::spawn(move || {
+        load_text_model(&app_handle, info);
+        load_visual_model(&app_handle, info);
+    })
+}
+
+fn load_text_model(app_handle: &tauri::AppHandle, info: &'static ModelInfo)
+{
+    let settings = app_handle.state::<SettingsState>().0.lock().unwrap().settings.clone();
+    let now = std::time::Instant::now();
+    let text_encoder = load_text_encoder(app_handle, info, &settings.onnx, settings.model_directory.as_deref());
+    match &text_encoder
+    {
+        Ok(_) => info!("Loaded the {} text encoder in {:?}", info.name, now.elapsed()),
+        Err(e) => error!("Unable to load the {} text encoder: {}", info.name, e),
+    }
+    let text_state = app_handle.state::<TextModelState>();
+    let mut text_state = text_state.0.lock().unwrap();
+    // The active model may have been switched while we were loading.
+    if text_state.info.id == info.id {
+        *text_state = InnerModelState::new(info, text_encoder);
+    }
+}
+
+fn load_visual_model(app_handle: &tauri::AppHandle, info: &'static ModelInfo)
+{
+    let settings = app_handle.state::<SettingsState>().0.lock().unwrap().settings.clone();
+    let now = std::time::Instant::now();
+    let image_encoder = load_image_encoder(app_handle, info, &settings.onnx, settings.model_directory.as_deref());
+    match &image_encoder
+    {
+        Ok(_) => info!("Loaded the {} image encoder in {:?}", info.name, now.elapsed()),
+        Err(e) => error!("Unable to load the {} image encoder: {}", info.name, e),
+    }
+    {
+        let visual_state = app_handle.state::<VisualModelState>();
+        let mut visual_state = visual_state.0.lock().unwrap();
+        if visual_state.info.id != info.id {
+            return;
+        }
+        *visual_state = InnerModelState::new(info, image_encoder);
+    }
+    encoding_queue::wake(app_handle);
+}
+
+/// Encodes the given files and stores the feature vectors in the database, keyed by the model's ID,
+/// in the given encoding. Files which fail to load or encode are recorded in the failed_encodings table,
+/// with the error; a failure only affects the file itself, not the rest of its batch.
+/// If region embeddings are enabled, the feature vectors of each image's tiles are stored in the region_features table,
+/// and if animation frames are, those of frames sampled from each animation (and of keyframes of videos) are stored
+/// in the frame_features table.
+/// See encoding_pipeline.rs for how decoding and inference are overlapped.
+pub fn encode_image_files(
+    model: &dyn ImageEncoder,
+    files: &[UUID],
+    connection: &mut SqliteConnection,
+    encoding: VectorEncoding,
+    pipeline_settings: &PipelineSettings,
+    decode_settings: &DecodeSettings) -> anyhow::Result<EncodingStats>
+{
+    let files = queries::get_filepaths(files, connection)?;
+
+    info!("Encoding {} images with {}...", files.len(), model.info().name);
+
+    encoding_pipeline::run(model, &files, pipeline_settings, decode_settings, |batch| {
+        // Serialize each image encoding in the configured format.
+        trace!("Serializing encodings...");
+        let serialized_encodings = batch.encoded.iter()
+            .map(|(file_id, feature_vector)| Ok((*file_id, quantization::encode(feature_vector, encoding)?)))
+            .collect::<anyhow::Result<Vec<(UUID, Vec<u8>)>>>()?;
+        trace!("Serialized encodings");
+
+        // Insert the image encodings into the image_features table, keyed by the file ID and model ID.
+        // The encoding is serialized according to the quantization module.
+        let new_image_features: Vec<NewImageFeature> = serialized_encodings.iter().map(|(file_id, serialized)| {
+            NewImageFeature {
+                file_id: *file_id,
+                model_id: model.id(),
+                feature_vector: serialized,
+                encoding: encoding.to_db(),
+            }
+        }).collect();
+        queries::insert_image_features(&new_image_features, connection)?;
+
+        if !batch.regions.is_empty()
+        {
+            let serialized_regions = batch.regions.iter()
+                .map(|region| quantization::encode(&region.feature_vector, encoding))
+                .collect::<anyhow::Result<Vec<Vec<u8>>>>()?;
+            let new_region_features: Vec<NewRegionFeature> = batch.regions.iter().zip(serialized_regions.iter()).map(|(region, serialized)| {
+                let bounds = region.file_region.region;
+                NewRegionFeature {
+                    id: region.id,
+                    file_id: region.file_region.file_id,
+                    model_id: model.id(),
+                    x: bounds.x as i32,
+                    y: bounds.y as i32,
+                    width: bounds.width as i32,
+                    height: bounds.height as i32,
+                    feature_vector: serialized,
+                    encoding: encoding.to_db(),
+                }
+            }).collect();
+            queries::insert_region_features(&new_region_features, connection)?;
+        }
+
+        if !batch.frames.is_empty()
+        {
+            let serialized_frames = batch.frames.iter()
+                .map(|frame| quantization::encode(&frame.feature_vector, encoding))
+                .collect::<anyhow::Result<Vec<Vec<u8>>>>()?;
+            let new_frame_features: Vec<NewFrameFeature> = batch.frames.iter().zip(serialized_frames.iter()).map(|(frame, serialized)| {
+                NewFrameFeature {
+                    id: frame.id,
+                    file_id: frame.file_frame.file_id,
+                    model_id: model.id(),
+                    frame_index: frame.file_frame.frame.index as i32,
+                    timestamp_ms: frame.file_frame.frame.timestamp_ms as i64,
+                    feature_vector: serialized,
+                    encoding: encoding.to_db(),
+                }
+            }).collect();
+            queries::insert_frame_features(&new_frame_features, connection)?;
+        }
+
+        if !batch.failures.is_empty()
+        {
+            warn!("Failed to encode {} images", batch.failures.len());
+            // Record the failed images in the failed_encodings table, so that we don't keep retrying them.
+            let new_failed_encodings: Vec<NewFailedEncoding> = batch.failures.into_iter().map(|(uuid, failure)| {
+                NewFailedEncoding {
+                    id: uuid,
+                    error: failure.error,
+                    failed_at: None,
+                    kind: failure.kind.to_db(),
+                }
+            }).collect();
+            queries::insert_failed_encodings(&new_failed_encodings, connection)?;
+        }
+        Ok(())
+    })
+}
+
+/// Encodes the files with the active model and adds the feature vectors to the search index.
+/// The throughput of the run is added to the EncodingStatsState.
+pub fn encode_files_and_add_to_search(
+    file_ids: &[UUID],
+    connection: &mut SqliteConnection,
+    app_handle: &tauri::AppHandle,
+) -> anyhow::Result<()>
+{
+    let search_state = app_handle.state::<SearchState>();
+    // Store the feature vectors in the same format as the search index holds them.
+    let encoding = search_state.0.lock().unwrap().encoding();
+    let settings = app_handle.state::<SettingsState>().0.lock().unwrap().settings.clone();
+
+    // We only hold the lock on the image encoder while encoding the images; searches use the text encoder,
+    // which has its own lock.
+    let (model_id, stats) = {
+        let model_state = app_handle.state::<VisualModelState>();
+        let model_state = model_state.0.lock().unwrap();
+        let model = model_state.model()?;
+        let stats = encode_image_files(model, file_ids, connection, encoding, &settings.encoding_pipeline, &settings.decode_settings())?;
+        (model.id(), stats)
+    };
+    app_handle.state::<EncodingStatsState>().0.lock().unwrap().record(stats);
+
+    // Add the resulting encodings to the search index.
+    let image_features = queries::get_image_feature_data(file_ids, model_id, connection)?;
+    let region_features = queries::get_region_feature_data(file_ids, model_id, connection)?;
+    let frame_features = queries::get_frame_feature_data(file_ids, model_id, connection)?;
+    {
+        let hnsw_elements = ann::convert_rows_to_hnsw_elements(&image_features)?;
+        let region_elements = ann::convert_region_rows_to_hnsw_elements(&region_features)?;
+        let frame_elements = ann::convert_frame_rows_to_hnsw_elements(&frame_features)?;
+        let mut search_inner = search_state.0.lock().unwrap();
+        // The active model may have been switched while we were encoding;
+        // the features are stored, but they don't belong in the new model's index.
+        if search_inner.model_id == model_id {
+            search_inner.insert(hnsw_elements);
+            search_inner.insert_regions(region_elements);
+            search_inner.insert_frames(frame_elements);
+        }
+    }
+
+    Ok(())
+}
+
+/// Makes the model active: swaps in a search index built from its feature vectors, loads its encoders in the background,
+/// and queues any files which have no features for it yet for encoding (see encoding_queue.rs).
+/// The setting is saved, so the model stays active on the next start.
+pub fn set_active_model(app_handle: &tauri::AppHandle, model_id: &str) -> Result<JoinHandle<()>, Error>
+{
+    let info = find_model_info(model_id)?;
+    let settings: Settings = app_handle.state::<SettingsState>().0.lock().unwrap().settings.clone();
+
+    // Check the model's files before touching any state, so that a missing model leaves the current one active.
+    ModelPaths::resolve(app_handle, info, settings.model_directory.as_deref())?;
+    *app_handle.state::<TextModelState>().0.lock().unwrap() = InnerModelState::loading(info);
+    *app_handle.state::<VisualModelState>().0.lock().unwrap() = InnerModelState::loading(info);
+
+    {
+        let settings_state = app_handle.state::<SettingsState>();
+        let mut settings_state = settings_state.0.lock().unwrap();
+        settings_state.settings.active_model = model_id.to_string();
+        if let Err(e) = settings_state.settings.save(app_handle) {
+            error!("Error saving settings: {:?}", e);
+        }
+    }
+
+    *app_handle.state::<SearchState>().0.lock().unwrap() = InnerSearchState::new_building(info.id, settings.index_backend, settings.vector_encoding);
+    info!("Switched the active model to {}", info.name);
+
+    let app_handle = app_handle.clone();
+    Ok(thread::spawn(move || {
+        // Files can be queued while the image encoder loads; they are encoded once it has.
+        if let Err(e) = encoding_queue::enqueue_files_without_features(&app_handle) {
+            error!("Error queuing files for encoding with the new model: {:?}", e);
+        }
+        let model_load = spawn_model_load(app_handle.clone(), info);
+        // Build the index from the stored features, so that searches are fast again as soon as possible.
+        if ann::spawn_index_build(app_handle.clone()).join().is_err() {
+            error!("The search index build panicked");
+        }
+        if model_load.join().is_err() {
+            error!("Loading the {} model panicked", info.name);
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn registry_is_consistent()
+    {
+        let manifest = model_files::manifest();
+        for (i, info) in REGISTRY.iter().enumerate()
+        {
+            assert!(REGISTRY[i + 1..].iter().all(|other| other.id != info.id), "Duplicate model ID {}", info.id);
+            assert!(manifest.contains_key(info.visual_filename));
+            assert!(manifest.contains_key(info.text_filename));
+        }
+        assert_eq!(find_model_info(DEFAULT_MODEL_ID).unwrap(), &CLIP_VIT_L_14_336PX);
+        assert!(matches!(find_model_info("not-a-model"), Err(Error::UnknownModel(_))));
+    }
+
+    #[test]
+    fn model_state_reports_why_an_encoder_is_unavailable()
+    {
+        let loading: InnerModelState<dyn TextEncoder> = InnerModelState::loading(&CLIP_VIT_L_14_336PX);
+        assert!(loading.is_loading());
+        assert!(matches!(loading.model(), Err(Error::ModelLoading(_))));
+
+        let failed: InnerModelState<dyn TextEncoder> = InnerModelState::new(&CLIP_VIT_L_14_336PX, Err(Error::UnknownModel("x".to_string())));
+        assert!(!failed.is_loading());
+        assert!(matches!(failed.model(), Err(Error::ModelUnavailable(_))));
+    }
+}
@@ -0,0 +1,906 @@
+/// A pipeline which decodes images and runs inference concurrently.
+///
+/// Decoding and resizing images is CPU-bound, while inference is mostly bound by the ONNX session (often on the GPU);
+/// running them in sequence leaves each idle while the other works. Instead, a producer thread decodes and preprocesses
+/// batches of images on a rayon pool and sends them over a bounded channel to the inference stage, which encodes each batch
+/// while the next ones are decoded. The bound on the channel limits how many decoded batches are held in memory at once.
+///
+/// The images of a batch are decoded at once, so a batch of large scans could take many gigabytes. Images are decoded
+/// at no more than the resolution which is needed (see image_loading::load_image_reduced()), and batches are cut short
+/// once the estimated memory of their decoded images reaches the batch_memory_mb setting.
+///
+/// Images with transparency are composited onto a background as they are decoded (see image_loading.rs); if a second
+/// background is configured, they are also encoded on it, and the two feature vectors are averaged.
+/// If region embeddings are enabled, the tiles of each image (see regions.rs) are cropped while it is decoded,
+/// and encoded after the batch's whole images. Likewise, sampled frames of animations (see animation.rs) are decoded
+/// with the animation's first frame, and encoded after the batch's whole images. Videos are decoded to their keyframes
+/// (see video.rs); the first is encoded as the video itself, and the rest as its frames.
+///
+/// Throughput statistics are returned for each run, and accumulated in the EncodingStatsState
+/// (see the get_encoding_stats command).
+
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use image::DynamicImage;
+use log::{error, info, warn};
+use ndarray::{s, Array, Array2, Array3, Axis, Dim};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::animation::{self, AnimationSettings, FileFrame};
+use crate::embedding::ImageEncoder;
+use crate::failed_encodings::EncodingFailure;
+use crate::image_loading::{self, TransparencySettings};
+use crate::regions::{self, FileRegion, RegionSettings};
+use crate::uuid::UUID;
+use crate::video::{self, VideoSettings};
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct PipelineSettings
+{
+    /// The number of images per inference batch.
+    pub batch_size: usize,
+    /// The number of decoded batches which may wait for inference.
+    pub queue_depth: usize,
+    /// The number of threads decoding images; 0 uses rayon's global pool (one thread per core).
+    pub decode_threads: usize,
+    /// The estimated memory the decoded images of a batch may take, in megabytes; batches are made smaller than
+    /// batch_size to stay within it. An image which is estimated to take more is decoded in a batch of its own.
+    pub batch_memory_mb: u64,
+}
+
+impl Default for PipelineSettings
+{
+    fn default() -> Self
+    {
+        PipelineSettings { batch_size: 32, queue_depth: 2, decode_threads: 0, batch_memory_mb: 1024 }
+    }
+}
+
+/// What is decoded from each file besides the image itself, and how transparent images are composited.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DecodeSettings
+{
+    pub regions: RegionSettings,
+    pub animation: AnimationSettings,
+    pub video: VideoSettings,
+    pub transparency: TransparencySettings,
+}
+
+/// The memory decoding is estimated to take for files whose size can't be read from their header
+/// (e.g. corrupt files, or videos when they aren't encoded).
+const DEFAULT_ESTIMATED_BYTES: u64 = 64 * 1024 * 1024;
+
+/// Throughput statistics of encoding runs.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Default, PartialEq)]
+pub struct EncodingStats
+{
+    /// The number of images which were encoded.
+    pub images: usize,
+    /// The number of images which failed to load or encode.
+    pub failed: usize,
+    /// The number of image regions (tiles) which were encoded. See regions.rs.
+    pub regions: usize,
+    /// The number of frames of animations and keyframes of videos which were encoded, besides their first.
+    /// See animation.rs and video.rs.
+    pub frames: usize,
+    /// The time spent decoding and preprocessing, summed over batches.
+    /// This overlaps with inference, so decode_seconds + inference_seconds may exceed elapsed_seconds.
+    pub decode_seconds: f64,
+    /// The time spent in inference, summed over batches.
+    pub inference_seconds: f64,
+    pub elapsed_seconds: f64,
+    pub images_per_second: f64,
+}
+
+impl EncodingStats
+{
+    /// Accumulates the statistics of another run.
+    pub fn add(&mut self, other: &EncodingStats)
+    {
+        self.images += other.images;
+        self.failed += other.failed;
+        self.regions += other.regions;
+        self.frames += other.frames;
+        self.decode_seconds += other.decode_seconds;
+        self.inference_seconds += other.inference_seconds;
+        self.elapsed_seconds += other.elapsed_seconds;
+        self.update_images_per_second();
+    }
+
+    fn update_images_per_second(&mut self)
+    {
+        self.images_per_second = if self.elapsed_seconds > 0.0 { self.images as f64 / self.elapsed_seconds } else { 0.0 };
+    }
+}
+
+/// A batch of images which were decoded and preprocessed, and the images which failed to load.
+struct DecodedBatch
+{
+    /// An image with transparency appears twice if it is encoded on a second background.
+    images: Vec<(UUID, Array3<f32>)>,
+    regions: Vec<DecodedRegion>,
+    frames: Vec<DecodedFrame>,
+    failures: Vec<(UUID, EncodingFailure)>,
+    decode_time: Duration,
+}
+
+/// A preprocessed image, and its preprocessed regions and frames.
+struct DecodedImage
+{
+    image: Array3<f32>,
+    /// The image on the second background, if one is configured and the image has transparency.
+    alternate: Option<Array3<f32>>,
+    regions: Vec<DecodedRegion>,
+    /// The sampled frames after the first, if the image is an animation, or the keyframes after the first of a video.
+    frames: Vec<DecodedFrame>,
+}
+
+/// A preprocessed tile of an image, with the ID of its feature vector.
+struct DecodedRegion
+{
+    id: UUID,
+    file_region: FileRegion,
+    image: Array3<f32>,
+}
+
+/// A preprocessed frame of an animation or keyframe of a video, with the ID of its feature vector.
+struct DecodedFrame
+{
+    id: UUID,
+    file_frame: FileFrame,
+    image: Array3<f32>,
+}
+
+/// The output of the inference stage for one batch.
+pub struct EncodedBatch
+{
+    /// The feature vector of each image which was encoded.
+    pub encoded: Vec<(UUID, Vec<f32>)>,
+    /// The error of each image which failed to load or encode.
+    pub failures: Vec<(UUID, EncodingFailure)>,
+    /// The feature vectors of the images' regions, if region embeddings are enabled.
+    pub regions: Vec<EncodedRegion>,
+    /// The feature vectors of sampled frames of animations and keyframes of videos, if enabled.
+    pub frames: Vec<EncodedFrame>,
+}
+
+/// The feature vector of a tile of an image. See regions.rs.
+pub struct EncodedRegion
+{
+    /// The ID of the region's feature vector; its key in the region_features table and the search index.
+    pub id: UUID,
+    pub file_region: FileRegion,
+    pub feature_vector: Vec<f32>,
+}
+
+/// The feature vector of a frame of an animation or keyframe of a video. See animation.rs and video.rs.
+pub struct EncodedFrame
+{
+    /// The ID of the frame's feature vector; its key in the frame_features table and the search index.
+    pub id: UUID,
+    pub file_frame: FileFrame,
+    pub feature_vector: Vec<f32>,
+}
+
+/// Decodes and encodes the files with the model, passing each encoded batch to `consume` as it is ready.
+/// Errors loading or encoding single images are reported in the EncodedBatch; an error from `consume`
+/// stops the pipeline and is returned. Regions, frames of animations and videos are only decoded and encoded if they
+/// are enabled in `decode_settings`.
+pub fn run(
+    model: &dyn ImageEncoder,
+    files: &[(UUID, PathBuf)],
+    settings: &PipelineSettings,
+    decode_settings: &DecodeSettings,
+    mut consume: impl FnMut(EncodedBatch) -> anyhow::Result<()>) -> anyhow::Result<EncodingStats>
+{
+    let start = Instant::now();
+    let batch_size = settings.batch_size.max(1);
+    let batch_memory = settings.batch_memory_mb.saturating_mul(1024 * 1024);
+    let min_size = min_decode_size(model, &decode_settings.regions);
+    let pool = match settings.decode_threads
+    {
+        0 => None,
+        threads => Some(rayon::ThreadPoolBuilder::new().num_threads(threads).build()?),
+    };
+    let mut stats = EncodingStats::default();
+
+    let (sender, receiver) = mpsc::sync_channel::<DecodedBatch>(settings.queue_depth.max(1));
+    let result = thread::scope(|scope| -> anyhow::Result<()> {
+        scope.spawn(move || {
+            let mut remaining = files;
+            while !remaining.is_empty()
+            {
+                let batch_len = next_batch_len(remaining, batch_size, batch_memory, |path| {
+                    estimated_decoded_bytes(path, min_size, decode_settings)
+                });
+                let (chunk, rest) = remaining.split_at(batch_len);
+                remaining = rest;
+                let batch = match &pool
+                {
+                    Some(pool) => pool.install(|| decode_batch(model, chunk, decode_settings)),
+                    None => decode_batch(model, chunk, decode_settings),
+                };
+                // The inference stage stopped early (due to an error); stop decoding.
+                if sender.send(batch).is_err() {
+                    break;
+                }
+            }
+        });
+
+        // Dropping the receiver (on returning early) stops the producer.
+        for batch in receiver
+        {
+            stats.decode_seconds += batch.decode_time.as_secs_f64();
+            let mut failures = batch.failures;
+            let mut encoded = Vec::new();
+            if !batch.images.is_empty()
+            {
+                let (file_ids, images): (Vec<UUID>, Vec<Array3<f32>>) = batch.images.into_iter().unzip();
+                let input = stack_images(&images, model.image_input_size());
+                drop(images);
+
+                let inference_start = Instant::now();
+                let batch = encode_batch_isolating_failures(&file_ids, input, |input| model.encode_image(input));
+                stats.inference_seconds += inference_start.elapsed().as_secs_f64();
+                let batch = merge_encodings(batch);
+                encoded = batch.encoded;
+                failures.extend(batch.failures);
+            }
+
+            let inference_start = Instant::now();
+            let regions = encode_regions(model, batch.regions, batch_size);
+            let frames = encode_frames(model, batch.frames, batch_size);
+            stats.inference_seconds += inference_start.elapsed().as_secs_f64();
+
+            stats.images += encoded.len();
+            stats.failed += failures.len();
+            stats.regions += regions.len();
+            stats.frames += frames.len();
+            consume(EncodedBatch { encoded, failures, regions, frames })?;
+        }
+        Ok(())
+    });
+
+    stats.elapsed_seconds = start.elapsed().as_secs_f64();
+    stats.update_images_per_second();
+    result?;
+    info!("Encoded {} images ({} failed) in {:.2}s: {:.1} images/s (decode {:.2}s, inference {:.2}s)",
+        stats.images, stats.failed, stats.elapsed_seconds, stats.images_per_second, stats.decode_seconds, stats.inference_seconds);
+    Ok(stats)
+}
+
+/// The number of files at the start of `files` which make up the next batch: at most batch_size files, whose total
+/// estimated decoded size is within batch_memory bytes. The first file is always included, however large it is.
+fn next_batch_len(files: &[(UUID, PathBuf)], batch_size: usize, batch_memory: u64, estimate: impl Fn(&Path) -> u64) -> usize
+{
+    let mut total = 0u64;
+    for (i, (_, path)) in files.iter().take(batch_size).enumerate()
+    {
+        total = total.saturating_add(estimate(path));
+        if total > batch_memory && i > 0 {
+            return i;
+        }
+    }
+    files.len().min(batch_size)
+}
+
+/// Estimates the memory decoding the file takes with the settings: a video's keyframes if videos are encoded, or the image
+/// (see image_loading::estimated_decoded_bytes()), times the number of frames decoded from it if it is an animation
+/// and animations are encoded.
+fn estimated_decoded_bytes(path: &Path, min_size: u32, settings: &DecodeSettings) -> u64
+{
+    if settings.video.enabled && video::is_video(path) {
+        return video::estimated_keyframe_bytes(&settings.video);
+    }
+    let image_bytes = image_loading::estimated_decoded_bytes(path, min_size).unwrap_or(DEFAULT_ESTIMATED_BYTES);
+    let frames = match settings.animation.enabled.then(|| animation::animation_info(path)).flatten()
+    {
+        Some(info) => animation::sample_frame_indices(info.frame_count, settings.animation.max_frames).len() as u64,
+        None => 1,
+    };
+    image_bytes.saturating_mul(frames)
+}
+
+/// The shortest side images are decoded with (at least) where they can be decoded at a reduced resolution:
+/// the model's input size, or with regions enabled, enough that the smallest tiles are as large as the model's input.
+fn min_decode_size(model: &dyn ImageEncoder, region_settings: &RegionSettings) -> u32
+{
+    let input_size = model.image_input_size() as u32;
+    if region_settings.enabled {
+        input_size.saturating_mul(2u32.saturating_pow(region_settings.levels.saturating_sub(1)))
+    } else {
+        input_size
+    }
+}
+
+/// Loads and preprocesses the files (and their regions and frames, if enabled) in parallel.
+fn decode_batch(model: &dyn ImageEncoder, files: &[(UUID, PathBuf)], settings: &DecodeSettings) -> DecodedBatch
+{
+    let start = Instant::now();
+    let decoded: Vec<(UUID, anyhow::Result<DecodedImage>)> = files.par_iter()
+        .map(|(uuid, path)| (*uuid, decode_file(model, *uuid, path, settings)))
+        .collect();
+
+    let mut images = Vec::new();
+    let mut regions = Vec::new();
+    let mut frames = Vec::new();
+    let mut failures = Vec::new();
+    for (uuid, result) in decoded
+    {
+        match result
+        {
+            Ok(decoded) => {
+                images.push((uuid, decoded.image));
+                if let Some(alternate) = decoded.alternate {
+                    images.push((uuid, alternate));
+                }
+                regions.extend(decoded.regions);
+                frames.extend(decoded.frames);
+            },
+            Err(e) => failures.push((uuid, EncodingFailure::load(&e))),
+        }
+    }
+    DecodedBatch { images, regions, frames, failures, decode_time: start.elapsed() }
+}
+
+/// Loads and preprocesses an image, with its regions and the frames of animations if they are enabled,
+/// or a video's keyframes if videos are enabled.
+fn decode_file(model: &dyn ImageEncoder, file_id: UUID, path: &Path, settings: &DecodeSettings) -> anyhow::Result<DecodedImage>
+{
+    if settings.video.enabled && video::is_video(path) {
+        return decode_video(model, file_id, path, settings);
+    }
+    let (image, full_size) = image_loading::load_image_reduced(path, min_decode_size(model, &settings.regions))
+        .map_err(|e| anyhow::Error::new(e).context(format!("Error loading image: {:?}", path)))?;
+    let mut decoded = decode_image(model, file_id, image, full_size, &settings.regions, &settings.transparency);
+    if settings.animation.enabled {
+        decoded.frames = decode_frames(model, file_id, path, &settings.animation, &settings.transparency);
+    }
+    Ok(decoded)
+}
+
+/// Extracts and preprocesses the keyframes of a video. The first is decoded as the video itself (with its regions,
+/// if they are enabled), and the rest as its frames, each with a new ID.
+fn decode_video(model: &dyn ImageEncoder, file_id: UUID, path: &Path, settings: &DecodeSettings) -> anyhow::Result<DecodedImage>
+{
+    let mut keyframes = video::extract_keyframes(path, &settings.video, settings.video.max_keyframes)?.into_iter();
+    let (_, first) = keyframes.next().ok_or(anyhow::anyhow!("No keyframes were extracted from {:?}", path))?;
+    let full_size = (first.width(), first.height());
+    let mut decoded = decode_image(model, file_id, first, full_size, &settings.regions, &settings.transparency);
+    decoded.frames = keyframes
+        .map(|(frame, image)| DecodedFrame {
+            id: Uuid::new_v4().into(),
+            file_frame: FileFrame { file_id, frame },
+            image: model.preprocess_image(&image),
+        })
+        .collect();
+    Ok(decoded)
+}
+
+/// Composites the image onto the background and preprocesses it, with its regions if they are enabled.
+/// Images with transparency are also preprocessed on the second background, if one is configured.
+/// `full_size` is the size of the image at full resolution, which it may have been decoded at a fraction of.
+fn decode_image(
+    model: &dyn ImageEncoder,
+    file_id: UUID,
+    image: DynamicImage,
+    full_size: (u32, u32),
+    region_settings: &RegionSettings,
+    transparency: &TransparencySettings) -> DecodedImage
+{
+    let alternate = match transparency.second_background
+    {
+        Some(background) if image_loading::has_transparency(&image) => {
+            Some(model.preprocess_image(&image_loading::composite_onto_background(image.clone(), background)))
+        },
+        _ => None,
+    };
+    let image = image_loading::composite_onto_background(image, transparency.background);
+    let regions = if region_settings.enabled { decode_regions(model, file_id, &image, full_size, region_settings) } else { Vec::new() };
+    DecodedImage { image: model.preprocess_image(&image), alternate, regions, frames: Vec::new() }
+}
+
+/// Crops and preprocesses the tiles of an image, giving each a new ID.
+/// Tiles are laid out over the image at its full resolution, so that their regions are in its pixels.
+fn decode_regions(
+    model: &dyn ImageEncoder,
+    file_id: UUID,
+    image: &DynamicImage,
+    full_size: (u32, u32),
+    region_settings: &RegionSettings) -> Vec<DecodedRegion>
+{
+    let tiles = regions::tile_regions(full_size.0, full_size.1, region_settings);
+    regions::crop_regions(image, full_size, &tiles).iter().zip(tiles)
+        .map(|(crop, region)| DecodedRegion {
+            id: Uuid::new_v4().into(),
+            file_region: FileRegion { file_id, region },
+            image: model.preprocess_image(crop),
+        })
+        .collect()
+}
+
+/// Loads and preprocesses the sampled frames of an animation after its first (which is encoded as the image itself),
+/// giving each a new ID. Still images have no frames to decode. If the frames fail to load, the animation is encoded
+/// from its first frame alone.
+fn decode_frames(
+    model: &dyn ImageEncoder,
+    file_id: UUID,
+    path: &Path,
+    animation_settings: &AnimationSettings,
+    transparency: &TransparencySettings) -> Vec<DecodedFrame>
+{
+    let Some(info) = animation::animation_info(path) else { return Vec::new() };
+    let indices = animation::sample_frame_indices(info.frame_count, animation_settings.max_frames);
+    let frames = match animation::load_frames(path, &indices[1..])
+    {
+        Ok(frames) => frames,
+        Err(e) => {
+            warn!("Failed to load the frames of {:?}: {}", path, e);
+            return Vec::new();
+        },
+    };
+    frames.into_iter()
+        .map(|(frame, image)| DecodedFrame {
+            id: Uuid::new_v4().into(),
+            file_frame: FileFrame { file_id, frame },
+            image: model.preprocess_image(&image_loading::composite_onto_background(image, transparency.background)),
+        })
+        .collect()
+}
+
+/// Encodes the regions in batches of `batch_size`.
+/// Regions which fail to encode are skipped; the feature vectors of their images are unaffected.
+fn encode_regions(model: &dyn ImageEncoder, regions: Vec<DecodedRegion>, batch_size: usize) -> Vec<EncodedRegion>
+{
+    let parts = regions.into_iter().map(|region| (region.id, region.file_region, region.image)).collect();
+    encode_parts(model, parts, batch_size, "image regions").into_iter()
+        .map(|(id, file_region, feature_vector)| EncodedRegion { id, file_region, feature_vector })
+        .collect()
+}
+
+/// Encodes the frames in batches of `batch_size`.
+/// Frames which fail to encode are skipped; the feature vectors of their animations are unaffected.
+fn encode_frames(model: &dyn ImageEncoder, frames: Vec<DecodedFrame>, batch_size: usize) -> Vec<EncodedFrame>
+{
+    let parts = frames.into_iter().map(|frame| (frame.id, frame.file_frame, frame.image)).collect();
+    encode_parts(model, parts, batch_size, "animation and video frames").into_iter()
+        .map(|(id, file_frame, feature_vector)| EncodedFrame { id, file_frame, feature_vector })
+        .collect()
+}
+
+/// Encodes parts of images (regions or frames), each with its own ID and where it is in its file, in batches of `batch_size`.
+/// Parts which fail to encode are skipped, with a warning naming what they are.
+fn encode_parts<T: Copy>(model: &dyn ImageEncoder, parts: Vec<(UUID, T, Array3<f32>)>, batch_size: usize, name: &str) -> Vec<(UUID, T, Vec<f32>)>
+{
+    let (locations, images): (Vec<(UUID, T)>, Vec<Array3<f32>>) = parts.into_iter()
+        .map(|(id, location, image)| ((id, location), image))
+        .unzip();
+
+    let mut encoded = Vec::new();
+    for (locations, images) in locations.chunks(batch_size).zip(images.chunks(batch_size))
+    {
+        let ids: Vec<UUID> = locations.iter().map(|(id, _)| *id).collect();
+        let locations: HashMap<UUID, T> = locations.iter().copied().collect();
+        let batch = encode_batch_isolating_failures(&ids, stack_images(images, model.image_input_size()), |input| model.encode_image(input));
+        if !batch.failures.is_empty() {
+            warn!("Failed to encode {} {}", batch.failures.len(), name);
+        }
+        encoded.extend(batch.encoded.into_iter().map(|(id, feature_vector)| (id, locations[&id], feature_vector)));
+    }
+    encoded
+}
+
+/// Averages the feature vectors of images which were encoded more than once (on each background; see TransparencySettings),
+/// normalizing the average as the model normalizes its feature vectors.
+/// An image which was encoded more than once only fails if none of its encodings succeeded.
+fn merge_encodings(batch: EncodedBatch) -> EncodedBatch
+{
+    // The index of each image's feature vector in `encoded`, and the number of encodings summed into it.
+    let mut merged: HashMap<UUID, (usize, usize)> = HashMap::new();
+    let mut encoded: Vec<(UUID, Vec<f32>)> = Vec::with_capacity(batch.encoded.len());
+    for (id, feature_vector) in batch.encoded
+    {
+        match merged.get_mut(&id)
+        {
+            Some((index, count)) => {
+                encoded[*index].1.iter_mut().zip(feature_vector).for_each(|(sum, x)| *sum += x);
+                *count += 1;
+            },
+            None => {
+                merged.insert(id, (encoded.len(), 1));
+                encoded.push((id, feature_vector));
+            },
+        }
+    }
+    for (index, count) in merged.values()
+    {
+        if *count > 1 {
+            let feature_vector = &mut encoded[*index].1;
+            let norm = feature_vector.iter().map(|x| x * x).sum::<f32>().sqrt();
+            if norm > 0.0 {
+                feature_vector.iter_mut().for_each(|x| *x /= norm);
+            }
+        }
+    }
+
+    let mut failed = HashSet::new();
+    let failures = batch.failures.into_iter()
+        .filter(|(id, _)| !merged.contains_key(id) && failed.insert(*id))
+        .collect();
+    EncodedBatch { encoded, failures, regions: batch.regions, frames: batch.frames }
+}
+
+/// Stacks preprocessed (3, size, size) images into a (batch, 3, size, size) array.
+pub fn stack_images(images: &[Array3<f32>], image_input_size: usize) -> Array<f32, Dim<[usize; 4]>>
+{
+    let mut input = Array::zeros((images.len(), 3, image_input_size, image_input_size));
+    for (i, image) in images.iter().enumerate()
+    {
+        input.index_axis_mut(Axis(0), i).assign(image);
+    }
+    input
+}
+
+/// Encodes a preprocessed batch, whose rows are the images of `file_ids`.
+/// If encoding the batch fails, each image is retried alone, so that a single bad image doesn't fail its whole batch.
+pub fn encode_batch_isolating_failures(
+    file_ids: &[UUID],
+    input: Array<f32, Dim<[usize; 4]>>,
+    encode: impl Fn(Array<f32, Dim<[usize; 4]>>) -> anyhow::Result<Array2<f32>>) -> EncodedBatch
+{
+    let encode_checked = |input: Array<f32, Dim<[usize; 4]>>| -> anyhow::Result<Array2<f32>> {
+        let rows = input.len_of(Axis(0));
+        let output = encode(input)?;
+        if output.len_of(Axis(0)) != rows {
+            return Err(anyhow::anyhow!("Expected {} feature vectors, but the model returned {}", rows, output.len_of(Axis(0))));
+        }
+        Ok(output)
+    };
+
+    let batch_error = match encode_checked(input.clone())
+    {
+        Ok(output) => {
+            let encoded = file_ids.iter().zip(output.outer_iter()).map(|(file_id, row)| (*file_id, row.to_vec())).collect();
+            return EncodedBatch { encoded, failures: Vec::new(), regions: Vec::new(), frames: Vec::new() };
+        },
+        Err(e) => e,
+    };
+    if file_ids.len() > 1 {
+        warn!("Encoding a batch of {} images failed ({}); retrying each image alone", file_ids.len(), batch_error);
+    }
+
+    let mut encoded = Vec::new();
+    let mut failures = Vec::new();
+    for (i, file_id) in file_ids.iter().enumerate()
+    {
+        let result = if file_ids.len() == 1 {
+            Err(anyhow::anyhow!("{}", batch_error))
+        } else {
+            encode_checked(input.slice(s![i..i + 1, .., .., ..]).to_owned())
+        };
+        match result
+        {
+            Ok(output) => encoded.push((*file_id, output.row(0).to_vec())),
+            Err(e) => {
+                error!("Error encoding image {}: {}", file_id, e);
+                failures.push((*file_id, EncodingFailure::encode(&e)));
+            },
+        }
+    }
+    EncodedBatch { encoded, failures, regions: Vec::new(), frames: Vec::new() }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use crate::embedding::{ModelInfo, CLIP_VIT_L_14_336PX};
+    use crate::failed_encodings::FailedEncodingKind;
+    use crate::image_loading::Background;
+    use crate::test_files::TempDir;
+
+    use super::*;
+
+    /// Fails any batch containing an image whose first value is negative; otherwise returns a row of the image's first value.
+    fn fake_encode(input: Array<f32, Dim<[usize; 4]>>) -> anyhow::Result<Array2<f32>>
+    {
+        let firsts: Vec<f32> = input.outer_iter().map(|image| image[[0, 0, 0]]).collect();
+        if firsts.iter().any(|x| *x < 0.0) {
+            return Err(anyhow::anyhow!("bad image"));
+        }
+        Ok(Array2::from_shape_fn((firsts.len(), 2), |(i, _)| firsts[i]))
+    }
+
+    fn test_batch(firsts: &[f32]) -> (Vec<UUID>, Array<f32, Dim<[usize; 4]>>)
+    {
+        let ids = firsts.iter().map(|_| uuid::Uuid::new_v4().into()).collect();
+        let input = Array::from_shape_fn((firsts.len(), 3, 2, 2), |(i, _, _, _)| firsts[i]);
+        (ids, input)
+    }
+
+    #[test]
+    fn encoded_batch_keeps_ids_with_their_vectors()
+    {
+        let (ids, input) = test_batch(&[1.0, 2.0, 3.0]);
+        let batch = encode_batch_isolating_failures(&ids, input, fake_encode);
+        assert!(batch.failures.is_empty());
+        assert_eq!(batch.encoded, vec![(ids[0], vec![1.0, 1.0]), (ids[1], vec![2.0, 2.0]), (ids[2], vec![3.0, 3.0])]);
+    }
+
+    #[test]
+    fn failed_batch_is_retried_one_image_at_a_time()
+    {
+        let (ids, input) = test_batch(&[1.0, -1.0, 3.0, -2.0]);
+        let batch = encode_batch_isolating_failures(&ids, input, fake_encode);
+        assert_eq!(batch.encoded, vec![(ids[0], vec![1.0, 1.0]), (ids[2], vec![3.0, 3.0])]);
+        let failed_ids: Vec<UUID> = batch.failures.iter().map(|(id, _)| *id).collect();
+        assert_eq!(failed_ids, vec![ids[1], ids[3]]);
+        assert!(batch.failures[0].1.error.contains("bad image"));
+        assert_eq!(batch.failures[0].1.kind, FailedEncodingKind::Encoding);
+
+        // A batch for which the model returns the wrong number of vectors fails, rather than misattributing them,
+        // and its images are retried alone.
+        let (ids, input) = test_batch(&[1.0, 2.0]);
+        let batch = encode_batch_isolating_failures(&ids, input, |input| {
+            let rows = input.len_of(Axis(0));
+            Ok(Array2::zeros((if rows > 1 { rows - 1 } else { 1 }, 2)))
+        });
+        assert_eq!(batch.encoded.len(), 2);
+        assert!(batch.failures.is_empty());
+    }
+
+    #[test]
+    fn encodings_of_the_same_image_are_averaged()
+    {
+        let (ids, _) = test_batch(&[0.0, 0.0, 0.0]);
+        let batch = EncodedBatch {
+            encoded: vec![(ids[0], vec![3.0, 0.0]), (ids[1], vec![1.0, 1.0]), (ids[0], vec![0.0, 4.0])],
+            failures: [ids[0], ids[2], ids[2]].iter().map(|id| (*id, EncodingFailure::encode(&anyhow::anyhow!("bad image")))).collect(),
+            regions: Vec::new(),
+            frames: Vec::new(),
+        };
+        let batch = merge_encodings(batch);
+        // Averaged and normalized; images encoded once are left as they are.
+        assert_eq!(batch.encoded, vec![(ids[0], vec![0.6, 0.8]), (ids[1], vec![1.0, 1.0])]);
+        // The image which encoded once is not a failure, and the image which failed twice is only reported once.
+        let failed_ids: Vec<UUID> = batch.failures.iter().map(|(id, _)| *id).collect();
+        assert_eq!(failed_ids, vec![ids[2]]);
+    }
+
+    #[test]
+    fn batches_are_cut_short_by_their_estimated_memory()
+    {
+        let files: Vec<(UUID, PathBuf)> = ["small", "small", "large", "small", "huge", "small"].iter()
+            .map(|name| (uuid::Uuid::new_v4().into(), PathBuf::from(name)))
+            .collect();
+        let estimate = |path: &Path| match path.to_str().unwrap() { "small" => 10, "large" => 60, _ => 1000 };
+
+        let mut batch_lens = Vec::new();
+        let mut remaining = &files[..];
+        while !remaining.is_empty()
+        {
+            let len = next_batch_len(remaining, 4, 100, estimate);
+            batch_lens.push(len);
+            remaining = &remaining[len..];
+        }
+        // The huge file is decoded alone, despite exceeding the budget by itself.
+        assert_eq!(batch_lens, vec![4, 1, 1]);
+        assert_eq!(next_batch_len(&files[..2], 4, 100, estimate), 2);
+        assert_eq!(next_batch_len(&files, 2, 100, estimate), 2);
+    }
+
+    #[test]
+    fn animations_and_videos_are_estimated_by_the_frames_decoded_from_them()
+    {
+        let dir = TempDir::new("pipeline");
+        let path = dir.join("animation.gif");
+        let frames = (0..6u8).map(|i| {
+            let buffer = image::RgbaImage::from_pixel(4, 4, image::Rgba([i * 10, 0, 0, 255]));
+            image::Frame::from_parts(buffer, 0, 0, image::Delay::from_numer_denom_ms(100, 1))
+        });
+        image::codecs::gif::GifEncoder::new(std::fs::File::create(&path).unwrap()).encode_frames(frames).unwrap();
+        let frame_bytes = image_loading::estimated_decoded_bytes(&path, 1).unwrap();
+
+        let animation = AnimationSettings { enabled: true, max_frames: 3, max_thumbnail_frames: 0 };
+        let decode_settings = DecodeSettings { animation, ..DecodeSettings::default() };
+        assert_eq!(estimated_decoded_bytes(&path, 1, &decode_settings), 3 * frame_bytes);
+        assert_eq!(estimated_decoded_bytes(&path, 1, &DecodeSettings::default()), frame_bytes);
+
+        let video = dir.join("clip.mp4");
+        let video_settings = VideoSettings { enabled: true, max_keyframes: 4, ..VideoSettings::default() };
+        let decode_settings = DecodeSettings { video: video_settings.clone(), ..DecodeSettings::default() };
+        assert_eq!(estimated_decoded_bytes(&video, 1, &decode_settings), video::estimated_keyframe_bytes(&video_settings));
+        assert_eq!(estimated_decoded_bytes(&video, 1, &DecodeSettings::default()), DEFAULT_ESTIMATED_BYTES);
+    }
+
+    /// Encodes each image as its red value (which preprocessing fills the whole array with);
+    /// fails any batch containing an image with a red value of 30.
+    struct FakeModel;
+
+    impl ImageEncoder for FakeModel
+    {
+        fn info(&self) -> &'static ModelInfo
+        {
+            &CLIP_VIT_L_14_336PX
+        }
+
+        fn image_input_size(&self) -> usize
+        {
+            4
+        }
+
+        fn feature_vector_length(&self) -> usize
+        {
+            1
+        }
+
+        fn preprocess_image(&self, image: &image::DynamicImage) -> Array3<f32>
+        {
+            Array3::from_elem((3, 4, 4), image.to_rgb8().get_pixel(0, 0).0[0] as f32)
+        }
+
+        fn encode_image(&self, images: Array<f32, Dim<[usize; 4]>>) -> anyhow::Result<Array2<f32>>
+        {
+            let reds: Vec<f32> = images.outer_iter().map(|image| image[[0, 0, 0]]).collect();
+            if reds.contains(&30.0) {
+                return Err(anyhow::anyhow!("bad image"));
+            }
+            Ok(Array2::from_shape_fn((reds.len(), 1), |(i, _)| reds[i]))
+        }
+    }
+
+    /// The red value of each test image.
+    type Reds = Vec<(UUID, f32)>;
+
+    /// Writes ten images with red values 0, 10, ..., 90 and one file which isn't an image, at index 4.
+    fn write_files(dir: &Path) -> (Vec<(UUID, PathBuf)>, Reds)
+    {
+        let mut files = Vec::new();
+        let mut reds = Vec::new();
+        for i in 0..10u8
+        {
+            let path = dir.join(format!("{}.png", i));
+            image::RgbImage::from_pixel(8, 8, image::Rgb([i * 10, 0, 0])).save(&path).unwrap();
+            let id: UUID = uuid::Uuid::new_v4().into();
+            files.push((id, path));
+            reds.push((id, (i * 10) as f32));
+        }
+        let path = dir.join("notes.txt");
+        std::fs::write(&path, "not an image").unwrap();
+        files.insert(4, (uuid::Uuid::new_v4().into(), path));
+        (files, reds)
+    }
+
+    #[test]
+    fn pipeline_encodes_every_image_with_its_id()
+    {
+        let dir = TempDir::new("pipeline");
+        let (files, reds) = write_files(dir.path());
+        let not_an_image = files[4].0;
+
+        let settings = PipelineSettings { batch_size: 3, queue_depth: 1, decode_threads: 2, ..PipelineSettings::default() };
+        let mut encoded = Vec::new();
+        let mut failures = Vec::new();
+        let stats = run(&FakeModel, &files, &settings, &DecodeSettings::default(), |batch| {
+            encoded.extend(batch.encoded);
+            failures.extend(batch.failures);
+            Ok(())
+        }).unwrap();
+
+        // Every image but the one which fails to encode, in order, with its own value.
+        let expected: Vec<(UUID, Vec<f32>)> = reds.iter().filter(|(_, red)| *red != 30.0).map(|(id, red)| (*id, vec![*red])).collect();
+        assert_eq!(encoded, expected);
+        let failed: HashMap<UUID, FailedEncodingKind> = failures.iter().map(|(id, failure)| (*id, failure.kind)).collect();
+        assert_eq!(failed.get(&not_an_image), Some(&FailedEncodingKind::Unsupported));
+        assert_eq!(failed.get(&reds[3].0), Some(&FailedEncodingKind::Encoding));
+        assert_eq!(failures.len(), 2);
+        assert_eq!((stats.images, stats.failed, stats.regions), (9, 2, 0));
+        assert!(stats.elapsed_seconds > 0.0);
+
+        // An error consuming a batch stops the pipeline.
+        let mut batches = 0;
+        let result = run(&FakeModel, &files, &settings, &DecodeSettings::default(), |_| {
+            batches += 1;
+            Err(anyhow::anyhow!("database is locked"))
+        });
+        assert!(result.is_err());
+        assert_eq!(batches, 1);
+    }
+
+    #[test]
+    fn pipeline_encodes_the_regions_of_each_image()
+    {
+        let dir = TempDir::new("pipeline");
+        // A wide image whose left half has a red value of 10 and right half 20.
+        let path = dir.join("wide.png");
+        image::RgbImage::from_fn(16, 8, |x, _| image::Rgb([if x < 8 { 10 } else { 20 }, 0, 0])).save(&path).unwrap();
+        let file_id: UUID = uuid::Uuid::new_v4().into();
+
+        let settings = PipelineSettings { batch_size: 1, queue_depth: 1, decode_threads: 1, ..PipelineSettings::default() };
+        let regions = RegionSettings { enabled: true, levels: 1, overlap: 0.5, min_tile_size: 1, max_regions: 16 };
+        let decode_settings = DecodeSettings { regions, ..DecodeSettings::default() };
+        let mut encoded = Vec::new();
+        let mut regions = Vec::new();
+        let stats = run(&FakeModel, &[(file_id, path)], &settings, &decode_settings, |batch| {
+            encoded.extend(batch.encoded);
+            regions.extend(batch.regions);
+            Ok(())
+        }).unwrap();
+
+        // The whole image (whose first pixel is on the left), and the tiles at either end; the center tile is the whole image's crop.
+        assert_eq!(encoded, vec![(file_id, vec![10.0])]);
+        let regions: Vec<(UUID, u32, Vec<f32>)> = regions.into_iter()
+            .map(|r| (r.file_region.file_id, r.file_region.region.x, r.feature_vector))
+            .collect();
+        assert_eq!(regions, vec![(file_id, 0, vec![10.0]), (file_id, 8, vec![20.0])]);
+        assert_eq!((stats.images, stats.regions), (1, 2));
+    }
+
+    #[test]
+    fn pipeline_encodes_sampled_frames_of_animations()
+    {
+        let dir = TempDir::new("pipeline");
+        // Six frames with red values 0, 10, ..., 50, each shown for 100ms.
+        let path = dir.join("animation.gif");
+        let frames = (0..6u8).map(|i| {
+            let buffer = image::RgbaImage::from_pixel(4, 4, image::Rgba([i * 10, 0, 0, 255]));
+            image::Frame::from_parts(buffer, 0, 0, image::Delay::from_numer_denom_ms(100, 1))
+        });
+        image::codecs::gif::GifEncoder::new(std::fs::File::create(&path).unwrap()).encode_frames(frames).unwrap();
+        let file_id: UUID = uuid::Uuid::new_v4().into();
+
+        let settings = PipelineSettings { batch_size: 2, queue_depth: 1, decode_threads: 1, ..PipelineSettings::default() };
+        let animation = AnimationSettings { enabled: true, max_frames: 3, max_thumbnail_frames: 0 };
+        let decode_settings = DecodeSettings { animation, ..DecodeSettings::default() };
+        let mut encoded = Vec::new();
+        let mut frames = Vec::new();
+        let stats = run(&FakeModel, &[(file_id, path)], &settings, &decode_settings, |batch| {
+            encoded.extend(batch.encoded);
+            frames.extend(batch.frames);
+            Ok(())
+        }).unwrap();
+
+        // The first frame is the image itself; frames 2 and 4 are encoded separately.
+        assert_eq!(encoded, vec![(file_id, vec![0.0])]);
+        let frames: Vec<(UUID, u32, u64, Vec<f32>)> = frames.into_iter()
+            .map(|f| (f.file_frame.file_id, f.file_frame.frame.index, f.file_frame.frame.timestamp_ms, f.feature_vector))
+            .collect();
+        assert_eq!(frames, vec![(file_id, 2, 200, vec![20.0]), (file_id, 4, 400, vec![40.0])]);
+        assert_eq!((stats.images, stats.frames), (1, 2));
+    }
+
+    #[test]
+    fn transparent_images_are_composited_onto_each_background()
+    {
+        let dir = TempDir::new("pipeline");
+        let path = dir.join("cut-out.png");
+        image::RgbaImage::from_pixel(8, 8, image::Rgba([0, 0, 0, 0])).save(&path).unwrap();
+        let files = vec![(uuid::Uuid::new_v4().into(), path)];
+        let settings = PipelineSettings { batch_size: 2, queue_depth: 1, decode_threads: 1, ..PipelineSettings::default() };
+
+        let encode = |transparency: TransparencySettings| {
+            let decode_settings = DecodeSettings { transparency, ..DecodeSettings::default() };
+            let mut encoded = Vec::new();
+            let stats = run(&FakeModel, &files, &settings, &decode_settings, |batch| {
+                encoded.extend(batch.encoded.into_iter().map(|(_, feature_vector)| feature_vector));
+                Ok(())
+            }).unwrap();
+            assert_eq!(stats.images, 1);
+            encoded
+        };
+
+        // The red value of the background, rather than of the transparent pixels.
+        assert_eq!(encode(TransparencySettings::default()), vec![vec![255.0]]);
+        let grey = TransparencySettings { background: Background::Grey, second_background: None };
+        assert_eq!(encode(grey), vec![vec![128.0]]);
+        // Encoded on both backgrounds (255 and 128), averaged and normalized.
+        let both = TransparencySettings { background: Background::White, second_background: Some(Background::Grey) };
+        assert_eq!(encode(both), vec![vec![1.0]]);
+    }
+}
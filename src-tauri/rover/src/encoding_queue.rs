@@ -0,0 +1,200 @@
+/// A persistent queue of files to encode with the active model, and the single background worker which encodes them.
+///
+/// The initial scan, new watched directories, the file watchers and model switches queue files in the
+/// encoding_jobs table rather than encoding them inline. The worker takes the highest priority jobs a batch at a time,
+/// so the model is only locked for one batch at a time, files the user is looking at can jump the queue,
+/// and pausing or cancelling takes effect after the batch in progress.
+/// Because the queue is stored in the database, quitting mid-scan loses at most the batch in progress;
+/// the remaining jobs are picked up when the app next starts.
+
+use std::sync::mpsc::Receiver;
+use std::thread::{self, JoinHandle};
+
+use log::{error, info};
+use tauri::{AppHandle, Manager};
+
+use crate::embedding;
+use crate::events::Event;
+use crate::interface::EncodingQueueStatus;
+use crate::queries;
+use crate::state::{ConnectionPoolState, EncodingQueueState, VisualModelState};
+use crate::uuid::UUID;
+
+/// The number of files the worker encodes between checks for pauses, cancellations and higher priority jobs.
+const JOB_BATCH_SIZE: usize = 64;
+
+/// Jobs with a higher priority are encoded first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum JobPriority
+{
+    /// Files found by the initial scan, and files to encode after switching models.
+    Background,
+    /// Files which were just added, by the file watchers or a new watched directory.
+    NewFiles,
+    /// Files the user is currently looking at, e.g. in the gallery.
+    Visible,
+}
+
+impl JobPriority
+{
+    fn to_db(self) -> i32
+    {
+        match self
+        {
+            JobPriority::Background => 0,
+            JobPriority::NewFiles => 1,
+            JobPriority::Visible => 2,
+        }
+    }
+}
+
+/// Queues the files for encoding with the active model, and wakes the worker.
+/// Files which are already queued are raised to the priority if it is higher.
+pub fn enqueue(app_handle: &AppHandle, file_ids: &[UUID], priority: JobPriority) -> anyhow::Result<()>
+{
+    if file_ids.is_empty() {
+        return Ok(());
+    }
+    let mut connection = app_handle.state::<ConnectionPoolState>().get_connection()?;
+    queries::enqueue_encoding_jobs(file_ids, priority.to_db(), &mut connection)?;
+    wake(app_handle);
+    Ok(())
+}
+
+/// Queues every file which has no feature vector for the active model.
+/// This picks up files which have not yet been encoded since the active model was switched,
+/// and files which were added before the queue existed or whose jobs were cancelled.
+/// Files can be queued while the image encoder is loading (or if it failed to load); they are encoded once it has loaded.
+pub fn enqueue_files_without_features(app_handle: &AppHandle) -> anyhow::Result<()>
+{
+    let model_id = app_handle.state::<VisualModelState>().0.lock().unwrap().info.id;
+
+    let mut connection = app_handle.state::<ConnectionPoolState>().get_connection()?;
+    let file_ids = queries::get_files_without_image_features(model_id, &mut connection)?;
+    if !file_ids.is_empty() {
+        info!("Queuing {} files for encoding with {}", file_ids.len(), model_id);
+    }
+    enqueue(app_handle, &file_ids, JobPriority::Background)
+}
+
+/// Stops the worker after the batch in progress. Jobs stay queued until it is resumed.
+/// The app always starts unpaused.
+pub fn pause(app_handle: &AppHandle) -> anyhow::Result<()>
+{
+    app_handle.state::<EncodingQueueState>().0.lock().unwrap().paused = true;
+    info!("Paused encoding");
+    emit_status(app_handle)
+}
+
+pub fn resume(app_handle: &AppHandle) -> anyhow::Result<()>
+{
+    app_handle.state::<EncodingQueueState>().0.lock().unwrap().paused = false;
+    info!("Resumed encoding");
+    wake(app_handle);
+    emit_status(app_handle)
+}
+
+/// Removes the files from the queue, or every queued file if `file_ids` is None.
+/// A batch which is already being encoded is finished.
+/// Cancelled files are not encoded until they are queued again; files which still have no feature vector
+/// for the active model are queued again when the app next starts.
+pub fn cancel(app_handle: &AppHandle, file_ids: Option<&[UUID]>) -> anyhow::Result<()>
+{
+    let mut connection = app_handle.state::<ConnectionPoolState>().get_connection()?;
+    match file_ids
+    {
+        Some(file_ids) => queries::delete_encoding_jobs(file_ids, &mut connection)?,
+        None => {
+            let cancelled = queries::delete_all_encoding_jobs(&mut connection)?;
+            info!("Cancelled encoding of {} files", cancelled);
+        },
+    }
+    emit_status(app_handle)
+}
+
+pub fn get_status(app_handle: &AppHandle) -> anyhow::Result<EncodingQueueStatus>
+{
+    let paused = app_handle.state::<EncodingQueueState>().0.lock().unwrap().paused;
+    let mut connection = app_handle.state::<ConnectionPoolState>().get_connection()?;
+    let queued = queries::count_encoding_jobs(&mut connection)? as usize;
+    Ok(EncodingQueueStatus { queued, paused })
+}
+
+/// Spawns the encoding worker, which runs for the lifetime of the app.
+/// `wake` receives a message whenever there may be new work: jobs were queued, or the queue was resumed.
+/// Any jobs left over from the last time the app ran are started right away.
+pub fn spawn_worker(app_handle: AppHandle, wake: Receiver<()>) -> JoinHandle<()>
+{
+    thread::spawn(move || {
+        loop
+        {
+            let worked = match encode_next_batch(&app_handle)
+            {
+                Ok(worked) => worked,
+                Err(e) => {
+                    // e.g. the model is unavailable. The jobs stay queued; try again when woken.
+                    error!("Error encoding queued files: {:?}", e);
+                    false
+                },
+            };
+            if let Err(e) = emit_status(&app_handle) {
+                error!("Error getting the encoding queue status: {:?}", e);
+            }
+            if worked {
+                continue;
+            }
+            // Nothing to do (or paused); wait until there is.
+            if wake.recv().is_err() {
+                return;
+            }
+        }
+    })
+}
+
+/// Encodes the next batch of jobs with the active model, removing them from the queue.
+/// Returns false if there was nothing to do, because the queue is empty or paused.
+fn encode_next_batch(app_handle: &AppHandle) -> anyhow::Result<bool>
+{
+    if app_handle.state::<EncodingQueueState>().0.lock().unwrap().paused {
+        return Ok(false);
+    }
+    let model_id = {
+        let visual_state = app_handle.state::<VisualModelState>();
+        let visual_state = visual_state.0.lock().unwrap();
+        // The worker is woken once the image encoder has loaded.
+        if visual_state.is_loading() {
+            return Ok(false);
+        }
+        visual_state.model()?.id()
+    };
+
+    let mut connection = app_handle.state::<ConnectionPoolState>().get_connection()?;
+    // Skip jobs which no longer need encoding, e.g. files queued again while they were being encoded.
+    queries::delete_finished_encoding_jobs(model_id, &mut connection)?;
+    let file_ids = queries::get_next_encoding_jobs(JOB_BATCH_SIZE, &mut connection)?;
+    if file_ids.is_empty() {
+        return Ok(false);
+    }
+
+    embedding::encode_files_and_add_to_search(&file_ids, &mut connection, app_handle)?;
+    queries::delete_encoding_jobs(&file_ids, &mut connection)?;
+    Ok(true)
+}
+
+/// Wakes the worker to check for work, e.g. once the image encoder has loaded.
+pub fn wake(app_handle: &AppHandle)
+{
+    // This only fails if the worker has stopped, in which case there is nothing to wake.
+    let _ = app_handle.state::<EncodingQueueState>().0.lock().unwrap().wake.send(());
+}
+
+fn emit_status(app_handle: &AppHandle) -> anyhow::Result<()>
+{
+    let status = get_status(app_handle)?;
+    let emit_result = app_handle.emit_all(Event::EncodingQueueStatus.event_name(), status);
+    if emit_result.is_err()
+    {
+        error!("Error emitting encoding queue status: {:?}", emit_result);
+    }
+    Ok(())
+}
@@ -8,6 +8,24 @@ pub enum Error {
     //       See ROVER-129.
     #[error("Error converting PathBuf to String. Path is likely not valid UTF-8.")]
     PathBufToString,
+    #[error(transparent)]
+    Ort(#[from] ort::Error),
+    #[error("Model file {filename} was not found. Searched: {searched}. Place the model files in one of these directories, or set model_directory in settings.json.")]
+    ModelNotFound { filename: String, searched: String },
+    #[error("Model file {0} is not listed in the model manifest.")]
+    ModelNotInManifest(String),
+    #[error("Model file {path} is {actual} bytes, but should be {expected} bytes. It may be incomplete, or a git LFS pointer rather than the model.")]
+    ModelSizeMismatch { path: String, expected: u64, actual: u64 },
+    #[error("Model file {path} does not match the model manifest (expected SHA-256 {expected}, found {actual}). It may be corrupt or the wrong version.")]
+    ModelChecksumMismatch { path: String, expected: String, actual: String },
+    /// The active embedding model failed to load; contains the reason.
+    #[error("The embedding model is not loaded: {0}")]
+    ModelUnavailable(String),
+    /// The active embedding model is still loading in the background; contains its name.
+    #[error("The {0} model is still loading")]
+    ModelLoading(String),
+    #[error("Unknown embedding model: {0}")]
+    UnknownModel(String),
 }
 
 // we must manually implement serde::Serialize
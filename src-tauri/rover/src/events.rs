@@ -5,6 +5,10 @@ pub enum Event
 {
     TaskStatus,
     TaskEnd,
+    /// Progress of the search index build on startup; the payload is an interface::IndexBuildProgress.
+    IndexBuildProgress,
+    /// The number of files waiting to be encoded, whenever it changes; the payload is an interface::EncodingQueueStatus.
+    EncodingQueueStatus,
 }
 
 // TODO I imagine we could also describe the payloads for each event kind here, with functions to help provide them...
@@ -17,6 +21,8 @@ impl Event
         {
             Event::TaskStatus => "task-status",
             Event::TaskEnd => "task-end",
+            Event::IndexBuildProgress => "index-build-progress",
+            Event::EncodingQueueStatus => "encoding-queue-status",
         }
     }
 }
\ No newline at end of file
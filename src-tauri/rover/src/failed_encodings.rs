@@ -0,0 +1,256 @@
+/// Inspecting and retrying files which failed to encode.
+///
+/// Files which fail to load or encode are recorded in the failed_encodings table with the error and its kind (see
+/// embedding::encode_image_files()), and are not retried automatically on startup. They are retried on request
+/// (see the retry_failed_encodings command), and when the file watcher reports that the file was modified,
+/// since a common cause is a file which was still being written when it was first encoded. Retrying queues the files
+/// for the encoding worker like any other files (see encoding_queue.rs), rather than encoding them on the caller's thread.
+
+use std::io;
+
+use image::ImageError;
+use log::info;
+use serde::{Deserialize, Serialize};
+use tauri::{AppHandle, Manager};
+
+use crate::encoding_queue::{self, JobPriority};
+use crate::interface::{FailedEncoding, FailedEncodingGroup, RetrySummary};
+use crate::queries;
+use crate::state::ConnectionPoolState;
+use crate::uuid::UUID;
+
+/// A coarse classification of encoding errors, for grouping failures in the front-end.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FailedEncodingKind
+{
+    /// The file no longer exists.
+    NotFound,
+    /// The file is not an image, or is in a format we can't decode.
+    Unsupported,
+    /// The image is corrupt or truncated.
+    Decoding,
+    /// The image is too large to decode.
+    Limits,
+    /// Other errors reading the file, e.g. permissions.
+    Io,
+    /// The image loaded, but the model failed to encode it.
+    Encoding,
+    Other,
+}
+
+impl FailedEncodingKind
+{
+    /// Classifies an error loading a file by the image::ImageError or io::Error which caused it, if any.
+    pub fn of_load_error(error: &anyhow::Error) -> Self
+    {
+        for cause in error.chain()
+        {
+            if let Some(e) = cause.downcast_ref::<ImageError>() {
+                return match e
+                {
+                    ImageError::IoError(e) => FailedEncodingKind::of_io_error(e),
+                    ImageError::Unsupported(_) => FailedEncodingKind::Unsupported,
+                    ImageError::Decoding(_) => FailedEncodingKind::Decoding,
+                    ImageError::Limits(_) => FailedEncodingKind::Limits,
+                    _ => FailedEncodingKind::Other,
+                };
+            }
+            if let Some(e) = cause.downcast_ref::<io::Error>() {
+                return FailedEncodingKind::of_io_error(e);
+            }
+        }
+        FailedEncodingKind::Other
+    }
+
+    fn of_io_error(error: &io::Error) -> Self
+    {
+        match error.kind()
+        {
+            io::ErrorKind::NotFound => FailedEncodingKind::NotFound,
+            _ => FailedEncodingKind::Io,
+        }
+    }
+
+    pub fn to_db(self) -> i32
+    {
+        match self
+        {
+            FailedEncodingKind::NotFound => 0,
+            FailedEncodingKind::Unsupported => 1,
+            FailedEncodingKind::Decoding => 2,
+            FailedEncodingKind::Limits => 3,
+            FailedEncodingKind::Io => 4,
+            FailedEncodingKind::Encoding => 5,
+            FailedEncodingKind::Other => 6,
+        }
+    }
+
+    /// Unknown values (e.g. written by a newer version) are Other.
+    pub fn from_db(value: i32) -> Self
+    {
+        match value
+        {
+            0 => FailedEncodingKind::NotFound,
+            1 => FailedEncodingKind::Unsupported,
+            2 => FailedEncodingKind::Decoding,
+            3 => FailedEncodingKind::Limits,
+            4 => FailedEncodingKind::Io,
+            5 => FailedEncodingKind::Encoding,
+            _ => FailedEncodingKind::Other,
+        }
+    }
+}
+
+/// Why a file failed to load or encode.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EncodingFailure
+{
+    pub kind: FailedEncodingKind,
+    /// The error, for display.
+    pub error: String,
+}
+
+impl EncodingFailure
+{
+    /// The file failed to load.
+    pub fn load(error: &anyhow::Error) -> Self
+    {
+        EncodingFailure { kind: FailedEncodingKind::of_load_error(error), error: format!("{:#}", error) }
+    }
+
+    /// The file loaded, but the model failed to encode it.
+    pub fn encode(error: &anyhow::Error) -> Self
+    {
+        EncodingFailure { kind: FailedEncodingKind::Encoding, error: format!("Error encoding image: {}", error) }
+    }
+}
+
+/// Groups the failed files by the kind of their error; groups are ordered by kind,
+/// and the files keep their order within each group.
+pub fn group_failed_encodings(failed: Vec<(FailedEncodingKind, FailedEncoding)>) -> Vec<FailedEncodingGroup>
+{
+    let mut groups: Vec<FailedEncodingGroup> = Vec::new();
+    for (kind, file) in failed
+    {
+        match groups.iter_mut().find(|group| group.kind == kind)
+        {
+            Some(group) => group.files.push(file),
+            None => groups.push(FailedEncodingGroup { kind, files: vec![file] }),
+        }
+    }
+    groups.sort_by_key(|group| group.kind);
+    groups
+}
+
+/// Gets the files which failed to encode, grouped by the kind of error.
+pub fn get_failed_encoding_groups(app_handle: &AppHandle) -> anyhow::Result<Vec<FailedEncodingGroup>>
+{
+    let mut connection = app_handle.state::<ConnectionPoolState>().get_connection()?;
+    let failed = queries::get_failed_encodings(&mut connection)?
+        .into_iter()
+        .map(|(file_id, filepath, error, failed_at, kind)| (FailedEncodingKind::from_db(kind), FailedEncoding {
+            file_id,
+            filepath,
+            error,
+            failed_at: failed_at.to_string(),
+        }))
+        .collect();
+    Ok(group_failed_encodings(failed))
+}
+
+/// Retries encoding the files with the active model, or every failed file if `file_ids` is None, by removing them from
+/// the failed_encodings table and queueing them with the priority (see encoding_queue.rs).
+/// Files which are not in the failed_encodings table are ignored.
+/// Files which encode are added to the search index; files which fail again are recorded with their new error.
+pub fn retry_failed_encodings(app_handle: &AppHandle, file_ids: Option<&[UUID]>, priority: JobPriority) -> anyhow::Result<RetrySummary>
+{
+    let mut connection = app_handle.state::<ConnectionPoolState>().get_connection()?;
+    let failed_ids = queries::get_failed_encoding_ids(&mut connection)?;
+    let file_ids: Vec<UUID> = match file_ids
+    {
+        Some(file_ids) => file_ids.iter().filter(|id| failed_ids.contains(id)).copied().collect(),
+        None => failed_ids,
+    };
+    if file_ids.is_empty() {
+        return Ok(RetrySummary::default());
+    }
+
+    info!("Retrying encoding of {} files", file_ids.len());
+    queries::delete_failed_encodings(&file_ids, &mut connection)?;
+    encoding_queue::enqueue(app_handle, &file_ids, priority)?;
+    Ok(RetrySummary { retried: file_ids.len() })
+}
+
+#[cfg(test)]
+mod tests
+{
+    use image::error::{DecodingError, ImageFormatHint, LimitError, LimitErrorKind, UnsupportedError, UnsupportedErrorKind};
+    use image::ImageFormat;
+
+    use super::*;
+
+    #[test]
+    fn classifies_load_errors_by_type()
+    {
+        let load_error = |e: ImageError| anyhow::Error::new(e).context("Error loading image: \"/a.png\"");
+        let io_error = |kind: io::ErrorKind| io::Error::new(kind, "message");
+        let cases = [
+            (load_error(ImageError::IoError(io_error(io::ErrorKind::NotFound))), FailedEncodingKind::NotFound),
+            (load_error(ImageError::IoError(io_error(io::ErrorKind::PermissionDenied))), FailedEncodingKind::Io),
+            (anyhow::Error::new(io_error(io::ErrorKind::NotFound)), FailedEncodingKind::NotFound),
+            (load_error(ImageError::Unsupported(UnsupportedError::from_format_and_kind(
+                ImageFormatHint::Unknown, UnsupportedErrorKind::Format(ImageFormatHint::Unknown)))), FailedEncodingKind::Unsupported),
+            (load_error(ImageError::Decoding(DecodingError::new(ImageFormatHint::Exact(ImageFormat::Png), "bad chunk"))), FailedEncodingKind::Decoding),
+            (load_error(ImageError::Limits(LimitError::from_kind(LimitErrorKind::InsufficientMemory))), FailedEncodingKind::Limits),
+            // Mentioning a kind of error in the message doesn't make it one.
+            (anyhow::anyhow!("ffmpeg failed: Decoding(kind: NotFound)"), FailedEncodingKind::Other),
+        ];
+        for (error, kind) in cases {
+            assert_eq!(EncodingFailure::load(&error).kind, kind, "{:#}", error);
+        }
+        assert_eq!(EncodingFailure::encode(&anyhow::anyhow!("Failed to run inference")).kind, FailedEncodingKind::Encoding);
+    }
+
+    #[test]
+    fn kinds_round_trip_through_the_db()
+    {
+        let kinds = [
+            FailedEncodingKind::NotFound,
+            FailedEncodingKind::Unsupported,
+            FailedEncodingKind::Decoding,
+            FailedEncodingKind::Limits,
+            FailedEncodingKind::Io,
+            FailedEncodingKind::Encoding,
+            FailedEncodingKind::Other,
+        ];
+        for kind in kinds {
+            assert_eq!(FailedEncodingKind::from_db(kind.to_db()), kind);
+        }
+        assert_eq!(FailedEncodingKind::from_db(100), FailedEncodingKind::Other);
+    }
+
+    #[test]
+    fn groups_by_kind()
+    {
+        let failed = |kind: FailedEncodingKind, error: &str| (kind, FailedEncoding {
+            file_id: uuid::Uuid::new_v4().into(),
+            filepath: "/a.jpg".to_string(),
+            error: error.to_string(),
+            failed_at: "2024-09-28 10:00:00.0".to_string(),
+        });
+        let files = vec![
+            failed(FailedEncodingKind::Encoding, "Error encoding image: a"),
+            failed(FailedEncodingKind::Unsupported, "Error loading image: \"/a.txt\": The image format could not be determined"),
+            failed(FailedEncodingKind::Encoding, "Error encoding image: b"),
+        ];
+        let ids: Vec<UUID> = files.iter().map(|(_, f)| f.file_id).collect();
+
+        let groups = group_failed_encodings(files);
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups[0].kind, FailedEncodingKind::Unsupported);
+        assert_eq!(groups[1].kind, FailedEncodingKind::Encoding);
+        let encoding_ids: Vec<UUID> = groups[1].files.iter().map(|f| f.file_id).collect();
+        assert_eq!(encoding_ids, vec![ids[0], ids[2]]);
+    }
+}
@@ -0,0 +1,176 @@
+/// Deciding which files in watched directories are indexed, i.e. inserted into the files table and encoded.
+///
+/// Watched directories hold more than images: text files, archives, and files the OS leaves behind (e.g. .DS_Store
+/// and the ._ AppleDouble files macOS writes next to images on other filesystems). Inserting those only for them to fail
+/// to load, and fill the failed encodings, helps no one, so scans and filesystem events check each file first.
+/// A file is indexed if its extension is in the watched directory's allowlist, or if its content is an image format
+/// (detected by imghdr) whose extension is in the allowlist, which catches images with no or mangled extensions
+/// (e.g. "photo.jpg_large"). Hidden files are never indexed.
+///
+/// Each watched directory may have its own allowlist, stored as a JSON array in the watched_directories table;
+/// directories without one use the indexed_extensions setting, which defaults to default_extensions().
+
+use std::collections::HashSet;
+use std::path::Path;
+
+use log::warn;
+
+#[cfg(feature = "camera-raw")]
+use crate::camera_raw;
+use crate::video::{self, VideoSettings};
+
+/// The extensions of images the image crate reads, documents (see image_formats.rs) and vector files (see vector_formats.rs).
+const IMAGE_EXTENSIONS: [&str; 26] = [
+    "jpg", "jpeg", "jfif", "png", "apng", "gif", "webp", "bmp", "tif", "tiff", "tga", "ico", "hdr", "exr",
+    "pbm", "pgm", "ppm", "pnm", "qoi", "dds",
+    "psd", "psb", "kra",
+    "svg", "svgz", "pdf",
+];
+
+/// The extensions indexed in watched directories which don't have their own allowlist: images, videos if they are
+/// encoded (see video.rs), and with the camera-raw feature, RAW files (see camera_raw.rs).
+pub fn default_extensions(videos_enabled: bool) -> Vec<String>
+{
+    let video_extensions = if videos_enabled { &video::VIDEO_EXTENSIONS[..] } else { &[] };
+    let extensions = IMAGE_EXTENSIONS.iter().chain(video_extensions.iter());
+    #[cfg(feature = "camera-raw")]
+    let extensions = extensions.chain(camera_raw::RAW_EXTENSIONS.iter());
+    extensions.map(|extension| extension.to_string()).collect()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileFilter
+{
+    /// Lowercase, without the leading dot.
+    extensions: HashSet<String>,
+}
+
+impl FileFilter
+{
+    pub fn new(extensions: &[String]) -> Self
+    {
+        let extensions = extensions.iter()
+            .map(|extension| extension.trim().trim_start_matches('.').to_ascii_lowercase())
+            .filter(|extension| !extension.is_empty())
+            .collect();
+        FileFilter { extensions }
+    }
+
+    /// The filter for a watched directory, given its stored allowlist (a JSON array of extensions)
+    /// and the allowlist used for directories without one.
+    pub fn for_watched_directory(indexed_extensions: Option<&str>, default_extensions: &[String]) -> Self
+    {
+        let Some(indexed_extensions) = indexed_extensions else {
+            return FileFilter::new(default_extensions);
+        };
+        match serde_json::from_str::<Vec<String>>(indexed_extensions)
+        {
+            Ok(extensions) => FileFilter::new(&extensions),
+            Err(e) => {
+                warn!("Unable to read the indexed extensions {:?}; using the defaults: {:?}", indexed_extensions, e);
+                FileFilter::new(default_extensions)
+            },
+        }
+    }
+
+    /// Whether the file should be inserted into the database and encoded.
+    /// Files whose extension isn't allowed are read to detect their format.
+    pub fn is_indexable(&self, path: &Path) -> bool
+    {
+        let Some(file_name) = path.file_name().and_then(|name| name.to_str()) else {
+            return false;
+        };
+        if file_name.starts_with('.') {
+            return false;
+        }
+        let extension = path.extension().and_then(|extension| extension.to_str()).map(str::to_ascii_lowercase);
+        if extension.is_some_and(|extension| self.extensions.contains(&extension)) {
+            return true;
+        }
+        detected_extensions(path).iter().any(|extension| self.extensions.contains(*extension))
+    }
+}
+
+impl Default for FileFilter
+{
+    fn default() -> Self
+    {
+        FileFilter::new(&default_extensions(VideoSettings::default().enabled))
+    }
+}
+
+/// The extensions of the image format detected from the file's content, for formats which the image crate reads.
+fn detected_extensions(path: &Path) -> &'static [&'static str]
+{
+    let image_type = match imghdr::from_file(path)
+    {
+        Ok(Some(image_type)) => image_type,
+        _ => return &[],
+    };
+    match image_type
+    {
+        imghdr::Type::Jpeg => &["jpg", "jpeg", "jfif"],
+        imghdr::Type::Png => &["png"],
+        imghdr::Type::Gif => &["gif"],
+        imghdr::Type::Webp => &["webp"],
+        imghdr::Type::Bmp => &["bmp"],
+        imghdr::Type::Tiff => &["tif", "tiff"],
+        imghdr::Type::Ico => &["ico"],
+        imghdr::Type::Exr => &["exr"],
+        imghdr::Type::Rgbe => &["hdr"],
+        imghdr::Type::Pbm => &["pbm", "pnm"],
+        imghdr::Type::Pgm => &["pgm", "pnm"],
+        imghdr::Type::Ppm => &["ppm", "pnm"],
+        // Formats the image crate can't read, e.g. Sun raster and X bitmaps.
+        _ => &[],
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use crate::test_files::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn files_are_indexed_by_extension_or_content()
+    {
+        let directory = TempDir::new("filter");
+        let png = {
+            let mut png = Vec::new();
+            image::DynamicImage::new_rgb8(1, 1).write_to(&mut std::io::Cursor::new(&mut png), image::ImageFormat::Png).unwrap();
+            png
+        };
+        let write = |name: &str, contents: &[u8]| {
+            let path = directory.join(name);
+            std::fs::write(&path, contents).unwrap();
+            path
+        };
+
+        let photo = write("photo.PNG", &png);
+        let no_extension = write("download", &png);
+        let mangled_extension = write("photo.png_large", &png);
+        let notes = write("notes.txt", b"not an image");
+        let archive = write("refs.zip", b"PK\x03\x04");
+        let ds_store = write(".DS_Store", b"\0\0\0\x01Bud1");
+        let apple_double = write("._photo.png", &png);
+
+        let filter = FileFilter::default();
+        assert!(filter.is_indexable(&photo));
+        assert!(filter.is_indexable(&no_extension));
+        assert!(filter.is_indexable(&mangled_extension));
+        assert!(!filter.is_indexable(&notes));
+        assert!(!filter.is_indexable(&archive));
+        assert!(!filter.is_indexable(&ds_store));
+        assert!(!filter.is_indexable(&apple_double));
+
+        // A directory which only indexes JPEGs skips PNGs, whatever their extension.
+        let jpegs_only = FileFilter::for_watched_directory(Some(r#"[".JPG", "jpeg"]"#), &default_extensions(false));
+        assert!(!jpegs_only.is_indexable(&photo));
+        assert!(!jpegs_only.is_indexable(&no_extension));
+        let with_text = FileFilter::for_watched_directory(Some(r#"["txt"]"#), &default_extensions(false));
+        assert!(with_text.is_indexable(&notes));
+        assert_eq!(FileFilter::for_watched_directory(Some("not json"), &default_extensions(false)), FileFilter::default());
+    }
+}
@@ -0,0 +1,178 @@
+/// Exact nearest neighbor search over feature vectors held in a dense, row-major matrix.
+/// A search is a single matrix-vector product, so this is fast for small to medium libraries,
+/// and its results are exact and deterministic (unlike HNSW), which is useful for tests and
+/// as the fallback while the HNSW index is built on startup.
+///
+/// Feature vectors are held as f32 regardless of the vector_encoding setting.
+
+use std::fs;
+use std::path::Path;
+
+use log::error;
+use ndarray::{ArrayView1, ArrayView2};
+use rustc_hash::FxHashMap;
+use serde::{Deserialize, Serialize};
+
+use crate::ann::{HnswElement, VectorIndex};
+use crate::uuid::UUID;
+
+const FLAT_INDEX_FILENAME: &str = "flat_index.bin";
+
+pub struct FlatIndex
+{
+    /// The (len, dimension) matrix of feature vectors, row-major.
+    data: Vec<f32>,
+    /// The length of the feature vectors; set by the first insertion.
+    dimension: usize,
+    /// The ID of the feature vector in each row.
+    ids: Vec<UUID>,
+    id_to_row: FxHashMap<UUID, usize>,
+}
+
+/// The on-disk format of a saved FlatIndex.
+#[derive(Serialize, Deserialize)]
+struct FlatIndexFile
+{
+    dimension: usize,
+    ids: Vec<UUID>,
+    data: Vec<f32>,
+}
+
+impl FlatIndex
+{
+    pub fn new() -> Self
+    {
+        FlatIndex { data: Vec::new(), dimension: 0, ids: Vec::new(), id_to_row: FxHashMap::default() }
+    }
+
+    pub fn contains(&self, id: &UUID) -> bool
+    {
+        self.id_to_row.contains_key(id)
+    }
+
+    pub fn ids(&self) -> &[UUID]
+    {
+        &self.ids
+    }
+
+    pub fn into_elements(self) -> Vec<HnswElement>
+    {
+        let dimension = self.dimension;
+        self.ids.into_iter().enumerate().map(|(row, id)| {
+            HnswElement { feature_vector: self.data[row * dimension..(row + 1) * dimension].to_vec(), id }
+        }).collect()
+    }
+
+    fn matrix(&self) -> ArrayView2<f32>
+    {
+        ArrayView2::from_shape((self.ids.len(), self.dimension), &self.data)
+            .expect("FlatIndex data should always hold len * dimension elements")
+    }
+}
+
+impl Default for FlatIndex
+{
+    fn default() -> Self
+    {
+        Self::new()
+    }
+}
+
+impl VectorIndex for FlatIndex
+{
+    /// Inserting an ID which is already in the index replaces its feature vector.
+    fn insert(&mut self, data: Vec<HnswElement>)
+    {
+        for elem in data
+        {
+            if self.ids.is_empty() {
+                self.dimension = elem.feature_vector.len();
+            }
+            if elem.feature_vector.len() != self.dimension {
+                error!("Feature vector for {} has length {}, expected {}; skipping.", elem.id, elem.feature_vector.len(), self.dimension);
+                continue;
+            }
+
+            let dimension = self.dimension;
+            match self.id_to_row.get(&elem.id)
+            {
+                Some(row) => self.data[row * dimension..(row + 1) * dimension].copy_from_slice(&elem.feature_vector),
+                None => {
+                    self.id_to_row.insert(elem.id, self.ids.len());
+                    self.ids.push(elem.id);
+                    self.data.extend_from_slice(&elem.feature_vector);
+                },
+            }
+        }
+    }
+
+    fn remove(&mut self, ids: &[UUID])
+    {
+        let dimension = self.dimension;
+        for id in ids
+        {
+            let row = match self.id_to_row.remove(id)
+            {
+                Some(row) => row,
+                None => continue,
+            };
+            // Move the last row into the removed row's place.
+            let last = self.ids.len() - 1;
+            if row != last {
+                self.data.copy_within(last * dimension..(last + 1) * dimension, row * dimension);
+                self.ids[row] = self.ids[last];
+                self.id_to_row.insert(self.ids[row], row);
+            }
+            self.ids.pop();
+            self.data.truncate(last * dimension);
+        }
+    }
+
+    fn search_filtered(
+        &self,
+        query: &[f32],
+        knbn: usize,
+        _ef_arg: usize,
+        distance_threshold: f32,
+        filter: &dyn Fn(&UUID) -> bool) -> Vec<(UUID, f32)>
+    {
+        if self.ids.is_empty() || query.len() != self.dimension {
+            return Vec::new();
+        }
+
+        // The feature vectors are L2 normalized, so the cosine distance is 1 - dot product.
+        let similarities = self.matrix().dot(&ArrayView1::from(query));
+        let mut results: Vec<(UUID, f32)> = similarities
+            .iter()
+            .enumerate()
+            .map(|(row, similarity)| (self.ids[row], 1.0 - similarity))
+            .filter(|(id, distance)| *distance < distance_threshold && filter(id))
+            .collect();
+        results.sort_unstable_by(|a, b| a.1.total_cmp(&b.1));
+        results.truncate(knbn);
+        results
+    }
+
+    fn len(&self) -> usize
+    {
+        self.ids.len()
+    }
+
+    fn save(&self, directory: &Path) -> anyhow::Result<()>
+    {
+        fs::create_dir_all(directory)?;
+        let file = FlatIndexFile { dimension: self.dimension, ids: self.ids.clone(), data: self.data.clone() };
+        fs::write(directory.join(FLAT_INDEX_FILENAME), bincode::serialize(&file)?)?;
+        Ok(())
+    }
+
+    fn load(directory: &Path) -> anyhow::Result<Self>
+    {
+        let file: FlatIndexFile = bincode::deserialize(&fs::read(directory.join(FLAT_INDEX_FILENAME))?)?;
+        if file.data.len() != file.ids.len() * file.dimension {
+            return Err(anyhow::anyhow!("Corrupt flat index in {:?}", directory));
+        }
+        let id_to_row = file.ids.iter().enumerate().map(|(row, id)| (*id, row)).collect();
+        Ok(FlatIndex { data: file.data, dimension: file.dimension, ids: file.ids, id_to_row })
+    }
+}
@@ -0,0 +1,508 @@
+/// Adapters for document formats which the image crate can't read, but which store a flattened copy of the document.
+///
+/// Photoshop documents (.psd, and .psb for large documents) store a composite of all layers after the layer data,
+/// and usually a small JPEG thumbnail among their image resources. Krita documents (.kra) are zip archives containing
+/// the full size merged image and a small preview, both as PNGs. We read the flattened image rather than the layers,
+/// since it is what the artist sees.
+
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+
+use image::{DynamicImage, GrayAlphaImage, GrayImage, ImageFormat, ImageResult, RgbImage, RgbaImage};
+
+use crate::image_loading::{self, format_decoding_error, format_io_error, format_unsupported_error};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DocumentFormat
+{
+    Photoshop,
+    Krita,
+}
+
+impl DocumentFormat
+{
+    /// The document format of the file, by its extension; None for other files, which the image crate reads itself.
+    pub fn from_path(path: &Path) -> Option<Self>
+    {
+        let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+        match extension.as_str()
+        {
+            "psd" | "psb" => Some(DocumentFormat::Photoshop),
+            "kra" => Some(DocumentFormat::Krita),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str
+    {
+        match self
+        {
+            DocumentFormat::Photoshop => "Photoshop",
+            DocumentFormat::Krita => "Krita",
+        }
+    }
+}
+
+/// Loads the flattened image stored in the document.
+pub fn load_document(format: DocumentFormat, path: &Path) -> ImageResult<DynamicImage>
+{
+    let mut reader = BufReader::new(File::open(path)?);
+    match format
+    {
+        DocumentFormat::Photoshop => read_photoshop(&mut reader),
+        DocumentFormat::Krita => read_krita(&mut reader),
+    }
+}
+
+/// Reads the width and height of the document's flattened image, without decoding it.
+pub fn document_dimensions(format: DocumentFormat, path: &Path) -> Option<(u32, u32)>
+{
+    let mut reader = BufReader::new(File::open(path).ok()?);
+    match format
+    {
+        DocumentFormat::Photoshop => PhotoshopHeader::read(&mut reader).ok().map(|header| (header.width, header.height)),
+        DocumentFormat::Krita => {
+            let mut archive = zip::ZipArchive::new(reader).ok()?;
+            let name = KRITA_IMAGES.into_iter().find(|name| archive.index_for_name(name).is_some())?;
+            // The PNG header is enough to get the size.
+            let mut header = Vec::new();
+            archive.by_name(name).ok()?.take(64).read_to_end(&mut header).ok()?;
+            let size = imagesize::blob_size(&header).ok()?;
+            Some((size.width as u32, size.height as u32))
+        },
+    }
+}
+
+// ---- Krita ----
+
+/// The images in a Krita document, in order of preference.
+/// Krita has written the full size mergedimage.png since 2.9; older documents only have the preview.
+const KRITA_IMAGES: [&str; 2] = ["mergedimage.png", "preview.png"];
+
+fn read_krita<R: Read + Seek>(reader: R) -> ImageResult<DynamicImage>
+{
+    let format = DocumentFormat::Krita;
+    let mut archive = zip::ZipArchive::new(reader).map_err(|e| format_decoding_error(format.name(), e.to_string()))?;
+    for name in KRITA_IMAGES
+    {
+        let mut entry = match archive.by_name(name)
+        {
+            Ok(entry) => entry,
+            Err(zip::result::ZipError::FileNotFound) => continue,
+            Err(e) => return Err(format_decoding_error(format.name(), e.to_string())),
+        };
+        // The archive may claim any size for the entry, so it is checked before allocating.
+        image_loading::decode_limits().reserve(entry.size())?;
+        let mut png = Vec::with_capacity(entry.size() as usize);
+        entry.read_to_end(&mut png)?;
+        return image_loading::load_from_memory(&png, ImageFormat::Png);
+    }
+    Err(format_decoding_error(format.name(), "The document has no merged image or preview"))
+}
+
+// ---- Photoshop ----
+
+/// The image resource ID of the JPEG thumbnail, written by Photoshop 5.0 and later.
+const THUMBNAIL_RESOURCE_ID: u16 = 1036;
+/// The size of the thumbnail resource's header, which precedes the JPEG data.
+const THUMBNAIL_HEADER_SIZE: usize = 28;
+
+struct PhotoshopHeader
+{
+    /// PSB (large document) files use 8 byte section lengths and 4 byte RLE row lengths.
+    large: bool,
+    channels: u16,
+    height: u32,
+    width: u32,
+    depth: u16,
+    color_mode: u16,
+}
+
+impl PhotoshopHeader
+{
+    fn read<R: Read>(reader: &mut R) -> ImageResult<Self>
+    {
+        let format = DocumentFormat::Photoshop;
+        let mut signature = [0u8; 4];
+        reader.read_exact(&mut signature).map_err(|e| format_io_error(DocumentFormat::Photoshop.name(), e))?;
+        if &signature != b"8BPS" {
+            return Err(format_decoding_error(format.name(), "Missing the 8BPS signature"));
+        }
+        let large = match read_u16(reader)?
+        {
+            1 => false,
+            2 => true,
+            version => return Err(format_unsupported_error(format.name(), format!("Version {}", version))),
+        };
+        let mut reserved = [0u8; 6];
+        reader.read_exact(&mut reserved).map_err(|e| format_io_error(DocumentFormat::Photoshop.name(), e))?;
+        Ok(PhotoshopHeader {
+            large,
+            channels: read_u16(reader)?,
+            height: read_u32(reader)?,
+            width: read_u32(reader)?,
+            depth: read_u16(reader)?,
+            color_mode: read_u16(reader)?,
+        })
+    }
+
+    /// The number of channels of the color mode, not counting alpha; None for color modes we don't decode
+    /// (bitmap, indexed, CMYK, multichannel and Lab). Duotone images are stored as grayscale.
+    fn color_channels(&self) -> Option<u16>
+    {
+        match self.color_mode
+        {
+            1 | 8 => Some(1),
+            3 => Some(3),
+            _ => None,
+        }
+    }
+
+    fn read_length<R: Read>(&self, reader: &mut R) -> ImageResult<u64>
+    {
+        if self.large { read_u64(reader) } else { read_u32(reader).map(u64::from) }
+    }
+}
+
+fn read_u16<R: Read>(reader: &mut R) -> ImageResult<u16>
+{
+    let mut bytes = [0u8; 2];
+    reader.read_exact(&mut bytes).map_err(|e| format_io_error(DocumentFormat::Photoshop.name(), e))?;
+    Ok(u16::from_be_bytes(bytes))
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> ImageResult<u32>
+{
+    let mut bytes = [0u8; 4];
+    reader.read_exact(&mut bytes).map_err(|e| format_io_error(DocumentFormat::Photoshop.name(), e))?;
+    Ok(u32::from_be_bytes(bytes))
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> ImageResult<u64>
+{
+    let mut bytes = [0u8; 8];
+    reader.read_exact(&mut bytes).map_err(|e| format_io_error(DocumentFormat::Photoshop.name(), e))?;
+    Ok(u64::from_be_bytes(bytes))
+}
+
+/// Reads the composite image of a Photoshop document. If it can't be decoded (e.g. it is CMYK),
+/// the JPEG thumbnail is used instead, if there is one.
+fn read_photoshop<R: Read + Seek>(reader: &mut R) -> ImageResult<DynamicImage>
+{
+    let header = PhotoshopHeader::read(reader)?;
+
+    // Color mode data, only used by indexed and duotone images.
+    let color_mode_data_length = read_u32(reader)?;
+    reader.seek(SeekFrom::Current(color_mode_data_length as i64))?;
+
+    let resources_length = read_u32(reader)? as u64;
+    let resources_start = reader.stream_position()?;
+    let thumbnail = read_thumbnail(reader, resources_start + resources_length)?;
+    reader.seek(SeekFrom::Start(resources_start + resources_length))?;
+
+    let merged_alpha = read_merged_alpha(reader, &header)?;
+
+    match read_composite(reader, &header, merged_alpha)
+    {
+        Ok(image) => Ok(image),
+        Err(e) => match thumbnail
+        {
+            Some(jpeg) => image_loading::load_from_memory(&jpeg, ImageFormat::Jpeg),
+            None => Err(e),
+        },
+    }
+}
+
+/// Finds the JPEG data of the thumbnail resource, among the image resources which end at `end`.
+fn read_thumbnail<R: Read + Seek>(reader: &mut R, end: u64) -> ImageResult<Option<Vec<u8>>>
+{
+    while reader.stream_position()? + 12 <= end
+    {
+        let mut signature = [0u8; 4];
+        reader.read_exact(&mut signature).map_err(|e| format_io_error(DocumentFormat::Photoshop.name(), e))?;
+        if &signature != b"8BIM" {
+            return Ok(None);
+        }
+        let id = read_u16(reader)?;
+        // A Pascal string name, padded to an even length including its length byte.
+        let mut name_length = [0u8; 1];
+        reader.read_exact(&mut name_length).map_err(|e| format_io_error(DocumentFormat::Photoshop.name(), e))?;
+        let name_padding = if name_length[0] % 2 == 0 { 1 } else { 0 };
+        reader.seek(SeekFrom::Current(name_length[0] as i64 + name_padding))?;
+        // Resource data is also padded to an even length.
+        let size = read_u32(reader)? as u64;
+        let padded_size = size + size % 2;
+        if id == THUMBNAIL_RESOURCE_ID && size as usize > THUMBNAIL_HEADER_SIZE {
+            image_loading::decode_limits().reserve(size)?;
+            let mut data = vec![0u8; size as usize];
+            reader.read_exact(&mut data).map_err(|e| format_io_error(DocumentFormat::Photoshop.name(), e))?;
+            return Ok(Some(data.split_off(THUMBNAIL_HEADER_SIZE)));
+        }
+        reader.seek(SeekFrom::Current(padded_size as i64))?;
+    }
+    Ok(None)
+}
+
+/// Reads the layer count from the layer and mask information section, leaving the reader at the image data.
+/// A negative layer count means the first channel after the color channels of the composite is its transparency;
+/// otherwise any further channels are saved selections, which are ignored.
+fn read_merged_alpha<R: Read + Seek>(reader: &mut R, header: &PhotoshopHeader) -> ImageResult<bool>
+{
+    let section_length = header.read_length(reader)?;
+    let section_start = reader.stream_position()?;
+    let mut merged_alpha = false;
+    if section_length > 0 {
+        let layer_info_length = header.read_length(reader)?;
+        if layer_info_length >= 2 {
+            merged_alpha = (read_u16(reader)? as i16) < 0;
+        }
+    }
+    reader.seek(SeekFrom::Start(section_start + section_length))?;
+    Ok(merged_alpha)
+}
+
+/// Reads the image data section: the composite image's channels, one after another, raw or RLE compressed.
+fn read_composite<R: Read>(reader: &mut R, header: &PhotoshopHeader, merged_alpha: bool) -> ImageResult<DynamicImage>
+{
+    let format = DocumentFormat::Photoshop;
+    let color_channels = header.color_channels()
+        .ok_or_else(|| format_unsupported_error(format.name(), format!("Color mode {}", header.color_mode)))?;
+    if header.depth != 8 && header.depth != 16 {
+        return Err(format_unsupported_error(format.name(), format!("{} bits per channel", header.depth)));
+    }
+    if header.channels < color_channels {
+        return Err(format_decoding_error(format.name(), format!("{} channels in color mode {}", header.channels, header.color_mode)));
+    }
+    let channels = if merged_alpha && header.channels > color_channels { color_channels + 1 } else { color_channels };
+
+    // The size of the channels' data; the header is checked before allocating it, since it may claim any size.
+    let bytes_per_sample = header.depth as usize / 8;
+    let data_size = u64::from(header.width)
+        .checked_mul(u64::from(header.height))
+        .and_then(|pixels| pixels.checked_mul(u64::from(channels)))
+        .and_then(|samples| samples.checked_mul(bytes_per_sample as u64))
+        .unwrap_or(u64::MAX);
+    image_loading::decode_limits().reserve(data_size)?;
+    let (width, height) = (header.width as usize, header.height as usize);
+    let row_size = width * bytes_per_sample;
+    let plane_size = data_size as usize / channels as usize;
+    let compression = read_u16(reader)?;
+    let planes: Vec<Vec<u8>> = match compression
+    {
+        // Raw
+        0 => (0..channels).map(|_| {
+            let mut plane = vec![0u8; plane_size];
+            reader.read_exact(&mut plane).map_err(|e| format_io_error(DocumentFormat::Photoshop.name(), e))?;
+            Ok(plane)
+        }).collect::<ImageResult<_>>()?,
+        // RLE: the compressed size of every row of every channel, then the rows.
+        1 => {
+            let row_lengths = (0..header.channels as usize * height)
+                .map(|_| if header.large { read_u32(reader) } else { read_u16(reader).map(u32::from) })
+                .collect::<ImageResult<Vec<u32>>>()?;
+            let mut compressed = Vec::new();
+            (0..channels as usize).map(|channel| {
+                let mut plane = Vec::with_capacity(plane_size);
+                for row_length in &row_lengths[channel * height..(channel + 1) * height]
+                {
+                    compressed.resize(*row_length as usize, 0);
+                    reader.read_exact(&mut compressed).map_err(|e| format_io_error(DocumentFormat::Photoshop.name(), e))?;
+                    unpack_bits(&compressed, row_size, &mut plane)?;
+                }
+                Ok(plane)
+            }).collect::<ImageResult<_>>()?
+        },
+        _ => return Err(format_unsupported_error(format.name(), format!("Compression method {}", compression))),
+    };
+
+    // We only need 8 bits per channel; 16 bit samples are big-endian, so their first byte is the most significant.
+    let sample = |plane: &[u8], index: usize| plane[index * bytes_per_sample];
+    let pixels: Vec<u8> = (0..width * height)
+        .flat_map(|index| planes.iter().map(move |plane| sample(plane, index)))
+        .collect();
+
+    let (width, height) = (header.width, header.height);
+    let image = match channels
+    {
+        1 => GrayImage::from_raw(width, height, pixels).map(DynamicImage::ImageLuma8),
+        2 => GrayAlphaImage::from_raw(width, height, pixels).map(DynamicImage::ImageLumaA8),
+        3 => RgbImage::from_raw(width, height, pixels).map(DynamicImage::ImageRgb8),
+        _ => RgbaImage::from_raw(width, height, pixels).map(DynamicImage::ImageRgba8),
+    };
+    image.ok_or_else(|| format_decoding_error(format.name(), "The image data does not match the image size"))
+}
+
+/// Decompresses a PackBits compressed row of `row_size` bytes onto the end of `out`.
+fn unpack_bits(compressed: &[u8], row_size: usize, out: &mut Vec<u8>) -> ImageResult<()>
+{
+    let end = out.len() + row_size;
+    let mut input = compressed.iter();
+    while out.len() < end
+    {
+        let header = match input.next()
+        {
+            Some(header) => *header as i8,
+            None => break,
+        };
+        match header
+        {
+            // A no-op, which some encoders emit.
+            -128 => {},
+            // Repeat the next byte 1 - n times.
+            ..=-1 => {
+                let value = *input.next().ok_or_else(|| format_decoding_error(DocumentFormat::Photoshop.name(), "Truncated RLE run"))?;
+                out.resize(out.len() + (1 - header as isize) as usize, value);
+            },
+            // Copy the next n + 1 bytes literally.
+            _ => {
+                let count = header as usize + 1;
+                if input.len() < count {
+                    return Err(format_decoding_error(DocumentFormat::Photoshop.name(), "Truncated RLE literal"));
+                }
+                out.extend(input.by_ref().take(count));
+            },
+        }
+    }
+    if out.len() != end {
+        return Err(format_decoding_error(DocumentFormat::Photoshop.name(), "An RLE row does not match the image width"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests
+{
+    use std::io::{Cursor, Write};
+    use std::path::PathBuf;
+
+    use image::{GenericImageView, ImageError, Rgba};
+
+    use super::*;
+
+    const RED: Rgba<u8> = Rgba([255, 0, 0, 255]);
+    const BLUE: Rgba<u8> = Rgba([0, 0, 255, 255]);
+
+    fn test_images() -> PathBuf
+    {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("test_images")
+    }
+
+    /// The fixtures (see scripts/format_fixtures.py) are 32x16, red on the left half and blue on the right.
+    fn assert_split_image(image: &DynamicImage)
+    {
+        assert_eq!(image.dimensions(), (32, 16));
+        assert_eq!(image.get_pixel(0, 0), RED);
+        assert_eq!(image.get_pixel(15, 15), RED);
+        assert_eq!(image.get_pixel(16, 0), BLUE);
+        assert_eq!(image.get_pixel(31, 15), BLUE);
+    }
+
+    #[test]
+    fn photoshop_documents_are_loaded()
+    {
+        let path = test_images().join("split.psd");
+        assert_eq!(DocumentFormat::from_path(&path), Some(DocumentFormat::Photoshop));
+        assert_split_image(&load_document(DocumentFormat::Photoshop, &path).unwrap());
+        assert_eq!(document_dimensions(DocumentFormat::Photoshop, &path), Some((32, 16)));
+    }
+
+    #[test]
+    fn krita_documents_are_loaded()
+    {
+        let path = test_images().join("split.kra");
+        assert_eq!(DocumentFormat::from_path(&path), Some(DocumentFormat::Krita));
+        // The merged image, rather than the smaller preview.
+        assert_split_image(&load_document(DocumentFormat::Krita, &path).unwrap());
+        assert_eq!(document_dimensions(DocumentFormat::Krita, &path), Some((32, 16)));
+    }
+
+    #[test]
+    fn krita_preview_is_used_without_a_merged_image()
+    {
+        let mut png = Vec::new();
+        RgbImage::from_pixel(4, 2, image::Rgb([0, 255, 0])).write_to(&mut Cursor::new(&mut png), ImageFormat::Png).unwrap();
+        let mut archive = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        archive.start_file("preview.png", zip::write::SimpleFileOptions::default()).unwrap();
+        archive.write_all(&png).unwrap();
+        let document = archive.finish().unwrap();
+
+        let image = read_krita(document).unwrap();
+        assert_eq!(image.dimensions(), (4, 2));
+        assert_eq!(image.get_pixel(0, 0), Rgba([0, 255, 0, 255]));
+    }
+
+    /// Writes a large (PSB) document with raw 16 bit RGBA image data, with a layer count of -1
+    /// so that the fourth channel is the composite's transparency.
+    fn large_document(width: u32, height: u32, pixel: impl Fn(u32, u32) -> [u16; 4]) -> Vec<u8>
+    {
+        let mut document = Vec::new();
+        document.extend(b"8BPS");
+        document.extend(2u16.to_be_bytes());
+        document.extend([0u8; 6]);
+        document.extend(4u16.to_be_bytes());
+        document.extend(height.to_be_bytes());
+        document.extend(width.to_be_bytes());
+        document.extend(16u16.to_be_bytes());
+        document.extend(3u16.to_be_bytes());
+        // Color mode data and image resources
+        document.extend(0u32.to_be_bytes());
+        document.extend(0u32.to_be_bytes());
+        // Layer and mask information, with only the layer count
+        document.extend(10u64.to_be_bytes());
+        document.extend(2u64.to_be_bytes());
+        document.extend((-1i16).to_be_bytes());
+        // Raw image data
+        document.extend(0u16.to_be_bytes());
+        for channel in 0..4
+        {
+            for y in 0..height
+            {
+                for x in 0..width
+                {
+                    document.extend(pixel(x, y)[channel].to_be_bytes());
+                }
+            }
+        }
+        document
+    }
+
+    #[test]
+    fn large_documents_with_transparency_are_loaded()
+    {
+        let document = large_document(3, 2, |x, _| if x == 0 { [0xFFFF, 0, 0, 0xFFFF] } else { [0, 0x8000, 0xFFFF, 0] });
+        let image = read_photoshop(&mut Cursor::new(document)).unwrap();
+        assert_eq!(image.dimensions(), (3, 2));
+        assert_eq!(image.get_pixel(0, 1), Rgba([255, 0, 0, 255]));
+        assert_eq!(image.get_pixel(2, 0), Rgba([0, 128, 255, 0]));
+    }
+
+    #[test]
+    fn documents_too_large_to_decode_are_limit_errors()
+    {
+        let mut document = large_document(1, 1, |_, _| [0, 0, 0, 0]);
+        // 100000 x 100000 pixels of 16 bit RGBA would be 80 GB; the header is all that's read.
+        document[14..18].copy_from_slice(&100_000u32.to_be_bytes());
+        document[18..22].copy_from_slice(&100_000u32.to_be_bytes());
+        let error = read_photoshop(&mut Cursor::new(document)).unwrap_err();
+        assert!(matches!(error, ImageError::Limits(_)), "{:?}", error);
+    }
+
+    #[test]
+    fn truncated_documents_are_decoding_errors()
+    {
+        let document = std::fs::read(test_images().join("split.psd")).unwrap();
+        let error = read_photoshop(&mut Cursor::new(&document[..document.len() - 10])).unwrap_err();
+        assert!(matches!(error, ImageError::Decoding(_)), "{:?}", error);
+    }
+
+    #[test]
+    fn unpack_bits_decodes_runs_and_literals()
+    {
+        let mut out = Vec::new();
+        // A run of 3 zeros, a no-op, then 2 literal bytes.
+        unpack_bits(&[0xFE, 0, 0x80, 0x01, 7, 9], 5, &mut out).unwrap();
+        assert_eq!(out, vec![0, 0, 0, 7, 9]);
+        assert!(unpack_bits(&[0xFE, 0], 5, &mut Vec::new()).is_err());
+    }
+}
@@ -0,0 +1,609 @@
+/// Loading images from disk as they are meant to be displayed, shared by encoding and thumbnailing.
+///
+/// Cameras (phones especially) store photos as the sensor captured them, and record how to rotate or flip them
+/// for display in the EXIF Orientation tag. The image crate decodes the stored pixels, so without applying the tag
+/// a sideways photo would be thumbnailed and encoded sideways. Both paths load images through this module,
+/// so the model sees the same image as the user.
+///
+/// Similarly, images with transparency (cut-outs, sprites, brushes) are composited onto a background before they are
+/// encoded or thumbnailed. Dropping the alpha channel would leave whatever color is stored under transparent pixels,
+/// which is often black, and which the user never sees.
+///
+/// The EXIF data of photos also records the camera and its settings, which read_camera_info() reads for the details view.
+///
+/// Scans and photos can be far larger than the model's input or a thumbnail. Decoding is limited to MAX_DECODE_BYTES,
+/// and load_image_reduced() decodes JPEGs at a fraction of their resolution (and memory) when that is all that's needed.
+
+use std::io::{self, BufReader, Cursor};
+use std::path::Path;
+use std::process::{Command, Output};
+
+use image::error::{DecodingError, ImageFormatHint, UnsupportedError, UnsupportedErrorKind};
+use image::{DynamicImage, GenericImageView, GrayImage, ImageError, ImageFormat, ImageReader, ImageResult, Limits, Rgb, RgbImage};
+use jpeg_decoder::PixelFormat;
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "camera-raw")]
+use crate::camera_raw;
+use crate::image_formats::{self, DocumentFormat};
+use crate::interface::CameraInfo;
+use crate::vector_formats::{self, VectorFormat};
+
+/// Images which would take more memory than this to decode fail to load (with a limits error), rather than risk
+/// running out of memory. 16k x 16k scans fit, even with 16 bits per channel.
+pub const MAX_DECODE_BYTES: u64 = 2 * 1024 * 1024 * 1024;
+
+/// The memory each pixel is assumed to take by estimated_decoded_bytes(); that of 8 bit RGBA.
+const ESTIMATED_BYTES_PER_PIXEL: u64 = 4;
+
+/// The background which transparent images are composited onto.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Default)]
+pub enum Background
+{
+    #[default]
+    White,
+    Grey,
+    /// A light grey and white checkerboard, as image editors show transparency.
+    Checker,
+}
+
+impl Background
+{
+    fn color_at(self, x: u32, y: u32, checker_size: u32) -> [u8; 3]
+    {
+        match self
+        {
+            Background::White => [255, 255, 255],
+            Background::Grey => [128, 128, 128],
+            Background::Checker => if (x / checker_size + y / checker_size) % 2 == 0 { [255, 255, 255] } else { [204, 204, 204] },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct TransparencySettings
+{
+    /// The background transparent images are composited onto, for encoding and for thumbnails.
+    pub background: Background,
+    /// If set, images with transparency are also encoded on this background, and the two feature vectors are averaged,
+    /// so that a cut-out's feature vector depends less on the background it was placed on. This encodes such images twice.
+    pub second_background: Option<Background>,
+}
+
+impl Default for TransparencySettings
+{
+    fn default() -> Self
+    {
+        TransparencySettings { background: Background::White, second_background: None }
+    }
+}
+
+/// Loads the image, rotated and flipped according to its EXIF orientation.
+/// Documents (e.g. Photoshop and Krita files) are loaded from the flattened image they store; see image_formats.rs.
+/// Vector files (SVGs and PDFs) are rasterized; see vector_formats.rs.
+/// With the camera-raw feature, RAW files are loaded from their embedded previews; see camera_raw.rs.
+pub fn load_image(path: &Path) -> image::ImageResult<DynamicImage>
+{
+    if let Some(format) = DocumentFormat::from_path(path) {
+        return image_formats::load_document(format, path);
+    }
+    if let Some(format) = VectorFormat::from_path(path) {
+        return vector_formats::rasterize(format, path);
+    }
+    #[cfg(feature = "camera-raw")]
+    if camera_raw::is_camera_raw(path) {
+        return Ok(apply_orientation(camera_raw::load_raw(path)?, read_orientation(path)));
+    }
+    // The format is detected from the content where possible, since images are indexed by their content
+    // as well as their extension (see file_filter.rs), e.g. if they have no extension.
+    let mut reader = ImageReader::open(path)?.with_guessed_format()?;
+    reader.limits(decode_limits());
+    let image = reader.decode()?;
+    Ok(apply_orientation(image, read_orientation(path)))
+}
+
+/// The limits images are decoded within: allocations of at most MAX_DECODE_BYTES in total.
+pub fn decode_limits() -> Limits
+{
+    let mut limits = Limits::default();
+    limits.max_alloc = Some(MAX_DECODE_BYTES);
+    limits
+}
+
+/// Decodes an image in the format from memory, e.g. one embedded in another file, within decode_limits().
+pub fn load_from_memory(data: &[u8], format: ImageFormat) -> ImageResult<DynamicImage>
+{
+    let mut reader = ImageReader::with_format(Cursor::new(data), format);
+    reader.limits(decode_limits());
+    reader.decode()
+}
+
+/// An error decoding a file in a format which the image crate doesn't read itself (e.g. documents, vector files and
+/// camera RAW files), under the format's name.
+pub fn format_decoding_error(format_name: &str, message: impl Into<String>) -> ImageError
+{
+    ImageError::Decoding(DecodingError::new(ImageFormatHint::Name(format_name.to_string()), message.into()))
+}
+
+/// An error for a feature of such a format which isn't supported.
+pub fn format_unsupported_error(format_name: &str, message: impl Into<String>) -> ImageError
+{
+    ImageError::Unsupported(UnsupportedError::from_format_and_kind(
+        ImageFormatHint::Name(format_name.to_string()),
+        UnsupportedErrorKind::GenericFeature(message.into())))
+}
+
+/// An error reading a file in such a format. A file which ends early is corrupt, rather than unreadable.
+pub fn format_io_error(format_name: &str, e: io::Error) -> ImageError
+{
+    if e.kind() == io::ErrorKind::UnexpectedEof {
+        format_decoding_error(format_name, "The file is truncated")
+    } else {
+        ImageError::IoError(e)
+    }
+}
+
+/// Runs a tool which decodes a format in a separate process (e.g. pdftoppm or ffmpeg), returning its output.
+/// A tool which isn't installed makes the format unsupported, with `install_hint` saying how to get it, rather than the
+/// file not found; a tool which fails is reported with the last line it wrote to stderr, which is usually the error.
+pub fn run_format_tool(command: &mut Command, format_name: &str, tool_name: &str, install_hint: &str) -> ImageResult<Output>
+{
+    let output = match command.output()
+    {
+        Ok(output) => output,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return Err(format_unsupported_error(format_name, format!("{} was not found; {}", tool_name, install_hint)));
+        },
+        Err(e) => return Err(ImageError::IoError(e)),
+    };
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let message = stderr.lines().rev().find(|line| !line.trim().is_empty()).unwrap_or("no output");
+        return Err(format_decoding_error(format_name, format!("{} failed ({}): {}", tool_name, output.status, message)));
+    }
+    Ok(output)
+}
+
+/// Loads the image like load_image(), but at a reduced resolution if it is a JPEG with its shortest side at least twice
+/// min_size: JPEGs can be scaled by 1/2, 1/4 or 1/8 as they are decoded (DCT scaling), which takes a fraction of the time
+/// and memory of decoding them at full resolution. The largest reduction which keeps the shortest side at least min_size
+/// is used. Other images are loaded at full resolution.
+/// Returns the image with its width and height at full resolution (as displayed), so that positions within it can be
+/// given in pixels of the full resolution image.
+pub fn load_image_reduced(path: &Path, min_size: u32) -> image::ImageResult<(DynamicImage, (u32, u32))>
+{
+    if ImageFormat::from_path(path).is_ok_and(|format| format == ImageFormat::Jpeg) {
+        match load_jpeg_reduced(path, min_size)
+        {
+            Ok(Some((image, (width, height)))) => {
+                let orientation = read_orientation(path);
+                let size = match orientation { 5..=8 => (height, width), _ => (width, height) };
+                return Ok((apply_orientation(image, orientation), size));
+            },
+            Ok(None) => {},
+            // The image is decoded at full resolution instead, which reports the error if the image is corrupt.
+            Err(e) => warn!("Unable to decode {:?} at a reduced resolution: {}", path, e),
+        }
+    }
+    let image = load_image(path)?;
+    let size = image.dimensions();
+    Ok((image, size))
+}
+
+/// An image with its width and height at full resolution, which it may have been decoded at a fraction of.
+type ReducedImage = (DynamicImage, (u32, u32));
+
+/// Decodes the JPEG scaled down by the largest DCT scaling factor which keeps its shortest side at least min_size,
+/// with its width and height at full resolution (as stored). None if it can't be scaled down, or is a format (e.g. CMYK)
+/// which the image crate converts for us at full resolution.
+fn load_jpeg_reduced(path: &Path, min_size: u32) -> Result<Option<ReducedImage>, jpeg_decoder::Error>
+{
+    let mut decoder = jpeg_decoder::Decoder::new(BufReader::new(std::fs::File::open(path)?));
+    decoder.set_max_decoding_buffer_size(MAX_DECODE_BYTES as usize);
+    decoder.read_info()?;
+    let Some(info) = decoder.info() else { return Ok(None) };
+    let (width, height) = (info.width as u32, info.height as u32);
+    let denominator = jpeg_scale_denominator(width.min(height), min_size);
+    if denominator == 1 || !matches!(info.pixel_format, PixelFormat::L8 | PixelFormat::RGB24) {
+        return Ok(None);
+    }
+
+    let (scaled_width, scaled_height) = decoder.scale(width.div_ceil(denominator) as u16, height.div_ceil(denominator) as u16)?;
+    let (scaled_width, scaled_height) = (scaled_width as u32, scaled_height as u32);
+    let pixels = decoder.decode()?;
+    let image = match info.pixel_format
+    {
+        PixelFormat::L8 => GrayImage::from_raw(scaled_width, scaled_height, pixels).map(DynamicImage::ImageLuma8),
+        _ => RgbImage::from_raw(scaled_width, scaled_height, pixels).map(DynamicImage::ImageRgb8),
+    };
+    Ok(image.map(|image| (image, (width, height))))
+}
+
+/// The largest JPEG DCT scaling denominator (8, 4 or 2, or 1 for none) which keeps a shortest side at least min_size.
+fn jpeg_scale_denominator(shortest: u32, min_size: u32) -> u32
+{
+    [8, 4, 2].into_iter().find(|denominator| shortest.div_ceil(*denominator) >= min_size).unwrap_or(1)
+}
+
+/// Estimates the memory the image takes once it is loaded by load_image_reduced() with min_size, from the size stored
+/// in its header and assuming 8 bit RGBA pixels. Vector files are estimated at the largest size they are rasterized at.
+/// None if the size can't be read without decoding the file, e.g. for videos.
+pub fn estimated_decoded_bytes(path: &Path, min_size: u32) -> Option<u64>
+{
+    if VectorFormat::from_path(path).is_some() {
+        let raster_size = u64::from(vector_formats::RASTER_SIZE);
+        return Some(raster_size * raster_size * ESTIMATED_BYTES_PER_PIXEL);
+    }
+    let (width, height) = match DocumentFormat::from_path(path)
+    {
+        Some(format) => image_formats::document_dimensions(format, path)?,
+        None => stored_dimensions(path)?,
+    };
+    let denominator = match ImageFormat::from_path(path)
+    {
+        Ok(ImageFormat::Jpeg) => jpeg_scale_denominator(width.min(height), min_size),
+        _ => 1,
+    };
+    Some(width.div_ceil(denominator) as u64 * height.div_ceil(denominator) as u64 * ESTIMATED_BYTES_PER_PIXEL)
+}
+
+/// Reads the width and height of the image as it is displayed (i.e. swapped for orientations which turn it on its side),
+/// without decoding it. Vector files report their intrinsic size rather than the size they are rasterized at.
+pub fn image_dimensions(path: &Path) -> Option<(u32, u32)>
+{
+    if let Some(format) = DocumentFormat::from_path(path) {
+        return image_formats::document_dimensions(format, path);
+    }
+    if let Some(format) = VectorFormat::from_path(path) {
+        return vector_formats::intrinsic_size(format, path);
+    }
+    let (width, height) = stored_dimensions(path)?;
+    match read_orientation(path)
+    {
+        5..=8 => Some((height, width)),
+        _ => Some((width, height)),
+    }
+}
+
+/// Reads the width and height of the image as it is stored.
+fn stored_dimensions(path: &Path) -> Option<(u32, u32)>
+{
+    #[cfg(feature = "camera-raw")]
+    if camera_raw::is_camera_raw(path) {
+        return camera_raw::raw_dimensions(path);
+    }
+    let size = imagesize::size(path).ok()?;
+    Some((size.width as u32, size.height as u32))
+}
+
+/// Reads the EXIF data of the image, if it has any which can be read.
+fn read_exif(path: &Path) -> Option<exif::Exif>
+{
+    let file = std::fs::File::open(path).ok()?;
+    exif::Reader::new().read_from_container(&mut std::io::BufReader::new(file)).ok()
+}
+
+/// Reads the EXIF orientation of the image, from 1 to 8.
+/// Images without EXIF data or an orientation (or whose EXIF data can't be read) are assumed to be upright, which is 1.
+pub fn read_orientation(path: &Path) -> u32
+{
+    let Some(exif) = read_exif(path) else { return 1 };
+
+    // Orientation is stored as a SHORT.  You could match `orientation.value`
+    // against `Value::Short`, but the standard recommends that readers
+    // should accept BYTE, SHORT, or LONG values for any unsigned integer
+    // field.  `Value::get_uint` is provided for that purpose.
+    exif.get_field(exif::Tag::Orientation, exif::In::PRIMARY)
+        .and_then(|orientation| orientation.value.get_uint(0))
+        .unwrap_or(1)
+}
+
+/// Reads the camera, lens and exposure settings which took the photo from its EXIF data.
+/// None if the image records none of them (e.g. it isn't a photo, or its EXIF data was stripped).
+pub fn read_camera_info(path: &Path) -> Option<CameraInfo>
+{
+    let exif = read_exif(path)?;
+    let field = |tag: exif::Tag| exif.get_field(tag, exif::In::PRIMARY).map(|field| &field.value);
+    let text = |tag: exif::Tag| match field(tag)
+    {
+        Some(exif::Value::Ascii(values)) => values.first()
+            .map(|value| String::from_utf8_lossy(value).trim_end_matches(['\0', ' ']).to_string())
+            .filter(|value| !value.is_empty()),
+        _ => None,
+    };
+    let rational = |tag: exif::Tag| match field(tag)
+    {
+        Some(exif::Value::Rational(values)) => values.first().filter(|value| value.denom != 0).copied(),
+        _ => None,
+    };
+
+    let info = CameraInfo {
+        make: text(exif::Tag::Make),
+        model: text(exif::Tag::Model),
+        lens_model: text(exif::Tag::LensModel),
+        date_taken: match field(exif::Tag::DateTimeOriginal)
+        {
+            Some(exif::Value::Ascii(values)) => values.first()
+                .and_then(|value| exif::DateTime::from_ascii(value).ok())
+                .map(|date| format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02}", date.year, date.month, date.day, date.hour, date.minute, date.second)),
+            _ => None,
+        },
+        // Exposures under a second are written as fractions, as cameras show them.
+        exposure_time: rational(exif::Tag::ExposureTime).map(|time| {
+            if time.num > 0 && time.num < time.denom {
+                format!("1/{} s", (time.denom as f64 / time.num as f64).round())
+            } else {
+                format!("{} s", time.to_f64())
+            }
+        }),
+        f_number: rational(exif::Tag::FNumber).map(|f_number| f_number.to_f64()),
+        iso: field(exif::Tag::PhotographicSensitivity).and_then(|iso| iso.get_uint(0)),
+        focal_length_mm: rational(exif::Tag::FocalLength).map(|focal_length| focal_length.to_f64()),
+    };
+    (info != CameraInfo::default()).then_some(info)
+}
+
+/// Rotates and flips the stored image so that it is upright.
+///
+/// EXIF Orientation is stored as a value 1-8, where:
+/// 1 = 0 degrees: the correct orientation, no adjustment is required.
+/// 2 = 0 degrees, mirrored: image has been flipped back-to-front.
+/// 3 = 180 degrees: image is upside down.
+/// 4 = 180 degrees, mirrored: image has been flipped back-to-front and is upside down.
+/// 5 = 90 degrees: image has been flipped back-to-front and is on its side.
+/// 6 = 90 degrees, mirrored: image is on its side.
+/// 7 = 270 degrees: image has been flipped back-to-front and is on its far side.
+/// 8 = 270 degrees, mirrored: image is on its far side.
+pub fn apply_orientation(image: DynamicImage, orientation: u32) -> DynamicImage
+{
+    match orientation
+    {
+        1 => image,
+        2 => image.fliph(),
+        3 => image.rotate180(),
+        4 => image.flipv(),
+        5 => image.rotate90().fliph(),
+        6 => image.rotate90(),
+        7 => image.rotate270().fliph(),
+        8 => image.rotate270(),
+        _ => {
+            warn!("Unsupported EXIF orientation: {}", orientation);
+            image
+        }
+    }
+}
+
+/// Whether the image has an alpha channel with any pixels which aren't fully opaque.
+pub fn has_transparency(image: &DynamicImage) -> bool
+{
+    if !image.color().has_alpha() {
+        return false;
+    }
+    match image.as_rgba8()
+    {
+        Some(rgba) => rgba.pixels().any(|pixel| pixel.0[3] < 255),
+        None => image.pixels().any(|(_, _, pixel)| pixel.0[3] < 255),
+    }
+}
+
+/// Composites an image with an alpha channel onto the background, as an RGB image.
+/// Images without an alpha channel are returned as they are.
+pub fn composite_onto_background(image: DynamicImage, background: Background) -> DynamicImage
+{
+    if !image.color().has_alpha() {
+        return image;
+    }
+    let rgba = image.to_rgba8();
+    let (width, height) = rgba.dimensions();
+    // The checkerboard scales with the image, so that it is still a checkerboard once the image is downsampled.
+    let checker_size = (width.min(height) / 16).max(8);
+    let composited = RgbImage::from_fn(width, height, |x, y| {
+        let [r, g, b, a] = rgba.get_pixel(x, y).0;
+        let background = background.color_at(x, y, checker_size);
+        let blend = |foreground: u8, background: u8| {
+            ((foreground as u32 * a as u32 + background as u32 * (255 - a as u32) + 127) / 255) as u8
+        };
+        Rgb([blend(r, background[0]), blend(g, background[1]), blend(b, background[2])])
+    });
+    DynamicImage::ImageRgb8(composited)
+}
+
+#[cfg(test)]
+mod tests
+{
+    use image::{Rgb, RgbImage};
+
+    use crate::test_files::TempDir;
+
+    use super::*;
+
+    /// The colors of the top left, top right, bottom left and bottom right quadrants of the upright test image.
+    const QUADRANTS: [[u8; 3]; 4] = [[255, 0, 0], [0, 255, 0], [0, 0, 255], [255, 255, 255]];
+
+    /// A 64x32 image with a different color in each quadrant, so that any rotation or flip changes it.
+    fn upright_image() -> DynamicImage
+    {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(64, 32, |x, y| {
+            Rgb(QUADRANTS[(y / 16 * 2 + x / 32) as usize])
+        }))
+    }
+
+    /// How a camera would store the upright image with the orientation; the inverse of apply_orientation().
+    fn stored_image(upright: &DynamicImage, orientation: u32) -> DynamicImage
+    {
+        match orientation
+        {
+            1 => upright.clone(),
+            2 => upright.fliph(),
+            3 => upright.rotate180(),
+            4 => upright.flipv(),
+            // 5 and 7 transpose the image across a diagonal, which is its own inverse.
+            5 => upright.rotate90().fliph(),
+            6 => upright.rotate270(),
+            7 => upright.rotate270().fliph(),
+            8 => upright.rotate90(),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Writes the image as a JPEG with an EXIF segment containing only the orientation.
+    fn write_jpeg_with_orientation(image: &DynamicImage, orientation: u16, path: &Path)
+    {
+        let mut jpeg = Vec::new();
+        image.write_to(&mut std::io::Cursor::new(&mut jpeg), image::ImageFormat::Jpeg).unwrap();
+
+        // A big-endian TIFF header followed by an IFD with a single entry: Orientation (0x0112), a SHORT.
+        let mut tiff = b"MM\x00\x2a\x00\x00\x00\x08\x00\x01\x01\x12\x00\x03\x00\x00\x00\x01".to_vec();
+        tiff.extend_from_slice(&orientation.to_be_bytes());
+        tiff.extend_from_slice(&[0, 0, 0, 0, 0, 0]);
+        let mut app1 = b"Exif\x00\x00".to_vec();
+        app1.extend_from_slice(&tiff);
+
+        // The APP1 segment goes right after the start of image marker.
+        let mut file = jpeg[..2].to_vec();
+        file.extend_from_slice(&[0xff, 0xe1]);
+        file.extend_from_slice(&((app1.len() + 2) as u16).to_be_bytes());
+        file.extend_from_slice(&app1);
+        file.extend_from_slice(&jpeg[2..]);
+        std::fs::write(path, file).unwrap();
+    }
+
+    fn assert_upright(image: &DynamicImage)
+    {
+        assert_eq!((image.width(), image.height()), (64, 32));
+        let image = image.to_rgb8();
+        // Sample the center of each quadrant; JPEG compression only changes the colors slightly.
+        for (i, (x, y)) in [(16, 8), (48, 8), (16, 24), (48, 24)].into_iter().enumerate()
+        {
+            let pixel = image.get_pixel(x, y).0;
+            let close = pixel.iter().zip(QUADRANTS[i]).all(|(a, b)| (*a as i32 - b as i32).abs() < 16);
+            assert!(close, "expected {:?} at ({}, {}), found {:?}", QUADRANTS[i], x, y, pixel);
+        }
+    }
+
+    #[test]
+    fn every_orientation_is_loaded_upright()
+    {
+        let dir = TempDir::new("image-loading");
+        let upright = upright_image();
+        for orientation in 1..=8u16
+        {
+            let path = dir.join(format!("{}.jpg", orientation));
+            write_jpeg_with_orientation(&stored_image(&upright, orientation as u32), orientation, &path);
+
+            assert_eq!(read_orientation(&path), orientation as u32);
+            assert_eq!(image_dimensions(&path), Some((64, 32)));
+            let loaded = load_image(&path).unwrap();
+            assert_upright(&loaded);
+        }
+    }
+
+    #[test]
+    fn large_jpegs_are_decoded_at_a_reduced_resolution()
+    {
+        let dir = TempDir::new("image-loading");
+        // Four times the size of the upright image, stored on its side.
+        let large = upright_image().resize(256, 128, image::imageops::FilterType::Nearest);
+        let path = dir.join("large.jpg");
+        write_jpeg_with_orientation(&stored_image(&large, 6), 6, &path);
+
+        // A quarter of the resolution keeps the shortest side at 32.
+        assert_eq!(jpeg_scale_denominator(128, 32), 4);
+        assert_eq!(estimated_decoded_bytes(&path, 32), Some(32 * 64 * ESTIMATED_BYTES_PER_PIXEL));
+        let (reduced, full_size) = load_image_reduced(&path, 32).unwrap();
+        assert_upright(&reduced);
+        assert_eq!(full_size, (256, 128));
+
+        // Images which are needed at full resolution, and other formats, are loaded at full resolution.
+        assert_eq!(jpeg_scale_denominator(128, 100), 1);
+        let (full, full_size) = load_image_reduced(&path, 100).unwrap();
+        assert_eq!((full.dimensions(), full_size), ((256, 128), (256, 128)));
+        let png = dir.join("large.png");
+        large.save(&png).unwrap();
+        assert_eq!(estimated_decoded_bytes(&png, 32), Some(256 * 128 * ESTIMATED_BYTES_PER_PIXEL));
+        assert_eq!(load_image_reduced(&png, 32).unwrap().1, (256, 128));
+    }
+
+    #[test]
+    fn transparency_is_composited_onto_the_background()
+    {
+        // Transparent black on the left, half transparent red on the right.
+        let image = DynamicImage::ImageRgba8(image::RgbaImage::from_fn(256, 256, |x, _| {
+            if x < 128 { image::Rgba([0, 0, 0, 0]) } else { image::Rgba([255, 0, 0, 128]) }
+        }));
+        assert!(has_transparency(&image));
+
+        let white = composite_onto_background(image.clone(), Background::White).to_rgb8();
+        assert_eq!(white.get_pixel(0, 0).0, [255, 255, 255]);
+        assert_eq!(white.get_pixel(200, 0).0, [255, 127, 127]);
+
+        let grey = composite_onto_background(image.clone(), Background::Grey).to_rgb8();
+        assert_eq!(grey.get_pixel(0, 0).0, [128, 128, 128]);
+
+        // Checker squares are a sixteenth of the image.
+        let checker = composite_onto_background(image, Background::Checker).to_rgb8();
+        assert_eq!(checker.get_pixel(0, 0).0, [255, 255, 255]);
+        assert_eq!(checker.get_pixel(16, 0).0, [204, 204, 204]);
+        assert_eq!(checker.get_pixel(16, 16).0, [255, 255, 255]);
+
+        // Opaque images are unchanged, even with an alpha channel.
+        let opaque = DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(4, 4, image::Rgba([1, 2, 3, 255])));
+        assert!(!has_transparency(&opaque));
+        assert_eq!(composite_onto_background(opaque, Background::Grey).to_rgb8().get_pixel(0, 0).0, [1, 2, 3]);
+        let rgb = upright_image();
+        assert!(!has_transparency(&rgb));
+        assert_eq!(composite_onto_background(rgb.clone(), Background::Grey), rgb);
+    }
+
+    #[test]
+    fn images_without_exif_data_are_upright()
+    {
+        let dir = TempDir::new("image-loading");
+        let path = dir.join("no-exif.png");
+        upright_image().save(&path).unwrap();
+
+        assert_eq!(read_orientation(&path), 1);
+        assert_eq!(image_dimensions(&path), Some((64, 32)));
+        assert_upright(&load_image(&path).unwrap());
+        assert_eq!(read_camera_info(&path), None);
+    }
+
+    #[test]
+    fn camera_settings_are_read_from_exif()
+    {
+        let field = |tag: exif::Tag, value: exif::Value| exif::Field { tag, ifd_num: exif::In::PRIMARY, value };
+        let ascii = |text: &str| exif::Value::Ascii(vec![text.as_bytes().to_vec()]);
+        let rational = |num: u32, denom: u32| exif::Value::Rational(vec![exif::Rational { num, denom }]);
+        let fields = [
+            field(exif::Tag::Make, ascii("Canon")),
+            field(exif::Tag::Model, ascii("Canon EOS R5")),
+            field(exif::Tag::DateTimeOriginal, ascii("2024:05:01 14:03:22")),
+            field(exif::Tag::ExposureTime, rational(1, 250)),
+            field(exif::Tag::FNumber, rational(28, 10)),
+            field(exif::Tag::PhotographicSensitivity, exif::Value::Short(vec![400])),
+            field(exif::Tag::FocalLength, rational(50, 1)),
+        ];
+        // RAW files are TIFF containers, as is this.
+        let mut writer = exif::experimental::Writer::new();
+        fields.iter().for_each(|field| writer.push_field(field));
+        let mut tiff = std::io::Cursor::new(Vec::new());
+        writer.write(&mut tiff, true).unwrap();
+        let dir = TempDir::new("image-loading");
+        let path = dir.join("photo.tif");
+        std::fs::write(&path, tiff.into_inner()).unwrap();
+
+        let expected = CameraInfo {
+            make: Some("Canon".to_string()),
+            model: Some("Canon EOS R5".to_string()),
+            lens_model: None,
+            date_taken: Some("2024-05-01 14:03:22".to_string()),
+            exposure_time: Some("1/250 s".to_string()),
+            f_number: Some(2.8),
+            iso: Some(400),
+            focal_length_mm: Some(50.0),
+        };
+        assert_eq!(read_camera_info(&path), Some(expected));
+    }
+}
@@ -5,6 +5,10 @@
 
 use serde::{Deserialize, Serialize};
 
+use crate::animation::Frame;
+use crate::encoding_pipeline::EncodingStats;
+use crate::failed_encodings::FailedEncodingKind;
+use crate::regions::Region;
 use crate::uuid::UUID;
 
 
@@ -15,6 +19,38 @@ pub struct Payload
     pub message: String,
 }
 
+/// The payload of the IndexBuildProgress event.
+/// Searches are exact (and slower) until `done` is true.
+#[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq)]
+pub struct IndexBuildProgress
+{
+    pub indexed: usize,
+    pub total: usize,
+    pub done: bool,
+    /// Set if the build failed; searches continue to use exact search.
+    pub error: Option<String>,
+}
+
+/// The payload of the EncodingQueueStatus event.
+#[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq)]
+pub struct EncodingQueueStatus
+{
+    /// The number of files waiting to be encoded.
+    pub queued: usize,
+    pub paused: bool,
+}
+
+/// A file matching a search.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct SearchResult
+{
+    pub file_id: UUID,
+    /// The region (tile) of the image which matched, if one matched better than the whole image. See regions.rs.
+    pub region: Option<Region>,
+    /// The frame of the animation which matched, if one matched better than its first frame. See animation.rs.
+    pub frame: Option<Frame>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct Thumbnail
 {
@@ -43,7 +79,109 @@ pub struct FileMetadata
     pub size: Option<ImageSize>,
     pub date_created: Option<String>,
     pub date_modified: Option<String>,
-    // TODO Other metadata fields such as EXIF information from the camera?
+    /// The number of frames of an animated image or video; None for still images.
+    pub frame_count: Option<u32>,
+    /// The duration of one loop of an animated image, or of a video, in milliseconds; None for still images.
+    pub duration_ms: Option<u64>,
+    /// The path of the file if it is a video, for the frontend to play it; None for images.
+    pub video_filepath: Option<String>,
+    /// The camera and settings which took the photo, from its EXIF data; None for files which don't record them.
+    pub camera: Option<CameraInfo>,
+}
+
+/// The camera, lens and exposure settings of a photo, from its EXIF data. Each is None if the photo doesn't record it.
+#[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq)]
+pub struct CameraInfo
+{
+    pub make: Option<String>,
+    pub model: Option<String>,
+    pub lens_model: Option<String>,
+    /// When the photo was taken, in the camera's local time, e.g. "2024-05-01 14:03:22".
+    pub date_taken: Option<String>,
+    /// e.g. "1/250 s".
+    pub exposure_time: Option<String>,
+    pub f_number: Option<f64>,
+    pub iso: Option<u32>,
+    pub focal_length_mm: Option<f64>,
+}
+
+/// A file within a group of near-duplicates, with what the user needs to choose which copies to keep.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct DuplicateFile
+{
+    pub file_id: UUID,
+    pub filepath: String,
+    /// The size of the file on disk, in bytes.
+    pub file_size: Option<u64>,
+    pub size: Option<ImageSize>,
+}
+
+/// A group of files which are near-duplicates of one another.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct DuplicateGroup
+{
+    pub group_id: UUID,
+    pub files: Vec<DuplicateFile>,
+}
+
+/// A file which failed to load or encode.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct FailedEncoding
+{
+    pub file_id: UUID,
+    pub filepath: String,
+    pub error: String,
+    /// In UTC.
+    pub failed_at: String,
+}
+
+/// Files which failed to encode with the same kind of error.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct FailedEncodingGroup
+{
+    pub kind: FailedEncodingKind,
+    pub files: Vec<FailedEncoding>,
+}
+
+/// The outcome of retrying failed encodings.
+#[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq)]
+pub struct RetrySummary
+{
+    /// The files which were queued for encoding. Those which fail again return to the failed encodings,
+    /// with their new errors, once they are encoded.
+    pub retried: usize,
+}
+
+/// The outcome of scanning a watched directory for new files.
+#[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq)]
+pub struct ScanSummary
+{
+    /// The new files, which were inserted into the database and queued for encoding.
+    pub added: usize,
+    /// The new files which weren't indexed, since they aren't images (or aren't in the directory's extensions).
+    pub skipped: usize,
+}
+
+/// The throughput of the encoding pipeline since the app started.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct EncodingThroughput
+{
+    /// None if no images have been encoded since the app started.
+    pub last_run: Option<EncodingStats>,
+    pub total: EncodingStats,
+}
+
+/// An embedding model which RefRover supports. See embedding.rs.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct EmbeddingModelInfo
+{
+    pub id: String,
+    pub name: String,
+    pub feature_vector_length: usize,
+    /// Whether the model's files were found.
+    pub installed: bool,
+    /// Whether this is the model used for encoding and search.
+    pub active: bool,
 }
 
 #[cfg(test)]
@@ -74,6 +212,10 @@ mod tests
             size: Some(ImageSize { width: 1920, height: 1080 }),
             date_created: Some("2021-01-01".to_string()),
             date_modified: Some("2021-01-02".to_string()),
+            frame_count: None,
+            duration_ms: None,
+            video_filepath: None,
+            camera: Some(CameraInfo { make: Some("Canon".to_string()), iso: Some(400), ..CameraInfo::default() }),
         };
         let serialized = serde_json::to_string(&metadata).unwrap();
         let deserialized: FileMetadata = serde_json::from_str(&serialized).unwrap();
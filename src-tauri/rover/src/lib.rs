@@ -3,14 +3,38 @@ mod schema;
 pub mod db;
 pub mod queries;
 pub mod clip;
-mod preprocessing;
+pub mod embedding;
+pub mod encoding_pipeline;
+pub mod encoding_queue;
+pub mod onnx;
+pub mod model_files;
+pub mod preprocessing;
 pub mod ann;
+pub mod flat_index;
+#[cfg(any(test, feature = "bench"))]
+pub mod ann_benchmark;
 pub mod commands;
 pub mod state;
 pub mod error;
 mod thumbnails;
+pub mod image_loading;
+pub mod image_formats;
+pub mod vector_formats;
+#[cfg(feature = "camera-raw")]
+pub mod camera_raw;
 mod junk_drawer;
 pub mod interface;
 pub mod notify_handlers;
+pub mod file_filter;
 pub mod uuid;
-pub mod events;
\ No newline at end of file
+pub mod events;
+pub mod quantization;
+pub mod regions;
+pub mod animation;
+pub mod video;
+pub mod settings;
+pub mod text_embedding_cache;
+pub mod duplicates;
+pub mod failed_encodings;
+#[cfg(test)]
+mod test_files;
\ No newline at end of file
@@ -4,31 +4,42 @@
 )]
 
 use std::path::PathBuf;
-use std::sync::Mutex;
+use std::sync::{mpsc, Mutex};
 use std::thread;
 
 use app::ann;
-use app::ann::HnswSearch;
-use app::clip::Clip;
 use app::db;
+use app::embedding;
+use app::encoding_queue;
 use app::error::Error;
+use app::file_filter::FileFilter;
+use app::interface::ScanSummary;
 use app::models::NewFile;
 use app::notify_handlers::FsEventHandler;
 use app::notify_handlers::FS_WATCHER_DEBOUNCER_DURATION;
 use app::queries;
-use app::state::ClipState;
-use app::state::ClipTokenizerState;
 use app::state::ConnectionPoolState;
-use app::state::InnerClipState;
-use app::state::InnerClipTokenizerState;
+use app::state::EncodingQueueState;
+use app::state::EncodingStatsState;
 use app::state::InnerConnectionPoolState;
+use app::state::InnerModelState;
+use app::state::InnerEncodingQueueState;
+use app::state::InnerEncodingStatsState;
 use app::state::InnerSearchState;
 use app::state::FsInnerWatcherState;
 use app::state::SearchState;
+use app::state::TextModelState;
+use app::state::VisualModelState;
+use app::state::TextEmbeddingCacheState;
+use app::state::InnerTextEmbeddingCacheState;
+use app::text_embedding_cache::TextEmbeddingCache;
+use app::settings::Settings;
+use app::state::InnerSettingsState;
+use app::state::SettingsState;
 use app::state::FsWatcherState;
-use app::uuid::UUID;
 use log::error;
 use log::info;
+use log::warn;
 use log::LevelFilter;
 use tauri::Manager;
 use tauri_plugin_log::LogTarget;
@@ -52,22 +63,70 @@ fn main() -> anyhow::Result<()> {
             ])
             .level(LOG_LEVEL)
             .build())
-        .manage(
-            ClipState(
-                    Mutex::new(InnerClipState { clip: Clip::new()? })
+        .setup(|app| {
+
+            // A settings file which can't be read (e.g. after a bad hand edit) shouldn't stop the app from starting.
+            // It is left as it is, so that the user can fix it.
+            let settings = Settings::load(&app.app_handle()).unwrap_or_else(|e| {
+                warn!("{}; using the default settings.", e);
+                Settings::default()
+            });
+            let vector_encoding = settings.vector_encoding;
+            let index_backend = settings.index_backend;
+
+            // The model is loaded in the background (see below), so it doesn't hold up the window opening.
+            // A missing or invalid model shouldn't prevent the window from opening either;
+            // commands which need the model report why it is unavailable.
+            let model_info = embedding::find_model_info(&settings.active_model).unwrap_or_else(|e| {
+                error!("{}; using the default model.", e);
+                &embedding::CLIP_VIT_L_14_336PX
+            });
+            app.manage(
+                TextModelState(
+                    Mutex::new(InnerModelState::loading(model_info))
+                )
+            );
+            app.manage(
+                VisualModelState(
+                    Mutex::new(InnerModelState::loading(model_info))
                 )
-            )
-        .manage(
-            ClipTokenizerState(
-                    Mutex::new(InnerClipTokenizerState { tokenizer: instant_clip_tokenizer::Tokenizer::new() })
+            );
+            app.manage(
+                TextEmbeddingCacheState(
+                    Mutex::new(InnerTextEmbeddingCacheState {
+                        cache: TextEmbeddingCache::new(settings.text_embedding_cache.capacity),
+                        settings: settings.text_embedding_cache.clone(),
+                    })
                 )
-        )
-        .manage(
-            SearchState(
-                    Mutex::new(InnerSearchState { hnsw: HnswSearch::new() })
+            );
+            app.manage(
+                SettingsState(
+                    Mutex::new(InnerSettingsState { settings })
                 )
-            )
-        .setup(|app| {
+            );
+
+            app.manage(
+                SearchState(
+                    Mutex::new(InnerSearchState::new_building(model_info.id, index_backend, vector_encoding))
+                )
+            );
+
+            app.manage(
+                EncodingStatsState(
+                    Mutex::new(InnerEncodingStatsState::default())
+                )
+            );
+
+            let (wake_encoding_worker, encoding_worker_wakes) = mpsc::channel();
+            app.manage(
+                EncodingQueueState(
+                    Mutex::new(InnerEncodingQueueState { paused: false, wake: wake_encoding_worker })
+                )
+            );
+
+            // Load the text encoder first, so that searches work as soon as possible;
+            // the image encoder follows, and the encoding worker starts once it has loaded.
+            embedding::spawn_model_load(app.app_handle().clone(), model_info);
 
             app.manage(
                 ConnectionPoolState(
@@ -90,7 +149,7 @@ fn main() -> anyhow::Result<()> {
 
             let pool_state = app.state::<ConnectionPoolState>();
 
-            let init_db_result = db::init(&pool_state, populate_dummy_data);
+            let init_db_result = db::init(&pool_state, populate_dummy_data, vector_encoding);
             match init_db_result {
                 Ok(_) => {},
                 Err(e) => {
@@ -106,6 +165,7 @@ fn main() -> anyhow::Result<()> {
             let watched_directories = queries::get_watched_directories(&mut connection)?;
 
             // Start watching watched directoreis.
+            let default_extensions = app.state::<SettingsState>().0.lock().unwrap().settings.indexed_extensions.clone();
             for watched_directory_row in &watched_directories {
                 // These should generally already by allowed via the persisted scope, but ensure it here.
                 let watched_path = &watched_directory_row.filepath;
@@ -117,6 +177,7 @@ fn main() -> anyhow::Result<()> {
                     app_handle: app.app_handle().clone(),
                     watch_directory_id: watched_uuid.clone(),
                     watch_directory_path: PathBuf::from(watched_path.clone()),
+                    file_filter: FileFilter::for_watched_directory(watched_directory_row.indexed_extensions.as_deref(), &default_extensions),
                 };
                 let watcher = notify_debouncer_full::new_debouncer(
                     FS_WATCHER_DEBOUNCER_DURATION,
@@ -142,13 +203,15 @@ fn main() -> anyhow::Result<()> {
             //      in case we ever need to recover information
             //      Or rather, a "deleted" flag in most tables, so we can mark it as deleted and recover if needed.
             //      Would need to modify queries to check for not-deleted, though.
-            info!("Populating HNSW index...");
-            let now = std::time::Instant::now();
-            ann::populate_hnsw(app)?;
-            let elapsed = now.elapsed();
-            info!("HNSW rebuild took {:?}", elapsed);
+            // The index is built on a background thread so that the window can open right away;
+            // searches use exact search over the loaded feature vectors until it is ready.
+            ann::spawn_index_build(app.app_handle().clone());
             info!("HNSW EF_CONSTRUCTION: {:?}", ann::DEFAULT_EF_CONSTRUCTION);
             info!("HNSW_MAX_ELEMS: {:?}", ann::DEFAULT_MAX_ELEMS);
+            info!("Search index backend: {:?}, vector encoding: {:?}", index_backend, vector_encoding);
+
+            // Continue encoding any files which were queued when the app last closed.
+            encoding_queue::spawn_worker(app.app_handle().clone(), encoding_worker_wakes);
 
             let app_handle = app.app_handle().clone();
             // Handle potentially long-running work that we don't want to block the application opening.
@@ -165,6 +228,18 @@ fn main() -> anyhow::Result<()> {
             app::commands::add_watched_directory,
             app::commands::delete_watched_directory,
             app::commands::get_watched_directories,
+            app::commands::find_duplicates,
+            app::commands::get_duplicate_groups,
+            app::commands::get_failed_encodings,
+            app::commands::retry_failed_encodings,
+            app::commands::get_encoding_stats,
+            app::commands::get_encoding_queue_status,
+            app::commands::pause_encoding,
+            app::commands::resume_encoding,
+            app::commands::cancel_encoding,
+            app::commands::prioritize_encoding,
+            app::commands::get_embedding_models,
+            app::commands::set_active_embedding_model,
             ])
         .run(tauri::generate_context!())?;
 
@@ -194,10 +269,12 @@ fn initial_scan(
     
     // Scan the files in the watched directories, and add any new files to the database.
     let mut connection = app_handle.state::<ConnectionPoolState>().get_connection()?;
-    let mut file_ids: Vec<UUID> = Vec::new();
+    let default_extensions = app_handle.state::<SettingsState>().0.lock().unwrap().settings.indexed_extensions.clone();
     let mut new_files: Vec<NewFile> = Vec::new();
+    let mut summary = ScanSummary::default();
     for watched_directory in watched_directories
     {
+        let file_filter = FileFilter::for_watched_directory(watched_directory.indexed_extensions.as_deref(), &default_extensions);
         for entry in WalkDir::new(&watched_directory.filepath)
             .follow_links(false)
             .into_iter()
@@ -208,29 +285,34 @@ fn initial_scan(
             {
                 if !queries::file_exists(file_path.to_str().ok_or(Error::PathBufToString)?, &mut connection)?
                 {
+                    // Files which aren't indexed are checked on every scan, since they aren't in the database.
+                    if !file_filter.is_indexable(file_path)
+                    {
+                        summary.skipped += 1;
+                        continue;
+                    }
                     let new_file = NewFile {
                         id: uuid::Uuid::new_v4().into(),
                         filepath: file_path.to_string_lossy().to_string(),
                         watched_directory_id: Some(watched_directory.id),
                     };
-                    file_ids.push(new_file.id.clone());
                     new_files.push(new_file);
                 }
             }
         }
     }
 
+    summary.added = new_files.len();
+    info!("Initial scan found {} new files, and skipped {} which aren't indexed", summary.added, summary.skipped);
     if !new_files.is_empty()
     {
         info!("Inserting {} new files into the database...", new_files.len());
         queries::insert_files_rows(&new_files, &mut connection)?;
-        let clip_state = app_handle.state::<ClipState>();
-
-        info!("Adding to HNSW index...");
-        let search_state = app_handle.state::<SearchState>();
-        Clip::encode_files_and_add_to_search(&file_ids, &mut connection, clip_state, search_state)?;
-        info!("Added to HNSW index.");
     }
 
+    // Queue the new files for encoding, along with any files which weren't encoded with the active model
+    // before the app last closed (e.g. if their jobs were cancelled). The encoding worker adds them to the index.
+    encoding_queue::enqueue_files_without_features(&app_handle)?;
+
     Ok(())
 }
\ No newline at end of file
@@ -0,0 +1,261 @@
+/// Locating and validating the ONNX model files at runtime.
+///
+/// Model files are looked for, in order, in:
+/// 1. The directory in the model_directory setting, if set.
+/// 2. The models/ directory within the Tauri resource directory, where the bundled models are installed.
+/// 3. The models/ directory within the app data directory.
+/// 4. In debug builds only, the models/ directory in the source tree, for development.
+/// The first directory containing a given file is used.
+///
+/// Files are validated against models/manifest.json, which lists the SHA-256 and size of each model file
+/// and is compiled into the binary. Hashing a model file takes a few seconds, so a file is only hashed when
+/// it is first seen (or has changed since); verified files are recorded in the app data directory.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::embedding::ModelInfo;
+use crate::error::Error;
+
+pub const VISUAL_MODEL_FILENAME: &str = "ViT-L_14_336px_visual.onnx";
+pub const TEXT_MODEL_FILENAME: &str = "ViT-L_14_336px_transformer.onnx";
+
+const MODELS_DIRNAME: &str = "models";
+const MANIFEST: &str = include_str!("../models/manifest.json");
+const VERIFIED_MODELS_FILENAME: &str = "verified_models.json";
+
+/// The expected contents of a model file.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ManifestEntry
+{
+    pub sha256: String,
+    pub size: u64,
+}
+
+/// Maps from model filenames to their expected contents.
+pub fn manifest() -> HashMap<String, ManifestEntry>
+{
+    serde_json::from_str(MANIFEST).expect("models/manifest.json should be valid")
+}
+
+/// A model file which has been hashed and matched its manifest entry.
+/// If the file's size and modification time are unchanged, it does not need to be hashed again.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+struct VerifiedModel
+{
+    sha256: String,
+    size: u64,
+    modified_secs: u64,
+}
+
+/// The paths of the model files of an embedding model.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ModelPaths
+{
+    pub visual: PathBuf,
+    pub text: PathBuf,
+}
+
+impl ModelPaths
+{
+    /// The CLIP model files within a single directory, without validation.
+    pub fn in_directory(directory: &Path) -> Self
+    {
+        ModelPaths {
+            visual: directory.join(VISUAL_MODEL_FILENAME),
+            text: directory.join(TEXT_MODEL_FILENAME),
+        }
+    }
+
+    /// Finds and validates the model's files. See the module documentation for where they are looked for.
+    /// `model_directory` is the model_directory setting.
+    pub fn resolve(app_handle: &tauri::AppHandle, info: &ModelInfo, model_directory: Option<&Path>) -> Result<Self, Error>
+    {
+        let visual = resolve_file(app_handle, info.visual_filename, model_directory)?;
+        let text = resolve_file(app_handle, info.text_filename, model_directory)?;
+        Ok(ModelPaths { visual, text })
+    }
+}
+
+/// Finds and validates a single model file, so that the text and visual encoders can be loaded separately.
+/// `model_directory` is the model_directory setting.
+pub fn resolve_file(app_handle: &tauri::AppHandle, filename: &str, model_directory: Option<&Path>) -> Result<PathBuf, Error>
+{
+    let candidates = candidate_directories(app_handle, model_directory);
+    let app_data_dir = app_handle.path_resolver().app_data_dir();
+    let verified_models_path = app_data_dir.map(|dir| dir.join(VERIFIED_MODELS_FILENAME));
+    let mut verified = load_verified_models(verified_models_path.as_deref());
+
+    let path = find_model(filename, &candidates)?;
+    validate_model(&path, filename, &manifest(), &mut verified)?;
+
+    if let Some(verified_models_path) = verified_models_path {
+        if let Err(e) = save_verified_models(&verified_models_path, &verified) {
+            warn!("Unable to record verified model files in {:?}: {}", verified_models_path, e);
+        }
+    }
+
+    info!("Using model file {:?}", path);
+    Ok(path)
+}
+
+/// The directories to look for model files in, in order of preference.
+pub fn candidate_directories(app_handle: &tauri::AppHandle, model_directory: Option<&Path>) -> Vec<PathBuf>
+{
+    let mut candidates = Vec::new();
+    if let Some(dir) = model_directory {
+        candidates.push(dir.to_path_buf());
+    }
+    if let Some(dir) = app_handle.path_resolver().resource_dir() {
+        candidates.push(dir.join(MODELS_DIRNAME));
+    }
+    if let Some(dir) = app_handle.path_resolver().app_data_dir() {
+        candidates.push(dir.join(MODELS_DIRNAME));
+    }
+    #[cfg(debug_assertions)]
+    candidates.push(Path::new(env!("CARGO_MANIFEST_DIR")).join(MODELS_DIRNAME));
+    candidates
+}
+
+/// Returns the path of the file in the first candidate directory which contains it.
+pub fn find_model(filename: &str, candidates: &[PathBuf]) -> Result<PathBuf, Error>
+{
+    candidates.iter()
+        .map(|dir| dir.join(filename))
+        .find(|path| path.is_file())
+        .ok_or_else(|| Error::ModelNotFound {
+            filename: filename.to_string(),
+            searched: candidates.iter().map(|dir| dir.display().to_string()).collect::<Vec<_>>().join(", "),
+        })
+}
+
+/// Checks the model file against its manifest entry, hashing it unless it is in `verified` and unchanged.
+/// Adds the file to `verified` if it is valid.
+fn validate_model(
+    path: &Path,
+    filename: &str,
+    manifest: &HashMap<String, ManifestEntry>,
+    verified: &mut HashMap<String, VerifiedModel>) -> Result<(), Error>
+{
+    let expected = manifest.get(filename).ok_or_else(|| Error::ModelNotInManifest(filename.to_string()))?;
+    let path_string = path.display().to_string();
+
+    let metadata = fs::metadata(path)?;
+    if metadata.len() != expected.size {
+        // Commonly, a git LFS pointer which was never pulled, or an interrupted copy.
+        return Err(Error::ModelSizeMismatch { path: path_string, expected: expected.size, actual: metadata.len() });
+    }
+    let modified_secs = metadata.modified()?.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+
+    let previously_verified = verified.get(&path_string)
+        .map(|v| v.sha256 == expected.sha256 && v.size == expected.size && v.modified_secs == modified_secs)
+        .unwrap_or(false);
+    if previously_verified {
+        return Ok(());
+    }
+
+    info!("Verifying the checksum of {:?}...", path);
+    let actual = sha256_file(path)?;
+    if actual != expected.sha256 {
+        return Err(Error::ModelChecksumMismatch { path: path_string, expected: expected.sha256.clone(), actual });
+    }
+    verified.insert(path_string, VerifiedModel { sha256: actual, size: expected.size, modified_secs });
+    Ok(())
+}
+
+/// The lowercase hex SHA-256 of the file's contents.
+fn sha256_file(path: &Path) -> io::Result<String>
+{
+    let mut file = fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; 1 << 20];
+    loop
+    {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+fn load_verified_models(path: Option<&Path>) -> HashMap<String, VerifiedModel>
+{
+    path.and_then(|path| fs::read_to_string(path).ok())
+        .and_then(|contents| serde_json::from_str(&contents).ok())
+        .unwrap_or_default()
+}
+
+fn save_verified_models(path: &Path, verified: &HashMap<String, VerifiedModel>) -> anyhow::Result<()>
+{
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, serde_json::to_string_pretty(verified)?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests
+{
+    use crate::test_files::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn manifest_lists_clip_models()
+    {
+        let manifest = manifest();
+        assert!(manifest.contains_key(VISUAL_MODEL_FILENAME));
+        assert!(manifest.contains_key(TEXT_MODEL_FILENAME));
+    }
+
+    #[test]
+    fn find_model_uses_first_directory_containing_the_file()
+    {
+        let (first, second) = (TempDir::new("model-files"), TempDir::new("model-files"));
+        fs::write(second.join(VISUAL_MODEL_FILENAME), b"model").unwrap();
+        let candidates = vec![first.path().to_path_buf(), second.path().to_path_buf()];
+        assert_eq!(find_model(VISUAL_MODEL_FILENAME, &candidates).unwrap(), second.join(VISUAL_MODEL_FILENAME));
+
+        fs::write(first.join(VISUAL_MODEL_FILENAME), b"model").unwrap();
+        assert_eq!(find_model(VISUAL_MODEL_FILENAME, &candidates).unwrap(), first.join(VISUAL_MODEL_FILENAME));
+
+        let missing = find_model(TEXT_MODEL_FILENAME, &candidates);
+        assert!(matches!(missing, Err(Error::ModelNotFound { .. })));
+    }
+
+    #[test]
+    fn validate_model_checks_size_and_checksum()
+    {
+        let dir = TempDir::new("model-files");
+        let path = dir.join("model.onnx");
+        fs::write(&path, b"hello world").unwrap();
+        // SHA-256 of "hello world".
+        let sha256 = "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9".to_string();
+
+        let mut verified = HashMap::new();
+        let manifest = HashMap::from([("model.onnx".to_string(), ManifestEntry { sha256: sha256.clone(), size: 11 })]);
+        validate_model(&path, "model.onnx", &manifest, &mut verified).unwrap();
+        assert_eq!(verified[&path.display().to_string()].sha256, sha256);
+
+        let wrong_size = HashMap::from([("model.onnx".to_string(), ManifestEntry { sha256: sha256.clone(), size: 12 })]);
+        let result = validate_model(&path, "model.onnx", &wrong_size, &mut HashMap::new());
+        assert!(matches!(result, Err(Error::ModelSizeMismatch { .. })));
+
+        let wrong_checksum = HashMap::from([("model.onnx".to_string(), ManifestEntry { sha256: "0".repeat(64), size: 11 })]);
+        let result = validate_model(&path, "model.onnx", &wrong_checksum, &mut HashMap::new());
+        assert!(matches!(result, Err(Error::ModelChecksumMismatch { .. })));
+
+        let result = validate_model(&path, "other.onnx", &manifest, &mut HashMap::new());
+        assert!(matches!(result, Err(Error::ModelNotInManifest(_))));
+    }
+}
@@ -50,6 +50,9 @@ pub struct NewFile {
 pub struct WatchedDirectory {
     pub id: UUID,
     pub filepath: String,
+    /// A JSON array of the extensions of files which are indexed, or None to use the indexed_extensions setting.
+    /// See file_filter.rs.
+    pub indexed_extensions: Option<String>,
 }
 
 #[derive(Insertable)]
@@ -57,6 +60,7 @@ pub struct WatchedDirectory {
 pub struct NewWatchedDirectory<'a> {
     pub id: UUID,
     pub filepath: &'a str,
+    pub indexed_extensions: Option<String>,
 }
 
 #[derive(Queryable, Selectable)]
@@ -104,19 +108,86 @@ pub struct NewTag<'a> {
 }
 
 #[derive(Insertable)]
-#[diesel(table_name = crate::schema::image_features_vit_l_14_336_px)]
-pub struct NewImageFeaturesVitL14336Px<'a> {
-    pub id: String,
+#[diesel(table_name = crate::schema::image_features)]
+pub struct NewImageFeature<'a> {
+    pub file_id: UUID,
+    /// See embedding::ModelInfo::id.
+    pub model_id: &'a str,
     pub feature_vector: &'a [u8],
+    /// See quantization::VectorEncoding::to_db().
+    pub encoding: i32,
 }
 
 #[derive(Queryable, Selectable)]
-#[diesel(table_name = crate::schema::image_features_vit_l_14_336_px)]
+#[diesel(table_name = crate::schema::image_features)]
 #[diesel(check_for_backend(diesel::sqlite::Sqlite))]
 #[derive(Serialize)]
-pub struct ImageFeatureVitL14336Px {
+pub struct ImageFeature {
+    pub file_id: UUID,
+    pub model_id: String,
+    pub feature_vector: Vec<u8>,
+    /// See quantization::VectorEncoding::from_db().
+    pub encoding: i32,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = crate::schema::region_features)]
+pub struct NewRegionFeature<'a> {
     pub id: UUID,
+    pub file_id: UUID,
+    /// See embedding::ModelInfo::id.
+    pub model_id: &'a str,
+    pub x: i32,
+    pub y: i32,
+    pub width: i32,
+    pub height: i32,
+    pub feature_vector: &'a [u8],
+    /// See quantization::VectorEncoding::to_db().
+    pub encoding: i32,
+}
+
+#[derive(Queryable, Selectable)]
+#[diesel(table_name = crate::schema::region_features)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct RegionFeature {
+    pub id: UUID,
+    pub file_id: UUID,
+    pub model_id: String,
+    pub x: i32,
+    pub y: i32,
+    pub width: i32,
+    pub height: i32,
     pub feature_vector: Vec<u8>,
+    /// See quantization::VectorEncoding::from_db().
+    pub encoding: i32,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = crate::schema::frame_features)]
+pub struct NewFrameFeature<'a> {
+    pub id: UUID,
+    pub file_id: UUID,
+    /// See embedding::ModelInfo::id.
+    pub model_id: &'a str,
+    pub frame_index: i32,
+    pub timestamp_ms: i64,
+    pub feature_vector: &'a [u8],
+    /// See quantization::VectorEncoding::to_db().
+    pub encoding: i32,
+}
+
+#[derive(Queryable, Selectable)]
+#[diesel(table_name = crate::schema::frame_features)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct FrameFeature {
+    pub id: UUID,
+    pub file_id: UUID,
+    pub model_id: String,
+    pub frame_index: i32,
+    pub timestamp_ms: i64,
+    pub feature_vector: Vec<u8>,
+    /// See quantization::VectorEncoding::from_db().
+    pub encoding: i32,
 }
 
 #[derive(Insertable)]
@@ -127,6 +198,8 @@ pub struct NewFailedEncoding {
     // When timestamp is None, the current time (the SQL default) is used.\
     // https://docs.rs/diesel/latest/diesel/fn.insert_into.html#inserting-default-value-for-a-column
     pub failed_at: Option<time::PrimitiveDateTime>,
+    /// See failed_encodings::FailedEncodingKind::from_db().
+    pub kind: i32,
 }
 
 #[derive(Queryable, Selectable)]
@@ -145,4 +218,34 @@ pub struct NewThumbnail<'a> {
     pub id: UUID,
     pub file_id: UUID,
     pub path: &'a str,
-}
\ No newline at end of file
+}
+
+#[derive(Queryable, Selectable)]
+#[diesel(table_name = crate::schema::duplicate_groups)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct DuplicateGroupMember {
+    pub file_id: UUID,
+    pub group_id: UUID,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = crate::schema::duplicate_groups)]
+pub struct NewDuplicateGroupMember {
+    pub file_id: UUID,
+    pub group_id: UUID,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = crate::schema::encoding_jobs)]
+pub struct NewEncodingJob {
+    pub file_id: UUID,
+    pub priority: i32,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = crate::schema::text_embeddings)]
+pub struct NewTextEmbedding<'a> {
+    pub model_id: &'a str,
+    pub query: &'a str,
+    pub feature_vector: &'a [u8],
+}
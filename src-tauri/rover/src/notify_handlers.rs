@@ -5,7 +5,7 @@ use log::{error, info, trace};
 use notify_debouncer_full::{notify::{event::{CreateKind, ModifyKind, RemoveKind, RenameMode}, EventKind}, DebounceEventResult, DebouncedEvent};
 use tauri::Manager;
 
-use crate::{clip::Clip, error::Error, events::Event, interface::Payload, queries, state::{ClipState, ConnectionPoolState, SearchState}, uuid::UUID};
+use crate::{encoding_queue::{self, JobPriority}, error::Error, failed_encodings, file_filter::FileFilter, events::Event, interface::Payload, queries, state::{ConnectionPoolState, SearchState}, uuid::UUID};
 
 
 pub const FS_WATCHER_DEBOUNCER_DURATION: std::time::Duration = std::time::Duration::from_millis(100);
@@ -16,6 +16,8 @@ pub struct FsEventHandler
     pub app_handle: tauri::AppHandle,
     pub watch_directory_id: UUID,
     pub watch_directory_path: PathBuf,
+    /// Which new files in the directory are inserted into the database and encoded.
+    pub file_filter: FileFilter,
 }
 
 impl notify_debouncer_full::DebounceEventHandler for FsEventHandler {
@@ -31,8 +33,8 @@ impl notify_debouncer_full::DebounceEventHandler for FsEventHandler {
             error!("Error emitting fs-event: {:?}", emit_result);
         }
 
-        // TODO On release ~200 images are taking *five seconds* to process?
-        // Slower than I would think. I suppose we could investigate later.
+        // Encoding overlaps decoding with inference; see encoding_pipeline.rs.
+        // The get_encoding_stats command reports the resulting throughput if batches of new files seem slow.
 
         match result {
             Ok(events) => {
@@ -147,7 +149,7 @@ impl FsEventHandler {
                 if path.is_dir() {
                     info!("Create event for directory: {:?}", path);
                 } else if path.is_file() {
-                    new_files.push(path.clone());
+                    self.push_if_indexable(path, new_files);
                 } else {
                     // Symlinks, etc - we'll ignore them for now.
                     info!("Ignoring non-file/non-folder create event: {:?}", path);
@@ -155,7 +157,7 @@ impl FsEventHandler {
             }
             CreateKind::File => 
             {
-                new_files.push(debounced_event.paths[0].clone());
+                self.push_if_indexable(&debounced_event.paths[0], new_files);
             },
             CreateKind::Folder => {
                 info!("CreateKind::Folder event for: {:?}", debounced_event.paths[0]);
@@ -175,23 +177,34 @@ impl FsEventHandler {
     {
         match modify_event_kind {
             ModifyKind::Any => {
-                // Intentionally ignore Modify::Any. 
+                // Otherwise, intentionally ignore Modify::Any.
                 // It triggers after a Create when copying files (at least on Windows).
-                // If there is a scenario where we should handle it, we can determine that from logs.
+                // If the file failed to encode on its Create because it was still being written, this retries it.
+                // If there is another scenario where we should handle it, we can determine that from logs.
                 trace!("ModifyKind::Any: {:?}", debounced_event);
+                let path = &debounced_event.paths[0];
+                if path.is_file() {
+                    self.retry_if_failed(path)?;
+                }
             },
             ModifyKind::Data(_) =>{
                 let path = &debounced_event.paths[0];
                 if path.is_dir() {
                     info!("Modify event for directory: {:?}", path);
+                } else if path.is_file() && !self.file_filter.is_indexable(path) {
+                    trace!("Ignoring modify event for a file which isn't indexed: {:?}", path);
                 } else if path.is_file() {
-                    // If the data has changed, then the encodings and thumbnail are no longer valid.
-                    // We'll handle it as if the file was removed and re-added, which is actually what is
-                    // emitted if e.g. a file is edited in Clip Studio Paint.
-                    // TODO Possibly handle this better. My concern is this will also remove tags.
-                    // But for now, this should be "OK".
-                    self.handle_remove_file(path)?;
-                    new_files.push(path.clone());
+                    // A file which failed to encode has no encoding to invalidate, so it is retried in place.
+                    let retried = self.retry_if_failed(path)?;
+                    if !retried {
+                        // If the data has changed, then the encodings and thumbnail are no longer valid.
+                        // We'll handle it as if the file was removed and re-added, which is actually what is
+                        // emitted if e.g. a file is edited in Clip Studio Paint.
+                        // TODO Possibly handle this better. My concern is this will also remove tags.
+                        // But for now, this should be "OK".
+                        self.handle_remove_file(path)?;
+                        new_files.push(path.clone());
+                    }
                 } else {
                     // Symlinks, etc - we'll ignore them for now.
                     trace!("Ignoring non-file/non-folder modify event: {:?}", path);
@@ -219,7 +232,7 @@ impl FsEventHandler {
                             return Err(anyhow::anyhow!("File/directory mismatch in RenameMode::Both event"));
                         }
 
-                        self.rename_file_in_db(from_path, to_path)?;
+                        self.rename_file_in_db(from_path, to_path, new_files)?;
                     },
                     RenameMode::To => {
                         if last_rename_from.is_none() {
@@ -239,7 +252,7 @@ impl FsEventHandler {
                             return Err(anyhow::anyhow!("File/directory mismatch in RenameMode::Both event"));
                         }
 
-                        self.rename_file_in_db(from_path, to_path)?;
+                        self.rename_file_in_db(from_path, to_path, new_files)?;
                     },
                     RenameMode::From => {
                         if last_rename_from.is_some() {
@@ -275,9 +288,7 @@ impl FsEventHandler {
             file.0.clone()
         }).collect::<Vec<UUID>>();
         
-        let clip_state = self.app_handle.state::<ClipState>();
-        let search_state = self.app_handle.state::<SearchState>();
-        Clip::encode_files_and_add_to_search(&file_ids, &mut connection, clip_state, search_state)?;
+        encoding_queue::enqueue(&self.app_handle, &file_ids, JobPriority::NewFiles)?;
 
         // TODO Lower priority: Possibly generate thumbnails here.
         //      Low priority since generating them as-needed is fine for now.
@@ -286,10 +297,43 @@ impl FsEventHandler {
         Ok(())
     }
 
+    /// Adds the file to the new files if it should be indexed; see file_filter.rs.
+    fn push_if_indexable(
+        &self,
+        path: &PathBuf,
+        new_files: &mut Vec<PathBuf>,
+    )
+    {
+        if self.file_filter.is_indexable(path) {
+            new_files.push(path.clone());
+        } else {
+            trace!("Skipping new file which isn't indexed: {:?}", path);
+        }
+    }
+
+    /// If the file previously failed to encode, queues it to be encoded again. Returns whether it had failed.
+    fn retry_if_failed(
+        &self,
+        path: &PathBuf,
+    ) -> anyhow::Result<bool>
+    {
+        let mut connection = self.app_handle.state::<ConnectionPoolState>().get_connection()?;
+        let file_id = queries::get_file_id_from_filepath(path.to_str().ok_or(Error::PathBufToString)?, &mut connection)?;
+        let file_id = match file_id {
+            Some(file_id) if queries::is_failed_encoding(file_id, &mut connection)? => file_id,
+            _ => return Ok(false),
+        };
+
+        info!("Retrying encoding of modified file {:?}", path);
+        failed_encodings::retry_failed_encodings(&self.app_handle, Some(&[file_id]), JobPriority::NewFiles)?;
+        Ok(true)
+    }
+
     fn rename_file_in_db(
         &self,
         from_path: &PathBuf,
         to_path: &PathBuf,
+        new_files: &mut Vec<PathBuf>,
     ) -> anyhow::Result<()>
     {
         // TODO Consider using OS file system ids here - they should match, I think.
@@ -301,17 +345,30 @@ impl FsEventHandler {
             &mut connection
         )?;
 
-        if file_id.is_none() {
-            error!("File ID not found for path: {:?}", from_path);
-            return Err(anyhow::anyhow!("File ID not found for path: {:?}", from_path));
+        // Files which aren't indexed aren't in the DB, so renames can move files into or out of the index.
+        match (file_id, self.file_filter.is_indexable(to_path))
+        {
+            (Some(file_id), true) => {
+                queries::update_filepath(
+                    &file_id, 
+                    to_path.to_str().ok_or(Error::PathBufToString)?,
+                    &mut connection
+                )?;
+            },
+            (Some(file_id), false) => {
+                info!("Removing file renamed to a file which isn't indexed: {:?} -> {:?}", from_path, to_path);
+                queries::delete_files_cascade(&[file_id], &mut connection, self.app_handle.clone())?;
+                self.app_handle.state::<SearchState>().0.lock().unwrap().remove(&[file_id]);
+            },
+            (None, true) => {
+                // Applications often save by writing a temporary file and renaming it.
+                info!("Adding file renamed from a file which isn't indexed: {:?} -> {:?}", from_path, to_path);
+                new_files.push(to_path.clone());
+            },
+            (None, false) => {
+                trace!("Ignoring rename of a file which isn't indexed: {:?} -> {:?}", from_path, to_path);
+            },
         }
-        let file_id = file_id.unwrap();
-
-        queries::update_filepath(
-            &file_id, 
-            to_path.to_str().ok_or(Error::PathBufToString)?,
-            &mut connection
-        )?;
 
         Ok(())
     }
@@ -369,6 +426,8 @@ impl FsEventHandler {
         let file_id = file_id.ok_or(anyhow::anyhow!("File ID not found"))?;
         
         queries::delete_files_cascade(&[file_id], &mut connection, self.app_handle.clone())?;
+        // Keep removed files out of search results.
+        self.app_handle.state::<SearchState>().0.lock().unwrap().remove(&[file_id]);
 
         Ok(())
     }
@@ -0,0 +1,158 @@
+/// Configuration of the ONNX runtime sessions which run our models.
+///
+/// The execution providers are tried in the configured order, and the first which is available
+/// in the ONNX runtime we are linked against is registered; the CPU provider is the final fallback.
+/// DirectML is only available on Windows, so the default on other platforms is the CPU.
+
+use log::{info, warn};
+use ort::{CPUExecutionProvider, CUDAExecutionProvider, CoreMLExecutionProvider, DirectMLExecutionProvider, ExecutionProvider, ExecutionProviderDispatch, GraphOptimizationLevel, SessionBuilder};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExecutionProviderKind
+{
+    DirectMl,
+    Cuda,
+    CoreMl,
+    Cpu,
+}
+
+impl ExecutionProviderKind
+{
+    /// Whether the provider can be registered with the ONNX runtime we are linked against, on this machine.
+    fn is_available(self) -> bool
+    {
+        let available = match self
+        {
+            ExecutionProviderKind::DirectMl => DirectMLExecutionProvider::default().is_available(),
+            ExecutionProviderKind::Cuda => CUDAExecutionProvider::default().is_available(),
+            ExecutionProviderKind::CoreMl => CoreMLExecutionProvider::default().is_available(),
+            ExecutionProviderKind::Cpu => return true,
+        };
+        available.unwrap_or(false)
+    }
+
+    fn build(self) -> ExecutionProviderDispatch
+    {
+        match self
+        {
+            ExecutionProviderKind::DirectMl => DirectMLExecutionProvider::default().build(),
+            ExecutionProviderKind::Cuda => CUDAExecutionProvider::default().build(),
+            ExecutionProviderKind::CoreMl => CoreMLExecutionProvider::default().build(),
+            ExecutionProviderKind::Cpu => CPUExecutionProvider::default().build(),
+        }
+    }
+}
+
+/// See ort::GraphOptimizationLevel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OptimizationLevel
+{
+    Disable,
+    Level1,
+    Level2,
+    Level3,
+}
+
+impl From<OptimizationLevel> for GraphOptimizationLevel
+{
+    fn from(level: OptimizationLevel) -> Self
+    {
+        match level
+        {
+            OptimizationLevel::Disable => GraphOptimizationLevel::Disable,
+            OptimizationLevel::Level1 => GraphOptimizationLevel::Level1,
+            OptimizationLevel::Level2 => GraphOptimizationLevel::Level2,
+            OptimizationLevel::Level3 => GraphOptimizationLevel::Level3,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct OnnxSettings
+{
+    /// The execution providers to try, in order of preference.
+    pub execution_providers: Vec<ExecutionProviderKind>,
+    /// The number of threads used to parallelize the execution within nodes.
+    pub intra_threads: usize,
+    /// The number of threads used to parallelize the execution of the graph (across nodes).
+    pub inter_threads: usize,
+    pub graph_optimization_level: OptimizationLevel,
+}
+
+impl Default for OnnxSettings
+{
+    fn default() -> Self
+    {
+        let execution_providers = if cfg!(target_os = "windows") {
+            vec![ExecutionProviderKind::DirectMl, ExecutionProviderKind::Cpu]
+        } else {
+            vec![ExecutionProviderKind::Cpu]
+        };
+        OnnxSettings {
+            execution_providers,
+            intra_threads: 4,
+            inter_threads: 1,
+            graph_optimization_level: OptimizationLevel::Level3,
+        }
+    }
+}
+
+impl OnnxSettings
+{
+    /// The first configured execution provider which is available, or the CPU if none are.
+    pub fn select_execution_provider(&self) -> ExecutionProviderKind
+    {
+        for provider in &self.execution_providers
+        {
+            if provider.is_available() {
+                return *provider;
+            }
+            warn!("ONNX execution provider {:?} is not available; trying the next provider.", provider);
+        }
+        ExecutionProviderKind::Cpu
+    }
+
+    /// Creates a session builder configured according to these settings.
+    /// `session_name` is only used for logging.
+    pub fn session_builder(&self, session_name: &str) -> ort::Result<SessionBuilder>
+    {
+        let provider = self.select_execution_provider();
+        // Since we checked that the provider is available, failing to register it is an error
+        // rather than a silent fallback to the CPU.
+        let builder = ort::Session::builder()?
+            .with_optimization_level(self.graph_optimization_level.into())?
+            .with_intra_threads(self.intra_threads)?
+            .with_inter_threads(self.inter_threads)?
+            .with_execution_providers([provider.build().error_on_failure()])?;
+        info!("Registered the {:?} ONNX execution provider for the {} session", provider, session_name);
+        Ok(builder)
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn missing_fields_use_defaults()
+    {
+        let settings: OnnxSettings = serde_json::from_str(r#"{ "intra_threads": 8 }"#).unwrap();
+        assert_eq!(settings.intra_threads, 8);
+        assert_eq!(settings.execution_providers, OnnxSettings::default().execution_providers);
+        assert_eq!(settings.graph_optimization_level, OptimizationLevel::Level3);
+    }
+
+    #[test]
+    fn cpu_is_the_fallback()
+    {
+        let settings = OnnxSettings { execution_providers: Vec::new(), ..OnnxSettings::default() };
+        assert_eq!(settings.select_execution_provider(), ExecutionProviderKind::Cpu);
+        #[cfg(not(target_os = "windows"))]
+        assert_eq!(OnnxSettings::default().select_execution_provider(), ExecutionProviderKind::Cpu);
+    }
+}
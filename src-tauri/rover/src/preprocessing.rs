@@ -2,51 +2,55 @@
 /// Do not use these functions for any other purpose (for example,
 /// to load images for purposes other than CLIP encoding).
 
-use std::path::PathBuf;
-use image::{imageops::FilterType, DynamicImage, GenericImageView};
-use ndarray::{Array, Array2, Dim};
+use std::path::{Path, PathBuf};
+use image::{imageops::{self, FilterType}, DynamicImage};
+use ndarray::{Array, Array2, Array3, Dim};
 use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
 
+use crate::image_loading;
 use crate::uuid::UUID;
 
+/// The image input size and feature vector length of CLIP ViT-L/14@336px. Other models describe their own in their ModelInfo.
 pub const IMAGE_INPUT_SIZE: usize = 336;
 pub const CONTEXT_LENGTH: usize = 77;
 pub const FEATURE_VECTOR_LENGTH: usize = 768;
 
+/// The per-channel (RGB) mean and standard deviation of CLIP's training images, used to normalize its input.
+/// See _transform() in https://github.com/openai/CLIP/blob/main/clip/clip.py
+pub const CLIP_MEAN: [f32; 3] = [0.48145466, 0.4578275, 0.40821073];
+pub const CLIP_STD: [f32; 3] = [0.26862954, 0.26130258, 0.27577711];
+
+/// Loads the image upright, according to its EXIF orientation; see image_loading.rs.
+pub fn load_image(path: &Path) -> anyhow::Result<Box<DynamicImage>>
+{
+	match image_loading::load_image(path)
+	{
+		Ok(img) => Ok(Box::new(img)),
+		Err(e) => Err(anyhow::anyhow!("Error loading image: {:?} {:?}", path , e)),
+	}
+}
+
 pub fn load_image_batch(paths: &[(UUID, PathBuf)]) -> Vec<(UUID, anyhow::Result<Box<DynamicImage>>)>
 {
 	// Load the images in parallel
 	let images = paths.par_iter().map(
 	{
-		| (uuid, path) |
-		{
-			let img = image::open(path);
-			match img
-			{
-				Ok(img) => (*uuid, Ok(Box::new(img))),
-				Err(e) =>
-				{
-					(*uuid, Err(anyhow::anyhow!("Error loading image: {:?} {:?}", path , e)))
-				}
-			}
-		}
+		| (uuid, path) | (*uuid, load_image(path))
 	}).collect::<Vec<(UUID, anyhow::Result<Box<DynamicImage>>)>>();
 
 	images
 }
 
-pub fn resize_images(images: Vec<(UUID, Box<DynamicImage>)>) -> Vec<(UUID, Box<DynamicImage>)>
+/// Resizes and crops the images to image_input_size x image_input_size RGB images, as CLIP's reference preprocessing does:
+/// the shortest side is resized to image_input_size with bicubic filtering, and then the center of the image is cropped.
+pub fn resize_images(images: Vec<(UUID, Box<DynamicImage>)>, image_input_size: usize) -> Vec<(UUID, Box<DynamicImage>)>
 {
 	// Resize the images in parallel
 	let resized_images = images.par_iter().map(
 	{
 		| (uuid, original_img) |
 		{
-			let img = original_img.as_ref()
-				.resize(
-					IMAGE_INPUT_SIZE as u32,
-					IMAGE_INPUT_SIZE as u32,
-					FilterType::CatmullRom);
+			let img = resize_and_center_crop(original_img.as_ref(), image_input_size as u32);
 			(*uuid, Box::new(img))
 		}
 	}).collect::<Vec<(UUID, Box<DynamicImage>)>>();
@@ -54,21 +58,63 @@ pub fn resize_images(images: Vec<(UUID, Box<DynamicImage>)>) -> Vec<(UUID, Box<D
 	resized_images
 }
 
-// Convert the images to a 4D array expected by CLIP
+pub fn resize_and_center_crop(img: &DynamicImage, size: u32) -> DynamicImage
+{
+	// Any alpha channel is dropped, as PIL's convert("RGB") does. The encoding pipeline composites
+	// transparent images onto a background first; see image_loading::composite_onto_background().
+	let img = img.to_rgb8();
+	let (width, height) = img.dimensions();
+	if width == 0 || height == 0 {
+		return DynamicImage::ImageRgb8(image::RgbImage::new(size, size));
+	}
+
+	// Resize the shortest side to size, truncating the longer side as torchvision's Resize does.
+	let (resized_width, resized_height) = if width <= height {
+		(size, ((size as u64 * height as u64) / width as u64).max(size as u64) as u32)
+	} else {
+		(((size as u64 * width as u64) / height as u64).max(size as u64) as u32, size)
+	};
+	// CatmullRom is the bicubic filter with a = -0.5, as PIL's BICUBIC; both widen the filter when downsampling.
+	let resized = imageops::resize(&img, resized_width, resized_height, FilterType::CatmullRom);
+
+	// As torchvision's CenterCrop, rounding the offsets.
+	let left = ((resized_width - size) as f32 / 2.0).round() as u32;
+	let top = ((resized_height - size) as f32 / 2.0).round() as u32;
+	DynamicImage::ImageRgb8(imageops::crop_imm(&resized, left, top, size, size).to_image())
+}
+
+// Convert the images to a 4D array expected by CLIP, normalized with CLIP_MEAN and CLIP_STD.
+// The images should already be image_input_size x image_input_size; see resize_images().
 // TODO I don't think we use the UUID here. Remove?
-pub fn image_to_clip_format(images: Vec<(UUID, Box<DynamicImage>)>) -> Array<f32, Dim<[usize; 4]>>
+pub fn image_to_clip_format(images: Vec<(UUID, Box<DynamicImage>)>, image_input_size: usize) -> Array<f32, Dim<[usize; 4]>>
 {
 	// Convert the images to arrays
-	let mut image_input = Array::zeros((images.len(), 3, IMAGE_INPUT_SIZE, IMAGE_INPUT_SIZE));
+	let mut image_input = Array::zeros((images.len(), 3, image_input_size, image_input_size));
 	for (idx, (_, img)) in images.iter().enumerate()
 	{
-		for pixel in img.pixels() {
-			let x = pixel.0 as _;
-			let y = pixel.1 as _;
-			let [r, g, b, _] = pixel.2.0;
-			image_input[[idx, 0, y, x]] = (r as f32) / 255.;
-			image_input[[idx, 1, y, x]] = (g as f32) / 255.;
-			image_input[[idx, 2, y, x]] = (b as f32) / 255.;
+		image_input.index_axis_mut(ndarray::Axis(0), idx).assign(&image_to_clip_array(img, image_input_size));
+	}
+
+	image_input
+}
+
+/// Converts a single image, which should already be image_input_size x image_input_size,
+/// to the (3, image_input_size, image_input_size) array expected by CLIP.
+pub fn image_to_clip_array(img: &DynamicImage, image_input_size: usize) -> Array3<f32>
+{
+	let mut image_input = Array3::zeros((3, image_input_size, image_input_size));
+	let img = img.to_rgb8();
+	debug_assert_eq!(img.dimensions(), (image_input_size as u32, image_input_size as u32));
+	for (x, y, pixel) in img.enumerate_pixels()
+	{
+		let (x, y) = (x as usize, y as usize);
+		if x >= image_input_size || y >= image_input_size {
+			continue;
+		}
+		for channel in 0..3
+		{
+			let value = (pixel.0[channel] as f32) / 255.;
+			image_input[[channel, y, x]] = (value - CLIP_MEAN[channel]) / CLIP_STD[channel];
 		}
 	}
 
@@ -88,4 +134,62 @@ pub fn tokenize_batch(text: Vec<&str>, tokenizer: &instant_clip_tokenizer::Token
 	let tokens = tokens.mapv(|x| x as i32);
 
 	tokens
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests
+{
+	use image::{Rgb, RgbImage, Rgba, RgbaImage};
+
+	use super::*;
+
+	fn to_input(img: DynamicImage, size: usize) -> Array<f32, Dim<[usize; 4]>>
+	{
+		let images = vec![(uuid::Uuid::new_v4().into(), Box::new(img))];
+		image_to_clip_format(resize_images(images, size), size)
+	}
+
+	#[test]
+	fn constant_image_is_normalized_with_clip_mean_and_std()
+	{
+		let img = DynamicImage::ImageRgb8(RgbImage::from_pixel(50, 80, Rgb([255, 128, 0])));
+		let input = to_input(img, 32);
+		assert_eq!(input.shape(), &[1, 3, 32, 32]);
+		for (channel, value) in [255u8, 128, 0].iter().enumerate()
+		{
+			let expected = (*value as f32 / 255. - CLIP_MEAN[channel]) / CLIP_STD[channel];
+			for v in input.index_axis(ndarray::Axis(1), channel).iter() {
+				assert!((v - expected).abs() < 1e-4, "channel {}: {} != {}", channel, v, expected);
+			}
+		}
+	}
+
+	#[test]
+	fn shortest_side_is_resized_and_center_cropped()
+	{
+		// A wide image: white in the center square, black at the sides, which are cropped away.
+		let mut img = RgbImage::from_pixel(96, 32, Rgb([0, 0, 0]));
+		for x in 32..64 {
+			for y in 0..32 {
+				img.put_pixel(x, y, Rgb([255, 255, 255]));
+			}
+		}
+		let resized = resize_and_center_crop(&DynamicImage::ImageRgb8(img.clone()), 32).to_rgb8();
+		assert_eq!(resized.dimensions(), (32, 32));
+		assert!(resized.pixels().all(|p| p.0 == [255, 255, 255]));
+
+		// The same, tall and downsampled. Only the edges of the crop are blurred by the filter.
+		let tall = DynamicImage::ImageRgb8(img).rotate90();
+		let resized = resize_and_center_crop(&tall, 16).to_rgb8();
+		assert_eq!(resized.dimensions(), (16, 16));
+		assert!(resized.get_pixel(8, 8).0[0] > 250);
+		assert!(resized.get_pixel(8, 2).0[0] > 250);
+	}
+
+	#[test]
+	fn alpha_is_dropped()
+	{
+		let img = DynamicImage::ImageRgba8(RgbaImage::from_pixel(20, 20, Rgba([10, 20, 30, 0])));
+		let resized = resize_and_center_crop(&img, 8).to_rgb8();
+		assert!(resized.pixels().all(|p| p.0 == [10, 20, 30]));
+	}
+}
@@ -0,0 +1,239 @@
+/// Compact storage formats for feature vectors.
+///
+/// Feature vectors are stored in the feature table as BLOBs and held in memory by the HNSW index.
+/// By default they are full f32 vectors, serialized with bincode (~3 KB for a 768-d vector).
+/// The quantized formats trade a small amount of recall for memory and disk space:
+///
+/// * F16: each component is stored as a half-precision float (~1.5 KB).
+/// * Int8: scalar quantization with a per-vector scale (~0.8 KB). Each component is stored as
+///   round(x / scale), where scale = max(|x|) / 127, so the largest component maps to +/-127.
+///
+/// Since the cosine distance is invariant to the scale of its inputs, the in-memory index
+/// does not need the per-vector scale; it compares the quantized components directly.
+
+use half::f16;
+use hnsw_rs::prelude::Distance;
+use serde::{Deserialize, Serialize};
+
+/// The format of a feature vector BLOB. The discriminant is what is stored in the `encoding` column.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum VectorEncoding
+{
+    /// A bincode serialized Vec<f32>. This is the format of all rows written before quantization was supported.
+    #[default]
+    F32 = 0,
+    /// Little-endian f16 components.
+    F16 = 1,
+    /// A little-endian f32 scale followed by the i8 components.
+    Int8 = 2,
+}
+
+impl VectorEncoding
+{
+    pub fn to_db(self) -> i32
+    {
+        self as i32
+    }
+
+    pub fn from_db(value: i32) -> anyhow::Result<Self>
+    {
+        match value
+        {
+            0 => Ok(VectorEncoding::F32),
+            1 => Ok(VectorEncoding::F16),
+            2 => Ok(VectorEncoding::Int8),
+            _ => Err(anyhow::anyhow!("Unknown feature vector encoding: {}", value)),
+        }
+    }
+}
+
+/// Serializes a feature vector for storage in the given encoding.
+pub fn encode(vector: &[f32], encoding: VectorEncoding) -> anyhow::Result<Vec<u8>>
+{
+    match encoding
+    {
+        VectorEncoding::F32 => Ok(bincode::serialize(vector)?),
+        VectorEncoding::F16 => {
+            Ok(vector.iter().flat_map(|x| f16::from_f32(*x).to_le_bytes()).collect())
+        },
+        VectorEncoding::Int8 => {
+            let (scale, values) = quantize_i8(vector);
+            let mut out = Vec::with_capacity(4 + values.len());
+            out.extend_from_slice(&scale.to_le_bytes());
+            out.extend(values.iter().map(|x| *x as u8));
+            Ok(out)
+        },
+    }
+}
+
+/// Deserializes a stored feature vector in the given encoding back to f32 components.
+pub fn decode(bytes: &[u8], encoding: VectorEncoding) -> anyhow::Result<Vec<f32>>
+{
+    match encoding
+    {
+        VectorEncoding::F32 => Ok(bincode::deserialize(bytes)?),
+        VectorEncoding::F16 => {
+            if bytes.len() % 2 != 0 {
+                return Err(anyhow::anyhow!("Invalid f16 feature vector length: {}", bytes.len()));
+            }
+            Ok(bytes.chunks_exact(2).map(|b| f16::from_le_bytes([b[0], b[1]]).to_f32()).collect())
+        },
+        VectorEncoding::Int8 => {
+            if bytes.len() < 4 {
+                return Err(anyhow::anyhow!("Invalid int8 feature vector length: {}", bytes.len()));
+            }
+            let scale = f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+            Ok(bytes[4..].iter().map(|x| (*x as i8) as f32 * scale).collect())
+        },
+    }
+}
+
+/// Returns the per-vector scale and the quantized components.
+pub fn quantize_i8(vector: &[f32]) -> (f32, Vec<i8>)
+{
+    let max_abs = vector.iter().fold(0.0f32, |acc, x| acc.max(x.abs()));
+    if max_abs == 0.0 {
+        return (1.0, vec![0; vector.len()]);
+    }
+    let scale = max_abs / 127.0;
+    let values = vector.iter().map(|x| (x / scale).round().clamp(-127.0, 127.0) as i8).collect();
+    (scale, values)
+}
+
+pub fn quantize_f16(vector: &[f32]) -> Vec<f16>
+{
+    vector.iter().map(|x| f16::from_f32(*x)).collect()
+}
+
+fn cosine_distance(dot: f32, norm_a: f32, norm_b: f32) -> f32
+{
+    // A zero vector has no direction; treat it as orthogonal to everything, rather than identical,
+    // so that a degenerate vector doesn't rank first for every query.
+    if norm_a == 0.0 || norm_b == 0.0 {
+        return 1.0;
+    }
+    // Clamp, since rounding may leave the similarity slightly outside of [-1, 1].
+    1.0 - (dot / (norm_a.sqrt() * norm_b.sqrt())).clamp(-1.0, 1.0)
+}
+
+/// Cosine distance between f16 vectors, accumulated in f32.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DistCosineF16;
+
+impl Distance<f16> for DistCosineF16
+{
+    fn eval(&self, va: &[f16], vb: &[f16]) -> f32
+    {
+        assert_eq!(va.len(), vb.len());
+        let (mut dot, mut norm_a, mut norm_b) = (0.0f32, 0.0f32, 0.0f32);
+        for (a, b) in va.iter().zip(vb.iter())
+        {
+            let (a, b) = (a.to_f32(), b.to_f32());
+            dot += a * b;
+            norm_a += a * a;
+            norm_b += b * b;
+        }
+        cosine_distance(dot, norm_a, norm_b)
+    }
+}
+
+/// Cosine distance between scalar-quantized i8 vectors, accumulated in i32.
+/// Each vector's scale cancels out, so it is not needed here.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DistCosineI8;
+
+impl Distance<i8> for DistCosineI8
+{
+    fn eval(&self, va: &[i8], vb: &[i8]) -> f32
+    {
+        assert_eq!(va.len(), vb.len());
+        let (mut dot, mut norm_a, mut norm_b) = (0i32, 0i32, 0i32);
+        for (a, b) in va.iter().zip(vb.iter())
+        {
+            let (a, b) = (*a as i32, *b as i32);
+            dot += a * b;
+            norm_a += a * a;
+            norm_b += b * b;
+        }
+        cosine_distance(dot as f32, norm_a as f32, norm_b as f32)
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use crate::ann_benchmark;
+    use crate::preprocessing::FEATURE_VECTOR_LENGTH;
+
+    use super::*;
+
+    fn max_abs_error(a: &[f32], b: &[f32]) -> f32
+    {
+        a.iter().zip(b.iter()).fold(0.0, |acc, (x, y)| acc.max((x - y).abs()))
+    }
+
+    #[test]
+    fn f32_is_bincode()
+    {
+        // Rows written before quantization was supported must still decode as F32.
+        let v = vec![0.25f32, -0.5, 1.0];
+        let legacy = bincode::serialize(&v).unwrap();
+        assert_eq!(encode(&v, VectorEncoding::F32).unwrap(), legacy);
+        assert_eq!(decode(&legacy, VectorEncoding::F32).unwrap(), v);
+    }
+
+    #[test]
+    fn round_trip()
+    {
+        let vectors = ann_benchmark::synthetic_feature_vectors(20, FEATURE_VECTOR_LENGTH, 4, 0.8, 3);
+        for v in &vectors
+        {
+            let f16_bytes = encode(v, VectorEncoding::F16).unwrap();
+            assert_eq!(f16_bytes.len(), 2 * FEATURE_VECTOR_LENGTH);
+            assert!(max_abs_error(v, &decode(&f16_bytes, VectorEncoding::F16).unwrap()) < 1e-3);
+
+            let i8_bytes = encode(v, VectorEncoding::Int8).unwrap();
+            assert_eq!(i8_bytes.len(), 4 + FEATURE_VECTOR_LENGTH);
+            let max_abs = v.iter().fold(0.0f32, |acc, x| acc.max(x.abs()));
+            // Error is at most half of a quantization step.
+            assert!(max_abs_error(v, &decode(&i8_bytes, VectorEncoding::Int8).unwrap()) <= max_abs / 127.0 * 0.5 + 1e-6);
+        }
+    }
+
+    #[test]
+    fn zero_vector()
+    {
+        let v = vec![0.0f32; 8];
+        let decoded = decode(&encode(&v, VectorEncoding::Int8).unwrap(), VectorEncoding::Int8).unwrap();
+        assert_eq!(decoded, v);
+    }
+
+    #[test]
+    fn zero_vectors_are_orthogonal()
+    {
+        let zero = vec![0.0f32; 8];
+        let v = vec![0.5f32; 8];
+        assert_eq!(DistCosineF16.eval(&quantize_f16(&zero), &quantize_f16(&v)), 1.0);
+        assert_eq!(DistCosineI8.eval(&quantize_i8(&zero).1, &quantize_i8(&v).1), 1.0);
+        assert_eq!(DistCosineI8.eval(&quantize_i8(&zero).1, &quantize_i8(&zero).1), 1.0);
+    }
+
+    #[test]
+    fn quantized_distances_match_f32()
+    {
+        let vectors = ann_benchmark::synthetic_feature_vectors(10, FEATURE_VECTOR_LENGTH, 2, 0.8, 5);
+        let dot = |a: &[f32], b: &[f32]| a.iter().zip(b.iter()).map(|(x, y)| x * y).sum::<f32>();
+        for a in &vectors
+        {
+            for b in &vectors
+            {
+                let expected = 1.0 - dot(a, b);
+                let d_f16 = DistCosineF16.eval(&quantize_f16(a), &quantize_f16(b));
+                let d_i8 = DistCosineI8.eval(&quantize_i8(a).1, &quantize_i8(b).1);
+                assert!((expected - d_f16).abs() < 1e-3);
+                assert!((expected - d_i8).abs() < 1e-2);
+            }
+        }
+    }
+}
@@ -14,7 +14,7 @@ use uuid::Uuid;
 use diesel::sql_types::Integer;
 
 use crate::error::Error;
-use crate::models::{File, ImageFeatureVitL14336Px, NewFile, NewTagEdge, NewThumbnail, RowsAffected, Thumbnail, WatchedDirectory};
+use crate::models::{File, FrameFeature, ImageFeature, NewDuplicateGroupMember, NewEncodingJob, NewFailedEncoding, NewFile, NewFrameFeature, NewImageFeature, NewRegionFeature, NewTagEdge, NewTextEmbedding, NewThumbnail, RegionFeature, RowsAffected, Thumbnail, WatchedDirectory};
 use crate::uuid::UUID;
 
 pub fn add_tag_edge(start_vertex_id: UUID, end_vertex_id: UUID, source: UUID, connection: &mut SqliteConnection) -> diesel::QueryResult<()>
@@ -444,29 +444,211 @@ pub fn get_tag_name(tag_id: UUID, connection: &mut SqliteConnection) -> anyhow::
    Ok(result)
 }
 
-pub fn get_all_image_feature_data(connection: &mut SqliteConnection) -> anyhow::Result<Vec<ImageFeatureVitL14336Px>>
+/// Gets the feature vectors of all files, according to the given embedding model.
+pub fn get_all_image_feature_data(model: &str, connection: &mut SqliteConnection) -> anyhow::Result<Vec<ImageFeature>>
 {
-   use crate::schema::image_features_vit_l_14_336_px::dsl::*;
+   use crate::schema::image_features::dsl::*;
 
-   let image_feature_data = image_features_vit_l_14_336_px
-      .select(ImageFeatureVitL14336Px::as_select())
+   let image_feature_data = image_features
+      .select(ImageFeature::as_select())
+      .filter(model_id.eq(model))
       .load(connection)?;
 
    Ok(image_feature_data)
 }
 
-pub fn get_image_feature_data(ids: &[UUID], connection: &mut SqliteConnection) -> anyhow::Result<Vec<ImageFeatureVitL14336Px>>
+pub fn get_image_feature_data(ids: &[UUID], model: &str, connection: &mut SqliteConnection) -> anyhow::Result<Vec<ImageFeature>>
 {
-   use crate::schema::image_features_vit_l_14_336_px::dsl::*;
+   use crate::schema::image_features::dsl::*;
 
-   let image_feature_data = image_features_vit_l_14_336_px
-      .select(ImageFeatureVitL14336Px::as_select())
-      .filter(id.eq_any(ids))
+   let image_feature_data = image_features
+      .select(ImageFeature::as_select())
+      .filter(file_id.eq_any(ids))
+      .filter(model_id.eq(model))
       .load(connection)?;
 
    Ok(image_feature_data)
 }
 
+/// Gets the image feature rows (of any model) which are not stored in the given encoding.
+pub fn get_image_feature_data_not_in_encoding(target_encoding: i32, connection: &mut SqliteConnection) -> anyhow::Result<Vec<ImageFeature>>
+{
+   use crate::schema::image_features::dsl::*;
+
+   let image_feature_data = image_features
+      .select(ImageFeature::as_select())
+      .filter(encoding.ne(target_encoding))
+      .load(connection)?;
+
+   Ok(image_feature_data)
+}
+
+pub fn update_image_feature_vector(file: UUID, model: &str, new_feature_vector: &[u8], new_encoding: i32, connection: &mut SqliteConnection) -> anyhow::Result<()>
+{
+   use crate::schema::image_features::dsl::*;
+
+   diesel::update(image_features.filter(file_id.eq(file)).filter(model_id.eq(model)))
+      .set((feature_vector.eq(new_feature_vector), encoding.eq(new_encoding)))
+      .execute(connection)?;
+
+   Ok(())
+}
+
+pub fn insert_image_features(new_image_features: &[NewImageFeature], connection: &mut SqliteConnection) -> anyhow::Result<()>
+{
+   use crate::schema::image_features;
+
+   diesel::insert_into(image_features::table)
+      .values(new_image_features)
+      .execute(connection)?;
+
+   Ok(())
+}
+
+/// Gets the region (tile) feature vectors of all files, according to the given embedding model. See regions.rs.
+pub fn get_all_region_feature_data(model: &str, connection: &mut SqliteConnection) -> anyhow::Result<Vec<RegionFeature>>
+{
+   use crate::schema::region_features::dsl::*;
+
+   let region_feature_data = region_features
+      .select(RegionFeature::as_select())
+      .filter(model_id.eq(model))
+      .load(connection)?;
+
+   Ok(region_feature_data)
+}
+
+pub fn get_region_feature_data(file_ids: &[UUID], model: &str, connection: &mut SqliteConnection) -> anyhow::Result<Vec<RegionFeature>>
+{
+   use crate::schema::region_features::dsl::*;
+
+   let region_feature_data = region_features
+      .select(RegionFeature::as_select())
+      .filter(file_id.eq_any(file_ids))
+      .filter(model_id.eq(model))
+      .load(connection)?;
+
+   Ok(region_feature_data)
+}
+
+pub fn insert_region_features(new_region_features: &[NewRegionFeature], connection: &mut SqliteConnection) -> anyhow::Result<()>
+{
+   use crate::schema::region_features;
+
+   diesel::insert_into(region_features::table)
+      .values(new_region_features)
+      .execute(connection)?;
+
+   Ok(())
+}
+
+/// Gets the region feature rows (of any model) which are not stored in the given encoding.
+pub fn get_region_feature_data_not_in_encoding(target_encoding: i32, connection: &mut SqliteConnection) -> anyhow::Result<Vec<RegionFeature>>
+{
+   use crate::schema::region_features::dsl::*;
+
+   let region_feature_data = region_features
+      .select(RegionFeature::as_select())
+      .filter(encoding.ne(target_encoding))
+      .load(connection)?;
+
+   Ok(region_feature_data)
+}
+
+pub fn update_region_feature_vector(region_id: UUID, new_feature_vector: &[u8], new_encoding: i32, connection: &mut SqliteConnection) -> anyhow::Result<()>
+{
+   use crate::schema::region_features::dsl::*;
+
+   diesel::update(region_features.filter(id.eq(region_id)))
+      .set((feature_vector.eq(new_feature_vector), encoding.eq(new_encoding)))
+      .execute(connection)?;
+
+   Ok(())
+}
+
+/// Gets the frame feature vectors of all files, according to the given embedding model. See animation.rs.
+pub fn get_all_frame_feature_data(model: &str, connection: &mut SqliteConnection) -> anyhow::Result<Vec<FrameFeature>>
+{
+   use crate::schema::frame_features::dsl::*;
+
+   let frame_feature_data = frame_features
+      .select(FrameFeature::as_select())
+      .filter(model_id.eq(model))
+      .load(connection)?;
+
+   Ok(frame_feature_data)
+}
+
+pub fn get_frame_feature_data(file_ids: &[UUID], model: &str, connection: &mut SqliteConnection) -> anyhow::Result<Vec<FrameFeature>>
+{
+   use crate::schema::frame_features::dsl::*;
+
+   let frame_feature_data = frame_features
+      .select(FrameFeature::as_select())
+      .filter(file_id.eq_any(file_ids))
+      .filter(model_id.eq(model))
+      .load(connection)?;
+
+   Ok(frame_feature_data)
+}
+
+pub fn insert_frame_features(new_frame_features: &[NewFrameFeature], connection: &mut SqliteConnection) -> anyhow::Result<()>
+{
+   use crate::schema::frame_features;
+
+   diesel::insert_into(frame_features::table)
+      .values(new_frame_features)
+      .execute(connection)?;
+
+   Ok(())
+}
+
+/// Gets the frame feature rows (of any model) which are not stored in the given encoding.
+pub fn get_frame_feature_data_not_in_encoding(target_encoding: i32, connection: &mut SqliteConnection) -> anyhow::Result<Vec<FrameFeature>>
+{
+   use crate::schema::frame_features::dsl::*;
+
+   let frame_feature_data = frame_features
+      .select(FrameFeature::as_select())
+      .filter(encoding.ne(target_encoding))
+      .load(connection)?;
+
+   Ok(frame_feature_data)
+}
+
+pub fn update_frame_feature_vector(frame_id: UUID, new_feature_vector: &[u8], new_encoding: i32, connection: &mut SqliteConnection) -> anyhow::Result<()>
+{
+   use crate::schema::frame_features::dsl::*;
+
+   diesel::update(frame_features.filter(id.eq(frame_id)))
+      .set((feature_vector.eq(new_feature_vector), encoding.eq(new_encoding)))
+      .execute(connection)?;
+
+   Ok(())
+}
+
+/// Gets the IDs of the files which have no feature vector for the given model,
+/// excluding those which previously failed to encode.
+pub fn get_files_without_image_features(model: &str, connection: &mut SqliteConnection) -> anyhow::Result<Vec<UUID>>
+{
+   use crate::schema::failed_encodings;
+   use crate::schema::files;
+   use crate::schema::image_features;
+
+   let encoded = image_features::table
+      .select(image_features::file_id)
+      .filter(image_features::model_id.eq(model));
+   let failed = failed_encodings::table.select(failed_encodings::id);
+
+   let file_ids = files::table
+      .select(files::id)
+      .filter(files::id.ne_all(encoded))
+      .filter(files::id.ne_all(failed))
+      .load(connection)?;
+
+   Ok(file_ids)
+}
+
 pub fn insert_thumbnail(thumbnail: &NewThumbnail, connection: &mut SqliteConnection) -> anyhow::Result<()>
 {
    use crate::schema::thumbnails;
@@ -636,14 +818,15 @@ pub fn get_watched_directories(connection: &mut SqliteConnection) -> anyhow::Res
    use crate::schema::watched_directories;
 
    let watched_directories: Vec<WatchedDirectory> = watched_directories::table
-      .select((watched_directories::id, watched_directories::filepath))
+      .select((watched_directories::id, watched_directories::filepath, watched_directories::indexed_extensions))
       .load(connection)?;
 
    Ok(watched_directories)
 }
 
 /// Returns the UUID of the new watched directory.
-pub fn insert_watched_directory(watched_directory: &str, connection: &mut SqliteConnection) -> anyhow::Result<UUID>
+/// If indexed_extensions is None, the directory indexes the extensions in the indexed_extensions setting.
+pub fn insert_watched_directory(watched_directory: &str, indexed_extensions: Option<&[String]>, connection: &mut SqliteConnection) -> anyhow::Result<UUID>
 {
    use crate::schema::watched_directories;
 
@@ -651,6 +834,7 @@ pub fn insert_watched_directory(watched_directory: &str, connection: &mut Sqlite
    let new_watched_directory = crate::models::NewWatchedDirectory {
       id: watched_directory_uuid,
       filepath: watched_directory,
+      indexed_extensions: indexed_extensions.map(serde_json::to_string).transpose()?,
    };
 
    diesel::insert_into(watched_directories::table)
@@ -720,6 +904,8 @@ pub fn delete_files_cascade(file_ids: &[UUID], connection: &mut SqliteConnection
 {
    delete_files_tags(file_ids, connection)?;
    delete_failed_encodings(file_ids, connection)?;
+   delete_encoding_jobs(file_ids, connection)?;
+   delete_duplicate_group_members(file_ids, connection)?;
    delete_files_encodings(file_ids, connection)?;
 
    let thumbnail_paths = get_thumbnail_filepaths_by_file_ids(file_ids, connection)?;
@@ -737,21 +923,81 @@ pub fn delete_files_cascade(file_ids: &[UUID], connection: &mut SqliteConnection
    Ok(())
 }
 
-pub fn delete_watched_directories_cascade(base_dir_ids: &[UUID], connection: &mut SqliteConnection, app_handle: AppHandle) -> anyhow::Result<()>
+/// Returns the IDs of the deleted files.
+pub fn delete_watched_directories_cascade(base_dir_ids: &[UUID], connection: &mut SqliteConnection, app_handle: AppHandle) -> anyhow::Result<Vec<UUID>>
 {
    // Note that since these IDs include those files in subdirectories, so we don't need to walk a tree.
    let file_ids = get_files_in_watched_directories(base_dir_ids, connection)?;
    delete_files_cascade(&file_ids, connection, app_handle)?;
    delete_watched_directories(base_dir_ids, connection)?;
 
-   Ok(())
+   Ok(file_ids)
 }
 
+/// Deletes the feature vectors of the files (and of their regions and frames), for every model.
 pub fn delete_files_encodings(file_ids: &[UUID], connection: &mut SqliteConnection) -> anyhow::Result<()>
 {
-   use crate::schema::image_features_vit_l_14_336_px;
+   use crate::schema::frame_features;
+   use crate::schema::image_features;
+   use crate::schema::region_features;
 
-   diesel::delete(image_features_vit_l_14_336_px::table.filter(image_features_vit_l_14_336_px::id.eq_any(file_ids)))
+   diesel::delete(image_features::table.filter(image_features::file_id.eq_any(file_ids)))
+      .execute(connection)?;
+   diesel::delete(region_features::table.filter(region_features::file_id.eq_any(file_ids)))
+      .execute(connection)?;
+   diesel::delete(frame_features::table.filter(frame_features::file_id.eq_any(file_ids)))
+      .execute(connection)?;
+
+   Ok(())
+}
+
+/// Gets the files which failed to encode, with their path, error, the time of the failure and its kind
+/// (see FailedEncodingKind::from_db()), most recent first.
+pub fn get_failed_encodings(connection: &mut SqliteConnection) -> anyhow::Result<Vec<(UUID, String, String, time::PrimitiveDateTime, i32)>>
+{
+   use crate::schema::failed_encodings;
+   use crate::schema::files;
+
+   let rows = failed_encodings::table
+      .inner_join(files::table)
+      .select((failed_encodings::id, files::filepath, failed_encodings::error, failed_encodings::failed_at, failed_encodings::kind))
+      .order((failed_encodings::failed_at.desc(), files::filepath))
+      .load(connection)?;
+
+   Ok(rows)
+}
+
+pub fn get_failed_encoding_ids(connection: &mut SqliteConnection) -> anyhow::Result<Vec<UUID>>
+{
+   use crate::schema::failed_encodings;
+
+   let ids = failed_encodings::table
+      .select(failed_encodings::id)
+      .load(connection)?;
+
+   Ok(ids)
+}
+
+pub fn is_failed_encoding(file_id: UUID, connection: &mut SqliteConnection) -> anyhow::Result<bool>
+{
+   use crate::schema::failed_encodings;
+
+   let exists: bool = select(
+      exists(
+         failed_encodings::table.filter(
+            failed_encodings::id.eq(file_id))))
+      .get_result(connection)?;
+
+   Ok(exists)
+}
+
+/// Records the files as having failed to encode, replacing any previous failure of the same files.
+pub fn insert_failed_encodings(new_failed_encodings: &[NewFailedEncoding], connection: &mut SqliteConnection) -> anyhow::Result<()>
+{
+   use crate::schema::failed_encodings;
+
+   diesel::replace_into(failed_encodings::table)
+      .values(new_failed_encodings)
       .execute(connection)?;
 
    Ok(())
@@ -777,6 +1023,210 @@ pub fn delete_files_tags(file_ids: &[UUID], connection: &mut SqliteConnection) -
    Ok(())
 }
 
+/// Replaces all duplicate groups with the given groups of file IDs.
+/// Each group should contain at least two files, and each file should be in at most one group.
+pub fn replace_duplicate_groups(groups: &[Vec<UUID>], connection: &mut SqliteConnection) -> anyhow::Result<()>
+{
+   use crate::schema::duplicate_groups;
+
+   let members: Vec<NewDuplicateGroupMember> = groups.iter().flat_map(|group| {
+      let group_id: UUID = Uuid::new_v4().into();
+      group.iter().map(move |file_id| NewDuplicateGroupMember { file_id: *file_id, group_id })
+   }).collect();
+
+   connection.transaction::<_, diesel::result::Error, _>(|connection| {
+      diesel::delete(duplicate_groups::table).execute(connection)?;
+      // Insert in batches to stay below SQLite's limit on the number of bound parameters.
+      for chunk in members.chunks(1000)
+      {
+         diesel::insert_into(duplicate_groups::table)
+            .values(chunk)
+            .execute(connection)?;
+      }
+      Ok(())
+   })?;
+
+   Ok(())
+}
+
+/// Gets the duplicate groups, as (group ID, [(file ID, filepath)]).
+/// Groups which have been reduced to a single file (e.g. because the other copies were deleted) are omitted.
+pub fn get_duplicate_groups(connection: &mut SqliteConnection) -> anyhow::Result<Vec<(UUID, Vec<(UUID, PathBuf)>)>>
+{
+   use crate::schema::duplicate_groups;
+   use crate::schema::files;
+
+   let rows: Vec<(UUID, UUID, String)> = duplicate_groups::table
+      .inner_join(files::table)
+      .select((duplicate_groups::group_id, files::id, files::filepath))
+      .order((duplicate_groups::group_id, files::filepath))
+      .load(connection)?;
+
+   let mut groups: Vec<(UUID, Vec<(UUID, PathBuf)>)> = Vec::new();
+   for (group_id, file_id, filepath) in rows
+   {
+      match groups.last_mut()
+      {
+         Some((last_group_id, members)) if *last_group_id == group_id => members.push((file_id, PathBuf::from(filepath))),
+         _ => groups.push((group_id, vec![(file_id, PathBuf::from(filepath))])),
+      }
+   }
+   groups.retain(|(_, members)| members.len() > 1);
+
+   Ok(groups)
+}
+
+pub fn delete_duplicate_group_members(file_ids: &[UUID], connection: &mut SqliteConnection) -> anyhow::Result<()>
+{
+   use crate::schema::duplicate_groups;
+
+   diesel::delete(duplicate_groups::table.filter(duplicate_groups::file_id.eq_any(file_ids)))
+      .execute(connection)?;
+
+   Ok(())
+}
+
+/// Queues the files for encoding with the given priority.
+/// Files which are already queued keep their place in the queue, but are raised to the priority if it is higher.
+pub fn enqueue_encoding_jobs(file_ids: &[UUID], priority: i32, connection: &mut SqliteConnection) -> anyhow::Result<()>
+{
+   use crate::schema::encoding_jobs;
+
+   connection.transaction::<_, diesel::result::Error, _>(|connection| {
+      // Insert in batches to stay below SQLite's limit on the number of bound parameters.
+      for chunk in file_ids.chunks(1000)
+      {
+         let new_jobs: Vec<NewEncodingJob> = chunk.iter().map(|file_id| NewEncodingJob { file_id: *file_id, priority }).collect();
+         diesel::insert_or_ignore_into(encoding_jobs::table)
+            .values(&new_jobs)
+            .execute(connection)?;
+         diesel::update(encoding_jobs::table
+               .filter(encoding_jobs::file_id.eq_any(chunk))
+               .filter(encoding_jobs::priority.lt(priority)))
+            .set(encoding_jobs::priority.eq(priority))
+            .execute(connection)?;
+      }
+      Ok(())
+   })?;
+
+   Ok(())
+}
+
+/// Gets the IDs of up to `limit` queued files, highest priority first, then in the order they were queued.
+pub fn get_next_encoding_jobs(limit: usize, connection: &mut SqliteConnection) -> anyhow::Result<Vec<UUID>>
+{
+   use crate::schema::encoding_jobs;
+
+   let file_ids = encoding_jobs::table
+      .select(encoding_jobs::file_id)
+      .order((encoding_jobs::priority.desc(), encoding_jobs::id))
+      .limit(limit as i64)
+      .load(connection)?;
+
+   Ok(file_ids)
+}
+
+pub fn count_encoding_jobs(connection: &mut SqliteConnection) -> anyhow::Result<i64>
+{
+   use crate::schema::encoding_jobs;
+
+   let count = encoding_jobs::table.count().get_result(connection)?;
+
+   Ok(count)
+}
+
+pub fn delete_encoding_jobs(file_ids: &[UUID], connection: &mut SqliteConnection) -> anyhow::Result<()>
+{
+   use crate::schema::encoding_jobs;
+
+   diesel::delete(encoding_jobs::table.filter(encoding_jobs::file_id.eq_any(file_ids)))
+      .execute(connection)?;
+
+   Ok(())
+}
+
+/// Empties the queue, returning the number of jobs which were removed.
+pub fn delete_all_encoding_jobs(connection: &mut SqliteConnection) -> anyhow::Result<usize>
+{
+   use crate::schema::encoding_jobs;
+
+   let deleted = diesel::delete(encoding_jobs::table).execute(connection)?;
+
+   Ok(deleted)
+}
+
+/// Removes the queued files which need no encoding with the given model:
+/// those which already have a feature vector for it, and those which failed to encode.
+pub fn delete_finished_encoding_jobs(model: &str, connection: &mut SqliteConnection) -> anyhow::Result<()>
+{
+   use crate::schema::encoding_jobs;
+   use crate::schema::failed_encodings;
+   use crate::schema::image_features;
+
+   let encoded = image_features::table
+      .select(image_features::file_id)
+      .filter(image_features::model_id.eq(model));
+   let failed = failed_encodings::table.select(failed_encodings::id);
+
+   diesel::delete(encoding_jobs::table
+         .filter(encoding_jobs::file_id.eq_any(encoded).or(encoding_jobs::file_id.eq_any(failed))))
+      .execute(connection)?;
+
+   Ok(())
+}
+
+/// Gets the stored text embedding of the normalized query, marking it as used.
+pub fn get_text_embedding(model: &str, normalized_query: &str, connection: &mut SqliteConnection) -> anyhow::Result<Option<Vec<u8>>>
+{
+   use crate::schema::text_embeddings;
+
+   let key = text_embeddings::table
+      .filter(text_embeddings::model_id.eq(model))
+      .filter(text_embeddings::query.eq(normalized_query));
+   let feature_vector = key
+      .select(text_embeddings::feature_vector)
+      .first(connection)
+      .optional()?;
+   if feature_vector.is_some() {
+      diesel::update(key)
+         .set(text_embeddings::used_at.eq(diesel::dsl::now))
+         .execute(connection)?;
+   }
+
+   Ok(feature_vector)
+}
+
+pub fn insert_text_embedding(model: &str, normalized_query: &str, feature_vector: &[u8], connection: &mut SqliteConnection) -> anyhow::Result<()>
+{
+   use crate::schema::text_embeddings;
+
+   diesel::replace_into(text_embeddings::table)
+      .values(&NewTextEmbedding { model_id: model, query: normalized_query, feature_vector })
+      .execute(connection)?;
+
+   Ok(())
+}
+
+/// Removes the least recently used text embeddings, keeping roughly `keep` of them.
+/// Embeddings used within the same second as the oldest kept embedding are kept too.
+pub fn delete_least_recently_used_text_embeddings(keep: usize, connection: &mut SqliteConnection) -> anyhow::Result<()>
+{
+   use crate::schema::text_embeddings;
+
+   let cutoff: Option<time::PrimitiveDateTime> = text_embeddings::table
+      .select(text_embeddings::used_at)
+      .order(text_embeddings::used_at.desc())
+      .offset(keep as i64)
+      .first(connection)
+      .optional()?;
+   if let Some(cutoff) = cutoff {
+      diesel::delete(text_embeddings::table.filter(text_embeddings::used_at.lt(cutoff)))
+         .execute(connection)?;
+   }
+
+   Ok(())
+}
+
 pub fn get_files_with_prefix(prefix: &[String], connection: &mut SqliteConnection) -> anyhow::Result<Vec<File>>
 {
    // Generate a raw SQL statement that gets the ID of all files with a path that starts with any of the given prefixes.
@@ -859,7 +1309,8 @@ mod tests
       let watched_dir_id: UUID = Uuid::new_v4().into();
       let watched_dir = NewWatchedDirectory {
          id: watched_dir_id,
-         filepath: "/path/to/watched/dir"
+         filepath: "/path/to/watched/dir",
+         indexed_extensions: None,
       };
       diesel::insert_into(watched_directories::table)
          .values(watched_dir)
@@ -885,7 +1336,8 @@ mod tests
       let watched_dir_id2: UUID = Uuid::new_v4().into();
       let watched_dir2 = NewWatchedDirectory {
          id: watched_dir_id2,
-         filepath: "/path/to/watched/dir2"
+         filepath: "/path/to/watched/dir2",
+         indexed_extensions: None,
       };
       diesel::insert_into(watched_directories::table)
          .values(watched_dir2)
@@ -925,4 +1377,257 @@ mod tests
       assert!(files.iter().any(|f| f.filepath == "/path/to/watched/dir2/file3.jpg"));
       assert!(files.iter().any(|f| f.filepath == "/path/to/watched/dir2/file4.jpg"));
    }
-}
\ No newline at end of file
+
+   #[test]
+   fn duplicate_groups_test()
+   {
+      let mut connection = setup().unwrap();
+
+      let new_files: Vec<NewFile> = (0..5).map(|i| NewFile {
+         id: Uuid::new_v4().into(),
+         filepath: format!("/path/to/file{}.jpg", i),
+         watched_directory_id: None
+      }).collect();
+      insert_files_rows(&new_files, &mut connection).unwrap();
+      let ids: Vec<UUID> = new_files.iter().map(|f| f.id).collect();
+
+      replace_duplicate_groups(&[vec![ids[0], ids[1], ids[2]], vec![ids[3], ids[4]]], &mut connection).unwrap();
+      let groups = get_duplicate_groups(&mut connection).unwrap();
+      assert_eq!(groups.len(), 2);
+      let mut sizes: Vec<usize> = groups.iter().map(|(_, members)| members.len()).collect();
+      sizes.sort();
+      assert_eq!(sizes, vec![2, 3]);
+      let first_group = groups.iter().find(|(_, members)| members.len() == 3).unwrap();
+      assert_eq!(first_group.1[0], (ids[0], PathBuf::from("/path/to/file0.jpg")));
+
+      // A group left with a single file is no longer a group of duplicates.
+      delete_duplicate_group_members(&[ids[4]], &mut connection).unwrap();
+      let groups = get_duplicate_groups(&mut connection).unwrap();
+      assert_eq!(groups.len(), 1);
+      assert_eq!(groups[0].1.len(), 3);
+
+      // Replacing removes the old groups.
+      replace_duplicate_groups(&[vec![ids[1], ids[3]]], &mut connection).unwrap();
+      let groups = get_duplicate_groups(&mut connection).unwrap();
+      assert_eq!(groups.len(), 1);
+      let members: Vec<UUID> = groups[0].1.iter().map(|(id, _)| *id).collect();
+      assert_eq!(members, vec![ids[1], ids[3]]);
+   }
+
+   #[test]
+   fn image_features_are_keyed_by_model_test()
+   {
+      let mut connection = setup().unwrap();
+
+      let new_files: Vec<NewFile> = (0..3).map(|i| NewFile {
+         id: Uuid::new_v4().into(),
+         filepath: format!("/path/to/file{}.jpg", i),
+         watched_directory_id: None
+      }).collect();
+      insert_files_rows(&new_files, &mut connection).unwrap();
+      let ids: Vec<UUID> = new_files.iter().map(|f| f.id).collect();
+
+      let vector = [0u8, 1, 2, 3];
+      let feature = |file_id: UUID, model_id| NewImageFeature { file_id, model_id, feature_vector: &vector, encoding: 0 };
+      insert_image_features(&[feature(ids[0], "model-a"), feature(ids[1], "model-a"), feature(ids[0], "model-b")], &mut connection).unwrap();
+
+      assert_eq!(get_all_image_feature_data("model-a", &mut connection).unwrap().len(), 2);
+      let model_b = get_all_image_feature_data("model-b", &mut connection).unwrap();
+      assert_eq!(model_b.len(), 1);
+      assert_eq!(model_b[0].file_id, ids[0]);
+      assert_eq!(model_b[0].model_id, "model-b");
+      assert_eq!(get_image_feature_data(&[ids[1]], "model-b", &mut connection).unwrap().len(), 0);
+
+      assert_eq!(get_files_without_image_features("model-a", &mut connection).unwrap(), vec![ids[2]]);
+      let mut missing_b = get_files_without_image_features("model-b", &mut connection).unwrap();
+      missing_b.sort_by_key(|id| id.to_string());
+      let mut expected = vec![ids[1], ids[2]];
+      expected.sort_by_key(|id| id.to_string());
+      assert_eq!(missing_b, expected);
+
+      // Deleting a file's encodings deletes them for every model.
+      delete_files_encodings(&[ids[0]], &mut connection).unwrap();
+      assert_eq!(get_all_image_feature_data("model-a", &mut connection).unwrap().len(), 1);
+      assert_eq!(get_all_image_feature_data("model-b", &mut connection).unwrap().len(), 0);
+   }
+
+   #[test]
+   fn region_features_test()
+   {
+      let mut connection = setup().unwrap();
+
+      let new_files: Vec<NewFile> = (0..2).map(|i| NewFile {
+         id: Uuid::new_v4().into(),
+         filepath: format!("/path/to/file{}.jpg", i),
+         watched_directory_id: None
+      }).collect();
+      insert_files_rows(&new_files, &mut connection).unwrap();
+      let ids: Vec<UUID> = new_files.iter().map(|f| f.id).collect();
+
+      let vector = [0u8, 1, 2, 3];
+      let region = |file_id: UUID, model_id, x| NewRegionFeature {
+         id: Uuid::new_v4().into(), file_id, model_id, x, y: 0, width: 100, height: 100, feature_vector: &vector, encoding: 0
+      };
+      insert_region_features(&[region(ids[0], "model-a", 0), region(ids[0], "model-a", 50), region(ids[1], "model-a", 0), region(ids[0], "model-b", 0)], &mut connection).unwrap();
+
+      assert_eq!(get_all_region_feature_data("model-a", &mut connection).unwrap().len(), 3);
+      let mut regions = get_region_feature_data(&[ids[0]], "model-a", &mut connection).unwrap();
+      regions.sort_by_key(|r| r.x);
+      assert_eq!(regions.iter().map(|r| (r.file_id, r.x, r.width)).collect::<Vec<_>>(), vec![(ids[0], 0, 100), (ids[0], 50, 100)]);
+
+      // Re-encoding a region only changes that region.
+      update_region_feature_vector(regions[0].id, &[4, 5], 1, &mut connection).unwrap();
+      let unconverted = get_region_feature_data_not_in_encoding(1, &mut connection).unwrap();
+      assert_eq!(unconverted.len(), 3);
+      assert!(unconverted.iter().all(|r| r.id != regions[0].id));
+      let converted = get_region_feature_data_not_in_encoding(0, &mut connection).unwrap();
+      assert_eq!(converted.iter().map(|r| (r.id, r.feature_vector.clone())).collect::<Vec<_>>(), vec![(regions[0].id, vec![4, 5])]);
+
+      // Deleting a file's encodings deletes its regions, for every model.
+      delete_files_encodings(&[ids[0]], &mut connection).unwrap();
+      assert_eq!(get_all_region_feature_data("model-a", &mut connection).unwrap().len(), 1);
+      assert_eq!(get_all_region_feature_data("model-b", &mut connection).unwrap().len(), 0);
+   }
+
+   #[test]
+   fn frame_features_test()
+   {
+      let mut connection = setup().unwrap();
+
+      let new_files: Vec<NewFile> = (0..2).map(|i| NewFile {
+         id: Uuid::new_v4().into(),
+         filepath: format!("/path/to/file{}.gif", i),
+         watched_directory_id: None
+      }).collect();
+      insert_files_rows(&new_files, &mut connection).unwrap();
+      let ids: Vec<UUID> = new_files.iter().map(|f| f.id).collect();
+
+      let vector = [0u8, 1, 2, 3];
+      let frame = |file_id: UUID, model_id, frame_index| NewFrameFeature {
+         id: Uuid::new_v4().into(), file_id, model_id, frame_index, timestamp_ms: frame_index as i64 * 100, feature_vector: &vector, encoding: 0
+      };
+      insert_frame_features(&[frame(ids[0], "model-a", 3), frame(ids[0], "model-a", 6), frame(ids[1], "model-a", 3), frame(ids[0], "model-b", 3)], &mut connection).unwrap();
+
+      assert_eq!(get_all_frame_feature_data("model-a", &mut connection).unwrap().len(), 3);
+      let mut frames = get_frame_feature_data(&[ids[0]], "model-a", &mut connection).unwrap();
+      frames.sort_by_key(|f| f.frame_index);
+      assert_eq!(frames.iter().map(|f| (f.file_id, f.frame_index, f.timestamp_ms)).collect::<Vec<_>>(), vec![(ids[0], 3, 300), (ids[0], 6, 600)]);
+
+      // Re-encoding a frame only changes that frame.
+      update_frame_feature_vector(frames[0].id, &[4, 5], 1, &mut connection).unwrap();
+      let unconverted = get_frame_feature_data_not_in_encoding(1, &mut connection).unwrap();
+      assert_eq!(unconverted.len(), 3);
+      assert!(unconverted.iter().all(|f| f.id != frames[0].id));
+      let converted = get_frame_feature_data_not_in_encoding(0, &mut connection).unwrap();
+      assert_eq!(converted.iter().map(|f| (f.id, f.feature_vector.clone())).collect::<Vec<_>>(), vec![(frames[0].id, vec![4, 5])]);
+
+      // Deleting a file's encodings deletes its frames, for every model.
+      delete_files_encodings(&[ids[0]], &mut connection).unwrap();
+      assert_eq!(get_all_frame_feature_data("model-a", &mut connection).unwrap().len(), 1);
+      assert_eq!(get_all_frame_feature_data("model-b", &mut connection).unwrap().len(), 0);
+   }
+
+   #[test]
+   fn failed_encodings_test()
+   {
+      let mut connection = setup().unwrap();
+
+      let new_files: Vec<NewFile> = (0..3).map(|i| NewFile {
+         id: Uuid::new_v4().into(),
+         filepath: format!("/path/to/file{}.jpg", i),
+         watched_directory_id: None
+      }).collect();
+      insert_files_rows(&new_files, &mut connection).unwrap();
+      let ids: Vec<UUID> = new_files.iter().map(|f| f.id).collect();
+
+      let failure = |id: UUID, error: &str| NewFailedEncoding { id, error: error.to_string(), failed_at: None, kind: 2 };
+      insert_failed_encodings(&[failure(ids[0], "first"), failure(ids[1], "second")], &mut connection).unwrap();
+      assert!(is_failed_encoding(ids[0], &mut connection).unwrap());
+      assert!(!is_failed_encoding(ids[2], &mut connection).unwrap());
+
+      // Failing again replaces the error.
+      insert_failed_encodings(&[failure(ids[0], "again")], &mut connection).unwrap();
+      let mut failed = get_failed_encodings(&mut connection).unwrap();
+      failed.sort_by(|a, b| a.1.cmp(&b.1));
+      assert_eq!(failed.len(), 2);
+      assert_eq!((failed[0].0, failed[0].1.as_str(), failed[0].2.as_str()), (ids[0], "/path/to/file0.jpg", "again"));
+      assert_eq!(failed[1].2, "second");
+      assert_eq!(failed[1].4, 2);
+
+      delete_failed_encodings(&[ids[0]], &mut connection).unwrap();
+      assert_eq!(get_failed_encoding_ids(&mut connection).unwrap(), vec![ids[1]]);
+      // Files which failed are not considered to be waiting for encoding.
+      assert_eq!(get_files_without_image_features("model", &mut connection).unwrap().len(), 2);
+   }
+
+   #[test]
+   fn encoding_jobs_test()
+   {
+      let mut connection = setup().unwrap();
+
+      let new_files: Vec<NewFile> = (0..4).map(|i| NewFile {
+         id: Uuid::new_v4().into(),
+         filepath: format!("/path/to/file{}.jpg", i),
+         watched_directory_id: None
+      }).collect();
+      insert_files_rows(&new_files, &mut connection).unwrap();
+      let ids: Vec<UUID> = new_files.iter().map(|f| f.id).collect();
+
+      enqueue_encoding_jobs(&ids[0..3], 0, &mut connection).unwrap();
+      // Queuing again raises the priority, but never lowers it or adds a second job.
+      enqueue_encoding_jobs(&[ids[2], ids[3]], 1, &mut connection).unwrap();
+      enqueue_encoding_jobs(&[ids[3]], 0, &mut connection).unwrap();
+      assert_eq!(count_encoding_jobs(&mut connection).unwrap(), 4);
+      assert_eq!(get_next_encoding_jobs(10, &mut connection).unwrap(), vec![ids[2], ids[3], ids[0], ids[1]]);
+      assert_eq!(get_next_encoding_jobs(1, &mut connection).unwrap(), vec![ids[2]]);
+
+      // Jobs for files which were encoded or which failed are finished.
+      let feature = NewImageFeature { file_id: ids[2], model_id: "model", feature_vector: &[0, 0, 0, 0], encoding: 0 };
+      insert_image_features(&[feature], &mut connection).unwrap();
+      insert_failed_encodings(&[NewFailedEncoding { id: ids[0], error: "error".to_string(), failed_at: None, kind: 6 }], &mut connection).unwrap();
+      delete_finished_encoding_jobs("model", &mut connection).unwrap();
+      assert_eq!(get_next_encoding_jobs(10, &mut connection).unwrap(), vec![ids[3], ids[1]]);
+
+      delete_encoding_jobs(&[ids[3]], &mut connection).unwrap();
+      assert_eq!(get_next_encoding_jobs(10, &mut connection).unwrap(), vec![ids[1]]);
+      assert_eq!(delete_all_encoding_jobs(&mut connection).unwrap(), 1);
+      assert_eq!(count_encoding_jobs(&mut connection).unwrap(), 0);
+   }
+
+   #[test]
+   fn text_embeddings_test()
+   {
+      use crate::schema::text_embeddings;
+
+      let mut connection = setup().unwrap();
+
+      assert_eq!(get_text_embedding("model", "a duck", &mut connection).unwrap(), None);
+      insert_text_embedding("model", "a duck", &[1, 2], &mut connection).unwrap();
+      insert_text_embedding("other-model", "a duck", &[3, 4], &mut connection).unwrap();
+      assert_eq!(get_text_embedding("model", "a duck", &mut connection).unwrap(), Some(vec![1, 2]));
+      assert_eq!(get_text_embedding("other-model", "a duck", &mut connection).unwrap(), Some(vec![3, 4]));
+
+      // Age the first embedding, so that it is the least recently used.
+      let old = time::PrimitiveDateTime::new(time::Date::from_calendar_date(2024, time::Month::January, 1).unwrap(), time::Time::MIDNIGHT);
+      diesel::update(text_embeddings::table.filter(text_embeddings::model_id.eq("model")))
+         .set(text_embeddings::used_at.eq(old))
+         .execute(&mut connection).unwrap();
+      delete_least_recently_used_text_embeddings(1, &mut connection).unwrap();
+      assert_eq!(get_text_embedding("model", "a duck", &mut connection).unwrap(), None);
+      assert_eq!(get_text_embedding("other-model", "a duck", &mut connection).unwrap(), Some(vec![3, 4]));
+   }
+   #[test]
+   fn watched_directory_extensions_test()
+   {
+      let mut connection = setup().unwrap();
+
+      let extensions = vec!["jpg".to_string(), "png".to_string()];
+      let with_extensions = insert_watched_directory("/refs/photos", Some(&extensions), &mut connection).unwrap();
+      let without_extensions = insert_watched_directory("/refs/mixed", None, &mut connection).unwrap();
+
+      let watched_directories = get_watched_directories(&mut connection).unwrap();
+      let indexed_extensions = |id: UUID| watched_directories.iter().find(|dir| dir.id == id).unwrap().indexed_extensions.clone();
+      assert_eq!(indexed_extensions(with_extensions), Some(r#"["jpg","png"]"#.to_string()));
+      assert_eq!(indexed_extensions(without_extensions), None);
+   }
+}
@@ -0,0 +1,205 @@
+/// Region (tile) embeddings: feature vectors of overlapping square crops of an image, in addition to the whole image.
+///
+/// CLIP encodes a center crop of each image at a low resolution, so details of large images (a character sheet,
+/// a collage) and the ends of wide or tall images (panoramas, comic strips) barely register in the image's feature vector.
+/// When enabled, the encoding pipeline also encodes a grid of overlapping tiles of each image, stored in the region_features
+/// table with their bounding boxes. Tiles are added to the search index under their own IDs; a search matching a tile
+/// returns its file, with the region which matched so the frontend can zoom to it.
+
+use image::{DynamicImage, GenericImageView};
+use serde::{Deserialize, Serialize};
+
+use crate::uuid::UUID;
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct RegionSettings
+{
+    /// Whether tiles are encoded. Each tile is encoded like an image, so this multiplies the encoding time.
+    /// Files which were encoded while this was disabled are not encoded again.
+    pub enabled: bool,
+    /// The number of tile sizes. The first level's tiles are as large as the image's shortest side, which covers the ends
+    /// of wide or tall images; each further level halves the tile size, covering details of large images.
+    pub levels: u32,
+    /// The fraction of a tile which overlaps its neighbors, so that objects on a tile boundary are whole in some tile.
+    pub overlap: f32,
+    /// Tiles smaller than this many pixels are not encoded, since upscaling them adds no detail.
+    pub min_tile_size: u32,
+    /// The most tiles encoded per image. A level is skipped (with the levels after it) if it would exceed this.
+    pub max_regions: usize,
+}
+
+impl Default for RegionSettings
+{
+    fn default() -> Self
+    {
+        RegionSettings { enabled: false, levels: 2, overlap: 0.5, min_tile_size: 256, max_regions: 16 }
+    }
+}
+
+/// A rectangle within an image, in pixels of the image.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Region
+{
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// The file of a region feature vector in the search index, and where the region is within the file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileRegion
+{
+    pub file_id: UUID,
+    pub region: Region,
+}
+
+/// The tiles of a width x height image, smallest level last.
+/// The center crop of the whole image is skipped, since it is what the image's own feature vector encodes.
+pub fn tile_regions(width: u32, height: u32, settings: &RegionSettings) -> Vec<Region>
+{
+    let shortest = width.min(height);
+    let center = Region { x: (width - shortest) / 2, y: (height - shortest) / 2, width: shortest, height: shortest };
+    let overlap = settings.overlap.clamp(0.0, 0.9);
+
+    let mut regions = Vec::new();
+    for level in 0..settings.levels
+    {
+        let side = shortest.checked_shr(level).unwrap_or(0);
+        if side == 0 || side < settings.min_tile_size {
+            break;
+        }
+        let stride = ((side as f32 * (1.0 - overlap)).round() as u32).max(1);
+        let level_regions: Vec<Region> = tile_offsets(height, side, stride).into_iter()
+            .flat_map(|y| tile_offsets(width, side, stride).into_iter().map(move |x| Region { x, y, width: side, height: side }))
+            .filter(|region| *region != center)
+            .collect();
+        if regions.len() + level_regions.len() > settings.max_regions {
+            break;
+        }
+        regions.extend(level_regions);
+    }
+    regions
+}
+
+/// The offsets of tiles of size `side` along a side of the given length, `stride` apart.
+/// The last tile is aligned with the end, so that the whole length is covered.
+fn tile_offsets(length: u32, side: u32, stride: u32) -> Vec<u32>
+{
+    let last = length - side;
+    let mut offsets: Vec<u32> = (0..=last).step_by(stride as usize).collect();
+    if offsets.last() != Some(&last) {
+        offsets.push(last);
+    }
+    offsets
+}
+
+/// Crops the regions, in pixels of the image at its full resolution of `full_size`, out of the image.
+/// The image may have been decoded at a reduced resolution (see image_loading::load_image_reduced()),
+/// in which case the regions are scaled down to it.
+pub fn crop_regions(image: &DynamicImage, full_size: (u32, u32), regions: &[Region]) -> Vec<DynamicImage>
+{
+    regions.iter()
+        .map(|region| region.scaled(full_size, image.dimensions()))
+        .map(|region| image.crop_imm(region.x, region.y, region.width, region.height))
+        .collect()
+}
+
+impl Region
+{
+    /// The region of an image of size `from`, scaled to the same region of the image resized to `to`.
+    /// Its edges are rounded outwards, so that a region covering the whole image still does.
+    fn scaled(self, from: (u32, u32), to: (u32, u32)) -> Region
+    {
+        if from == to {
+            return self;
+        }
+        let scale = |value: u32, from: u32, to: u32, round_up: bool| {
+            let scaled = value as u64 * to as u64;
+            let scaled = if round_up { scaled.div_ceil(from as u64) } else { scaled / from as u64 };
+            (scaled as u32).min(to)
+        };
+        let x = scale(self.x, from.0, to.0, false);
+        let y = scale(self.y, from.1, to.1, false);
+        let right = scale(self.x + self.width, from.0, to.0, true);
+        let bottom = scale(self.y + self.height, from.1, to.1, true);
+        Region { x, y, width: right - x, height: bottom - y }
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn settings(levels: u32) -> RegionSettings
+    {
+        RegionSettings { enabled: true, levels, overlap: 0.5, min_tile_size: 100, max_regions: 100 }
+    }
+
+    fn contains(outer: &Region, x: u32, y: u32) -> bool
+    {
+        x >= outer.x && x < outer.x + outer.width && y >= outer.y && y < outer.y + outer.height
+    }
+
+    #[test]
+    fn wide_images_are_tiled_end_to_end()
+    {
+        let regions = tile_regions(1000, 400, &settings(1));
+        // Tiles of 400 at a stride of 200; the center crop (at 300) is not among them.
+        let offsets: Vec<u32> = regions.iter().map(|r| r.x).collect();
+        assert_eq!(offsets, vec![0, 200, 400, 600]);
+        assert!(regions.iter().all(|r| r.y == 0 && r.width == 400 && r.height == 400));
+        // Both ends are covered.
+        assert!(regions.iter().any(|r| contains(r, 0, 0)));
+        assert!(regions.iter().any(|r| contains(r, 999, 399)));
+    }
+
+    #[test]
+    fn center_crop_is_not_a_tile()
+    {
+        // A square image's only first level tile is the whole image.
+        assert!(tile_regions(400, 400, &settings(1)).is_empty());
+
+        let regions = tile_regions(400, 400, &settings(2));
+        assert_eq!(regions.len(), 9);
+        assert!(regions.iter().all(|r| r.width == 200 && r.height == 200));
+        for (x, y) in [(0, 0), (399, 0), (0, 399), (399, 399), (200, 200)]
+        {
+            assert!(regions.iter().any(|r| contains(r, x, y)), "({}, {}) is not covered", x, y);
+        }
+    }
+
+    #[test]
+    fn regions_are_cropped_from_reduced_images()
+    {
+        // A 1000x400 image decoded at a quarter of its resolution, whose left half is black and right half white.
+        let reduced = DynamicImage::ImageLuma8(image::GrayImage::from_fn(250, 100, |x, _| image::Luma([if x < 125 { 0 } else { 255 }])));
+        let regions = [Region { x: 0, y: 0, width: 400, height: 400 }, Region { x: 600, y: 0, width: 400, height: 400 }];
+        let crops = crop_regions(&reduced, (1000, 400), &regions);
+        assert_eq!(crops.iter().map(|crop| crop.dimensions()).collect::<Vec<_>>(), vec![(100, 100), (100, 100)]);
+        assert_eq!(crops[0].to_luma8().get_pixel(99, 50).0, [0]);
+        assert_eq!(crops[1].to_luma8().get_pixel(0, 50).0, [255]);
+
+        // Edges which don't fall on a pixel of the reduced image are rounded outwards.
+        let odd = Region { x: 3, y: 5, width: 10, height: 10 }.scaled((1000, 400), (250, 100));
+        assert_eq!(odd, Region { x: 0, y: 1, width: 4, height: 3 });
+    }
+
+    #[test]
+    fn small_tiles_and_excess_levels_are_skipped()
+    {
+        // Half of 150 is below the minimum tile size.
+        let regions = tile_regions(300, 150, &settings(3));
+        assert!(regions.iter().all(|r| r.width == 150));
+
+        // The second level (21 tiles) would exceed the limit, so only the first level is kept.
+        let limited = RegionSettings { max_regions: 8, ..settings(2) };
+        let regions = tile_regions(800, 400, &limited);
+        assert!(!regions.is_empty());
+        assert!(regions.iter().all(|r| r.width == 400));
+
+        assert!(tile_regions(50, 50, &settings(2)).is_empty());
+    }
+}
@@ -1,10 +1,26 @@
 // @generated automatically by Diesel CLI.
 
+diesel::table! {
+    duplicate_groups (file_id) {
+        file_id -> Text,
+        group_id -> Text,
+    }
+}
+
+diesel::table! {
+    encoding_jobs (id) {
+        id -> Integer,
+        file_id -> Text,
+        priority -> Integer,
+    }
+}
+
 diesel::table! {
     failed_encodings (id) {
         id -> Text,
         error -> Text,
         failed_at -> Timestamp,
+        kind -> Integer,
     }
 }
 
@@ -24,9 +40,37 @@ diesel::table! {
 }
 
 diesel::table! {
-    image_features_vit_l_14_336_px (id) {
+    frame_features (id) {
         id -> Text,
+        file_id -> Text,
+        model_id -> Text,
+        frame_index -> Integer,
+        timestamp_ms -> BigInt,
         feature_vector -> Binary,
+        encoding -> Integer,
+    }
+}
+
+diesel::table! {
+    image_features (file_id, model_id) {
+        file_id -> Text,
+        model_id -> Text,
+        feature_vector -> Binary,
+        encoding -> Integer,
+    }
+}
+
+diesel::table! {
+    region_features (id) {
+        id -> Text,
+        file_id -> Text,
+        model_id -> Text,
+        x -> Integer,
+        y -> Integer,
+        width -> Integer,
+        height -> Integer,
+        feature_vector -> Binary,
+        encoding -> Integer,
     }
 }
 
@@ -50,6 +94,15 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    text_embeddings (model_id, query) {
+        model_id -> Text,
+        query -> Text,
+        feature_vector -> Binary,
+        used_at -> Timestamp,
+    }
+}
+
 diesel::table! {
     thumbnails (id) {
         id -> Text,
@@ -62,23 +115,33 @@ diesel::table! {
     watched_directories (id) {
         id -> Text,
         filepath -> Text,
+        indexed_extensions -> Nullable<Text>,
     }
 }
 
+diesel::joinable!(duplicate_groups -> files (file_id));
+diesel::joinable!(encoding_jobs -> files (file_id));
 diesel::joinable!(failed_encodings -> files (id));
 diesel::joinable!(file_tags -> files (file_id));
 diesel::joinable!(file_tags -> tags (tag_id));
 diesel::joinable!(files -> watched_directories (watched_directory_id));
-diesel::joinable!(image_features_vit_l_14_336_px -> files (id));
+diesel::joinable!(frame_features -> files (file_id));
+diesel::joinable!(image_features -> files (file_id));
+diesel::joinable!(region_features -> files (file_id));
 diesel::joinable!(thumbnails -> files (file_id));
 
 diesel::allow_tables_to_appear_in_same_query!(
+    duplicate_groups,
+    encoding_jobs,
     failed_encodings,
     file_tags,
     files,
-    image_features_vit_l_14_336_px,
+    frame_features,
+    image_features,
+    region_features,
     tag_edges,
     tags,
+    text_embeddings,
     thumbnails,
     watched_directories,
 );
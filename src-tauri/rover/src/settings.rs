@@ -0,0 +1,177 @@
+/// User settings, stored as JSON in settings.json within the app config directory.
+/// The file is created with the default settings if it does not exist, so that users
+/// (and our workstation/CI setups) have something to edit.
+/// Missing fields take their default values, so older settings files keep working as fields are added.
+
+use std::fs;
+use std::path::PathBuf;
+
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use crate::animation::AnimationSettings;
+use crate::ann::IndexBackend;
+use crate::embedding;
+use crate::file_filter;
+use crate::encoding_pipeline::{DecodeSettings, PipelineSettings};
+use crate::image_loading::TransparencySettings;
+use crate::onnx::OnnxSettings;
+use crate::quantization::VectorEncoding;
+use crate::regions::RegionSettings;
+use crate::text_embedding_cache::TextEmbeddingCacheSettings;
+use crate::video::VideoSettings;
+
+const SETTINGS_FILENAME: &str = "settings.json";
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct Settings
+{
+    /// The format of stored feature vectors and of the in-memory search index.
+    /// Changing this converts the stored feature vectors the next time the app starts.
+    pub vector_encoding: VectorEncoding,
+    /// The implementation of the search index; approximate (HNSW) or exact (flat).
+    pub index_backend: IndexBackend,
+    /// How the ONNX runtime executes the models: execution providers, threads and graph optimization.
+    pub onnx: OnnxSettings,
+    /// A directory containing the model files, searched before the bundled models. See model_files.rs.
+    pub model_directory: Option<PathBuf>,
+    /// The ID of the embedding model used for encoding and search. See embedding.rs.
+    pub active_model: String,
+    /// Batch size, queue depth and decoding threads of the encoding pipeline. See encoding_pipeline.rs.
+    pub encoding_pipeline: PipelineSettings,
+    /// The size of the cache of search query embeddings, and whether it is kept between sessions. See text_embedding_cache.rs.
+    pub text_embedding_cache: TextEmbeddingCacheSettings,
+    /// Whether (and how) tiles of each image are encoded in addition to the whole image. See regions.rs.
+    pub region_embeddings: RegionSettings,
+    /// The background transparent images are composited onto, for encoding and thumbnails. See image_loading.rs.
+    pub transparency: TransparencySettings,
+    /// Whether frames of animated GIFs and WebPs are encoded, and how many frames animated thumbnails have. See animation.rs.
+    pub animation: AnimationSettings,
+    /// Whether videos are encoded, where ffmpeg is, and how keyframes are selected. See video.rs.
+    pub video: VideoSettings,
+    /// The extensions of files indexed in watched directories which weren't added with their own. See file_filter.rs.
+    pub indexed_extensions: Vec<String>,
+}
+
+impl Default for Settings
+{
+    fn default() -> Self
+    {
+        Settings {
+            vector_encoding: VectorEncoding::default(),
+            index_backend: IndexBackend::default(),
+            onnx: OnnxSettings::default(),
+            model_directory: None,
+            active_model: embedding::DEFAULT_MODEL_ID.to_string(),
+            encoding_pipeline: PipelineSettings::default(),
+            text_embedding_cache: TextEmbeddingCacheSettings::default(),
+            region_embeddings: RegionSettings::default(),
+            transparency: TransparencySettings::default(),
+            animation: AnimationSettings::default(),
+            video: VideoSettings::default(),
+            indexed_extensions: file_filter::default_extensions(VideoSettings::default().enabled),
+        }
+    }
+}
+
+impl Settings
+{
+    /// Loads the settings from the app config directory, writing the defaults if no settings file exists yet.
+    pub fn load(app_handle: &tauri::AppHandle) -> anyhow::Result<Settings>
+    {
+        let path = get_settings_path(app_handle)?;
+        if !path.exists() {
+            info!("No settings file found; writing defaults to {:?}", path);
+            let settings = Settings::default();
+            settings.save(app_handle)?;
+            return Ok(settings);
+        }
+
+        let contents = fs::read_to_string(&path)?;
+        let settings = Settings::parse(&contents)
+            .map_err(|e| anyhow::anyhow!("Error parsing settings file {:?}: {}", path, e))?;
+        Ok(settings)
+    }
+
+    /// Reads settings from JSON. Unless the settings list their own indexed extensions, the defaults include video
+    /// extensions if videos are enabled.
+    fn parse(contents: &str) -> serde_json::Result<Settings>
+    {
+        let value: serde_json::Value = serde_json::from_str(contents)?;
+        let has_indexed_extensions = value.get("indexed_extensions").is_some();
+        let mut settings: Settings = serde_json::from_value(value)?;
+        if !has_indexed_extensions {
+            settings.indexed_extensions = file_filter::default_extensions(settings.video.enabled);
+        }
+        Ok(settings)
+    }
+
+    /// The settings which decide what the encoding pipeline decodes from each file.
+    pub fn decode_settings(&self) -> DecodeSettings
+    {
+        DecodeSettings {
+            regions: self.region_embeddings.clone(),
+            animation: self.animation.clone(),
+            video: self.video.clone(),
+            transparency: self.transparency.clone(),
+        }
+    }
+
+    pub fn save(&self, app_handle: &tauri::AppHandle) -> anyhow::Result<()>
+    {
+        let path = get_settings_path(app_handle)?;
+        fs::write(&path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+}
+
+/// Gets the path to the settings file, ensuring that its parent directory exists.
+fn get_settings_path(app_handle: &tauri::AppHandle) -> anyhow::Result<PathBuf>
+{
+    let dir = app_handle.path_resolver().app_config_dir().ok_or(anyhow::anyhow!("Error getting app config path"))?;
+    fs::create_dir_all(&dir)?;
+    Ok(dir.join(SETTINGS_FILENAME))
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn missing_fields_use_defaults()
+    {
+        let settings: Settings = serde_json::from_str("{}").unwrap();
+        assert_eq!(settings, Settings::default());
+    }
+
+    #[test]
+    fn video_extensions_are_indexed_by_default_with_videos_enabled()
+    {
+        assert!(!Settings::default().indexed_extensions.contains(&"mp4".to_string()));
+        let settings = Settings::parse(r#"{ "video": { "enabled": true } }"#).unwrap();
+        assert!(settings.indexed_extensions.contains(&"mp4".to_string()));
+        let settings = Settings::parse(r#"{ "video": { "enabled": true }, "indexed_extensions": ["png"] }"#).unwrap();
+        assert_eq!(settings.indexed_extensions, vec!["png".to_string()]);
+        assert_eq!(Settings::parse("{}").unwrap(), Settings::default());
+    }
+
+    #[test]
+    fn vector_encoding_serialization()
+    {
+        let settings: Settings = serde_json::from_str(r#"{ "vector_encoding": "int8" }"#).unwrap();
+        assert_eq!(settings.vector_encoding, VectorEncoding::Int8);
+        let serialized = serde_json::to_string(&settings).unwrap();
+        let deserialized: Settings = serde_json::from_str(&serialized).unwrap();
+        assert_eq!(settings, deserialized);
+    }
+
+    #[test]
+    fn index_backend_serialization()
+    {
+        let settings: Settings = serde_json::from_str(r#"{ "index_backend": "flat" }"#).unwrap();
+        assert_eq!(settings.index_backend, IndexBackend::Flat);
+        assert_eq!(settings.vector_encoding, VectorEncoding::default());
+    }
+}
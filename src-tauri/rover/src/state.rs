@@ -1,24 +1,288 @@
-use std::sync::Mutex;
+use std::collections::{HashMap, HashSet};
+use std::sync::{mpsc, Mutex};
 
 use diesel::{r2d2::{ConnectionManager, Pool, PooledConnection}, SqliteConnection};
-use instant_clip_tokenizer;
+use log::info;
 use notify_debouncer_full::{notify::RecommendedWatcher, Debouncer, FileIdMap};
+use rustc_hash::FxHashSet;
 
-use crate::{ann::HnswSearch, clip::Clip};
+use crate::{animation::FileFrame, ann::{self, HnswElement, IndexBackend, VectorIndex}, embedding::{ImageEncoder, ModelInfo, TextEncoder}, encoding_pipeline::EncodingStats, error::Error, flat_index::FlatIndex, quantization::VectorEncoding, regions::FileRegion, settings::Settings, text_embedding_cache::{TextEmbeddingCache, TextEmbeddingCacheSettings}, uuid::UUID};
 
 pub struct InnerSearchState<'a>
 {
-    pub hnsw: HnswSearch<'a>,
+    pub index: Box<dyn VectorIndex + 'a>,
+    /// While the index is built in the background on startup, searches fall back to an exact search
+    /// over the feature vectors loaded so far. None once the built index has been swapped in.
+    /// See ann::spawn_index_build().
+    pub fallback: Option<FlatIndex>,
+    /// The IDs inserted into or removed from the fallback since the build started. The build's index holds the feature
+    /// vectors it loaded on startup, so those of these IDs must be replaced or removed when it is swapped in.
+    touched_during_build: HashSet<UUID>,
+    /// The ID of the embedding model whose feature vectors are in the index.
+    pub model_id: String,
+    /// The implementation of the index.
+    pub backend: IndexBackend,
+    /// The format in which feature vectors are stored, and held by an HNSW index.
+    pub encoding: VectorEncoding,
+    /// The file and bounding box of each region (tile) feature vector in the index, by the region's ID. See regions.rs.
+    pub regions: HashMap<UUID, FileRegion>,
+    /// The file and frame of each animation frame feature vector in the index, by the frame's ID. See animation.rs.
+    /// Every ID in the index which is neither a region nor a frame is a file ID.
+    pub frames: HashMap<UUID, FileFrame>,
+}
+
+impl<'a> InnerSearchState<'a>
+{
+    /// An empty search state which is waiting on the startup index build.
+    pub fn new_building(model_id: &str, backend: IndexBackend, encoding: VectorEncoding) -> Self
+    {
+        InnerSearchState {
+            index: ann::new_index(backend, encoding),
+            fallback: Some(FlatIndex::new()),
+            touched_during_build: HashSet::new(),
+            model_id: model_id.to_string(),
+            backend,
+            encoding,
+            regions: HashMap::new(),
+            frames: HashMap::new(),
+        }
+    }
+
+    /// Adds the elements to the index, or to the fallback if the index is still being built.
+    pub fn insert(&mut self, data: Vec<HnswElement>)
+    {
+        match &mut self.fallback
+        {
+            Some(fallback) => {
+                self.touched_during_build.extend(data.iter().map(|element| element.id));
+                fallback.insert(data);
+            },
+            None => self.index.insert(data),
+        }
+    }
+
+    /// Adds the feature vectors loaded by the index build to the fallback, keeping any which were
+    /// inserted or removed since they were loaded.
+    pub fn insert_loaded(&mut self, data: Vec<HnswElement>)
+    {
+        if let Some(fallback) = &mut self.fallback
+        {
+            fallback.insert(data.into_iter().filter(|element| !self.touched_during_build.contains(&element.id)).collect());
+        }
+    }
+
+    /// Replaces the fallback with the built index, which holds the feature vectors in loaded_ids.
+    /// Those inserted, replaced or removed during the build are updated in the index first.
+    pub fn finish_build(&mut self, mut index: Box<dyn VectorIndex + 'a>, loaded_ids: &FxHashSet<UUID>)
+    {
+        let Some(fallback) = self.fallback.take() else {
+            return;
+        };
+        let touched_during_build = std::mem::take(&mut self.touched_during_build);
+
+        // Anything loaded but no longer in the fallback was removed during the build.
+        let removed_during_build: Vec<UUID> = loaded_ids.iter().filter(|id| !fallback.contains(id)).copied().collect();
+        if !removed_during_build.is_empty() {
+            info!("Removing {} feature vectors removed during the index build", removed_during_build.len());
+        }
+        index.remove(&removed_during_build);
+
+        // Anything not loaded, or loaded and then re-encoded, is inserted; inserting an ID replaces its old feature vector.
+        let inserted_during_build: Vec<HnswElement> = fallback.into_elements()
+            .into_iter()
+            .filter(|e| !loaded_ids.contains(&e.id) || touched_during_build.contains(&e.id))
+            .collect();
+        if !inserted_during_build.is_empty() {
+            info!("Adding {} feature vectors inserted during the index build", inserted_during_build.len());
+        }
+        index.insert(inserted_during_build);
+        self.index = index;
+    }
+
+    /// Adds the feature vectors of image regions, keyed by the regions' IDs.
+    pub fn insert_regions(&mut self, data: Vec<(HnswElement, FileRegion)>)
+    {
+        let elements = data.into_iter().map(|(element, file_region)| {
+            self.regions.insert(element.id, file_region);
+            element
+        }).collect();
+        self.insert(elements);
+    }
+
+    /// Adds the feature vectors of animation frames, keyed by the frames' IDs.
+    pub fn insert_frames(&mut self, data: Vec<(HnswElement, FileFrame)>)
+    {
+        let elements = data.into_iter().map(|(element, file_frame)| {
+            self.frames.insert(element.id, file_frame);
+            element
+        }).collect();
+        self.insert(elements);
+    }
+
+    /// Removes the elements from the index (and from the fallback, if the index is still being built).
+    /// The regions and frames of any files among them are removed too.
+    pub fn remove(&mut self, ids: &[UUID])
+    {
+        let mut ids = ids.to_vec();
+        if !self.regions.is_empty() || !self.frames.is_empty()
+        {
+            let files: HashSet<UUID> = ids.iter().copied().collect();
+            let region_ids: Vec<UUID> = self.regions.iter()
+                .filter(|(_, file_region)| files.contains(&file_region.file_id))
+                .map(|(id, _)| *id)
+                .collect();
+            for id in &region_ids {
+                self.regions.remove(id);
+            }
+            let frame_ids: Vec<UUID> = self.frames.iter()
+                .filter(|(_, file_frame)| files.contains(&file_frame.file_id))
+                .map(|(id, _)| *id)
+                .collect();
+            for id in &frame_ids {
+                self.frames.remove(id);
+            }
+            ids.extend(region_ids);
+            ids.extend(frame_ids);
+        }
+
+        match &mut self.fallback
+        {
+            Some(fallback) => {
+                self.touched_during_build.extend(ids.iter().copied());
+                fallback.remove(&ids);
+            },
+            None => self.index.remove(&ids),
+        }
+    }
+
+    /// The file and bounding box of the element, if it is a region rather than a whole file.
+    pub fn file_region(&self, id: &UUID) -> Option<&FileRegion>
+    {
+        self.regions.get(id)
+    }
+
+    /// The frame of the element, if it is a frame of an animation rather than a whole file.
+    pub fn file_frame(&self, id: &UUID) -> Option<&FileFrame>
+    {
+        self.frames.get(id)
+    }
+
+    /// The ID of the file of the element, which is the element's own ID unless it is a region or a frame.
+    pub fn file_id(&self, id: &UUID) -> UUID
+    {
+        match (self.file_region(id), self.file_frame(id))
+        {
+            (Some(file_region), _) => file_region.file_id,
+            (None, Some(file_frame)) => file_frame.file_id,
+            (None, None) => *id,
+        }
+    }
+
+    /// Whether the index holds the feature vectors of regions or frames, in addition to those of whole files.
+    pub fn has_file_parts(&self) -> bool
+    {
+        !self.regions.is_empty() || !self.frames.is_empty()
+    }
+
+    /// See VectorIndex::search_filtered(). Searches are exact while the index is still being built.
+    /// The filter is given file IDs; a region or frame is kept if its file is.
+    pub fn search_filtered(
+        &self,
+        query: &[f32],
+        knbn: usize,
+        ef_arg: usize,
+        distance_threshold: f32,
+        filter: &dyn Fn(&UUID) -> bool) -> Vec<(UUID, f32)>
+    {
+        self.search_elements_filtered(query, knbn, ef_arg, distance_threshold, &|id| filter(&self.file_id(id)))
+    }
+
+    /// Like search_filtered(), but the filter is given the IDs of the elements themselves, so that e.g. only the feature
+    /// vectors of whole files can be searched, without their regions and frames.
+    pub fn search_elements_filtered(
+        &self,
+        query: &[f32],
+        knbn: usize,
+        ef_arg: usize,
+        distance_threshold: f32,
+        filter: &dyn Fn(&UUID) -> bool) -> Vec<(UUID, f32)>
+    {
+        match &self.fallback
+        {
+            Some(fallback) => fallback.search_filtered(query, knbn, ef_arg, distance_threshold, filter),
+            None => self.index.search_filtered(query, knbn, ef_arg, distance_threshold, filter),
+        }
+    }
+
+    /// See VectorIndex::search(). Searches are exact while the index is still being built.
+    pub fn search(&self, query: &[f32], knbn: usize, ef_arg: usize, distance_threshold: f32) -> Vec<(UUID, f32)>
+    {
+        self.search_filtered(query, knbn, ef_arg, distance_threshold, &|_| true)
+    }
+
+    pub fn is_building(&self) -> bool
+    {
+        self.fallback.is_some()
+    }
+
+    /// The format in which feature vectors are stored.
+    pub fn encoding(&self) -> VectorEncoding
+    {
+        self.encoding
+    }
 }
 
 pub struct SearchState<'a>(pub Mutex<InnerSearchState<'a>>);
 
-pub struct InnerClipState
+/// One of the active model's encoders: loading, loaded, or failed to load.
+pub struct InnerModelState<M: ?Sized>
 {
-    pub clip: Clip,
+    /// The model the encoder belongs to; known while it loads, so that e.g. files can be queued for it.
+    pub info: &'static ModelInfo,
+    /// None while the encoder is loading or if it failed to load; see load_error.
+    pub model: Option<Box<M>>,
+    pub load_error: Option<String>,
 }
 
-pub struct ClipState(pub Mutex<InnerClipState>);
+impl<M: ?Sized> InnerModelState<M>
+{
+    pub fn loading(info: &'static ModelInfo) -> Self
+    {
+        InnerModelState { info, model: None, load_error: None }
+    }
+
+    pub fn new(info: &'static ModelInfo, model: Result<Box<M>, Error>) -> Self
+    {
+        match model
+        {
+            Ok(model) => InnerModelState { info, model: Some(model), load_error: None },
+            Err(e) => InnerModelState { info, model: None, load_error: Some(e.to_string()) },
+        }
+    }
+
+    pub fn is_loading(&self) -> bool
+    {
+        self.model.is_none() && self.load_error.is_none()
+    }
+
+    /// The encoder, or an error explaining why it is unavailable.
+    pub fn model(&self) -> Result<&M, Error>
+    {
+        match (&self.model, &self.load_error)
+        {
+            (Some(model), _) => Ok(&**model),
+            (None, Some(load_error)) => Err(Error::ModelUnavailable(load_error.clone())),
+            (None, None) => Err(Error::ModelLoading(self.info.name.to_string())),
+        }
+    }
+}
+
+/// The text encoder of the active embedding model, used for searches. See embedding.rs.
+pub struct TextModelState(pub Mutex<InnerModelState<dyn TextEncoder>>);
+
+/// The image encoder of the active embedding model, used for encoding files.
+/// It has its own lock, so that searches don't wait for a batch of images to be encoded.
+pub struct VisualModelState(pub Mutex<InnerModelState<dyn ImageEncoder>>);
 
 pub struct InnerConnectionPoolState
 {
@@ -35,17 +299,57 @@ impl ConnectionPoolState
     }
 }
 
-pub struct InnerClipTokenizerState
+pub struct FsInnerWatcherState
 {
-    pub tokenizer: instant_clip_tokenizer::Tokenizer,
+    /// Maps from a path to a debouncer for that path.
+    pub watchers: std::collections::HashMap<String, Debouncer<RecommendedWatcher, FileIdMap>>,
 }
 
-pub struct ClipTokenizerState(pub Mutex<InnerClipTokenizerState>);
+pub struct FsWatcherState(pub Mutex<FsInnerWatcherState>);
 
-pub struct FsInnerWatcherState
+pub struct InnerSettingsState
 {
-    /// Maps from a path to a debouncer for that path.
-    pub watchers: std::collections::HashMap<String, Debouncer<RecommendedWatcher, FileIdMap>>,
+    pub settings: Settings,
+}
+
+pub struct SettingsState(pub Mutex<InnerSettingsState>);
+
+#[derive(Default)]
+pub struct InnerEncodingStatsState
+{
+    /// The most recent encoding run, if any have completed since the app started.
+    pub last_run: Option<EncodingStats>,
+    /// All encoding runs since the app started.
+    pub total: EncodingStats,
+}
+
+impl InnerEncodingStatsState
+{
+    pub fn record(&mut self, stats: EncodingStats)
+    {
+        self.last_run = Some(stats);
+        self.total.add(&stats);
+    }
+}
+
+/// Throughput of the encoding pipeline. See encoding_pipeline.rs.
+pub struct EncodingStatsState(pub Mutex<InnerEncodingStatsState>);
+
+pub struct InnerEncodingQueueState
+{
+    pub paused: bool,
+    /// Wakes the encoding worker when jobs are queued or it is resumed.
+    pub wake: mpsc::Sender<()>,
+}
+
+/// Control of the encoding worker. The queue itself is in the database. See encoding_queue.rs.
+pub struct EncodingQueueState(pub Mutex<InnerEncodingQueueState>);
+
+pub struct InnerTextEmbeddingCacheState
+{
+    pub cache: TextEmbeddingCache,
+    pub settings: TextEmbeddingCacheSettings,
 }
 
-pub struct FsWatcherState(pub Mutex<FsInnerWatcherState>);
\ No newline at end of file
+/// Text embeddings of recent search queries. See text_embedding_cache.rs.
+pub struct TextEmbeddingCacheState(pub Mutex<InnerTextEmbeddingCacheState>);
\ No newline at end of file
@@ -0,0 +1,38 @@
+/// Temporary directories for tests which write files, removed when they are dropped, so that nothing is left behind
+/// when an assertion fails.
+
+use std::path::{Path, PathBuf};
+
+/// A uniquely named directory in the system's temporary directory, removed with its contents when dropped.
+pub struct TempDir(PathBuf);
+
+impl TempDir
+{
+    /// Creates the directory, named after the tests which use it, e.g. "refrover-animation-test-<uuid>".
+    pub fn new(name: &str) -> Self
+    {
+        let path = std::env::temp_dir().join(format!("refrover-{}-test-{}", name, uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+
+    pub fn path(&self) -> &Path
+    {
+        &self.0
+    }
+
+    /// The path of a file in the directory.
+    pub fn join(&self, name: impl AsRef<Path>) -> PathBuf
+    {
+        self.0.join(name)
+    }
+}
+
+impl Drop for TempDir
+{
+    fn drop(&mut self)
+    {
+        // Errors are ignored rather than panicking while a failed test is unwinding.
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
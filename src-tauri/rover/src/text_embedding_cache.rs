@@ -0,0 +1,201 @@
+/// A cache of the text embeddings of search queries, so that repeated queries skip inference.
+///
+/// Searches re-run as the user types and whenever the folder filters change, often with a query
+/// which was just encoded. Embeddings are kept in a least-recently-used cache keyed by the model ID and
+/// the normalized query, and (optionally) persisted in the text_embeddings table, so that queries from
+/// previous sessions are cached too. The hit rate is logged with each lookup.
+
+use std::collections::HashMap;
+
+use diesel::SqliteConnection;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+
+use crate::queries;
+use crate::quantization::{self, VectorEncoding};
+use crate::state::TextEmbeddingCacheState;
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct TextEmbeddingCacheSettings
+{
+    /// The number of query embeddings kept in memory.
+    pub capacity: usize,
+    /// Whether query embeddings are stored in the database, to be reused after the app restarts.
+    pub persist: bool,
+    /// The number of query embeddings kept in the database; the least recently used are removed.
+    pub persisted_capacity: usize,
+}
+
+impl Default for TextEmbeddingCacheSettings
+{
+    fn default() -> Self
+    {
+        TextEmbeddingCacheSettings { capacity: 256, persist: true, persisted_capacity: 4096 }
+    }
+}
+
+/// The cache key of a query: lowercase, with runs of whitespace collapsed to single spaces.
+/// The CLIP tokenizer makes the same normalization, so normalized queries have the same embedding.
+pub fn normalize_query(query: &str) -> String
+{
+    query.split_whitespace().collect::<Vec<&str>>().join(" ").to_lowercase()
+}
+
+struct Entry
+{
+    embedding: Vec<f32>,
+    /// The value of TextEmbeddingCache::clock when the entry was last used.
+    last_used: u64,
+}
+
+/// A least-recently-used cache of text embeddings, keyed by model ID and normalized query.
+pub struct TextEmbeddingCache
+{
+    capacity: usize,
+    entries: HashMap<(String, String), Entry>,
+    clock: u64,
+    hits: u64,
+    lookups: u64,
+}
+
+impl TextEmbeddingCache
+{
+    pub fn new(capacity: usize) -> Self
+    {
+        TextEmbeddingCache { capacity, entries: HashMap::new(), clock: 0, hits: 0, lookups: 0 }
+    }
+
+    /// Gets the embedding of the normalized query, counting the lookup towards the hit rate.
+    pub fn get(&mut self, model_id: &str, query: &str) -> Option<Vec<f32>>
+    {
+        self.lookups += 1;
+        self.clock += 1;
+        let entry = self.entries.get_mut(&(model_id.to_string(), query.to_string()))?;
+        entry.last_used = self.clock;
+        self.hits += 1;
+        Some(entry.embedding.clone())
+    }
+
+    /// Caches the embedding of the normalized query, evicting the least recently used entry if the cache is full.
+    pub fn insert(&mut self, model_id: &str, query: &str, embedding: Vec<f32>)
+    {
+        if self.capacity == 0 {
+            return;
+        }
+        self.clock += 1;
+        let key = (model_id.to_string(), query.to_string());
+        if !self.entries.contains_key(&key) && self.entries.len() >= self.capacity
+        {
+            // The cache is small, so a linear scan for the oldest entry is cheap next to encoding a query.
+            let oldest = self.entries.iter().min_by_key(|(_, entry)| entry.last_used).map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                self.entries.remove(&oldest);
+            }
+        }
+        self.entries.insert(key, Entry { embedding, last_used: self.clock });
+    }
+
+    /// The fraction of lookups which were hits since the cache was created.
+    pub fn hit_rate(&self) -> f64
+    {
+        if self.lookups == 0 { 0.0 } else { self.hits as f64 / self.lookups as f64 }
+    }
+}
+
+/// Gets the embedding of the query from the cache or the database, or encodes it with `encode` and caches it.
+/// The cache is not locked while encoding, so other searches aren't blocked on inference.
+pub fn get_or_encode(
+    cache_state: &TextEmbeddingCacheState,
+    model_id: &str,
+    query: &str,
+    connection: &mut SqliteConnection,
+    encode: impl FnOnce() -> anyhow::Result<Vec<f32>>) -> anyhow::Result<Vec<f32>>
+{
+    let normalized = normalize_query(query);
+    let (cached, settings, hit_rate) = {
+        let mut cache_state = cache_state.0.lock().unwrap();
+        let cached = cache_state.cache.get(model_id, &normalized);
+        (cached, cache_state.settings.clone(), cache_state.cache.hit_rate())
+    };
+    if let Some(embedding) = cached {
+        info!("Text embedding cache hit for {:?} (hit rate {:.1}%)", normalized, hit_rate * 100.0);
+        return Ok(embedding);
+    }
+
+    let persisted = if settings.persist { get_persisted(model_id, &normalized, connection) } else { None };
+    let embedding = match persisted
+    {
+        Some(embedding) => {
+            info!("Text embedding cache miss for {:?}, found in the database (hit rate {:.1}%)", normalized, hit_rate * 100.0);
+            embedding
+        },
+        None => {
+            info!("Text embedding cache miss for {:?} (hit rate {:.1}%)", normalized, hit_rate * 100.0);
+            let embedding = encode()?;
+            if settings.persist {
+                // The embedding can always be encoded again, so failing to store it isn't an error for the search.
+                if let Err(e) = persist(model_id, &normalized, &embedding, settings.persisted_capacity, connection) {
+                    error!("Error storing the text embedding of {:?}: {:?}", normalized, e);
+                }
+            }
+            embedding
+        },
+    };
+
+    cache_state.0.lock().unwrap().cache.insert(model_id, &normalized, embedding.clone());
+    Ok(embedding)
+}
+
+fn get_persisted(model_id: &str, query: &str, connection: &mut SqliteConnection) -> Option<Vec<f32>>
+{
+    let result = queries::get_text_embedding(model_id, query, connection)
+        .and_then(|bytes| bytes.map(|bytes| quantization::decode(&bytes, VectorEncoding::F32)).transpose());
+    match result
+    {
+        Ok(embedding) => embedding,
+        Err(e) => {
+            error!("Error loading the text embedding of {:?}: {:?}", query, e);
+            None
+        },
+    }
+}
+
+fn persist(model_id: &str, query: &str, embedding: &[f32], persisted_capacity: usize, connection: &mut SqliteConnection) -> anyhow::Result<()>
+{
+    let bytes = quantization::encode(embedding, VectorEncoding::F32)?;
+    queries::insert_text_embedding(model_id, query, &bytes, connection)?;
+    queries::delete_least_recently_used_text_embeddings(persisted_capacity, connection)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn queries_are_normalized()
+    {
+        assert_eq!(normalize_query("  A red\tDuck \n"), "a red duck");
+        assert_eq!(normalize_query("a red duck"), normalize_query("A  RED duck"));
+    }
+
+    #[test]
+    fn least_recently_used_entry_is_evicted()
+    {
+        let mut cache = TextEmbeddingCache::new(2);
+        cache.insert("model", "duck", vec![1.0]);
+        cache.insert("model", "goose", vec![2.0]);
+        // Using "duck" makes "goose" the least recently used.
+        assert_eq!(cache.get("model", "duck"), Some(vec![1.0]));
+        cache.insert("model", "swan", vec![3.0]);
+
+        assert_eq!(cache.get("model", "goose"), None);
+        assert_eq!(cache.get("model", "duck"), Some(vec![1.0]));
+        assert_eq!(cache.get("model", "swan"), Some(vec![3.0]));
+        // Entries are per model.
+        assert_eq!(cache.get("other-model", "duck"), None);
+        assert_eq!(cache.hit_rate(), 3.0 / 5.0);
+    }
+}
@@ -1,11 +1,13 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
 use std::path::Path;
 
-use image::{imageops, DynamicImage, GenericImageView, ImageBuffer, RgbaImage};
+use image::{DynamicImage, GenericImageView, ImageBuffer, RgbImage};
 
-use log::warn;
+use tauri::Manager;
 use uuid::Uuid;
 
-use crate::{db, error::Error, models::NewThumbnail, queries, state::ConnectionPoolState, uuid::UUID};
+use crate::{animation::{self, AnimationInfo}, db, error::Error, image_loading, models::NewThumbnail, queries, state::{ConnectionPoolState, SettingsState}, uuid::UUID, video};
 
 const MAX_THUMBNAIL_DIMENSION: u32 = 600;
 
@@ -80,45 +82,32 @@ pub fn ensure_thumbnail_exists(
     }
     let file_path = &file_path[0].clone().1;
 
-    // Load exif data to determine image rotation, if applicable
-    let file = std::fs::File::open(file_path)?;
-    let mut bufreader = std::io::BufReader::new(file);
-    let exifreader = exif::Reader::new();
-    let exif = exifreader.read_from_container(&mut bufreader)?;
-
-    // EXIF Rotation data is stored as a value 1-8, where:
-    // 1 = 0 degrees: the correct orientation, no adjustment is required.
-    // 2 = 0 degrees, mirrored: image has been flipped back-to-front.
-    // 3 = 180 degrees: image is upside down.
-    // 4 = 180 degrees, mirrored: image has been flipped back-to-front and is upside down.
-    // 5 = 90 degrees: image has been flipped back-to-front and is on its side.
-    // 6 = 90 degrees, mirrored: image is on its side.
-    // 7 = 270 degrees: image has been flipped back-to-front and is on its far side.
-    // 8 = 270 degrees, mirrored: image is on its far side.
-    //
-    // In our case, we assume that if there is no rotation data available or EXIF loading fails,
-    // that the image is in the correct orientation.
-    //
-    // Orientation is stored as a SHORT.  You could match `orientation.value`
-    // against `Value::Short`, but the standard recommends that readers
-    // should accept BYTE, SHORT, or LONG values for any unsigned integer
-    // field.  `Value::get_uint` is provided for that purpose.
-    let orientation = match exif.get_field(exif::Tag::Orientation, exif::In::PRIMARY) {
-        Some(orientation) => {
-            let orientation = orientation.value.get_uint(0);
-            match orientation {
-                Some(v) => v,
-                None => 1,
-            }
-        },
-        None => 1,
+    let (background, max_animation_frames, video_settings) = {
+        let settings_state = app_handle.state::<SettingsState>();
+        let settings = &settings_state.0.lock().unwrap().settings;
+        (settings.transparency.background, settings.animation.max_thumbnail_frames, settings.video.clone())
     };
-
-    // Load the image from the file 
-    let orig_image = image::open(&file_path)?;
-    let mut thumbnail = thumbnail(&orig_image);
-    fix_orientation(&mut thumbnail, orientation);
-    thumbnail.save_with_format(new_thumbnail_full_path.clone(), image::ImageFormat::WebP)?;
+    match animation::animation_info(file_path)
+    {
+        Some(info) if max_animation_frames > 1 => {
+            save_animated_thumbnail(file_path, &info, max_animation_frames, background, &new_thumbnail_full_path)?;
+        },
+        None if video_settings.enabled && video::is_video(file_path) => {
+            // Videos are shown by their first keyframe, which is also what they are encoded as.
+            // ffmpeg isn't run while videos are off, so they fail to thumbnail like other files which aren't images.
+            let poster_frame = video::load_poster_frame(file_path, &video_settings)?;
+            thumbnail(&poster_frame).save_with_format(new_thumbnail_full_path.clone(), image::ImageFormat::WebP)?;
+        },
+        _ => {
+            // Load the image from the file, upright according to its EXIF orientation,
+            // and on the same background as it is encoded on if it has transparency.
+            // Large JPEGs are decoded at a reduced resolution which is still larger than the thumbnail.
+            let (orig_image, _) = image_loading::load_image_reduced(file_path, MAX_THUMBNAIL_DIMENSION)?;
+            let orig_image = image_loading::composite_onto_background(orig_image, background);
+            let thumbnail = thumbnail(&orig_image);
+            thumbnail.save_with_format(new_thumbnail_full_path.clone(), image::ImageFormat::WebP)?;
+        },
+    }
 
     // Add the thumbnail to the thumbnails table.
     let new_thumbnail_db = NewThumbnail {
@@ -138,32 +127,29 @@ pub fn ensure_thumbnail_exists(
     Ok((new_thumbnail_id, full_path))
 }
 
-fn fix_orientation(image: &mut RgbaImage, orientation: u32) {
-    // TODO imageops doesn't support in-place rotation for every case -
-    //      maybe go make a pull request to add that functionality?
-    // TODO It would further be better to use matrix ops, as in:
-    //      https://magnushoff.com/articles/jpeg-orientation/
-    //      But that adds some complication interacting with the `image` crate.
-    match orientation
-    {
-        1 => {},
-        2 => imageops::flip_horizontal_in_place(image),
-        3 => imageops::rotate180_in_place(image),
-        4 => {
-            imageops::flip_vertical_in_place(image);
-        },
-        5 => {
-            *image = imageops::rotate90(image);
-            imageops::flip_horizontal_in_place(image);
-        },
-        6 => *image = imageops::rotate90(image),
-        7 => {
-            *image = imageops::rotate270(image);
-            imageops::flip_horizontal_in_place(image);
-        },
-        8 => *image = imageops::rotate270(image),
-        _ => {
-            warn!("Unsupported EXIF orientation: {}", orientation);
-        }
-    }
-}
\ No newline at end of file
+/// Saves an animated WebP thumbnail of the animation, with up to `max_frames` frames sampled evenly through it.
+/// Each sampled frame is shown until the next, so the thumbnail plays for as long as the animation.
+fn save_animated_thumbnail(
+    file_path: &Path,
+    info: &AnimationInfo,
+    max_frames: u32,
+    background: image_loading::Background,
+    thumbnail_path: &Path,
+) -> anyhow::Result<()>
+{
+    let indices = animation::sample_frame_indices(info.frame_count, max_frames);
+    let frames = animation::load_frames(file_path, &indices)?;
+    let timestamps: Vec<animation::Frame> = frames.iter().map(|(frame, _)| *frame).collect();
+    let durations = animation::frame_durations(&timestamps, info);
+    let frames: Vec<(RgbImage, u64)> = frames.into_iter().zip(durations)
+        .map(|((_, image), duration)| {
+            let image = image_loading::composite_onto_background(image, background);
+            (DynamicImage::ImageRgba8(thumbnail(&image)).to_rgb8(), duration)
+        })
+        .collect();
+
+    let mut file = BufWriter::new(File::create(thumbnail_path)?);
+    animation::write_animated_webp(&mut file, &frames)?;
+    file.flush()?;
+    Ok(())
+}
@@ -0,0 +1,208 @@
+/// Rasterization of vector files (SVG and PDF), which the image crate can't read.
+///
+/// SVGs are rendered with resvg, and the first page of a PDF with pdftoppm (from poppler), which is run from the PATH;
+/// without it, PDFs fail to encode like any other file which can't be loaded. Vector files are rasterized with their
+/// longest side at RASTER_SIZE whatever their intrinsic size, so that a small icon is as sharp as any other image in
+/// its thumbnail and for the model; their intrinsic size is reported as their size.
+
+use std::path::Path;
+use std::process::Command;
+use std::sync::{Arc, OnceLock};
+
+use image::{DynamicImage, ImageFormat, ImageResult, RgbaImage};
+use resvg::{tiny_skia, usvg};
+
+use crate::image_loading::{self, format_decoding_error};
+
+/// The length of the longest side of rasterized vector files, in pixels.
+/// Larger than thumbnails and the model's input, so both are downscaled from it.
+pub const RASTER_SIZE: u32 = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VectorFormat
+{
+    Svg,
+    Pdf,
+}
+
+impl VectorFormat
+{
+    /// The vector format of the file, by its extension; None for other files.
+    pub fn from_path(path: &Path) -> Option<Self>
+    {
+        let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+        match extension.as_str()
+        {
+            "svg" | "svgz" => Some(VectorFormat::Svg),
+            "pdf" => Some(VectorFormat::Pdf),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str
+    {
+        match self
+        {
+            VectorFormat::Svg => "SVG",
+            VectorFormat::Pdf => "PDF",
+        }
+    }
+}
+
+/// Rasterizes the file (the first page, for PDFs) with its longest side at RASTER_SIZE.
+pub fn rasterize(format: VectorFormat, path: &Path) -> ImageResult<DynamicImage>
+{
+    match format
+    {
+        VectorFormat::Svg => rasterize_svg(path),
+        VectorFormat::Pdf => rasterize_pdf(path),
+    }
+}
+
+/// Reads the intrinsic size of the file (of its first page, for PDFs), in CSS pixels for SVGs and points for PDFs,
+/// which are the same size.
+pub fn intrinsic_size(format: VectorFormat, path: &Path) -> Option<(u32, u32)>
+{
+    match format
+    {
+        VectorFormat::Svg => {
+            // Fonts don't affect the size, so aren't loaded.
+            let tree = read_svg(path, &usvg::Options::default()).ok()?;
+            Some((tree.size().width().round() as u32, tree.size().height().round() as u32))
+        },
+        VectorFormat::Pdf => {
+            let output = run_poppler(Command::new("pdfinfo").arg(path), "pdfinfo").ok()?;
+            parse_pdfinfo_page_size(&String::from_utf8_lossy(&output))
+        },
+    }
+}
+
+/// The size of a rasterized image with an intrinsic size of `width` by `height`.
+fn raster_size(width: f32, height: f32) -> (u32, u32)
+{
+    let scale = RASTER_SIZE as f32 / width.max(height);
+    (((width * scale).round() as u32).max(1), ((height * scale).round() as u32).max(1))
+}
+
+// ---- SVG ----
+
+/// The system fonts, for text in SVGs. Loading them takes a while, so it is only done once.
+fn system_fonts() -> Arc<usvg::fontdb::Database>
+{
+    static FONTS: OnceLock<Arc<usvg::fontdb::Database>> = OnceLock::new();
+    FONTS.get_or_init(|| {
+        let mut fonts = usvg::fontdb::Database::new();
+        fonts.load_system_fonts();
+        Arc::new(fonts)
+    }).clone()
+}
+
+fn read_svg(path: &Path, options: &usvg::Options) -> ImageResult<usvg::Tree>
+{
+    let data = std::fs::read(path)?;
+    usvg::Tree::from_data(&data, options).map_err(|e| format_decoding_error(VectorFormat::Svg.name(), e.to_string()))
+}
+
+fn rasterize_svg(path: &Path) -> ImageResult<DynamicImage>
+{
+    let options = usvg::Options {
+        // Images linked from the SVG are relative to it.
+        resources_dir: path.parent().map(Path::to_path_buf),
+        fontdb: system_fonts(),
+        ..usvg::Options::default()
+    };
+    let tree = read_svg(path, &options)?;
+    let size = tree.size();
+    let (width, height) = raster_size(size.width(), size.height());
+    let mut pixmap = tiny_skia::Pixmap::new(width, height)
+        .ok_or_else(|| format_decoding_error(VectorFormat::Svg.name(), "The image is empty"))?;
+    let transform = tiny_skia::Transform::from_scale(width as f32 / size.width(), height as f32 / size.height());
+    resvg::render(&tree, transform, &mut pixmap.as_mut());
+
+    // tiny-skia's pixels are premultiplied by their alpha.
+    let pixels = pixmap.pixels().iter()
+        .flat_map(|pixel| {
+            let color = pixel.demultiply();
+            [color.red(), color.green(), color.blue(), color.alpha()]
+        })
+        .collect();
+    let image = RgbaImage::from_raw(width, height, pixels).expect("The pixmap has width * height pixels");
+    Ok(DynamicImage::ImageRgba8(image))
+}
+
+// ---- PDF ----
+
+fn rasterize_pdf(path: &Path) -> ImageResult<DynamicImage>
+{
+    // With no output file, pdftoppm writes the page to stdout.
+    let mut command = Command::new("pdftoppm");
+    command.args(["-f", "1", "-l", "1", "-singlefile", "-png", "-scale-to", &RASTER_SIZE.to_string()]).arg(path);
+    let png = run_poppler(&mut command, "pdftoppm")?;
+    image_loading::load_from_memory(&png, ImageFormat::Png)
+}
+
+/// Runs a poppler tool, returning what it wrote to stdout.
+fn run_poppler(command: &mut Command, name: &str) -> ImageResult<Vec<u8>>
+{
+    let output = image_loading::run_format_tool(command, VectorFormat::Pdf.name(), name, "install poppler to encode PDFs")?;
+    Ok(output.stdout)
+}
+
+/// Reads the size of the first page from pdfinfo's output, in points, turned on its side if the page is rotated.
+fn parse_pdfinfo_page_size(info: &str) -> Option<(u32, u32)>
+{
+    let field = |name: &str| info.lines().find_map(|line| line.strip_prefix(name)).map(str::trim);
+
+    // e.g. "Page size:      612 x 792 pts (letter)"
+    let mut size = field("Page size:")?.split_whitespace();
+    let width: f64 = size.next()?.parse().ok()?;
+    let height: f64 = size.nth(1)?.parse().ok()?;
+    let (width, height) = (width.round() as u32, height.round() as u32);
+    match field("Page rot:").and_then(|rotation| rotation.parse::<u32>().ok())
+    {
+        Some(90 | 270) => Some((height, width)),
+        _ => Some((width, height)),
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use image::ImageError;
+
+    use crate::test_files::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn svgs_are_rasterized_at_the_raster_size()
+    {
+        // A 32x16 icon whose left half is red and right half is blue, sized by its viewBox only.
+        let dir = TempDir::new("vector");
+        let path = dir.join("icon.svg");
+        std::fs::write(&path, r#"<svg xmlns="http://www.w3.org/2000/svg" viewBox="0 0 32 16">
+            <rect x="0" y="0" width="16" height="16" fill="red"/>
+            <rect x="16" y="0" width="16" height="16" fill="blue"/>
+        </svg>"#).unwrap();
+
+        assert_eq!(VectorFormat::from_path(&path), Some(VectorFormat::Svg));
+        assert_eq!(intrinsic_size(VectorFormat::Svg, &path), Some((32, 16)));
+        let image = rasterize(VectorFormat::Svg, &path).unwrap().to_rgba8();
+        assert_eq!(image.dimensions(), (1024, 512));
+        assert_eq!(image.get_pixel(100, 256).0, [255, 0, 0, 255]);
+        assert_eq!(image.get_pixel(900, 256).0, [0, 0, 255, 255]);
+
+        std::fs::write(&path, "not an svg").unwrap();
+        assert!(matches!(rasterize(VectorFormat::Svg, &path), Err(ImageError::Decoding(_))));
+    }
+
+    #[test]
+    fn pdf_page_size_is_read_from_pdfinfo()
+    {
+        let info = "Producer:        Inkscape 1.3\nPages:           2\nPage size:       841.89 x 595.276 pts (A4)\nPage rot:        0\n";
+        assert_eq!(parse_pdfinfo_page_size(info), Some((842, 595)));
+        let rotated = "Page size:       612 x 792 pts (letter)\nPage rot:        90\n";
+        assert_eq!(parse_pdfinfo_page_size(rotated), Some((792, 612)));
+        assert_eq!(parse_pdfinfo_page_size("Pages: 0\n"), None);
+    }
+}
@@ -0,0 +1,320 @@
+/// Video references: extracting keyframes to encode, and reading the size and duration of clips.
+///
+/// Videos are decoded by ffmpeg rather than in process, since references come in codecs (H.264, HEVC, ProRes, VP9...)
+/// which no pure Rust decoder covers. ffmpeg and ffprobe are run from the PATH, or from the configured directory. Since they
+/// aren't bundled, videos are off by default (see VideoSettings::enabled); if they are enabled without ffmpeg, videos fail
+/// to encode like any other file which can't be loaded, and can be retried once ffmpeg is installed (see failed_encodings.rs).
+///
+/// Keyframes are taken at a fixed interval, or when the scene changes. The first keyframe is encoded as the video itself,
+/// and the rest are stored in the frame_features table with their timestamps, as the frames of animations are
+/// (see animation.rs); so a search matching a keyframe returns its video with the time to open it at.
+
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+use anyhow::Context;
+use image::{DynamicImage, ImageFormat};
+use serde::{Deserialize, Serialize};
+
+use crate::animation::Frame;
+use crate::image_loading;
+
+/// The extensions of files which are loaded as videos.
+pub const VIDEO_EXTENSIONS: [&str; 9] = ["mp4", "m4v", "mov", "webm", "mkv", "avi", "wmv", "mpg", "mpeg"];
+
+/// Keyframes wider than this are scaled down as ffmpeg extracts them; the model and thumbnails need far less,
+/// and it bounds the size of the frames ffmpeg sends us.
+const MAX_KEYFRAME_WIDTH: u32 = 1024;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyframeSelection
+{
+    /// A keyframe every `interval_seconds`.
+    #[default]
+    Interval,
+    /// The first frame, and each frame whose difference from the previous exceeds `scene_change_threshold`.
+    SceneChange,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct VideoSettings
+{
+    /// Whether videos are encoded, which needs ffmpeg; off by default. When disabled, video extensions are left out of the
+    /// default indexed extensions (see file_filter::default_extensions()), and videos in watched directories which index
+    /// them anyway fail to encode like other files which aren't images.
+    pub enabled: bool,
+    /// The directory containing the ffmpeg and ffprobe executables. If unset, they are run from the PATH.
+    pub ffmpeg_directory: Option<PathBuf>,
+    pub keyframe_selection: KeyframeSelection,
+    /// The time between keyframes, when they are selected at an interval.
+    pub interval_seconds: f64,
+    /// ffmpeg's scene change score, from 0 (any change) to 1 (a completely different frame), above which a frame is
+    /// selected as a keyframe, when they are selected on scene changes.
+    pub scene_change_threshold: f64,
+    /// The most keyframes encoded per video, including the first (which is encoded as the video itself).
+    pub max_keyframes: u32,
+}
+
+impl Default for VideoSettings
+{
+    fn default() -> Self
+    {
+        VideoSettings {
+            enabled: false,
+            ffmpeg_directory: None,
+            keyframe_selection: KeyframeSelection::default(),
+            interval_seconds: 2.0,
+            scene_change_threshold: 0.3,
+            max_keyframes: 32,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VideoInfo
+{
+    pub width: u32,
+    pub height: u32,
+    pub frame_count: Option<u32>,
+    pub duration_ms: Option<u64>,
+}
+
+/// Whether the file is loaded as a video, by its extension.
+pub fn is_video(path: &Path) -> bool
+{
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| VIDEO_EXTENSIONS.contains(&extension.to_ascii_lowercase().as_str()))
+}
+
+/// Estimates the memory extracting the video's keyframes takes, without running ffmpeg: up to max_keyframes keyframes,
+/// assumed to be square at MAX_KEYFRAME_WIDTH, both as the BMPs ffmpeg writes (3 bytes per pixel) and decoded (4).
+pub fn estimated_keyframe_bytes(settings: &VideoSettings) -> u64
+{
+    let keyframe_pixels = u64::from(MAX_KEYFRAME_WIDTH) * u64::from(MAX_KEYFRAME_WIDTH);
+    u64::from(settings.max_keyframes.max(1)) * keyframe_pixels * (3 + 4)
+}
+
+/// Reads the size, frame count and duration of the video's first video stream with ffprobe.
+/// The frame count is only known for containers which record it.
+pub fn probe(path: &Path, settings: &VideoSettings) -> anyhow::Result<VideoInfo>
+{
+    let mut command = Command::new(tool_path(settings, "ffprobe"));
+    command.args(["-v", "error", "-select_streams", "v:0"])
+        .args(["-show_entries", "stream=width,height,nb_frames:format=duration", "-of", "json"])
+        .arg(path);
+    let output = run(command, "ffprobe")?;
+    parse_probe_output(&String::from_utf8_lossy(&output.stdout))
+        .with_context(|| format!("Error reading the video stream of {:?}", path))
+}
+
+/// Extracts up to `max_keyframes` keyframes of the video, selected according to the settings, with their timestamps.
+/// Each keyframe's index is its position among the keyframes, rather than in the video.
+pub fn extract_keyframes(path: &Path, settings: &VideoSettings, max_keyframes: u32) -> anyhow::Result<Vec<(Frame, DynamicImage)>>
+{
+    let mut command = Command::new(tool_path(settings, "ffmpeg"));
+    command.args(["-hide_banner", "-nostdin", "-nostats", "-loglevel", "info"])
+        .arg("-i").arg(path)
+        .args(["-an", "-sn", "-vf", &keyframe_filter(settings), "-fps_mode", "vfr"])
+        .args(["-frames:v", &max_keyframes.max(1).to_string()])
+        .args(["-f", "image2pipe", "-c:v", "bmp", "-"]);
+    let output = run(command, "ffmpeg")?;
+
+    let images = split_bmp_stream(&output.stdout)?;
+    if images.is_empty() {
+        return Err(anyhow::anyhow!("ffmpeg extracted no frames from {:?}", path));
+    }
+    let timestamps = keyframe_timestamps(&String::from_utf8_lossy(&output.stderr), images.len(), settings)
+        .with_context(|| format!("Error reading the keyframe timestamps of {:?}", path))?;
+    images.into_iter().zip(timestamps).enumerate()
+        .map(|(index, (data, timestamp_ms))| {
+            let image = image_loading::load_from_memory(data, ImageFormat::Bmp)?;
+            Ok((Frame { index: index as u32, timestamp_ms }, image))
+        })
+        .collect()
+}
+
+/// Loads the first keyframe of the video, for its thumbnail.
+pub fn load_poster_frame(path: &Path, settings: &VideoSettings) -> anyhow::Result<DynamicImage>
+{
+    let (_, image) = extract_keyframes(path, settings, 1)?.into_iter().next()
+        .ok_or(anyhow::anyhow!("ffmpeg extracted no frames from {:?}", path))?;
+    Ok(image)
+}
+
+fn tool_path(settings: &VideoSettings, name: &str) -> PathBuf
+{
+    match &settings.ffmpeg_directory
+    {
+        Some(directory) => directory.join(format!("{}{}", name, std::env::consts::EXE_SUFFIX)),
+        None => PathBuf::from(name),
+    }
+}
+
+/// Runs ffmpeg or ffprobe, failing if it isn't installed or exits unsuccessfully.
+fn run(mut command: Command, name: &str) -> anyhow::Result<Output>
+{
+    Ok(image_loading::run_format_tool(&mut command, "video", name,
+        "install ffmpeg, or set its directory in the video settings, to encode videos")?)
+}
+
+/// The filter graph which selects keyframes, scales them down, and logs their timestamps (with showinfo).
+fn keyframe_filter(settings: &VideoSettings) -> String
+{
+    let select = match settings.keyframe_selection
+    {
+        KeyframeSelection::Interval => format!("fps=fps={:.6}", 1.0 / settings.interval_seconds.max(0.1)),
+        KeyframeSelection::SceneChange => format!("select='eq(n,0)+gt(scene,{:.3})'", settings.scene_change_threshold.clamp(0.0, 1.0)),
+    };
+    format!("{},scale='min({},iw)':-2,showinfo", select, MAX_KEYFRAME_WIDTH)
+}
+
+/// Splits ffmpeg's image2pipe output into its BMP files, by the file size in each one's header.
+fn split_bmp_stream(mut data: &[u8]) -> anyhow::Result<Vec<&[u8]>>
+{
+    let mut images = Vec::new();
+    while !data.is_empty()
+    {
+        if data.len() < 6 || &data[..2] != b"BM" {
+            return Err(anyhow::anyhow!("Expected a BMP frame from ffmpeg"));
+        }
+        let size = u32::from_le_bytes([data[2], data[3], data[4], data[5]]) as usize;
+        if size < 6 || size > data.len() {
+            return Err(anyhow::anyhow!("Truncated BMP frame from ffmpeg"));
+        }
+        images.push(&data[..size]);
+        data = &data[size..];
+    }
+    Ok(images)
+}
+
+/// The timestamps of the `count` extracted keyframes, in milliseconds, from ffmpeg's log.
+/// showinfo logs each frame as it is extracted; if its output can't be matched to the frames, keyframes selected at an
+/// interval are taken to be at multiples of it, but those selected on scene changes could be anywhere, so that's an error.
+fn keyframe_timestamps(log: &str, count: usize, settings: &VideoSettings) -> anyhow::Result<Vec<u64>>
+{
+    let timestamps = parse_showinfo_timestamps(log);
+    if timestamps.len() == count {
+        return Ok(timestamps);
+    }
+    match settings.keyframe_selection
+    {
+        KeyframeSelection::Interval => {
+            Ok((0..count).map(|index| (index as f64 * settings.interval_seconds * 1000.0) as u64).collect())
+        },
+        KeyframeSelection::SceneChange => {
+            Err(anyhow::anyhow!("ffmpeg logged {} frame timestamps for {} keyframes", timestamps.len(), count))
+        },
+    }
+}
+
+/// Reads the timestamp of each frame which showinfo logged, in milliseconds.
+fn parse_showinfo_timestamps(log: &str) -> Vec<u64>
+{
+    log.lines()
+        .filter(|line| line.contains("Parsed_showinfo"))
+        .filter_map(|line| line.split_once("pts_time:"))
+        .filter_map(|(_, rest)| rest.split_whitespace().next()?.parse::<f64>().ok())
+        .map(|seconds| (seconds.max(0.0) * 1000.0).round() as u64)
+        .collect()
+}
+
+#[derive(Deserialize)]
+struct ProbeOutput
+{
+    #[serde(default)]
+    streams: Vec<ProbeStream>,
+    format: Option<ProbeFormat>,
+}
+
+#[derive(Deserialize)]
+struct ProbeStream
+{
+    width: Option<u32>,
+    height: Option<u32>,
+    /// ffprobe writes the frame count and duration as strings.
+    nb_frames: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ProbeFormat
+{
+    duration: Option<String>,
+}
+
+fn parse_probe_output(json: &str) -> anyhow::Result<VideoInfo>
+{
+    let output: ProbeOutput = serde_json::from_str(json)?;
+    let stream = output.streams.into_iter().next().ok_or(anyhow::anyhow!("No video stream"))?;
+    let (Some(width), Some(height)) = (stream.width, stream.height) else {
+        return Err(anyhow::anyhow!("The video stream has no size"));
+    };
+    let frame_count = stream.nb_frames.and_then(|count| count.parse().ok()).filter(|count| *count > 0);
+    let duration_ms = output.format.and_then(|format| format.duration)
+        .and_then(|duration| duration.parse::<f64>().ok())
+        .map(|seconds| (seconds * 1000.0).round() as u64);
+    Ok(VideoInfo { width, height, frame_count, duration_ms })
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn videos_are_recognized_by_extension()
+    {
+        assert!(is_video(Path::new("walk cycle.MP4")));
+        assert!(is_video(Path::new("reference/turnaround.mov")));
+        assert!(!is_video(Path::new("walk cycle.gif")));
+        assert!(!is_video(Path::new("mp4")));
+    }
+
+    #[test]
+    fn ffmpeg_output_is_split_into_frames_with_timestamps()
+    {
+        let frames: Vec<Vec<u8>> = [[255, 0, 0], [0, 0, 255]].iter()
+            .map(|color| {
+                let mut bmp = std::io::Cursor::new(Vec::new());
+                image::RgbImage::from_pixel(3, 2, image::Rgb(*color)).write_to(&mut bmp, ImageFormat::Bmp).unwrap();
+                bmp.into_inner()
+            })
+            .collect();
+        let stream = frames.concat();
+        let split = split_bmp_stream(&stream).unwrap();
+        assert_eq!(split, vec![&frames[0][..], &frames[1][..]]);
+        let second = image::load_from_memory_with_format(split[1], ImageFormat::Bmp).unwrap().to_rgb8();
+        assert_eq!(second.get_pixel(0, 0).0, [0, 0, 255]);
+        assert!(split_bmp_stream(&stream[..stream.len() - 1]).is_err());
+
+        let log = "\
+[Parsed_showinfo_2 @ 0x600001234] config in time_base: 1/1000, frame_rate: 1/2
+[Parsed_showinfo_2 @ 0x600001234] n:   0 pts:      0 pts_time:0       duration:   2000 fmt:bgr24
+[Parsed_showinfo_2 @ 0x600001234]   color_range:unknown color_space:unknown
+[Parsed_showinfo_2 @ 0x600001234] n:   1 pts:   2000 pts_time:2.0415  duration:   2000 fmt:bgr24
+frame=    2 fps=0.0 q=-0.0 Lsize=N/A time=00:00:04.00 bitrate=N/A speed=  42x";
+        assert_eq!(parse_showinfo_timestamps(log), vec![0, 2042]);
+
+        // Without timestamps for every keyframe, those selected on scene changes can't be placed.
+        let interval = VideoSettings { interval_seconds: 1.5, ..VideoSettings::default() };
+        let scene_change = VideoSettings { keyframe_selection: KeyframeSelection::SceneChange, ..VideoSettings::default() };
+        assert_eq!(keyframe_timestamps(log, 2, &scene_change).unwrap(), vec![0, 2042]);
+        assert_eq!(keyframe_timestamps(log, 3, &interval).unwrap(), vec![0, 1500, 3000]);
+        assert!(keyframe_timestamps(log, 3, &scene_change).is_err());
+    }
+
+    #[test]
+    fn probe_output_is_parsed()
+    {
+        let json = r#"{ "programs": [], "streams": [ { "width": 1920, "height": 1080, "nb_frames": "240" } ], "format": { "duration": "10.010000" } }"#;
+        assert_eq!(parse_probe_output(json).unwrap(), VideoInfo { width: 1920, height: 1080, frame_count: Some(240), duration_ms: Some(10010) });
+
+        // WebM doesn't record its frame count.
+        let json = r#"{ "streams": [ { "width": 640, "height": 360 } ], "format": { "duration": "3.5" } }"#;
+        assert_eq!(parse_probe_output(json).unwrap().frame_count, None);
+
+        assert!(parse_probe_output(r#"{ "streams": [], "format": {} }"#).is_err());
+    }
+}
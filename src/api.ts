@@ -1,8 +1,16 @@
 import { invoke } from "@tauri-apps/api/tauri"
 
 import { convertFileSrc } from "@tauri-apps/api/tauri"
+import type DuplicateGroup from "./interfaces/DuplicateGroup"
+import type EmbeddingModelInfo from "./interfaces/EmbeddingModelInfo"
+import type EncodingQueueStatus from "./interfaces/EncodingQueueStatus"
+import type EncodingThroughput from "./interfaces/EncodingThroughput"
+import type FailedEncodingGroup from "./interfaces/FailedEncodingGroup"
 import type FileMetadata from "./interfaces/FileMetadata"
 import type FileUuid from "./interfaces/FileUuid"
+import type RetrySummary from "./interfaces/RetrySummary"
+import type ScanSummary from "./interfaces/ScanSummary"
+import type SearchResult from "./interfaces/SearchResult"
 import type Thumbnail from "./interfaces/thumbnail"
 
 // TODO Ensure we're using proper interfaces for e.g. File UUIDs.
@@ -18,6 +26,9 @@ export async function fetchMetadata(fileUuid: string) {
 
     // Convert the thumbnail file path to a file URL
     result.thumbnail_filepath = convertFileSrc(result.thumbnail_filepath)
+    if (result.video_filepath) {
+      result.video_filepath = convertFileSrc(result.video_filepath)
+    }
 
     return result
   } catch (error) {
@@ -33,14 +44,15 @@ export async function searchImages(
   distanceThreshold: number,
 ) {
   try {
-    const fileUuids = await invoke<FileUuid[]>("search_images", {
+    // Each result includes the region of the image or the frame of the animation or video which matched, if any.
+    const results = await invoke<SearchResult[]>("search_images", {
       pathPrefixes,
       queryString,
       numberNeighbors,
       efArg,
       distanceThreshold,
     })
-    return fileUuids
+    return results
   } catch (error) {
     console.error("Error fetching image UUIDs:", error)
     throw new Error("Failed to fetch image UUIDs")
@@ -74,16 +86,23 @@ export async function fetchThumbnails(fileIds: FileUuid[]) {
   }
 }
 
-export async function addWatchedDirectory(directory: string) {
+// Only files with the given extensions (or whose content is an image with one of them) are indexed;
+// if extensions is omitted, the indexed_extensions setting is used.
+export async function addWatchedDirectory(directory: string, extensions?: string[]) {
   try {
-    await invoke("add_watched_directory", {
+    await invoke<ScanSummary>("add_watched_directory", {
       directory,
+      extensions: extensions ?? null,
     })
       .catch((error: unknown) => {
         console.error("Error adding watched directory:", error)
       })
-      .then(() => {
-        console.log("Successfully added watched directory")
+      .then((summary) => {
+        if (summary) {
+          console.log(
+            `Successfully added watched directory: ${summary.added} files added, ${summary.skipped} skipped`,
+          )
+        }
       })
   } catch (error) {
     console.error("Error adding watched directory:", error)
@@ -109,3 +128,79 @@ export async function deleteWatchedDirectory(directory: string) {
 export function getWatchedDirectories() {
   return invoke<string[]>("get_watched_directories")
 }
+
+// Finds groups of near-duplicate images, replacing the previous results.
+// Returns the number of groups found; fetch them with getDuplicateGroups().
+// If distanceThreshold is omitted, the back-end default is used.
+export async function findDuplicates(distanceThreshold?: number) {
+  try {
+    return await invoke<number>("find_duplicates", {
+      distanceThreshold: distanceThreshold ?? null,
+    })
+  } catch (error) {
+    console.error("Error finding duplicates:", error)
+    throw new Error("Failed to find duplicates")
+  }
+}
+
+export function getDuplicateGroups() {
+  return invoke<DuplicateGroup[]>("get_duplicate_groups")
+}
+
+export function getFailedEncodings() {
+  return invoke<FailedEncodingGroup[]>("get_failed_encodings")
+}
+
+// Retries the given files, or every failed file if fileIds is omitted.
+export async function retryFailedEncodings(fileIds?: string[]) {
+  try {
+    return await invoke<RetrySummary>("retry_failed_encodings", {
+      fileIds: fileIds ?? null,
+    })
+  } catch (error) {
+    console.error("Error retrying failed encodings:", error)
+    throw new Error("Failed to retry failed encodings")
+  }
+}
+
+export function getEncodingStats() {
+  return invoke<EncodingThroughput>("get_encoding_stats")
+}
+
+// The status is also emitted with the "encoding-queue-status" event whenever it changes.
+export function getEncodingQueueStatus() {
+  return invoke<EncodingQueueStatus>("get_encoding_queue_status")
+}
+
+export function pauseEncoding() {
+  return invoke("pause_encoding")
+}
+
+export function resumeEncoding() {
+  return invoke("resume_encoding")
+}
+
+// Cancels the given files, or every queued file if fileIds is omitted.
+export function cancelEncoding(fileIds?: string[]) {
+  return invoke("cancel_encoding", { fileIds: fileIds ?? null })
+}
+
+// Moves the files to the front of the encoding queue, e.g. because they are visible.
+export function prioritizeEncoding(fileIds: FileUuid[]) {
+  return invoke("prioritize_encoding", { fileIds })
+}
+
+export function getEmbeddingModels() {
+  return invoke<EmbeddingModelInfo[]>("get_embedding_models")
+}
+
+// Files not yet encoded with the model are encoded in the background,
+// and don't appear in search results until they are.
+export async function setActiveEmbeddingModel(modelId: string) {
+  try {
+    await invoke("set_active_embedding_model", { modelId })
+  } catch (error) {
+    console.error("Error setting the active embedding model:", error)
+    throw new Error("Failed to set the active embedding model")
+  }
+}
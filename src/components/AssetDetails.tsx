@@ -2,6 +2,27 @@ import { fetchMetadata } from "@/api"
 import useRoverStore from "@/hooks/store"
 import { useEffect, useState } from "react"
 import type FileMetadata from "../interfaces/FileMetadata"
+import type { CameraInfo } from "../interfaces/FileMetadata"
+
+// e.g. "Canon EOS R5"; models often repeat the make, so it isn't repeated here.
+function formatCamera(camera: CameraInfo): string | null {
+  if (camera.make && camera.model?.startsWith(camera.make)) {
+    return camera.model
+  }
+  const parts = [camera.make, camera.model].filter((part) => part !== null)
+  return parts.length > 0 ? parts.join(" ") : null
+}
+
+// e.g. "1/250 s, f/2.8, ISO 400, 50 mm"; null if the photo records none of them.
+function formatExposure(camera: CameraInfo): string | null {
+  const parts = [
+    camera.exposure_time,
+    camera.f_number !== null ? `f/${camera.f_number.toFixed(1)}` : null,
+    camera.iso !== null ? `ISO ${camera.iso}` : null,
+    camera.focal_length_mm !== null ? `${camera.focal_length_mm} mm` : null,
+  ].filter((part) => part !== null)
+  return parts.length > 0 ? parts.join(", ") : null
+}
 
 const AssetDetails: React.FC = () => {
   const [fileMetadata, setFileMetadata] = useState<FileMetadata | null>(null)
@@ -9,6 +30,9 @@ const AssetDetails: React.FC = () => {
   const detailsViewFileUuid = useRoverStore(
     (state) => state.detailsViewFileUuid,
   )
+  const detailsViewTimestampMs = useRoverStore(
+    (state) => state.detailsViewTimestampMs,
+  )
 
   useEffect(() => {
     const fetchData = async () => {
@@ -38,7 +62,17 @@ const AssetDetails: React.FC = () => {
   return (
     <div>
       <div className="flex justify-center p-4">
-        <img src={fileMetadata.thumbnail_filepath} alt="Thumbnail" />
+        {fileMetadata.video_filepath ? (
+          // Opens at the keyframe which matched the search, if any, with a media fragment.
+          <video
+            key={`${fileMetadata.file_id}-${detailsViewTimestampMs ?? 0}`}
+            src={`${fileMetadata.video_filepath}#t=${(detailsViewTimestampMs ?? 0) / 1000}`}
+            poster={fileMetadata.thumbnail_filepath}
+            controls
+          />
+        ) : (
+          <img src={fileMetadata.thumbnail_filepath} alt="Thumbnail" />
+        )}
       </div>
       <div className="flex justify-center">
         <table className="table-auto">
@@ -61,6 +95,36 @@ const AssetDetails: React.FC = () => {
                 </td>
               </tr>
             )}
+            {fileMetadata.duration_ms !== null && (
+              <tr>
+                <th className="text-right pr-4">Duration:</th>
+                <td>{(fileMetadata.duration_ms / 1000).toFixed(1)}s</td>
+              </tr>
+            )}
+            {fileMetadata.camera && formatCamera(fileMetadata.camera) && (
+              <tr>
+                <th className="text-right pr-4">Camera:</th>
+                <td>{formatCamera(fileMetadata.camera)}</td>
+              </tr>
+            )}
+            {fileMetadata.camera?.lens_model && (
+              <tr>
+                <th className="text-right pr-4">Lens:</th>
+                <td>{fileMetadata.camera.lens_model}</td>
+              </tr>
+            )}
+            {fileMetadata.camera && formatExposure(fileMetadata.camera) && (
+              <tr>
+                <th className="text-right pr-4">Exposure:</th>
+                <td>{formatExposure(fileMetadata.camera)}</td>
+              </tr>
+            )}
+            {fileMetadata.camera?.date_taken && (
+              <tr>
+                <th className="text-right pr-4">Taken:</th>
+                <td>{fileMetadata.camera.date_taken}</td>
+              </tr>
+            )}
             {fileMetadata.date_created && (
               <tr>
                 <th className="text-right pr-4">Created:</th>
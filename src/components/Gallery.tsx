@@ -4,7 +4,7 @@ import useRoverStore from "@/hooks/store"
 import type FileUuid from "@/interfaces/FileUuid"
 import { useEffect, useState } from "react"
 import { Masonry } from "react-plock"
-import { fetchThumbnails, searchImages } from "../api"
+import { fetchThumbnails, prioritizeEncoding, searchImages } from "../api"
 import GalleryCard from "./GalleryCard"
 import Thumbnail from "@/interfaces/thumbnail"
 
@@ -33,6 +33,11 @@ export const Gallery: React.FC<GalleryProps> = ({
   const distanceThreshold = 0.85
 
   const [searchResults, setSearchResults] = useState<FileUuid[] | null>(null)
+  // The time of the frame which matched, for results which matched by a frame of an animation or video,
+  // keyed by the file UUID as a string (as thumbnails have it).
+  const [matchedTimestamps, setMatchedTimestamps] = useState<
+    Map<string, number>
+  >(new Map())
 
   const pathPrefixes = useRoverStore((state) => state.pathPrefixes)
   
@@ -46,7 +51,22 @@ export const Gallery: React.FC<GalleryProps> = ({
           efArg,
           distanceThreshold,
         )
-        setSearchResults(result)
+        setSearchResults(result.map((searchResult) => searchResult.file_id))
+        setMatchedTimestamps(
+          new Map(
+            result.flatMap((searchResult): Array<[string, number]> =>
+              searchResult.frame
+                ? [
+                    [
+                      // File UUIDs are serialized as plain strings.
+                      searchResult.file_id as unknown as string,
+                      searchResult.frame.timestamp_ms,
+                    ],
+                  ]
+                : [],
+            ),
+          ),
+        )
       } catch (error) {
         console.error("Error fetching search results:", error)
       }
@@ -63,10 +83,18 @@ export const Gallery: React.FC<GalleryProps> = ({
     return <div className="text-center">No results found</div>
   }
 
-  return searchResults ? <GalleryContent fileUuids={searchResults} /> : null
+  return searchResults ? (
+    <GalleryContent
+      fileUuids={searchResults}
+      matchedTimestamps={matchedTimestamps}
+    />
+  ) : null
 }
 
-const GalleryContent: React.FC<{ fileUuids: FileUuid[] }> = ({ fileUuids }) => {
+const GalleryContent: React.FC<{
+  fileUuids: FileUuid[]
+  matchedTimestamps: Map<string, number>
+}> = ({ fileUuids, matchedTimestamps }) => {
   const [thumbnails, setThumbnails] = useState<Thumbnail[] | null>(null)
 
   useEffect(() => {
@@ -84,6 +112,13 @@ const GalleryContent: React.FC<{ fileUuids: FileUuid[] }> = ({ fileUuids }) => {
     })
   }, [fileUuids])
 
+  useEffect(() => {
+    // Files in view which aren't encoded yet (e.g. when browsing a folder) are encoded first.
+    prioritizeEncoding(fileUuids).catch((error: unknown) => {
+      console.error("Error prioritizing encoding:", error)
+    })
+  }, [fileUuids])
+
   const setDetailsViewFileUuid = useRoverStore(
     (state) => state.setDetailsViewFileUuid,
   )
@@ -106,7 +141,10 @@ const GalleryContent: React.FC<{ fileUuids: FileUuid[] }> = ({ fileUuids }) => {
         <GalleryCard
           imageSrc={item.path}
           onClick={() => {
-            setDetailsViewFileUuid(item.file_uuid)
+            setDetailsViewFileUuid(
+              item.file_uuid,
+              matchedTimestamps.get(item.file_uuid) ?? null,
+            )
           }}
         />
       )}
@@ -2,7 +2,9 @@ import { create } from "zustand"
 
 type RoverStore = {
   detailsViewFileUuid: string
-  setDetailsViewFileUuid: (uuid: string) => void
+  // When the details view shows a video, the time to open it at, e.g. of the keyframe which matched the search.
+  detailsViewTimestampMs: number | null
+  setDetailsViewFileUuid: (uuid: string, timestampMs?: number | null) => void
   clearDetailsViewFileUuid: () => void
 
   // TODO I think we want to instead have a set of IDs corresponding to currently-active-background-tasks.
@@ -24,11 +26,12 @@ type RoverStore = {
 
 const useRoverStore = create<RoverStore>((set) => ({
   detailsViewFileUuid: "",
-  setDetailsViewFileUuid: (uuid) => {
-    set(() => ({ detailsViewFileUuid: uuid }))
+  detailsViewTimestampMs: null,
+  setDetailsViewFileUuid: (uuid, timestampMs = null) => {
+    set(() => ({ detailsViewFileUuid: uuid, detailsViewTimestampMs: timestampMs }))
   },
   clearDetailsViewFileUuid: () => {
-    set(() => ({ detailsViewFileUuid: "" }))
+    set(() => ({ detailsViewFileUuid: "", detailsViewTimestampMs: null }))
   },
   fsEventStatus: "rover-analyzer",
   setFsEventStatus: (status) => {
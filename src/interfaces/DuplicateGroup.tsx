@@ -0,0 +1,18 @@
+// Should be kept in synch with the Rust DuplicateFile struct.
+export type DuplicateFile = {
+  file_id: string
+  filepath: string
+  file_size: number | null
+  size: {
+    width: number
+    height: number
+  } | null
+}
+
+// Should be kept in synch with the Rust DuplicateGroup struct.
+type DuplicateGroup = {
+  group_id: string
+  files: DuplicateFile[]
+}
+
+export default DuplicateGroup
@@ -0,0 +1,10 @@
+// Should be kept in synch with the Rust EmbeddingModelInfo struct.
+type EmbeddingModelInfo = {
+  id: string
+  name: string
+  feature_vector_length: number
+  installed: boolean
+  active: boolean
+}
+
+export default EmbeddingModelInfo
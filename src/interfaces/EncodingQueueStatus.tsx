@@ -0,0 +1,7 @@
+// Should be kept in synch with the Rust EncodingQueueStatus struct.
+type EncodingQueueStatus = {
+  queued: number
+  paused: boolean
+}
+
+export default EncodingQueueStatus
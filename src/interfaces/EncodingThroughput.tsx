@@ -0,0 +1,19 @@
+// Should be kept in synch with the Rust EncodingStats struct.
+type EncodingStats = {
+  images: number
+  failed: number
+  regions: number
+  frames: number
+  decode_seconds: number
+  inference_seconds: number
+  elapsed_seconds: number
+  images_per_second: number
+}
+
+// Should be kept in synch with the Rust EncodingThroughput struct.
+type EncodingThroughput = {
+  last_run: EncodingStats | null
+  total: EncodingStats
+}
+
+export default EncodingThroughput
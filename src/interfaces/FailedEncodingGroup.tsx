@@ -0,0 +1,26 @@
+// Should be kept in synch with the Rust FailedEncodingKind enum.
+export type FailedEncodingKind =
+  | "not_found"
+  | "unsupported"
+  | "decoding"
+  | "limits"
+  | "io"
+  | "encoding"
+  | "other"
+
+// Should be kept in synch with the Rust FailedEncoding struct.
+export type FailedEncoding = {
+  file_id: string
+  filepath: string
+  error: string
+  // In UTC.
+  failed_at: string
+}
+
+// Should be kept in synch with the Rust FailedEncodingGroup struct.
+type FailedEncodingGroup = {
+  kind: FailedEncodingKind
+  files: FailedEncoding[]
+}
+
+export default FailedEncodingGroup
@@ -1,3 +1,15 @@
+// Should be kept in synch with the Rust CameraInfo struct.
+export type CameraInfo = {
+  make: string | null
+  model: string | null
+  lens_model: string | null
+  date_taken: string | null
+  exposure_time: string | null
+  f_number: number | null
+  iso: number | null
+  focal_length_mm: number | null
+}
+
 // Should be kept in synch with the Rust FileMetadata struct.
 type FileMetadata = {
   file_id: string
@@ -10,6 +22,13 @@ type FileMetadata = {
   } | null
   date_created: string | null
   date_modified: string | null
+  // Set for animated images and videos only.
+  frame_count: number | null
+  duration_ms: number | null
+  // Set for videos only.
+  video_filepath: string | null
+  // Set for photos which record their camera in their EXIF data.
+  camera: CameraInfo | null
 }
 
 export default FileMetadata
@@ -0,0 +1,6 @@
+// Should be kept in synch with the Rust RetrySummary struct.
+type RetrySummary = {
+  retried: number
+}
+
+export default RetrySummary
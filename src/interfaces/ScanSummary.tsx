@@ -0,0 +1,9 @@
+// Should be kept in synch with the Rust ScanSummary struct.
+type ScanSummary = {
+  // The new files, which were added to the database and queued for encoding.
+  added: number
+  // The new files which weren't added, since they aren't images (or aren't in the directory's extensions).
+  skipped: number
+}
+
+export default ScanSummary
@@ -0,0 +1,28 @@
+import type FileUuid from "./FileUuid"
+
+// A rectangle within an image, in pixels of the image.
+// Should be kept in synch with the Rust Region struct.
+export type Region = {
+  x: number
+  y: number
+  width: number
+  height: number
+}
+
+// A frame of an animation or keyframe of a video, and when it is shown, in milliseconds from the start.
+// Should be kept in synch with the Rust Frame struct.
+export type Frame = {
+  index: number
+  timestamp_ms: number
+}
+
+// Should be kept in synch with the Rust SearchResult struct.
+type SearchResult = {
+  file_id: FileUuid
+  // The region of the image which matched, if one matched better than the whole image.
+  region: Region | null
+  // The frame of the animation or video which matched, if one matched better than its first frame.
+  frame: Frame | null
+}
+
+export default SearchResult